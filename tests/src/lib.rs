@@ -0,0 +1,275 @@
+//! End-to-end tests of the relay, running a `tuic-server` and a `tuic-client`
+//! in-process on loopback
+//!
+//! The client relays through a process-wide connection pool, so each test
+//! binary in `tests/` starts a single [`Harness`] with the options it covers,
+//! and its tests share it. Tests run their body with [`block_on()`], on the
+//! runtime the server and the client are spawned on
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    future::Future,
+    io::{Error as IoError, ErrorKind},
+    net::SocketAddr,
+    path::PathBuf,
+    process,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use serde_json::json;
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    runtime::{Builder, Runtime},
+    sync::OnceCell,
+    time,
+};
+use tuic::Address;
+use tuic_server::{
+    AppContext, Config, Server,
+    config::{QuicConfig, TlsConfig},
+};
+use uuid::Uuid;
+
+const UUID: Uuid = Uuid::from_u128(0x6a0a_5f3e_2c1d_4b7a_9e8f_0d1c_2b3a_4958);
+const PASSWORD: &str = "tuic-tests";
+
+/// How long the server keeps a connection with no traffic open. Kept short so
+/// tests can have the client reconnect, see [`wait_idle()`]
+const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a round trip through the relay may take before failing
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+static RUNTIME: LazyLock<Runtime> = LazyLock::new(|| {
+    Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build the test runtime")
+});
+
+static HARNESS: OnceCell<Harness> = OnceCell::const_new();
+
+/// What the server and the client of a test binary are set up with
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    /// `udp_relay_mode` of the client, `native`, `quic` or `auto`
+    pub udp_relay_mode: &'static str,
+    /// Whether both ends enable the 0-RTT handshake
+    pub zero_rtt: bool,
+    /// Whether the client authenticates with a password the server does not
+    /// know
+    pub wrong_password: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            udp_relay_mode: "native",
+            zero_rtt: false,
+            wrong_password: false,
+        }
+    }
+}
+
+/// A server and a client relaying to echo servers, all on loopback
+pub struct Harness {
+    pub server: SocketAddr,
+    pub tcp_echo: SocketAddr,
+    pub udp_echo: SocketAddr,
+    ctx: Arc<AppContext>,
+}
+
+/// Runs `fut` to completion on the runtime shared by the tests of the binary
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    RUNTIME.block_on(fut)
+}
+
+/// Starts the harness of the test binary on first call, returning it. Later
+/// calls return the same harness, whatever `opts`
+pub async fn start(opts: Options) -> &'static Harness {
+    HARNESS
+        .get_or_init(|| async {
+            Harness::init(opts)
+                .await
+                .unwrap_or_else(|err| panic!("failed to start the harness: {err}"))
+        })
+        .await
+}
+
+/// Waits for the server to close the idle connection of the client, so the
+/// next relay reconnects, resuming the TLS session
+pub async fn wait_idle() {
+    time::sleep(IDLE_TIMEOUT * 2).await;
+}
+
+/// Returns `len` bytes of a pattern not repeating every power of 2, so
+/// misordered fragments show
+pub fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+impl Harness {
+    async fn init(opts: Options) -> Result<Self, Box<dyn std::error::Error>> {
+        let (cert, key) = write_cert()?;
+
+        let cfg = Config {
+            server: "127.0.0.1:0".parse().unwrap(),
+            users: HashMap::from([(UUID, PASSWORD.to_owned())]),
+            tls: TlsConfig {
+                certificate: cert.clone(),
+                private_key: key,
+                ..Default::default()
+            },
+            quic: QuicConfig {
+                max_idle_time: IDLE_TIMEOUT,
+                ..Default::default()
+            },
+            zero_rtt_handshake: opts.zero_rtt,
+            dual_stack: false,
+            max_external_packet_size: 65535,
+            ..Default::default()
+        };
+
+        let ctx = Arc::new(AppContext::new(cfg, None)?);
+        let server = Box::leak(Box::new(Server::init(ctx.clone()).await?));
+        let server_addr = server.local_addr()?;
+        tokio::spawn(server.start());
+
+        let password = if opts.wrong_password {
+            "not-the-password"
+        } else {
+            PASSWORD
+        };
+
+        // heartbeats keep the connection open while tasks are running
+        let relay = serde_json::from_value(json!({
+            "server": format!("localhost:{port}", port = server_addr.port()),
+            "ip": "127.0.0.1",
+            "uuid": UUID.to_string(),
+            "password": password,
+            "certificates": [cert],
+            "disable_native_certs": true,
+            "udp_relay_mode": opts.udp_relay_mode,
+            "zero_rtt_handshake": opts.zero_rtt,
+            "heartbeat": "250ms",
+        }))?;
+        tuic_client::init(relay).await?;
+
+        Ok(Self {
+            server: server_addr,
+            tcp_echo: spawn_tcp_echo().await?,
+            udp_echo: spawn_udp_echo().await?,
+            ctx,
+        })
+    }
+
+    /// Returns the number of authentications the server received in 0-RTT
+    /// early data
+    pub fn zero_rtt_authentications(&self) -> u64 {
+        self.ctx.replay_cache.zero_rtt()
+    }
+
+    /// Sends `data` to the TCP echo server through the relay, returning as
+    /// many bytes echoed back
+    ///
+    /// The stream is only shut down once everything is echoed, as the server
+    /// ends the relay as soon as either side closes
+    pub async fn tcp_round_trip(&self, data: &[u8]) -> Result<Vec<u8>, IoError> {
+        let conn = tuic_client::connect_tcp(Address::SocketAddress(self.tcp_echo))
+            .await
+            .map_err(IoError::other)?;
+        let (mut recv, mut send) = io::split(conn);
+
+        let write = send.write_all(data);
+        let read = async {
+            let mut buf = vec![0; data.len()];
+            recv.read_exact(&mut buf).await.map(|_| buf)
+        };
+
+        let (written, echoed) = time::timeout(TIMEOUT, async { tokio::join!(write, read) })
+            .await
+            .map_err(|_| IoError::from(ErrorKind::TimedOut))?;
+        written?;
+        let echoed = echoed?;
+        send.shutdown().await?;
+        Ok(echoed)
+    }
+
+    /// Sends `data` in a packet to the UDP echo server through the relay, on
+    /// a new association, returning the packet echoed back
+    pub async fn udp_round_trip(&self, data: &[u8]) -> Result<Bytes, IoError> {
+        let mut assoc = tuic_client::udp_associate().await.map_err(IoError::other)?;
+        let addr = Address::SocketAddress(self.udp_echo);
+
+        assoc
+            .send_to(Bytes::copy_from_slice(data), addr.clone())
+            .await
+            .map_err(IoError::other)?;
+
+        let (pkt, from) = time::timeout(TIMEOUT, assoc.recv_from())
+            .await
+            .map_err(|_| IoError::from(ErrorKind::TimedOut))?
+            .ok_or(ErrorKind::ConnectionAborted)?;
+
+        if from != addr {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                format!("packet echoed from {from}, expected {addr}"),
+            ));
+        }
+
+        Ok(pkt)
+    }
+}
+
+/// Generates a certificate for `localhost`, writing it and its key as PEM
+/// files in a directory of the process
+fn write_cert() -> Result<(PathBuf, PathBuf), Box<dyn std::error::Error>> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+
+    let dir = env::temp_dir().join(format!("tuic-tests-{pid}", pid = process::id()));
+    fs::create_dir_all(&dir)?;
+
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    fs::write(&cert_path, cert.cert.pem())?;
+    fs::write(&key_path, cert.key_pair.serialize_pem())?;
+
+    Ok((cert_path, key_path))
+}
+
+async fn spawn_tcp_echo() -> Result<SocketAddr, IoError> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut recv, mut send) = stream.into_split();
+                if io::copy(&mut recv, &mut send).await.is_ok() {
+                    let _ = send.shutdown().await;
+                }
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+async fn spawn_udp_echo() -> Result<SocketAddr, IoError> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let addr = socket.local_addr()?;
+
+    tokio::spawn(async move {
+        let mut buf = vec![0; 65535];
+        while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+            let _ = socket.send_to(&buf[..len], from).await;
+        }
+    });
+
+    Ok(addr)
+}
@@ -0,0 +1,34 @@
+use tuic_tests::{Options, block_on, payload, start};
+
+const OPTIONS: Options = Options {
+    udp_relay_mode: "native",
+    zero_rtt: false,
+    wrong_password: true,
+};
+
+#[test]
+fn refuses_tcp_with_wrong_password() {
+    block_on(async {
+        let harness = start(OPTIONS).await;
+        let data = payload(1024);
+
+        // the stream may be opened before the server closes the connection,
+        // but nothing is relayed on it
+        assert!(
+            harness.tcp_round_trip(&data).await.is_err(),
+            "relayed with a wrong password"
+        );
+    });
+}
+
+#[test]
+fn refuses_udp_with_wrong_password() {
+    block_on(async {
+        let harness = start(OPTIONS).await;
+        let data = payload(512);
+        assert!(
+            harness.udp_round_trip(&data).await.is_err(),
+            "relayed with a wrong password"
+        );
+    });
+}
@@ -0,0 +1,38 @@
+use tuic_tests::{Options, block_on, payload, start};
+
+#[test]
+fn relays_tcp() {
+    block_on(async {
+        let harness = start(Options::default()).await;
+        let data = payload(1024);
+        assert_eq!(harness.tcp_round_trip(&data).await.unwrap(), data);
+    });
+}
+
+#[test]
+fn relays_large_tcp_stream() {
+    block_on(async {
+        let harness = start(Options::default()).await;
+        let data = payload(4 * 1024 * 1024);
+        assert_eq!(harness.tcp_round_trip(&data).await.unwrap(), data);
+    });
+}
+
+#[test]
+fn relays_concurrent_tcp_streams() {
+    block_on(async {
+        let harness = start(Options::default()).await;
+        let data = payload(64 * 1024);
+
+        let tasks = (0..16)
+            .map(|_| {
+                let data = data.clone();
+                tokio::spawn(async move { harness.tcp_round_trip(&data).await })
+            })
+            .collect::<Vec<_>>();
+
+        for task in tasks {
+            assert_eq!(task.await.unwrap().unwrap(), data);
+        }
+    });
+}
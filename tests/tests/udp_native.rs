@@ -0,0 +1,27 @@
+use tuic_tests::{Options, block_on, payload, start};
+
+const OPTIONS: Options = Options {
+    udp_relay_mode: "native",
+    zero_rtt: false,
+    wrong_password: false,
+};
+
+#[test]
+fn relays_udp() {
+    block_on(async {
+        let harness = start(OPTIONS).await;
+        let data = payload(512);
+        assert_eq!(harness.udp_round_trip(&data).await.unwrap(), data);
+    });
+}
+
+#[test]
+fn relays_fragmented_udp() {
+    block_on(async {
+        let harness = start(OPTIONS).await;
+        // larger than any datagram on a 1200 bytes MTU path, so fragmented
+        // both ways
+        let data = payload(8000);
+        assert_eq!(harness.udp_round_trip(&data).await.unwrap(), data);
+    });
+}
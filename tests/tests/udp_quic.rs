@@ -0,0 +1,25 @@
+use tuic_tests::{Options, block_on, payload, start};
+
+const OPTIONS: Options = Options {
+    udp_relay_mode: "quic",
+    zero_rtt: false,
+    wrong_password: false,
+};
+
+#[test]
+fn relays_udp() {
+    block_on(async {
+        let harness = start(OPTIONS).await;
+        let data = payload(512);
+        assert_eq!(harness.udp_round_trip(&data).await.unwrap(), data);
+    });
+}
+
+#[test]
+fn relays_large_udp() {
+    block_on(async {
+        let harness = start(OPTIONS).await;
+        let data = payload(8000);
+        assert_eq!(harness.udp_round_trip(&data).await.unwrap(), data);
+    });
+}
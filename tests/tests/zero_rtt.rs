@@ -0,0 +1,33 @@
+use tuic_tests::{Options, block_on, payload, start, wait_idle};
+
+const OPTIONS: Options = Options {
+    udp_relay_mode: "native",
+    zero_rtt: true,
+    wrong_password: false,
+};
+
+#[test]
+fn relays_after_0rtt_reconnect() {
+    block_on(async {
+        let harness = start(OPTIONS).await;
+        let data = payload(1024);
+
+        // the first connection has no session to resume
+        assert_eq!(harness.tcp_round_trip(&data).await.unwrap(), data);
+        assert_eq!(harness.udp_round_trip(&data).await.unwrap(), data);
+
+        // the next ones are resumed, sending their first commands as early
+        // data
+        for _ in 0..2 {
+            wait_idle().await;
+            assert_eq!(harness.tcp_round_trip(&data).await.unwrap(), data);
+            wait_idle().await;
+            assert_eq!(harness.udp_round_trip(&data).await.unwrap(), data);
+        }
+
+        assert!(
+            harness.zero_rtt_authentications() > 0,
+            "no authentication was received in 0-RTT early data"
+        );
+    });
+}
@@ -0,0 +1,52 @@
+use std::sync::Arc;
+
+use anyhow::{Context, anyhow};
+
+use crate::{
+    config::Config,
+    error::Error,
+    fake_ip::FakeIp,
+    utils::{self, ServerAddr},
+};
+
+/// Validates the parts of the configuration that can only be checked at
+/// runtime, without binding any socket. Used by `--check`
+pub async fn check(cfg: &Config) -> Result<(), Error> {
+    utils::load_certs(
+        cfg.relay.certificates.clone(),
+        cfg.relay.disable_native_certs,
+    )
+    .context("failed to load certificates in `relay.certificates`")?;
+
+    if let Some(dial_via) = &cfg.relay.dial_via
+        && [&dial_via.username, &dial_via.password]
+            .into_iter()
+            .flatten()
+            .any(|cred| cred.len() > u8::MAX as usize)
+    {
+        return Err(Error::DialViaCredentialsTooLong);
+    }
+
+    if let Some(range) = cfg.dns_inbound.as_ref().and_then(|dns| dns.fake_ip) {
+        FakeIp::check(range)?;
+    }
+
+    let resolver = utils::build_resolver(&cfg.relay.dns)
+        .context("invalid `relay.dns` config")?
+        .map(Arc::new);
+
+    for (idx, (domain, port)) in cfg.relay.server.iter().enumerate() {
+        let server = ServerAddr::new(
+            domain.clone(),
+            *port,
+            cfg.relay.ip.filter(|_| idx == 0),
+            resolver.clone(),
+        );
+
+        if server.resolve().await?.next().is_none() {
+            return Err(Error::Other(anyhow!("cannot resolve server `{server}`")));
+        }
+    }
+
+    Ok(())
+}
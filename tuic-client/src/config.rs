@@ -1,32 +1,53 @@
 use std::{
-    env::ArgsOs,
+    collections::HashMap,
+    ffi::OsString,
     fmt::Display,
-    fs::File,
+    fs::{self, File},
     io::{BufReader, Error as IoError},
     net::{IpAddr, SocketAddr},
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::FromStr,
     sync::Arc,
     time::Duration,
 };
 
 use humantime::Duration as HumanDuration;
-use lexopt::{Arg, Error as ArgumentError, Parser};
-use log::LevelFilter;
-use serde::{Deserialize, Deserializer, de::Error as DeError};
-use serde_json::Error as SerdeError;
+use lexopt::{Arg, Error as ArgumentError, Parser, ValueExt};
+use serde::{
+    Deserialize, Deserializer,
+    de::{DeserializeOwned, Error as DeError},
+};
+use serde_json::{Error as SerdeError, Map, Value, json};
 use thiserror::Error;
+use tuic::{ShareLink, ShareLinkError};
 use uuid::Uuid;
 
-use crate::utils::{CongestionControl, UdpRelayMode};
+use crate::utils::{
+    CongestionControl, DnsProtocol, IpCidr, LogFilter, LogFormat, LogRotation, MultipathMode,
+    PortRange, Resolve, UdpRelayMode,
+};
 
 const HELP_MSG: &str = r#"
 Usage tuic-client [arguments]
 
 Arguments:
-    -c, --config <path>     Path to the config file (required)
+    -c, --config <path>     Path to the config file (required unless --url is given)
+    -u, --url <link>        Use the server in a tuic:// share link, on top of the config file if any
+    -f, --format <format>   Format of the config file: json, toml or yaml
+                            Default: detected from the file extension, or json
+    --check                 Validate the configuration and resolve the server, then exit
+    --speedtest <MB>        Measure the latency and throughput to the server, uploading then
+                            downloading <MB> megabytes through the relay, then exit
+    --nat-check             Report the NAT type of the UDP traffic relayed by the server, probing
+                            two STUN servers through the relay, then exit
+    --set <key>=<value>     Override a config field, can be repeated
+                            e.g. --set relay.server=example.com:443 --set local.listen=[::]:1080
     -v, --version           Print the version
     -h, --help              Print this help message
+
+Windows only:
+    --install-service       Install as a Windows service, using the given config file
+    --uninstall-service     Stop and uninstall the Windows service
 "#;
 
 #[derive(Deserialize)]
@@ -36,15 +57,88 @@ pub struct Config {
 
     pub local: Local,
 
-    #[serde(default = "default::log_level")]
-    pub log_level: LevelFilter,
+    pub dns_inbound: Option<DnsInbound>,
+
+    pub stats: Option<Stats>,
+
+    #[serde(
+        default = "default::log_level",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub log_level: LogFilter,
+
+    #[serde(
+        default = "default::log_format",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub log_format: LogFormat,
+
+    pub log_file: Option<LogFile>,
+
+    #[serde(default)]
+    pub runtime: Runtime,
+
+    /// Set by `--check`, only validate the configuration and exit
+    #[serde(skip)]
+    pub check: bool,
+
+    /// Set by `--speedtest`, only measure the relay with this many bytes and
+    /// exit
+    #[serde(skip)]
+    pub speedtest: Option<u64>,
+
+    /// Set by `--nat-check`, only report the NAT type of the relayed UDP
+    /// traffic and exit
+    #[serde(skip)]
+    pub nat_check: bool,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Runtime {
+    /// Number of worker threads, 0 for one per CPU core
+    #[serde(default)]
+    pub worker_threads: usize,
+
+    /// CPU cores the runtime threads are pinned to in turn, empty to not pin
+    #[serde(default)]
+    pub cpu_affinity: Vec<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogFile {
+    pub path: PathBuf,
+
+    #[serde(
+        default = "default::log_file::rotation",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub rotation: LogRotation,
+
+    #[serde(default = "default::log_file::max_size")]
+    pub max_size: u64,
+
+    #[serde(default = "default::log_file::max_files")]
+    pub max_files: usize,
+}
+
+impl LogFile {
+    pub fn open(&self) -> Result<tuic_common::log_file::LogFile, IoError> {
+        tuic_common::log_file::LogFile::open(
+            &self.path,
+            self.rotation,
+            self.max_size,
+            self.max_files,
+        )
+    }
 }
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Relay {
-    #[serde(deserialize_with = "deserialize_server")]
-    pub server: (String, u16),
+    #[serde(deserialize_with = "deserialize_servers")]
+    pub server: Vec<(String, u16)>,
 
     pub uuid: Uuid,
 
@@ -62,18 +156,35 @@ pub struct Relay {
     )]
     pub udp_relay_mode: UdpRelayMode,
 
+    #[serde(default = "default::relay::udp_relay_rules")]
+    pub udp_relay_rules: Vec<UdpRelayRule>,
+
     #[serde(
         default = "default::relay::congestion_control",
         deserialize_with = "deserialize_from_str"
     )]
     pub congestion_control: CongestionControl,
 
+    #[serde(default)]
+    pub congestion: Congestion,
+
     #[serde(
         default = "default::relay::alpn",
         deserialize_with = "deserialize_alpn"
     )]
     pub alpn: Vec<Vec<u8>>,
 
+    /// Put in front of the UUID in the TLS exporter label the authentication
+    /// token is derived with. Empty keeps the protocol default, must match
+    /// the server
+    #[serde(default)]
+    pub exporter_label: String,
+
+    /// Put in front of the password in the TLS exporter context the
+    /// authentication token is derived with, like `exporter_label`
+    #[serde(default)]
+    pub exporter_context: String,
+
     #[serde(default = "default::relay::zero_rtt_handshake")]
     pub zero_rtt_handshake: bool,
 
@@ -92,6 +203,14 @@ pub struct Relay {
     )]
     pub heartbeat: Duration,
 
+    /// Upper bound the heartbeat interval backs off to while the connection
+    /// is active, zero keeping it fixed
+    #[serde(
+        default = "default::relay::heartbeat_max",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub heartbeat_max: Duration,
+
     #[serde(default = "default::relay::disable_native_certs")]
     pub disable_native_certs: bool,
 
@@ -113,6 +232,29 @@ pub struct Relay {
     #[serde(default = "default::relay::pmtu")]
     pub pmtu: bool,
 
+    #[serde(default)]
+    pub send_buffer_size: Option<usize>,
+
+    #[serde(default)]
+    pub recv_buffer_size: Option<usize>,
+
+    /// Network interface the endpoint socket is bound to (`SO_BINDTODEVICE`)
+    #[serde(default)]
+    pub bind_interface: Option<String>,
+
+    /// Firewall mark set on the endpoint socket (`SO_MARK`)
+    #[serde(default)]
+    pub fwmark: Option<u32>,
+
+    /// DSCP value of the QUIC packets
+    #[serde(default)]
+    pub dscp: Option<u8>,
+
+    /// An already bound UDP socket to use as the endpoint socket, e.g. created
+    /// by an Android app and protected from its VPN. Unix only
+    #[serde(default)]
+    pub socket_fd: Option<i32>,
+
     #[serde(
         default = "default::relay::gc_interval",
         deserialize_with = "deserialize_duration"
@@ -125,31 +267,319 @@ pub struct Relay {
     )]
     pub gc_lifetime: Duration,
 
+    #[serde(default = "default::relay::max_pending_packets")]
+    pub max_pending_packets: usize,
+
+    #[serde(default = "default::relay::max_reassembly_memory")]
+    pub max_reassembly_memory: usize,
+
+    #[serde(default = "default::relay::reorder_window")]
+    pub reorder_window: u16,
+
+    pub padding: Option<Padding>,
+
+    pub compression: Option<Compression>,
+
+    pub multipath: Option<Multipath>,
+
+    #[serde(default = "default::relay::connect_status")]
+    pub connect_status: bool,
+
+    #[serde(default = "default::relay::eager_connect")]
+    pub eager_connect: bool,
+
+    #[serde(default = "default::relay::transport")]
+    pub transport: Transport,
+
     #[serde(default = "default::relay::skip_cert_verify")]
     pub skip_cert_verify: bool,
+
+    pub dial_via: Option<DialVia>,
+
+    #[serde(default = "default::relay::dns")]
+    pub dns: Vec<DnsServer>,
+
+    #[serde(default = "default::relay::connections")]
+    pub connections: usize,
+
+    #[serde(default = "default::relay::failover_threshold")]
+    pub failover_threshold: u32,
+
+    #[serde(
+        default = "default::relay::primary_retry_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub primary_retry_interval: Duration,
+
+    /// How often to probe the connections in use when multiple servers are
+    /// set, zero disabling it
+    #[serde(
+        default = "default::relay::health_check_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub health_check_interval: Duration,
+
+    /// How often to check whether the local address used to reach the server
+    /// changed, zero disabling it
+    #[serde(
+        default = "default::relay::network_check_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub network_check_interval: Duration,
+
+    #[serde(default = "default::relay::reconnect")]
+    pub reconnect: Reconnect,
 }
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct Local {
+pub struct Padding {
+    #[serde(default = "default::padding::threshold")]
+    pub threshold: usize,
+
+    #[serde(default = "default::padding::max_len")]
+    pub max_len: u16,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Compression {
+    #[serde(default = "default::compression::level")]
+    pub level: i32,
+}
+
+/// Parameters of the congestion controller, `None` keeping the defaults of
+/// quinn
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Congestion {
+    #[serde(default)]
+    pub initial_window: Option<u64>,
+
+    /// Only used by `new_reno`
+    #[serde(default)]
+    pub loss_reduction_factor: Option<f32>,
+
+    /// Sending rate in Mbit/s, required by `brutal`
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Transport {
+    #[serde(default)]
+    pub connection_receive_window: Option<u64>,
+
+    #[serde(default = "default::transport::max_concurrent_bi_streams")]
+    pub max_concurrent_bi_streams: u32,
+
+    #[serde(default = "default::transport::max_concurrent_uni_streams")]
+    pub max_concurrent_uni_streams: u32,
+
+    #[serde(
+        default = "default::transport::initial_rtt",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub initial_rtt: Duration,
+
+    #[serde(
+        default = "default::transport::keep_alive_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub keep_alive_interval: Duration,
+
+    #[serde(default = "default::transport::datagram_receive_buffer")]
+    pub datagram_receive_buffer: usize,
+
+    #[serde(default = "default::transport::datagram_send_buffer")]
+    pub datagram_send_buffer: usize,
+}
+
+/// Backoff between the attempts to reconnect to an unreachable server
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Reconnect {
+    #[serde(
+        default = "default::reconnect::initial_delay",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub initial_delay: Duration,
+
+    #[serde(default = "default::reconnect::multiplier")]
+    pub multiplier: f64,
+
+    #[serde(
+        default = "default::reconnect::max_delay",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub max_delay: Duration,
+
+    /// Fraction of the delay it is randomly shortened or lengthened by
+    #[serde(default = "default::reconnect::jitter")]
+    pub jitter: f64,
+
+    /// Failed attempts in a row before a relay task gives up
+    #[serde(default = "default::reconnect::max_failures")]
+    pub max_failures: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UdpRelayRule {
+    #[serde(default, deserialize_with = "deserialize_optional_from_str")]
+    pub port: Option<PortRange>,
+
+    pub domain: Option<String>,
+
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub mode: UdpRelayMode,
+}
+
+/// Sends `native` mode UDP packets over a second network path as well
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Multipath {
+    /// Network interface of the second path
+    pub interface: String,
+
+    #[serde(
+        default = "default::multipath::mode",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub mode: MultipathMode,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DialVia {
     pub server: SocketAddr,
 
+    pub username: Option<String>,
+
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DnsServer {
+    pub address: SocketAddr,
+
+    #[serde(
+        default = "default::dns_server::protocol",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub protocol: DnsProtocol,
+
+    pub tls_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Local {
+    #[serde(alias = "server", deserialize_with = "deserialize_listen")]
+    pub listen: Vec<SocketAddr>,
+
     #[serde(deserialize_with = "deserialize_optional_bytes", default)]
     pub username: Option<Vec<u8>>,
 
     #[serde(deserialize_with = "deserialize_optional_bytes", default)]
     pub password: Option<Vec<u8>>,
 
+    #[serde(default = "default::local::users")]
+    pub users: HashMap<String, String>,
+
+    #[serde(default = "default::local::mixed")]
+    pub mixed: bool,
+
     pub dual_stack: Option<bool>,
 
-    #[serde(default = "default::local::max_packet_size")]
-    pub max_packet_size: usize,
+    /// Sized for the UDP relay mode if not set, see `socks5::Server`
+    pub max_packet_size: Option<usize>,
+
+    pub udp_bind_ip: Option<IpAddr>,
+
+    #[serde(default, deserialize_with = "deserialize_optional_from_str")]
+    pub udp_port_range: Option<PortRange>,
+
+    pub udp_advertise_ip: Option<IpAddr>,
+
+    #[serde(
+        default = "default::local::allowed_ips",
+        deserialize_with = "deserialize_vec_from_str"
+    )]
+    pub allowed_ips: Vec<IpCidr>,
+
+    #[serde(default = "default::local::max_udp_associations")]
+    pub max_udp_associations: usize,
+
+    #[serde(
+        default = "default::local::udp_idle_timeout",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub udp_idle_timeout: Duration,
+
+    #[serde(default = "default::local::dissociate_on_close")]
+    pub dissociate_on_close: bool,
+
+    #[serde(
+        default = "default::local::udp_fragment_timeout",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub udp_fragment_timeout: Duration,
+
+    #[serde(
+        default = "default::local::resolve",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub resolve: Resolve,
+
+    #[serde(default = "default::local::resolve_rules")]
+    pub resolve_rules: Vec<ResolveRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResolveRule {
+    pub domain: String,
+
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub resolve: Resolve,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DnsInbound {
+    pub listen: SocketAddr,
+
+    pub upstream: SocketAddr,
+
+    #[serde(
+        default = "default::dns_inbound::timeout",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub timeout: Duration,
+
+    #[serde(default, deserialize_with = "deserialize_optional_from_str")]
+    pub fake_ip: Option<IpCidr>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Stats {
+    pub listen: SocketAddr,
 }
 
 impl Config {
-    pub fn parse(args: ArgsOs) -> Result<Self, ConfigError> {
+    pub fn parse(args: impl IntoIterator<Item = OsString>) -> Result<Self, ConfigError> {
         let mut parser = Parser::from_iter(args);
         let mut path = None;
+        let mut format = None;
+        let mut link = None;
+        let mut overrides = Vec::new();
+        let mut check = false;
+        let mut speedtest = None;
+        let mut nat_check = false;
 
         while let Some(arg) = parser.next()? {
             match arg {
@@ -160,6 +590,31 @@ impl Config {
                         return Err(ConfigError::Argument(arg.unexpected()));
                     }
                 }
+                Arg::Short('f') | Arg::Long("format") => {
+                    format = Some(parser.value()?.parse::<ConfigFormat>()?);
+                }
+                Arg::Short('u') | Arg::Long("url") => {
+                    link = Some(parser.value()?.string()?.parse::<ShareLink>()?);
+                }
+                Arg::Long("check") => check = true,
+                Arg::Long("speedtest") => {
+                    let mb: u64 = parser.value()?.parse()?;
+                    speedtest = Some(mb * 1_000_000);
+                }
+                Arg::Long("nat-check") => nat_check = true,
+                Arg::Long("set") => {
+                    let value = parser.value()?.string()?;
+                    match value.split_once('=') {
+                        Some((key, value)) => {
+                            // parsed as JSON if possible, so booleans, numbers and
+                            // lists can be given, otherwise used as a string
+                            let value = serde_json::from_str(value)
+                                .unwrap_or_else(|_| Value::String(value.to_owned()));
+                            overrides.push((key.to_owned(), value));
+                        }
+                        None => return Err(ConfigError::InvalidOverride(value)),
+                    }
+                }
                 Arg::Short('v') | Arg::Long("version") => {
                     return Err(ConfigError::Version(env!("CARGO_PKG_VERSION")));
                 }
@@ -168,23 +623,164 @@ impl Config {
             }
         }
 
-        if path.is_none() {
+        if path.is_none() && link.is_none() {
             return Err(ConfigError::NoConfig);
         }
 
-        let file = File::open(path.unwrap())?;
-        let reader = BufReader::new(file);
-        Ok(serde_json::from_reader(reader)?)
+        let path = path.map(PathBuf::from);
+        let format = format
+            .or_else(|| path.as_deref().and_then(ConfigFormat::from_path))
+            .unwrap_or(ConfigFormat::Json);
+
+        // `--set` takes precedence over the share link
+        if let Some(link) = link {
+            overrides.splice(0..0, share_link_overrides(link));
+        }
+
+        let mut config: Self = match path {
+            Some(path) if overrides.is_empty() => format.read(&path)?,
+            path => {
+                // the overrides are applied to the untyped config, so they are
+                // checked just like the fields from the file
+                let mut config = match path {
+                    Some(path) => format.read(&path)?,
+                    None => json!({ "local": { "listen": "127.0.0.1:1080" } }),
+                };
+
+                for (key, value) in overrides {
+                    set_value(&mut config, &key, value)?;
+                }
+
+                serde_json::from_value(config)?
+            }
+        };
+
+        config.check = check;
+        config.speedtest = speedtest;
+        config.nat_check = nat_check;
+        Ok(config)
+    }
+}
+
+/// Sets the field at the dotted `key` path, creating the missing sections
+fn set_value(config: &mut Value, key: &str, value: Value) -> Result<(), ConfigError> {
+    let mut target = config;
+
+    for field in key.split('.') {
+        let Value::Object(section) = target else {
+            return Err(ConfigError::InvalidOverride(key.to_owned()));
+        };
+
+        target = section
+            .entry(field)
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+
+    *target = value;
+    Ok(())
+}
+
+/// Converts a share link into overrides of the `relay` section
+///
+/// The SNI in the link is used as the server name when the host is an IP
+/// address, which is then set as `relay.ip`.
+fn share_link_overrides(link: ShareLink) -> Vec<(String, Value)> {
+    let mut overrides = Vec::new();
+    let mut set = |key: &str, value: Value| overrides.push((format!("relay.{key}"), value));
+
+    match (link.sni, link.host.parse::<IpAddr>()) {
+        (Some(sni), Ok(ip)) => {
+            set("server", json!(format!("{sni}:{}", link.port)));
+            set("ip", json!(ip));
+        }
+        _ => set("server", json!(format!("{}:{}", link.host, link.port))),
+    }
+
+    set("uuid", json!(link.uuid));
+    set("password", json!(link.password));
+
+    if !link.alpn.is_empty() {
+        set("alpn", json!(link.alpn));
+    }
+
+    if let Some(mode) = link.udp_relay_mode {
+        set("udp_relay_mode", json!(mode));
+    }
+
+    if let Some(cc) = link.congestion_control {
+        set("congestion_control", json!(cc));
+    }
+
+    if link.allow_insecure {
+        set("skip_cert_verify", json!(true));
+    }
+
+    if link.disable_sni {
+        set("disable_sni", json!(true));
+    }
+
+    overrides
+}
+
+/// Format of the config file
+#[derive(Clone, Copy)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Detects the format from the file extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension()?.to_str()?.parse().ok()
+    }
+
+    fn read<T: DeserializeOwned>(self, path: &Path) -> Result<T, ConfigError> {
+        match self {
+            Self::Json => {
+                let file = File::open(path)?;
+                let reader = BufReader::new(file);
+                Ok(serde_json::from_reader(reader)?)
+            }
+            Self::Toml => Ok(toml::from_str(&fs::read_to_string(path)?)?),
+            Self::Yaml => {
+                let file = File::open(path)?;
+                let reader = BufReader::new(file);
+                Ok(serde_yaml::from_reader(reader)?)
+            }
+        }
+    }
+}
+
+impl FromStr for ConfigFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("json") {
+            Ok(Self::Json)
+        } else if s.eq_ignore_ascii_case("toml") {
+            Ok(Self::Toml)
+        } else if s.eq_ignore_ascii_case("yaml") || s.eq_ignore_ascii_case("yml") {
+            Ok(Self::Yaml)
+        } else {
+            Err("invalid config format, available options: json, toml, yaml")
+        }
     }
 }
 
 mod default {
-    use log::LevelFilter;
+    use std::str::FromStr;
+
+    use crate::utils::{LogFilter, LogFormat};
 
     pub mod relay {
         use std::{path::PathBuf, time::Duration};
 
-        use crate::utils::{CongestionControl, UdpRelayMode};
+        use crate::{
+            config::{DnsServer, Reconnect, Transport, UdpRelayRule},
+            utils::{CongestionControl, UdpRelayMode},
+        };
 
         pub fn certificates() -> Vec<PathBuf> {
             Vec::new()
@@ -194,6 +790,10 @@ mod default {
             UdpRelayMode::Native
         }
 
+        pub fn udp_relay_rules() -> Vec<UdpRelayRule> {
+            Vec::new()
+        }
+
         pub fn congestion_control() -> CongestionControl {
             CongestionControl::Cubic
         }
@@ -210,6 +810,26 @@ mod default {
             false
         }
 
+        pub fn connect_status() -> bool {
+            false
+        }
+
+        pub fn eager_connect() -> bool {
+            false
+        }
+
+        pub fn transport() -> Transport {
+            Transport {
+                connection_receive_window: None,
+                max_concurrent_bi_streams: super::transport::max_concurrent_bi_streams(),
+                max_concurrent_uni_streams: super::transport::max_concurrent_uni_streams(),
+                initial_rtt: super::transport::initial_rtt(),
+                keep_alive_interval: super::transport::keep_alive_interval(),
+                datagram_receive_buffer: super::transport::datagram_receive_buffer(),
+                datagram_send_buffer: super::transport::datagram_send_buffer(),
+            }
+        }
+
         pub fn timeout() -> Duration {
             Duration::from_secs(8)
         }
@@ -218,6 +838,10 @@ mod default {
             Duration::from_secs(3)
         }
 
+        pub fn heartbeat_max() -> Duration {
+            Duration::ZERO
+        }
+
         pub fn disable_native_certs() -> bool {
             false
         }
@@ -260,19 +884,216 @@ mod default {
             Duration::from_secs(15)
         }
 
+        pub fn max_pending_packets() -> usize {
+            256
+        }
+
+        pub fn max_reassembly_memory() -> usize {
+            16 * 1024 * 1024
+        }
+
+        pub fn reorder_window() -> u16 {
+            64
+        }
+
         pub fn skip_cert_verify() -> bool {
             false
         }
+
+        pub fn dns() -> Vec<DnsServer> {
+            Vec::new()
+        }
+
+        pub fn connections() -> usize {
+            1
+        }
+
+        pub fn failover_threshold() -> u32 {
+            3
+        }
+
+        pub fn primary_retry_interval() -> Duration {
+            Duration::from_secs(60)
+        }
+
+        pub fn health_check_interval() -> Duration {
+            Duration::from_secs(30)
+        }
+
+        pub fn network_check_interval() -> Duration {
+            Duration::from_secs(2)
+        }
+
+        pub fn reconnect() -> Reconnect {
+            Reconnect {
+                initial_delay: super::reconnect::initial_delay(),
+                multiplier: super::reconnect::multiplier(),
+                max_delay: super::reconnect::max_delay(),
+                jitter: super::reconnect::jitter(),
+                max_failures: super::reconnect::max_failures(),
+            }
+        }
+    }
+
+    pub mod reconnect {
+        use std::time::Duration;
+
+        pub fn initial_delay() -> Duration {
+            Duration::from_millis(200)
+        }
+
+        pub fn multiplier() -> f64 {
+            2.0
+        }
+
+        pub fn max_delay() -> Duration {
+            Duration::from_secs(10)
+        }
+
+        pub fn jitter() -> f64 {
+            0.2
+        }
+
+        pub fn max_failures() -> u32 {
+            1
+        }
+    }
+
+    pub mod dns_server {
+        use crate::utils::DnsProtocol;
+
+        pub fn protocol() -> DnsProtocol {
+            DnsProtocol::Udp
+        }
+    }
+
+    pub mod log_file {
+        use crate::utils::LogRotation;
+
+        pub fn rotation() -> LogRotation {
+            LogRotation::Never
+        }
+
+        pub fn max_size() -> u64 {
+            10 * 1024 * 1024
+        }
+
+        pub fn max_files() -> usize {
+            7
+        }
     }
 
     pub mod local {
-        pub fn max_packet_size() -> usize {
-            1500
+        use std::{collections::HashMap, time::Duration};
+
+        use crate::{
+            config::ResolveRule,
+            utils::{IpCidr, Resolve},
+        };
+
+        pub fn allowed_ips() -> Vec<IpCidr> {
+            Vec::new()
+        }
+
+        pub fn users() -> HashMap<String, String> {
+            HashMap::new()
+        }
+
+        pub fn mixed() -> bool {
+            false
+        }
+
+        pub fn max_udp_associations() -> usize {
+            0
+        }
+
+        pub fn udp_idle_timeout() -> Duration {
+            Duration::ZERO
+        }
+
+        pub fn dissociate_on_close() -> bool {
+            true
+        }
+
+        pub fn udp_fragment_timeout() -> Duration {
+            Duration::from_secs(5)
+        }
+
+        pub fn resolve() -> Resolve {
+            Resolve::Remote
+        }
+
+        pub fn resolve_rules() -> Vec<ResolveRule> {
+            Vec::new()
+        }
+    }
+
+    pub mod padding {
+        pub fn threshold() -> usize {
+            256
+        }
+
+        pub fn max_len() -> u16 {
+            128
+        }
+    }
+
+    pub mod compression {
+        pub fn level() -> i32 {
+            3
+        }
+    }
+
+    pub mod multipath {
+        use crate::utils::MultipathMode;
+
+        pub fn mode() -> MultipathMode {
+            MultipathMode::Duplicate
+        }
+    }
+
+    pub mod transport {
+        use std::time::Duration;
+
+        pub fn max_concurrent_bi_streams() -> u32 {
+            32
+        }
+
+        pub fn max_concurrent_uni_streams() -> u32 {
+            32
+        }
+
+        pub fn initial_rtt() -> Duration {
+            Duration::from_millis(333)
+        }
+
+        pub fn keep_alive_interval() -> Duration {
+            Duration::ZERO
+        }
+
+        pub fn datagram_receive_buffer() -> usize {
+            1250000
+        }
+
+        pub fn datagram_send_buffer() -> usize {
+            1048576
+        }
+    }
+
+    pub mod dns_inbound {
+        use std::time::Duration;
+
+        pub fn timeout() -> Duration {
+            Duration::from_secs(5)
         }
     }
 
-    pub fn log_level() -> LevelFilter {
-        LevelFilter::Warn
+    pub fn log_level() -> LogFilter {
+        LogFilter::from_str("warn").unwrap()
+    }
+
+    pub fn log_format() -> LogFormat {
+        LogFormat::Text
     }
 }
 
@@ -286,20 +1107,84 @@ where
     T::from_str(&s).map_err(DeError::custom)
 }
 
-pub fn deserialize_server<'de, D>(deserializer: D) -> Result<(String, u16), D::Error>
+pub fn deserialize_optional_from_str<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: FromStr,
+    <T as FromStr>::Err: Display,
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    T::from_str(&s).map(Some).map_err(DeError::custom)
+}
+
+pub fn deserialize_servers<'de, D>(deserializer: D) -> Result<Vec<(String, u16)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Servers {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let servers = match Servers::deserialize(deserializer)? {
+        Servers::One(s) => vec![s],
+        Servers::Many(v) => v,
+    };
+
+    if servers.is_empty() {
+        return Err(DeError::custom("no server address"));
+    }
+
+    servers
+        .into_iter()
+        .map(|mut s| {
+            let (domain, port) = s
+                .rsplit_once(':')
+                .ok_or(DeError::custom("invalid server address"))?;
+
+            let port = port.parse().map_err(DeError::custom)?;
+            s.truncate(domain.len());
+
+            Ok::<_, D::Error>((s, port))
+        })
+        .collect()
+}
+
+pub fn deserialize_vec_from_str<'de, T, D>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    T: FromStr,
+    <T as FromStr>::Err: Display,
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| T::from_str(s).map_err(DeError::custom))
+        .collect()
+}
+
+pub fn deserialize_listen<'de, D>(deserializer: D) -> Result<Vec<SocketAddr>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let mut s = String::deserialize(deserializer)?;
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Listen {
+        One(SocketAddr),
+        Many(Vec<SocketAddr>),
+    }
 
-    let (domain, port) = s
-        .rsplit_once(':')
-        .ok_or(DeError::custom("invalid server address"))?;
+    let listen = match Listen::deserialize(deserializer)? {
+        Listen::One(addr) => vec![addr],
+        Listen::Many(addrs) => addrs,
+    };
 
-    let port = port.parse().map_err(DeError::custom)?;
-    s.truncate(domain.len());
+    if listen.is_empty() {
+        return Err(DeError::custom("no listening address"));
+    }
 
-    Ok((s, port))
+    Ok(listen)
 }
 
 pub fn deserialize_password<'de, D>(deserializer: D) -> Result<Arc<[u8]>, D::Error>
@@ -343,6 +1228,10 @@ pub enum ConfigError {
     Argument(#[from] ArgumentError),
     #[error("no config file specified")]
     NoConfig,
+    #[error("invalid config override `{0}`, expected `--set <key>=<value>`")]
+    InvalidOverride(String),
+    #[error("invalid share link: {0}")]
+    ShareLink(#[from] ShareLinkError),
     #[error("{0}")]
     Version(&'static str),
     #[error("{0}")]
@@ -351,4 +1240,8 @@ pub enum ConfigError {
     Io(#[from] IoError),
     #[error(transparent)]
     Serde(#[from] SerdeError),
+    #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
 }
@@ -0,0 +1,90 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use tokio::time;
+
+use crate::config::Reconnect;
+
+/// Spaces out the attempts to reconnect to the server while it is unreachable
+pub struct Backoff {
+    cfg: Reconnect,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+/// Snapshot of the reconnection backoff
+#[derive(Clone, Copy, Debug)]
+pub struct BackoffState {
+    /// Failed attempts to connect in a row
+    pub failures: u32,
+    /// Time left before the next attempt
+    pub retry_in: Duration,
+}
+
+impl Backoff {
+    pub fn new(cfg: Reconnect) -> Self {
+        Self {
+            cfg,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Number of failed attempts in a row before a relay task gives up
+    pub fn max_failures(&self) -> u32 {
+        self.cfg.max_failures.max(1)
+    }
+
+    /// Waits until the next attempt is due
+    pub async fn wait(&self) {
+        let retry_at = self.state.lock().unwrap().retry_at;
+        if let Some(retry_at) = retry_at {
+            time::sleep_until(retry_at.into()).await;
+        }
+    }
+
+    /// Records a failed attempt, delaying the next one. Returns the number of
+    /// failed attempts in a row
+    pub fn fail(&self) -> u32 {
+        let mut state = self.state.lock().unwrap();
+        state.failures = state.failures.saturating_add(1);
+        state.retry_at = Some(Instant::now() + self.delay(state.failures));
+        state.failures
+    }
+
+    pub fn succeed(&self) {
+        *self.state.lock().unwrap() = State::default();
+    }
+
+    pub fn state(&self) -> BackoffState {
+        let state = self.state.lock().unwrap();
+        BackoffState {
+            failures: state.failures,
+            retry_in: state.retry_at.map_or(Duration::ZERO, |at| {
+                at.saturating_duration_since(Instant::now())
+            }),
+        }
+    }
+
+    fn delay(&self, failures: u32) -> Duration {
+        let exp = i32::try_from(failures - 1).unwrap_or(i32::MAX);
+        let delay = (self.cfg.initial_delay.as_secs_f64() * self.cfg.multiplier.powi(exp))
+            .min(self.cfg.max_delay.as_secs_f64());
+
+        let jitter = self.cfg.jitter.clamp(0.0, 1.0);
+        let factor = if jitter > 0.0 {
+            rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter)
+        } else {
+            1.0
+        };
+
+        Duration::from_secs_f64(delay * factor)
+    }
+}
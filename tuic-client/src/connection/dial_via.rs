@@ -0,0 +1,350 @@
+use std::{
+    fmt::{Debug, Formatter, Result as FmtResult},
+    io::{Error as IoError, ErrorKind, IoSliceMut},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll, ready},
+};
+
+use bytes::{BufMut, BytesMut};
+use futures_util::task::AtomicWaker;
+use quinn::{
+    AsyncUdpSocket, Endpoint as QuinnEndpoint, UdpPoller,
+    udp::{RecvMeta, Transmit},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, ReadBuf},
+    net::{TcpStream, UdpSocket},
+    sync::Mutex as AsyncMutex,
+    task::AbortHandle,
+};
+
+use crate::{config::DialVia, error::Error};
+
+const VERSION: u8 = 0x05;
+const METHOD_NONE: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_IPV6: u8 = 0x04;
+
+/// The `UDP ASSOCIATE` session the endpoint is bound to, negotiated again
+/// once the proxy closes its control connection
+pub struct Session {
+    cfg: DialVia,
+    socket: AsyncMutex<Weak<Socks5UdpSocket>>,
+}
+
+impl Session {
+    /// Negotiates the first session, returning the socket to bind the endpoint
+    /// to
+    pub async fn new(cfg: DialVia) -> Result<(Self, Arc<Socks5UdpSocket>), Error> {
+        let socket = Arc::new(Socks5UdpSocket::associate(&cfg).await?);
+        let session = Self {
+            cfg,
+            socket: AsyncMutex::new(Arc::downgrade(&socket)),
+        };
+        Ok((session, socket))
+    }
+
+    /// Negotiates a new session and rebinds `ep` to it if the proxy closed the
+    /// current one
+    pub async fn ensure_open(&self, ep: &QuinnEndpoint) -> Result<(), Error> {
+        let mut current = self.socket.lock().await;
+        if current
+            .upgrade()
+            .is_some_and(|socket| !socket.ctrl.closed.load(Ordering::Acquire))
+        {
+            return Ok(());
+        }
+
+        let socket = Arc::new(Socks5UdpSocket::associate(&self.cfg).await?);
+        ep.rebind_abstract(socket.clone())?;
+        *current = Arc::downgrade(&socket);
+        Ok(())
+    }
+}
+
+/// A UDP socket that tunnels every datagram through a SOCKS5 `UDP ASSOCIATE`
+/// session, so the QUIC endpoint can be reached through an upstream proxy
+pub struct Socks5UdpSocket {
+    io: UdpSocket,
+    local_addr: SocketAddr,
+    ctrl: Arc<Control>,
+    /// The task holding the control TCP connection open, aborted when the
+    /// socket is dropped
+    ctrl_task: AbortHandle,
+}
+
+/// The state of the control TCP connection of a session, which ends the
+/// session once closed
+#[derive(Default)]
+struct Control {
+    closed: AtomicBool,
+    /// Woken up once closed, so a pending receive fails
+    recv_waker: AtomicWaker,
+}
+
+impl Socks5UdpSocket {
+    /// Negotiates a `UDP ASSOCIATE` session with the proxy. The control TCP
+    /// connection is kept open in the background for the session lifetime
+    async fn associate(cfg: &DialVia) -> Result<Self, Error> {
+        let mut ctrl = TcpStream::connect(cfg.server).await?;
+
+        let method = if cfg.username.is_some() {
+            METHOD_PASSWORD
+        } else {
+            METHOD_NONE
+        };
+        ctrl.write_all(&[VERSION, 1, method]).await?;
+
+        let mut buf = [0; 2];
+        ctrl.read_exact(&mut buf).await?;
+        if buf[0] != VERSION || buf[1] != method {
+            return Err(proxy_err("proxy rejected the authentication method"));
+        }
+
+        if let Some(username) = &cfg.username {
+            let password = cfg.password.as_deref().unwrap_or_default();
+            let (Ok(username_len), Ok(password_len)) =
+                (u8::try_from(username.len()), u8::try_from(password.len()))
+            else {
+                return Err(Error::DialViaCredentialsTooLong);
+            };
+
+            let mut req = BytesMut::with_capacity(3 + username.len() + password.len());
+            req.put_u8(0x01);
+            req.put_u8(username_len);
+            req.put_slice(username.as_bytes());
+            req.put_u8(password_len);
+            req.put_slice(password.as_bytes());
+            ctrl.write_all(&req).await?;
+
+            ctrl.read_exact(&mut buf).await?;
+            if buf[1] != 0x00 {
+                return Err(proxy_err("proxy authentication failed"));
+            }
+        }
+
+        ctrl.write_all(&[
+            VERSION,
+            CMD_UDP_ASSOCIATE,
+            0x00,
+            ATYP_IPV4,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ])
+        .await?;
+
+        let mut buf = [0; 4];
+        ctrl.read_exact(&mut buf).await?;
+        if buf[1] != 0x00 {
+            return Err(proxy_err("proxy refused UDP associate"));
+        }
+
+        let relay_ip = match buf[3] {
+            ATYP_IPV4 => {
+                let mut ip = [0; 4];
+                ctrl.read_exact(&mut ip).await?;
+                IpAddr::from(ip)
+            }
+            ATYP_IPV6 => {
+                let mut ip = [0; 16];
+                ctrl.read_exact(&mut ip).await?;
+                IpAddr::from(ip)
+            }
+            _ => return Err(proxy_err("proxy replied with an unsupported address type")),
+        };
+        let relay_port = ctrl.read_u16().await?;
+
+        // Some proxies reply with an unspecified address, meaning "same host as
+        // the control connection"
+        let relay_addr = if relay_ip.is_unspecified() {
+            SocketAddr::new(cfg.server.ip(), relay_port)
+        } else {
+            SocketAddr::new(relay_ip, relay_port)
+        };
+
+        let io = match relay_addr {
+            SocketAddr::V4(_) => UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))),
+            SocketAddr::V6(_) => UdpSocket::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))),
+        }
+        .await?;
+        io.connect(relay_addr).await?;
+        let local_addr = io.local_addr()?;
+
+        tracing::info!("[relay] [dial-via] UDP associated through {relay_addr}");
+
+        let control = Arc::new(Control::default());
+        let ctrl_task = tokio::spawn({
+            let control = control.clone();
+            async move {
+                let mut buf = [0; 1];
+                _ = ctrl.read(&mut buf).await;
+                tracing::warn!("[relay] [dial-via] proxy control connection closed");
+
+                control.closed.store(true, Ordering::Release);
+                control.recv_waker.wake();
+            }
+        })
+        .abort_handle();
+
+        Ok(Self {
+            io,
+            local_addr,
+            ctrl: control,
+            ctrl_task,
+        })
+    }
+
+    fn check_open(&self) -> Result<(), IoError> {
+        if self.ctrl.closed.load(Ordering::Acquire) {
+            return Err(IoError::new(
+                ErrorKind::ConnectionAborted,
+                "proxy control connection closed",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Socks5UdpSocket {
+    fn drop(&mut self) {
+        self.ctrl_task.abort();
+    }
+}
+
+impl AsyncUdpSocket for Socks5UdpSocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        Box::pin(Socks5UdpPoller(self))
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> Result<(), IoError> {
+        self.check_open()?;
+
+        let mut buf = BytesMut::with_capacity(22 + transmit.contents.len());
+        buf.put_slice(&[0, 0, 0]);
+
+        match transmit.destination {
+            SocketAddr::V4(addr) => {
+                buf.put_u8(ATYP_IPV4);
+                buf.put_slice(&addr.ip().octets());
+                buf.put_u16(addr.port());
+            }
+            SocketAddr::V6(addr) => match addr.ip().to_ipv4_mapped() {
+                Some(ip) => {
+                    buf.put_u8(ATYP_IPV4);
+                    buf.put_slice(&ip.octets());
+                    buf.put_u16(addr.port());
+                }
+                None => {
+                    buf.put_u8(ATYP_IPV6);
+                    buf.put_slice(&addr.ip().octets());
+                    buf.put_u16(addr.port());
+                }
+            },
+        }
+
+        buf.put_slice(transmit.contents);
+        self.io.try_send(&buf)?;
+        Ok(())
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<Result<usize, IoError>> {
+        self.ctrl.recv_waker.register(cx.waker());
+        self.check_open()?;
+
+        loop {
+            let n = {
+                let mut buf = ReadBuf::new(&mut bufs[0]);
+                ready!(self.io.poll_recv(cx, &mut buf))?;
+                buf.filled().len()
+            };
+
+            let Some((src_addr, hdr_len)) = parse_udp_header(&bufs[0][..n]) else {
+                tracing::debug!("[relay] [dial-via] dropping malformed packet from proxy");
+                continue;
+            };
+
+            let src_addr = match src_addr {
+                SocketAddr::V4(addr) if self.local_addr.is_ipv6() => {
+                    SocketAddr::new(addr.ip().to_ipv6_mapped().into(), addr.port())
+                }
+                addr => addr,
+            };
+
+            bufs[0].copy_within(hdr_len..n, 0);
+            let len = n - hdr_len;
+            meta[0] = RecvMeta {
+                addr: src_addr,
+                len,
+                stride: len,
+                ..Default::default()
+            };
+
+            return Poll::Ready(Ok(1));
+        }
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, IoError> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Debug for Socks5UdpSocket {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("Socks5UdpSocket")
+            .field("local_addr", &self.local_addr)
+            .field("relay_addr", &self.io.peer_addr().ok())
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+struct Socks5UdpPoller(Arc<Socks5UdpSocket>);
+
+impl UdpPoller for Socks5UdpPoller {
+    fn poll_writable(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), IoError>> {
+        self.0.io.poll_send_ready(cx)
+    }
+}
+
+/// Parses the SOCKS5 UDP request header, returning the source address and the
+/// header length. Fragmented and domain-addressed packets are rejected
+fn parse_udp_header(buf: &[u8]) -> Option<(SocketAddr, usize)> {
+    if buf.len() < 4 || buf[2] != 0 {
+        return None;
+    }
+
+    match buf[3] {
+        ATYP_IPV4 if buf.len() >= 10 => {
+            let ip: [u8; 4] = buf[4..8].try_into().ok()?;
+            let port = u16::from_be_bytes([buf[8], buf[9]]);
+            Some((SocketAddr::from((ip, port)), 10))
+        }
+        ATYP_IPV6 if buf.len() >= 22 => {
+            let ip: [u8; 16] = buf[4..20].try_into().ok()?;
+            let port = u16::from_be_bytes([buf[20], buf[21]]);
+            Some((SocketAddr::from((ip, port)), 22))
+        }
+        _ => None,
+    }
+}
+
+fn proxy_err(msg: &'static str) -> Error {
+    Error::Io(IoError::other(msg))
+}
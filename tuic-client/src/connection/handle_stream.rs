@@ -46,27 +46,33 @@ impl Connection {
     }
 
     pub async fn handle_uni_stream(self, recv: RecvStream, _reg: Register) {
-        log::debug!("[relay] incoming unidirectional stream");
+        tracing::debug!("[relay] incoming unidirectional stream");
 
         let res = match self.model.accept_uni_stream(recv).await {
             Err(err) => Err(Error::Model(err)),
-            Ok(Task::Packet(pkt)) => match self.udp_relay_mode {
-                UdpRelayMode::Quic => {
-                    Self::handle_packet(pkt).await;
-                    Ok(())
-                }
-                UdpRelayMode::Native => Err(Error::WrongPacketSource),
-            },
+            Ok(Task::Packet(pkt)) if self.accepts_udp_relay_mode(UdpRelayMode::Quic) => {
+                Self::handle_packet(pkt).await;
+                Ok(())
+            }
+            Ok(Task::Packet(_)) => Err(Error::WrongPacketSource),
+            Ok(Task::Notice(kind, message)) => {
+                self.handle_notice(kind, message).await;
+                Ok(())
+            }
+            Ok(Task::Negotiate(version, capabilities)) => {
+                self.handle_negotiate(version, capabilities).await;
+                Ok(())
+            }
             _ => unreachable!(), // already filtered in `tuic_quinn`
         };
 
         if let Err(err) = res {
-            log::warn!("[relay] incoming unidirectional stream error: {err}");
+            tracing::warn!("[relay] incoming unidirectional stream error: {err}");
         }
     }
 
     pub async fn handle_bi_stream(self, send: SendStream, recv: RecvStream, _reg: Register) {
-        log::debug!("[relay] incoming bidirectional stream");
+        tracing::debug!("[relay] incoming bidirectional stream");
 
         let res = match self.model.accept_bi_stream(send, recv).await {
             Err(err) => Err::<(), _>(Error::Model(err)),
@@ -74,27 +80,39 @@ impl Connection {
         };
 
         if let Err(err) = res {
-            log::warn!("[relay] incoming bidirectional stream error: {err}");
+            tracing::warn!("[relay] incoming bidirectional stream error: {err}");
         }
     }
 
     pub async fn handle_datagram(self, dg: Bytes) {
-        log::debug!("[relay] incoming datagram");
+        tracing::debug!("[relay] incoming datagram");
 
         let res = match self.model.accept_datagram(dg) {
             Err(err) => Err(Error::Model(err)),
-            Ok(Task::Packet(pkt)) => match self.udp_relay_mode {
-                UdpRelayMode::Native => {
+            Ok(Task::Packet(pkt)) if self.accepts_udp_relay_mode(UdpRelayMode::Native) => {
+                Self::handle_packet(pkt).await;
+                Ok(())
+            }
+            Ok(Task::Packet(_)) => Err(Error::WrongPacketSource),
+            Ok(Task::Batch(pkts)) if self.accepts_udp_relay_mode(UdpRelayMode::Native) => {
+                for pkt in pkts {
                     Self::handle_packet(pkt).await;
-                    Ok(())
                 }
-                UdpRelayMode::Quic => Err(Error::WrongPacketSource),
-            },
+                Ok(())
+            }
+            Ok(Task::Batch(_)) => Err(Error::WrongPacketSource),
+            Ok(Task::HeartbeatEcho(rtt)) => {
+                tracing::debug!(
+                    "[relay] [heartbeat] rtt {rtt:?}, transport rtt {transport_rtt:?}",
+                    transport_rtt = self.conn.rtt(),
+                );
+                Ok(())
+            }
             _ => unreachable!(), // already filtered in `tuic_quinn`
         };
 
         if let Err(err) = res {
-            log::warn!("[relay] incoming datagram error: {err}");
+            tracing::warn!("[relay] incoming datagram error: {err}");
         }
     }
 }
@@ -1,105 +1,288 @@
-use std::time::Duration;
+use std::{
+    io::{Error as IoError, ErrorKind},
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
 
 use bytes::Bytes;
 use quinn::ZeroRttAccepted;
 use socks5_proto::Address as Socks5Address;
-use tokio::time;
-use tuic::Address;
+use tokio::{io, time};
+use tracing::Instrument;
+use tuic::{Address, Capabilities, Header, NoticeKind, Packet as TuicPacket, VERSION};
 use tuic_quinn::{Connect, Packet};
 
-use super::Connection;
-use crate::{error::Error, socks5::UDP_SESSIONS as SOCKS5_UDP_SESSIONS, utils::UdpRelayMode};
+use super::{Connection, ENDPOINT, ERROR_CODE, KEEP_OPEN, MULTIPATH, UDP_RELAY_RULES};
+use crate::{
+    embed,
+    error::Error,
+    fake_ip,
+    socks5::UDP_SESSIONS as SOCKS5_UDP_SESSIONS,
+    stats,
+    utils::{self, UdpRelayMode},
+};
 
 impl Connection {
     pub async fn authenticate(self, zero_rtt_accepted: Option<ZeroRttAccepted>) {
         if let Some(zero_rtt_accepted) = zero_rtt_accepted {
-            log::debug!("[relay] [authenticate] waiting for connection to be fully established");
+            tracing::debug!(
+                "[relay] [authenticate] waiting for connection to be fully established"
+            );
             zero_rtt_accepted.await;
         }
 
-        log::debug!("[relay] [authenticate] sending authentication");
+        tracing::debug!("[relay] [authenticate] sending authentication");
 
         match self
             .model
             .authenticate(self.uuid, self.password.clone())
             .await
         {
-            Ok(()) => log::info!("[relay] [authenticate] {uuid}", uuid = self.uuid),
-            Err(err) => log::warn!("[relay] [authenticate] authentication sending error: {err}"),
+            Ok(()) => tracing::info!("[relay] [authenticate] {uuid}", uuid = self.uuid),
+            Err(err) => {
+                tracing::warn!("[relay] [authenticate] authentication sending error: {err}")
+            }
         }
     }
 
+    #[tracing::instrument(parent = &self.span, skip_all)]
     pub async fn connect(&self, addr: Address) -> Result<Connect, Error> {
+        let addr = fake_ip::restore(addr);
         let addr_display = addr.to_string();
-        log::info!("[relay] [connect] {addr_display}");
+        tracing::info!("[relay] [connect] {addr_display}");
 
-        match self.model.connect(addr).await {
-            Ok(conn) => Ok(conn),
+        let res = if self.capabilities.contains(Capabilities::CONNECT_STATUS) {
+            self.model.connect_with_status(addr).await
+        } else {
+            self.model.connect(addr).await
+        };
+
+        match res {
+            Ok(mut conn) => match conn.status().await {
+                Ok(Some(status)) if !status.is_success() => {
+                    tracing::warn!(
+                        "[relay] [connect] server failed connecting to {addr_display}: {status}"
+                    );
+                    let _ = conn.reset(ERROR_CODE);
+                    Err(Error::ConnectStatus(status))
+                }
+                Ok(_) => Ok(conn),
+                Err(err) => {
+                    tracing::warn!(
+                        "[relay] [connect] failed receiving the status of {addr_display}: {err}"
+                    );
+                    let _ = conn.reset(ERROR_CODE);
+                    Err(Error::Model(err))
+                }
+            },
             Err(err) => {
-                log::warn!("[relay] [connect] failed initializing relay to {addr_display}: {err}");
+                tracing::warn!(
+                    "[relay] [connect] failed initializing relay to {addr_display}: {err}"
+                );
                 Err(Error::Model(err))
             }
         }
     }
 
+    /// Waits until the extensions are negotiated with the server, returning
+    /// whether `SPEEDTEST` is one of them
+    pub async fn wait_speedtest(&self, timeout: Duration) -> bool {
+        let wait = async {
+            loop {
+                if let Some((_, caps)) = self.model.negotiated() {
+                    return caps.contains(Capabilities::SPEEDTEST);
+                }
+                time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+
+        time::timeout(timeout, wait).await.unwrap_or(false)
+    }
+
+    /// Uploads `upload` bytes then downloads `download` bytes through a
+    /// `Speedtest` command, returning the time taken
+    pub async fn speedtest(&self, upload: u64, download: u64) -> Result<Duration, Error> {
+        const CHUNK: [u8; 16384] = [0; 16384];
+
+        let start = Instant::now();
+        let (mut send, mut recv) = self.model.speedtest(download).await?;
+
+        let mut sent = 0;
+        while sent < upload {
+            let len = (upload - sent).min(CHUNK.len() as u64) as usize;
+            send.write_all(&CHUNK[..len]).await.map_err(IoError::from)?;
+            sent += len as u64;
+        }
+        send.finish().map_err(IoError::from)?;
+
+        let received = io::copy(&mut recv, &mut io::sink()).await?;
+        if received != download {
+            return Err(Error::Io(IoError::new(
+                ErrorKind::UnexpectedEof,
+                format!("speedtest received {received} of {download} bytes"),
+            )));
+        }
+
+        Ok(start.elapsed())
+    }
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
     pub async fn packet(&self, pkt: Bytes, addr: Address, assoc_id: u16) -> eyre::Result<()> {
+        let addr = fake_ip::restore(addr);
+        stats::record_tx(assoc_id, pkt.len());
+
+        match udp_relay_rule(&addr).unwrap_or(self.udp_relay_mode) {
+            UdpRelayMode::Native => self.relay_native(pkt, addr, assoc_id),
+            UdpRelayMode::Quic => self.relay_quic(pkt, addr, assoc_id).await,
+            UdpRelayMode::Auto if self.auto_relays_quic(&pkt, &addr, assoc_id) => {
+                self.relay_quic(pkt, addr, assoc_id).await
+            }
+            UdpRelayMode::Auto => self.relay_native(pkt, addr, assoc_id),
+        }
+    }
+
+    fn relay_native(&self, pkt: Bytes, addr: Address, assoc_id: u16) -> eyre::Result<()> {
         let addr_display = addr.to_string();
+        tracing::info!("[relay] [packet] [{assoc_id:#06x}] [to-native] to {addr_display}");
 
-        match self.udp_relay_mode {
-            UdpRelayMode::Native => {
-                log::info!("[relay] [packet] [{assoc_id:#06x}] [to-native] to {addr_display}");
-                match self.model.packet_native(pkt, addr, assoc_id) {
-                    Ok(()) => Ok(()),
-                    Err(err) => {
-                        log::warn!(
-                            "[relay] [packet] [{assoc_id:#06x}] [to-native] to {addr_display}: \
-                             {err}"
-                        );
-                        Err(err)?
-                    }
-                }
+        let bonded = self
+            .model
+            .negotiated()
+            .is_some_and(|(_, caps)| caps.contains(Capabilities::MULTIPATH));
+
+        let res = match MULTIPATH.get() {
+            Some(multipath) if bonded => multipath.packet_native(self, &pkt, addr, assoc_id),
+            _ => self.model.packet_native(pkt, addr, assoc_id),
+        };
+
+        match res {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                tracing::warn!(
+                    "[relay] [packet] [{assoc_id:#06x}] [to-native] to {addr_display}: {err}"
+                );
+                Err(err)?
             }
-            UdpRelayMode::Quic => {
-                log::info!("[relay] [packet] [{assoc_id:#06x}] [to-quic] {addr_display}");
-                match self.model.packet_quic(pkt, addr, assoc_id).await {
-                    Ok(()) => Ok(()),
-                    Err(err) => {
-                        log::warn!(
-                            "[relay] [packet] [{assoc_id:#06x}] [to-quic] to {addr_display}: {err}"
-                        );
-                        Err(err)
-                    }
-                }
+        }
+    }
+
+    async fn relay_quic(&self, pkt: Bytes, addr: Address, assoc_id: u16) -> eyre::Result<()> {
+        let addr_display = addr.to_string();
+        tracing::info!("[relay] [packet] [{assoc_id:#06x}] [to-quic] {addr_display}");
+
+        match self.model.packet_quic(pkt, addr, assoc_id).await {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                tracing::warn!(
+                    "[relay] [packet] [{assoc_id:#06x}] [to-quic] to {addr_display}: {err}"
+                );
+                Err(err)
             }
         }
     }
 
+    /// Whether packets may be received in `mode`. With `auto` or any
+    /// per-destination rule, the server may reply in both modes
+    pub fn accepts_udp_relay_mode(&self, mode: UdpRelayMode) -> bool {
+        self.udp_relay_mode == mode || mixes_udp_relay_modes(self.udp_relay_mode)
+    }
+
+    /// Whether a packet of the association is relayed in `quic` mode in `auto`
+    /// mode. An association switches to `quic` for good with its first packet
+    /// not fitting in a single datagram, so the server replies in a single mode
+    /// per association. Servers not accepting mixed modes get every packet in
+    /// `native` mode, fragmented if needed
+    fn auto_relays_quic(&self, pkt: &[u8], addr: &Address, assoc_id: u16) -> bool {
+        let Some(max_size) = self.conn.max_datagram_size() else {
+            return true;
+        };
+
+        let mixed = self
+            .model
+            .negotiated()
+            .is_some_and(|(_, caps)| caps.contains(Capabilities::MIXED_UDP_RELAY));
+        if !mixed {
+            return false;
+        }
+
+        let mut quic_assocs = self
+            .quic_assocs
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if quic_assocs.contains(&assoc_id) {
+            return true;
+        }
+
+        let header_len = Header::Packet(TuicPacket::new(0, 0, 1, 0, 0, addr.clone())).len();
+        if header_len + pkt.len() <= max_size {
+            return false;
+        }
+
+        quic_assocs.insert(assoc_id);
+        true
+    }
+
+    #[tracing::instrument(parent = &self.span, skip_all)]
     pub async fn dissociate(&self, assoc_id: u16) -> eyre::Result<()> {
-        log::info!("[relay] [dissociate] [{assoc_id:#06x}]");
+        tracing::info!("[relay] [dissociate] [{assoc_id:#06x}]");
+        stats::remove(assoc_id);
+        self.quic_assocs
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .remove(&assoc_id);
+
+        if let Some(multipath) = MULTIPATH.get() {
+            multipath.dissociate(assoc_id).await;
+        }
+
         match self.model.dissociate(assoc_id).await {
             Ok(()) => Ok(()),
             Err(err) => {
-                log::warn!("[relay] [dissociate] [{assoc_id:#06x}] {err}");
+                tracing::warn!("[relay] [dissociate] [{assoc_id:#06x}] {err}");
                 Err(err)?
             }
         }
     }
 
-    pub async fn heartbeat(self, heartbeat: Duration) {
+    /// Sends heartbeats every `min` interval while relay tasks exist. While the
+    /// server keeps acknowledging other traffic, the connection is evidently
+    /// alive, so the interval doubles up to `max` instead
+    pub async fn heartbeat(self, min: Duration, max: Duration) {
+        let mut interval = min;
+        let mut last_acks = self.conn.stats().frame_rx.acks;
+        let mut sent = false;
+
         loop {
-            time::sleep(heartbeat).await;
+            time::sleep(interval).await;
 
             if self.is_closed() {
                 break;
             }
 
-            if self.model.task_connect_count() + self.model.task_associate_count() == 0 {
+            // the acknowledgement of the previous heartbeat is not activity
+            let acks = self.conn.stats().frame_rx.acks;
+            let active = acks > last_acks + u64::from(sent);
+            last_acks = acks;
+            sent = false;
+
+            if !KEEP_OPEN.load(Ordering::Relaxed)
+                && self.model.task_connect_count() + self.model.task_associate_count() == 0
+            {
+                interval = min;
+                continue;
+            }
+
+            if active && interval < max {
+                interval = (interval * 2).min(max);
                 continue;
             }
 
+            interval = min;
+            sent = true;
+
             match self.model.heartbeat().await {
-                Ok(()) => log::debug!("[relay] [heartbeat]"),
-                Err(err) => log::warn!("[relay] [heartbeat] {err}"),
+                Ok(()) => tracing::debug!("[relay] [heartbeat]"),
+                Err(err) => tracing::warn!("[relay] [heartbeat] {err}"),
             }
         }
     }
@@ -116,7 +299,7 @@ impl Connection {
             unreachable!()
         };
 
-        log::info!(
+        tracing::info!(
             "[relay] [packet] [{assoc_id:#06x}] [from-{mode}] [{pkt_id:#06x}] fragment \
              {frag_id}/{frag_total}",
             frag_id = pkt.frag_id() + 1,
@@ -125,9 +308,22 @@ impl Connection {
 
         match pkt.accept().await {
             Ok(Some((pkt, addr, _))) => {
-                log::info!(
+                tracing::info!(
                     "[relay] [packet] [{assoc_id:#06x}] [from-{mode}] [{pkt_id:#06x}] from {addr}"
                 );
+                stats::record_rx(assoc_id, pkt.len());
+
+                let assoc = embed::ASSOCIATIONS.read().await.get(&assoc_id).cloned();
+                if let Some(assoc) = assoc {
+                    if assoc.try_send((pkt, addr)).is_err() {
+                        stats::record_rx_dropped(assoc_id);
+                        tracing::warn!(
+                            "[relay] [packet] [{assoc_id:#06x}] [from-{mode}] [{pkt_id:#06x}] \
+                             association queue full, packet dropped"
+                        );
+                    }
+                    return;
+                }
 
                 let addr = match addr {
                     Address::None => unreachable!(),
@@ -137,33 +333,107 @@ impl Connection {
                     Address::SocketAddress(addr) => Socks5Address::SocketAddress(addr),
                 };
 
-                let session = SOCKS5_UDP_SESSIONS
-                    .get()
-                    .unwrap()
-                    .read()
-                    .await
-                    .get(&assoc_id)
-                    .cloned();
+                let session = match SOCKS5_UDP_SESSIONS.get() {
+                    Some(sessions) => sessions.read().await.get(&assoc_id).cloned(),
+                    None => None,
+                };
 
                 if let Some(session) = session {
                     if let Err(err) = session.send(pkt, addr).await {
-                        log::warn!(
+                        stats::record_rx_dropped(assoc_id);
+                        tracing::warn!(
                             "[relay] [packet] [{assoc_id:#06x}] [from-native] [{pkt_id:#06x}] \
                              failed sending packet to socks5 client: {err}",
                         );
                     }
                 } else {
-                    log::warn!(
+                    stats::record_rx_dropped(assoc_id);
+                    tracing::warn!(
                         "[relay] [packet] [{assoc_id:#06x}] [from-native] [{pkt_id:#06x}] unable \
                          to find socks5 associate session"
                     );
                 }
             }
             Ok(None) => {}
-            Err(err) => log::warn!(
+            Err(err) => tracing::warn!(
                 "[relay] [packet] [{assoc_id:#06x}] [from-native] [{pkt_id:#06x}] packet \
                  receiving error: {err}"
             ),
         }
     }
+
+    /// Replies to the offer of the server with the version and extensions
+    /// supported by both sides
+    pub async fn handle_negotiate(self, version: u8, capabilities: Capabilities) {
+        let version = version.min(VERSION);
+        let capabilities = capabilities & self.capabilities;
+
+        match self.model.negotiate(version, capabilities).await {
+            Ok(()) => tracing::debug!(
+                "[relay] [negotiate] version {version:#04x}, capabilities {caps:#x}",
+                caps = capabilities.bits(),
+            ),
+            Err(err) => tracing::warn!("[relay] [negotiate] failed sending reply: {err}"),
+        }
+
+        if let Some(multipath) = MULTIPATH.get()
+            && capabilities.contains(Capabilities::MULTIPATH)
+        {
+            match self.model.bond(multipath.bond_id).await {
+                Ok(()) => tracing::debug!("[relay] [multipath] bonded"),
+                Err(err) => tracing::warn!("[relay] [multipath] failed bonding: {err}"),
+            }
+        }
+    }
+
+    /// Logs a notice pushed by the server. On a shutdown notice, new relay
+    /// tasks are moved on to the next server and this connection is closed once
+    /// idle
+    pub async fn handle_notice(self, kind: NoticeKind, message: String) {
+        match kind {
+            NoticeKind::Info => tracing::info!("[relay] [notice] {message}"),
+            _ => tracing::warn!("[relay] [notice] [{kind}] {message}"),
+        }
+
+        if kind != NoticeKind::Shutdown {
+            return;
+        }
+
+        if let Some(next) = ENDPOINT.get().unwrap().read().await.fail_over() {
+            tracing::warn!("[relay] server is shutting down, failing over to server {next}");
+            tokio::spawn(self.close_when_idle().in_current_span());
+        }
+    }
+}
+
+/// Returns the mode of the first `udp_relay_rules` entry matching `addr`
+/// Whether packets of a connection may be relayed in both modes, declared to
+/// the server with the `MIXED_UDP_RELAY` extension
+pub(super) fn mixes_udp_relay_modes(udp_relay_mode: UdpRelayMode) -> bool {
+    udp_relay_mode == UdpRelayMode::Auto || !UDP_RELAY_RULES.get().unwrap().is_empty()
+}
+
+fn udp_relay_rule(addr: &Address) -> Option<UdpRelayMode> {
+    let (domain, port) = match addr {
+        Address::DomainAddress(domain, port) => (Some(domain.as_str()), *port),
+        Address::SocketAddress(addr) => (None, addr.port()),
+        Address::None => return None,
+    };
+
+    UDP_RELAY_RULES
+        .get()
+        .unwrap()
+        .iter()
+        .find(|rule| {
+            let port_matches = rule
+                .port
+                .is_none_or(|range| (range.start..=range.end).contains(&port));
+
+            let domain_matches = rule.domain.as_deref().is_none_or(|rule_domain| {
+                domain.is_some_and(|domain| utils::is_same_or_subdomain(domain, rule_domain))
+            });
+
+            port_matches && domain_matches
+        })
+        .map(|rule| rule.mode)
 }
@@ -1,15 +1,23 @@
 use std::{
+    collections::HashSet,
+    fmt::Write,
+    io::{Error as IoError, ErrorKind},
+    mem,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
-    sync::{Arc, atomic::AtomicU32},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    },
     time::Duration,
 };
 
-use anyhow::Context;
+use anyhow::{Context, anyhow};
 use crossbeam_utils::atomic::AtomicCell;
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use once_cell::sync::OnceCell;
 use quinn::{
-    ClientConfig, Connection as QuinnConnection, Endpoint as QuinnEndpoint, EndpointConfig,
-    TokioRuntime, TransportConfig, VarInt, ZeroRttAccepted,
+    AsyncUdpSocket, ClientConfig, Connection as QuinnConnection, Endpoint as QuinnEndpoint,
+    EndpointConfig, Runtime, TokioRuntime, TransportConfig, VarInt, ZeroRttAccepted,
     congestion::{BbrConfig, CubicConfig, NewRenoConfig},
     crypto::rustls::QuicClientConfig,
 };
@@ -18,28 +26,58 @@ use rustls::{
     ClientConfig as RustlsClientConfig,
     pki_types::{CertificateDer, ServerName, UnixTime},
 };
+use serde_json::{Value, json};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
 use tokio::{
     sync::{OnceCell as AsyncOnceCell, RwLock as AsyncRwLock},
     time,
 };
-use tuic_quinn::{Connection as Model, side};
+use tracing::{Instrument, Span};
+use tuic::Capabilities;
+use tuic_common::socket::set_dscp;
+use tuic_quinn::{
+    BrutalConfig, CompressionPolicy, Connection as Model, DscpSocket, ExporterLabels, MemoryBudget,
+    PaddingPolicy, ReassemblyLimits, ReassemblyStats, side,
+};
 use uuid::Uuid;
 
+use self::{
+    backoff::Backoff,
+    dial_via::Session as DialViaSession,
+    multipath::{MULTIPATH, Multipath},
+};
 use crate::{
-    config::Relay,
+    config::{Relay, UdpRelayRule},
+    embed::PROTECT,
     error::Error,
     utils::{self, CongestionControl, ServerAddr, UdpRelayMode},
 };
 
+mod backoff;
+mod dial_via;
 mod handle_stream;
 mod handle_task;
+mod multipath;
 
-static ENDPOINT: OnceCell<AsyncRwLock<Endpoint>> = OnceCell::new();
-static CONNECTION: AsyncOnceCell<AsyncRwLock<Connection>> = AsyncOnceCell::const_new();
+static ENDPOINT: OnceCell<AsyncRwLock<Arc<Endpoint>>> = OnceCell::new();
+static CONNECTIONS: OnceCell<Box<[AsyncOnceCell<AsyncRwLock<Connection>>]>> = OnceCell::new();
+static NEXT_CONNECTION: AtomicUsize = AtomicUsize::new(0);
 static TIMEOUT: AtomicCell<Duration> = AtomicCell::new(Duration::from_secs(0));
+static UDP_RELAY_RULES: OnceCell<Box<[UdpRelayRule]>> = OnceCell::new();
+/// Whether idle connections are kept open with heartbeats
+static KEEP_OPEN: AtomicBool = AtomicBool::new(false);
+/// Initial stream limits of new connections, as (bidirectional, unidirectional)
+static CONCURRENT_STREAMS: AtomicCell<(u32, u32)> = AtomicCell::new((32, 32));
 
 pub const ERROR_CODE: VarInt = VarInt::from_u32(0);
-const DEFAULT_CONCURRENT_STREAMS: u32 = 32;
+
+/// Protocol extensions always accepted when offered by the server
+const CAPABILITIES: Capabilities = Capabilities::NOTICE
+    .union(Capabilities::PADDING)
+    .union(Capabilities::BATCH)
+    .union(Capabilities::SPEEDTEST);
+// RFC 8305, section 5
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
 
 #[derive(Clone)]
 pub struct Connection {
@@ -48,10 +86,15 @@ pub struct Connection {
     uuid: Uuid,
     password: Arc<[u8]>,
     udp_relay_mode: UdpRelayMode,
+    /// Associations switched to `quic` mode in `auto` mode
+    quic_assocs: Arc<Mutex<HashSet<u16>>>,
+    /// Protocol extensions accepted when offered by the server
+    capabilities: Capabilities,
     remote_uni_stream_cnt: Counter,
     remote_bi_stream_cnt: Counter,
     max_concurrent_uni_streams: Arc<AtomicU32>,
     max_concurrent_bi_streams: Arc<AtomicU32>,
+    span: Span,
 }
 
 impl Connection {
@@ -137,16 +180,34 @@ impl Connection {
             QuicClientConfig::try_from(crypto).context("no initial cipher suite found")?,
         ));
         let mut tp_cfg = TransportConfig::default();
+        let transport = &cfg.transport;
 
         tp_cfg
-            .max_concurrent_bidi_streams(VarInt::from(DEFAULT_CONCURRENT_STREAMS))
-            .max_concurrent_uni_streams(VarInt::from(DEFAULT_CONCURRENT_STREAMS))
+            .max_concurrent_bidi_streams(VarInt::from(transport.max_concurrent_bi_streams))
+            .max_concurrent_uni_streams(VarInt::from(transport.max_concurrent_uni_streams))
             .send_window(cfg.send_window)
             .stream_receive_window(VarInt::from_u32(cfg.receive_window))
+            .receive_window(
+                transport
+                    .connection_receive_window
+                    .and_then(|window| VarInt::from_u64(window).ok())
+                    .unwrap_or(VarInt::MAX),
+            )
+            .initial_rtt(transport.initial_rtt)
+            .keep_alive_interval(
+                (!transport.keep_alive_interval.is_zero()).then_some(transport.keep_alive_interval),
+            )
+            .datagram_receive_buffer_size(Some(transport.datagram_receive_buffer))
+            .datagram_send_buffer_size(transport.datagram_send_buffer)
             .max_idle_timeout(None)
             .initial_mtu(cfg.initial_mtu)
             .min_mtu(cfg.min_mtu);
 
+        CONCURRENT_STREAMS.store((
+            transport.max_concurrent_bi_streams,
+            transport.max_concurrent_uni_streams,
+        ));
+
         if !cfg.gso {
             tp_cfg.enable_segmentation_offload(false);
         }
@@ -154,96 +215,527 @@ impl Connection {
             tp_cfg.mtu_discovery_config(None);
         }
 
+        let cc = &cfg.congestion;
         match cfg.congestion_control {
             CongestionControl::Cubic => {
-                tp_cfg.congestion_controller_factory(Arc::new(CubicConfig::default()))
+                let mut cubic_config = CubicConfig::default();
+                if let Some(initial_window) = cc.initial_window {
+                    cubic_config.initial_window(initial_window);
+                }
+                tp_cfg.congestion_controller_factory(Arc::new(cubic_config))
             }
             CongestionControl::NewReno => {
-                tp_cfg.congestion_controller_factory(Arc::new(NewRenoConfig::default()))
+                let mut new_reno = NewRenoConfig::default();
+                if let Some(initial_window) = cc.initial_window {
+                    new_reno.initial_window(initial_window);
+                }
+                if let Some(factor) = cc.loss_reduction_factor {
+                    new_reno.loss_reduction_factor(factor);
+                }
+                tp_cfg.congestion_controller_factory(Arc::new(new_reno))
             }
             CongestionControl::Bbr => {
-                tp_cfg.congestion_controller_factory(Arc::new(BbrConfig::default()))
+                let mut bbr_config = BbrConfig::default();
+                if let Some(initial_window) = cc.initial_window {
+                    bbr_config.initial_window(initial_window);
+                }
+                tp_cfg.congestion_controller_factory(Arc::new(bbr_config))
+            }
+            CongestionControl::Brutal => {
+                let bandwidth =
+                    cc.bandwidth
+                        .filter(|bandwidth| *bandwidth > 0)
+                        .ok_or_else(|| {
+                            Error::Other(anyhow!("brutal congestion control requires a bandwidth"))
+                        })?;
+                let brutal = BrutalConfig::new(bandwidth * 1_000_000 / 8);
+                tp_cfg.congestion_controller_factory(Arc::new(brutal))
             }
         };
 
         config.transport_config(Arc::new(tp_cfg));
 
-        let server = ServerAddr::new(cfg.server.0, cfg.server.1, cfg.ip);
-        let server_ip: Option<IpAddr> = match server.resolve().await?.next() {
+        let resolver = utils::build_resolver(&cfg.dns)?.map(Arc::new);
+
+        // the `ip` override only applies to the primary server
+        let servers = cfg
+            .server
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (domain, port))| {
+                ServerAddr::new(domain, port, cfg.ip.filter(|_| idx == 0), resolver.clone())
+            })
+            .collect::<Vec<_>>();
+        let server_ip: Option<IpAddr> = match servers[0].resolve().await?.next() {
             Some(SocketAddr::V4(v4)) => Some(v4.ip().to_owned().into()),
             Some(SocketAddr::V6(v6)) => Some(v6.ip().to_owned().into()),
             None => None,
         };
         let server_ip = server_ip.expect("Server ip not found");
-        let socket = if server_ip.is_ipv4() {
-            UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))?
+
+        let socket_opts = SocketOptions {
+            send_buffer_size: cfg.send_buffer_size,
+            recv_buffer_size: cfg.recv_buffer_size,
+            bind_interface: cfg.bind_interface,
+            fwmark: cfg.fwmark,
+            dscp: cfg.dscp,
+        };
+
+        let mut dial_via = None;
+
+        let mut ep = if let Some(dial_via_cfg) = cfg.dial_via {
+            let (session, socket) = DialViaSession::new(dial_via_cfg).await?;
+            dial_via = Some(session);
+
+            QuinnEndpoint::new_with_abstract_socket(
+                EndpointConfig::default(),
+                None,
+                socket,
+                Arc::new(TokioRuntime),
+            )?
         } else {
-            UdpSocket::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)))?
+            // servers may resolve to both address families, which are raced
+            // against each other when connecting
+            let dual_stack = || {
+                bind_dual_stack()
+                    .inspect_err(|err| {
+                        tracing::debug!(
+                            "[relay] failed to bind dual-stack UDP socket, only the address \
+                             family of the primary server is available: {err}"
+                        )
+                    })
+                    .ok()
+            };
+
+            let socket = if let Some(fd) = cfg.socket_fd {
+                socket_from_fd(fd)?
+            } else if let Some(socket) = dual_stack() {
+                socket
+            } else if server_ip.is_ipv4() {
+                UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))?
+            } else {
+                UdpSocket::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)))?
+            };
+
+            QuinnEndpoint::new_with_abstract_socket(
+                EndpointConfig::default(),
+                None,
+                socket_opts.setup(socket)?,
+                Arc::new(TokioRuntime),
+            )?
         };
 
-        let mut ep = QuinnEndpoint::new(
-            EndpointConfig::default(),
-            None,
-            socket,
-            Arc::new(TokioRuntime),
-        )?;
+        if let Some(multipath) = cfg.multipath {
+            if dial_via.is_some() {
+                return Err(Error::Other(anyhow!(
+                    "`multipath` is not supported with `dial_via`"
+                )));
+            }
+
+            let socket_opts = SocketOptions {
+                send_buffer_size: cfg.send_buffer_size,
+                recv_buffer_size: cfg.recv_buffer_size,
+                bind_interface: Some(multipath.interface),
+                fwmark: cfg.fwmark,
+                dscp: cfg.dscp,
+            };
+
+            let mut multipath_ep = QuinnEndpoint::new_with_abstract_socket(
+                EndpointConfig::default(),
+                None,
+                socket_opts.setup(bind_like(&ep)?)?,
+                Arc::new(TokioRuntime),
+            )?;
+            multipath_ep.set_default_client_config(config.clone());
+
+            MULTIPATH
+                .set(Multipath::new(multipath_ep, multipath.mode))
+                .map_err(|_| "multipath already initialized")
+                .unwrap();
+        }
 
         ep.set_default_client_config(config);
 
+        let failover = servers.len() > 1;
+        let has_dial_via = dial_via.is_some();
+
         let ep = Endpoint {
             ep,
-            server,
+            servers,
+            current: AtomicUsize::new(0),
+            failures: AtomicU32::new(0),
+            failover_threshold: cfg.failover_threshold.max(1),
             uuid: cfg.uuid,
             password: cfg.password,
             udp_relay_mode: cfg.udp_relay_mode,
             zero_rtt_handshake: cfg.zero_rtt_handshake,
             heartbeat: cfg.heartbeat,
+            heartbeat_max: cfg.heartbeat_max.max(cfg.heartbeat),
             gc_interval: cfg.gc_interval,
             gc_lifetime: cfg.gc_lifetime,
+            reassembly_limits: ReassemblyLimits {
+                timeout: cfg.gc_lifetime,
+                max_pending_packets: cfg.max_pending_packets,
+                memory: Arc::new(MemoryBudget::new(cfg.max_reassembly_memory)),
+                reorder_window: cfg.reorder_window,
+                stats: Arc::new(ReassemblyStats::default()),
+            },
+            padding: cfg.padding.map(|padding| PaddingPolicy {
+                threshold: padding.threshold,
+                max_len: padding.max_len,
+            }),
+            compression: cfg.compression.map(|compression| CompressionPolicy {
+                level: compression.level,
+            }),
+            connect_status: cfg.connect_status,
+            exporter_labels: ExporterLabels {
+                label: cfg.exporter_label.into_bytes(),
+                context: cfg.exporter_context.into_bytes(),
+            },
+            backoff: Backoff::new(cfg.reconnect),
+            // a socket handed over by `socket_fd` cannot be replaced
+            socket_opts: cfg.socket_fd.is_none().then_some(socket_opts),
+            dial_via,
         };
 
         ENDPOINT
-            .set(AsyncRwLock::new(ep))
+            .set(AsyncRwLock::new(Arc::new(ep)))
             .map_err(|_| "endpoint already initialized")
             .unwrap();
 
+        CONNECTIONS
+            .set(
+                (0..cfg.connections.max(1))
+                    .map(|_| AsyncOnceCell::new())
+                    .collect(),
+            )
+            .map_err(|_| "connection pool already initialized")
+            .unwrap();
+
         TIMEOUT.store(cfg.timeout);
 
+        UDP_RELAY_RULES
+            .set(cfg.udp_relay_rules.into_boxed_slice())
+            .map_err(|_| "UDP relay rules already initialized")
+            .unwrap();
+
+        if failover {
+            tokio::spawn(Self::retry_primary(cfg.primary_retry_interval));
+
+            if !cfg.health_check_interval.is_zero() {
+                tokio::spawn(Self::check_health(cfg.health_check_interval));
+            }
+        }
+
+        if cfg.eager_connect {
+            KEEP_OPEN.store(true, Ordering::Relaxed);
+            tokio::spawn(Self::keep_open(cfg.heartbeat));
+        }
+
+        // the path through the SOCKS5 proxy is not ours to follow
+        if !has_dial_via && !cfg.network_check_interval.is_zero() {
+            tokio::spawn(Self::watch_network(cfg.network_check_interval));
+        }
+
         Ok(())
     }
 
+    /// Returns a connection from the pool, spreading relay tasks across the
+    /// pooled connections in turn
     pub async fn get_conn() -> Result<Connection, Error> {
-        let try_init_conn = async {
-            ENDPOINT
-                .get()
-                .unwrap()
-                .read()
-                .await
-                .connect()
-                .await
-                .map(AsyncRwLock::new)
-        };
+        let idx = NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed);
+        Self::get_conn_at(idx).await
+    }
+
+    /// Returns the pooled connection a UDP session is pinned to, so all
+    /// packets of an association go through the same connection
+    pub async fn get_assoc_conn(assoc_id: u16) -> Result<Connection, Error> {
+        Self::get_conn_at(assoc_id as usize).await
+    }
+
+    async fn get_conn_at(idx: usize) -> Result<Connection, Error> {
+        let pool = CONNECTIONS.get().unwrap();
+        let slot = &pool[idx % pool.len()];
+
+        let mut failures = 0;
+
+        loop {
+            let try_init_conn = async { endpoint().await.reconnect().await.map(AsyncRwLock::new) };
 
-        let try_get_conn = async {
-            let mut conn = CONNECTION
-                .get_or_try_init(|| try_init_conn)
-                .await?
-                .write()
-                .await;
+            let try_get_conn = async {
+                let mut conn = slot.get_or_try_init(|| try_init_conn).await?.write().await;
+
+                if conn.is_closed() {
+                    let new_conn = endpoint().await.reconnect().await?;
+                    *conn = new_conn;
+                }
+
+                Ok::<_, Error>(conn.clone())
+            };
 
+            let res = try_get_conn.await;
+
+            let ep = ENDPOINT.get().unwrap().read().await;
+            match res {
+                Ok(conn) => {
+                    ep.report_success();
+                    ep.backoff.succeed();
+                    return Ok(conn);
+                }
+                Err(err) => {
+                    ep.report_failure();
+                    let in_a_row = ep.backoff.fail();
+
+                    failures += 1;
+                    if failures >= ep.backoff.max_failures() {
+                        return Err(err);
+                    }
+
+                    tracing::warn!(
+                        "[relay] failed to connect to the server ({in_a_row} failed attempts in a \
+                         row), retrying: {err}"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Returns the largest datagram the connection can currently send, `None`
+    /// if the server does not accept datagrams
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        self.conn.max_datagram_size()
+    }
+
+    /// Returns the statistics of the established pooled connections, the
+    /// reconnection backoff and the UDP packets dropped by reassembly
+    pub async fn stats() -> Value {
+        let ep = ENDPOINT.get().unwrap().read().await;
+
+        let mut connections = Vec::new();
+        for (idx, slot) in CONNECTIONS.get().unwrap().iter().enumerate() {
+            let Some(conn) = slot.get() else {
+                continue;
+            };
+
+            let conn = conn.read().await;
             if conn.is_closed() {
-                let new_conn = ENDPOINT.get().unwrap().read().await.connect().await?;
-                *conn = new_conn;
+                continue;
             }
 
-            Ok::<_, Error>(conn.clone())
+            let stats = conn.conn.stats();
+            let heartbeat_rtt = conn.model.heartbeat_rtt();
+            connections.push(json!({
+                "index": idx,
+                "server": conn.conn.remote_address(),
+                "rtt_ms": stats.path.rtt.as_secs_f64() * 1000.0,
+                "heartbeat_rtt_ms": heartbeat_rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+                "cwnd": stats.path.cwnd,
+                "congestion_events": stats.path.congestion_events,
+                "mtu": stats.path.current_mtu,
+                "max_datagram_size": conn.conn.max_datagram_size(),
+                "sent_packets": stats.path.sent_packets,
+                "lost_packets": stats.path.lost_packets,
+                "lost_bytes": stats.path.lost_bytes,
+                "sent_bytes": stats.udp_tx.bytes,
+                "received_bytes": stats.udp_rx.bytes,
+                "tcp_tasks": conn.model.task_connect_count(),
+                "udp_tasks": conn.model.task_associate_count(),
+            }));
+        }
+
+        let backoff = ep.backoff.state();
+        let reassembly = &ep.reassembly_limits.stats;
+
+        json!({
+            "connections": connections,
+            "reconnect": {
+                "failures": backoff.failures,
+                "retry_in_ms": backoff.retry_in.as_millis() as u64,
+            },
+            "udp_reassembly": {
+                "duplicated": reassembly.duplicated(),
+                "out_of_window": reassembly.out_of_window(),
+            },
+        })
+    }
+
+    /// Writes a line per established pooled connection to `out`, with its
+    /// relay tasks, then the reconnection backoff and the memory used by UDP
+    /// reassembly, for the state dump
+    pub async fn dump(out: &mut String) {
+        // the relay is not set up yet
+        let (Some(ep), Some(conns)) = (ENDPOINT.get(), CONNECTIONS.get()) else {
+            return;
         };
 
-        let conn = time::timeout(TIMEOUT.load(), try_get_conn)
-            .await
-            .map_err(|_| Error::Timeout)??;
+        let ep = ep.read().await;
+        let _ = writeln!(out, "relay connections:");
+
+        for (idx, slot) in conns.iter().enumerate() {
+            let Some(conn) = slot.get() else {
+                continue;
+            };
+
+            let conn = conn.read().await;
+            if conn.is_closed() {
+                continue;
+            }
+
+            let stats = conn.conn.stats();
+            let _ = writeln!(
+                out,
+                "  #{idx} {server}: rtt {rtt:?}, {tcp} TCP relays, {udp} UDP sessions, {lost} of \
+                 {sent} packets lost",
+                server = conn.conn.remote_address(),
+                rtt = stats.path.rtt,
+                tcp = conn.model.task_connect_count(),
+                udp = conn.model.task_associate_count(),
+                lost = stats.path.lost_packets,
+                sent = stats.path.sent_packets,
+            );
+        }
+
+        let backoff = ep.backoff.state();
+        let _ = writeln!(
+            out,
+            "reconnect: {failures} failures, retrying in {retry_in:?}",
+            failures = backoff.failures,
+            retry_in = backoff.retry_in,
+        );
+        let _ = writeln!(
+            out,
+            "UDP reassembly: {} bytes buffered",
+            ep.reassembly_limits.memory.used(),
+        );
+    }
+
+    /// Establishes all pooled connections, then redials the ones closed since
+    /// every `interval`
+    async fn keep_open(interval: Duration) {
+        let pool_size = CONNECTIONS.get().unwrap().len();
+
+        loop {
+            for idx in 0..pool_size {
+                if let Err(err) = Self::get_conn_at(idx).await {
+                    tracing::warn!("[relay] failed to establish connection in advance: {err}");
+                }
+            }
+
+            time::sleep(interval).await;
+        }
+    }
+
+    /// Watches the local address the packets to the server are sent from, so
+    /// that after a network change (e.g. Wi-Fi to cellular or a DHCP renewal)
+    /// the connections are migrated to the new path right away, instead of
+    /// stalling until the idle timeout
+    async fn watch_network(interval: Duration) {
+        let mut last = None;
+
+        loop {
+            time::sleep(interval).await;
+
+            let Some(server_addr) = CONNECTIONS
+                .get()
+                .unwrap()
+                .iter()
+                .filter_map(|slot| slot.get())
+                .find_map(|conn| conn.try_read().ok().map(|conn| conn.conn.remote_address()))
+            else {
+                continue;
+            };
+
+            let ep = ENDPOINT.get().unwrap().read().await;
+
+            // no route at all, e.g. while switching networks
+            let Some(local_ip) = route_source(server_addr, ep.socket_opts.as_ref()) else {
+                continue;
+            };
+
+            let Some(last_ip) = last.replace(local_ip) else {
+                continue;
+            };
 
-        Ok(conn)
+            if last_ip == local_ip {
+                continue;
+            }
+
+            tracing::info!(
+                "[relay] network change detected ({last_ip} -> {local_ip}), migrating connections"
+            );
+
+            if let Some(socket_opts) = &ep.socket_opts {
+                let rebind = bind_like(&ep.ep)
+                    .and_then(|socket| socket_opts.setup(socket))
+                    .and_then(|socket| ep.ep.rebind_abstract(socket));
+
+                if let Err(err) = rebind {
+                    tracing::warn!("[relay] failed to rebind endpoint socket: {err}");
+                }
+            }
+
+            for slot in CONNECTIONS.get().unwrap().iter() {
+                if let Some(conn) = slot.get() {
+                    let conn = conn.read().await.clone();
+                    let span = conn.span.clone();
+                    tokio::spawn(conn.probe_path().instrument(span));
+                }
+            }
+        }
+    }
+
+    /// Periodically tries to reconnect to the primary server while a backup
+    /// server is in use, switching new relay tasks back once it is reachable
+    async fn retry_primary(interval: Duration) {
+        loop {
+            time::sleep(interval).await;
+
+            let ep = endpoint().await;
+            if ep.current.load(Ordering::Acquire) == 0 {
+                continue;
+            }
+
+            let primary = &ep.servers[0];
+            let probe_conn = match time::timeout(TIMEOUT.load(), ep.connect_to(primary)).await {
+                Ok(Ok(conn)) => conn,
+                Ok(Err(err)) => {
+                    tracing::debug!("[relay] primary server {primary} still unavailable: {err}");
+                    continue;
+                }
+                Err(_) => {
+                    tracing::debug!("[relay] primary server {primary} still unavailable: timeout");
+                    continue;
+                }
+            };
+
+            tracing::info!("[relay] switching back to primary server {primary}");
+            ep.current.store(0, Ordering::Release);
+            ep.failures.store(0, Ordering::Release);
+
+            // uninitialized slots connect to the primary server on first use
+            let mut probe_conn = Some(probe_conn);
+            for slot in CONNECTIONS.get().unwrap().iter() {
+                let Some(conn) = slot.get() else {
+                    continue;
+                };
+
+                let new_conn = match probe_conn.take() {
+                    Some(new_conn) => new_conn,
+                    None => match time::timeout(TIMEOUT.load(), ep.connect_to(primary)).await {
+                        Ok(Ok(new_conn)) => new_conn,
+                        _ => continue,
+                    },
+                };
+
+                let old_conn = mem::replace(&mut *conn.write().await, new_conn);
+                let span = old_conn.span.clone();
+                tokio::spawn(old_conn.close_when_idle().instrument(span));
+            }
+
+            if let Some(probe_conn) = probe_conn {
+                probe_conn.conn.close(ERROR_CODE, &[]);
+            }
+        }
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -254,24 +746,66 @@ impl Connection {
         uuid: Uuid,
         password: Arc<[u8]>,
         heartbeat: Duration,
+        heartbeat_max: Duration,
         gc_interval: Duration,
         gc_lifetime: Duration,
+        reassembly_limits: ReassemblyLimits,
+        padding: Option<PaddingPolicy>,
+        compression: Option<CompressionPolicy>,
+        connect_status: bool,
+        exporter_labels: ExporterLabels,
     ) -> Self {
+        let span = tracing::info_span!("conn", id = conn.stable_id() as u32, user = %uuid);
+
+        let model = Model::<side::Client>::new(conn.clone());
+        model.set_reassembly_limits(reassembly_limits);
+        model.set_exporter_labels(exporter_labels);
+        if let Some(padding) = padding {
+            model.set_padding_policy(padding);
+        }
+
+        let mut capabilities = CAPABILITIES;
+        if let Some(compression) = compression {
+            model.set_compression_policy(compression);
+            capabilities = capabilities | Capabilities::COMPRESSION;
+        }
+        if connect_status {
+            capabilities = capabilities | Capabilities::CONNECT_STATUS;
+        }
+        if MULTIPATH.get().is_some() {
+            capabilities = capabilities | Capabilities::MULTIPATH;
+        }
+        if handle_task::mixes_udp_relay_modes(udp_relay_mode) {
+            capabilities = capabilities | Capabilities::MIXED_UDP_RELAY;
+        }
+
+        let (max_concurrent_bi_streams, max_concurrent_uni_streams) = CONCURRENT_STREAMS.load();
+
         let conn = Self {
-            conn: conn.clone(),
-            model: Model::<side::Client>::new(conn),
+            conn,
+            model,
             uuid,
             password,
             udp_relay_mode,
+            quic_assocs: Arc::new(Mutex::new(HashSet::new())),
+            capabilities,
             remote_uni_stream_cnt: Counter::new(),
             remote_bi_stream_cnt: Counter::new(),
-            max_concurrent_uni_streams: Arc::new(AtomicU32::new(DEFAULT_CONCURRENT_STREAMS)),
-            max_concurrent_bi_streams: Arc::new(AtomicU32::new(DEFAULT_CONCURRENT_STREAMS)),
+            max_concurrent_uni_streams: Arc::new(AtomicU32::new(max_concurrent_uni_streams)),
+            max_concurrent_bi_streams: Arc::new(AtomicU32::new(max_concurrent_bi_streams)),
+            span,
         };
 
         tokio::spawn(
             conn.clone()
-                .init(zero_rtt_accepted, heartbeat, gc_interval, gc_lifetime),
+                .init(
+                    zero_rtt_accepted,
+                    heartbeat,
+                    heartbeat_max,
+                    gc_interval,
+                    gc_lifetime,
+                )
+                .instrument(conn.span.clone()),
         );
 
         conn
@@ -281,39 +815,138 @@ impl Connection {
         self,
         zero_rtt_accepted: Option<ZeroRttAccepted>,
         heartbeat: Duration,
+        heartbeat_max: Duration,
         gc_interval: Duration,
         gc_lifetime: Duration,
     ) {
-        log::info!("[relay] connection established");
+        tracing::info!("[relay] connection established");
 
-        tokio::spawn(self.clone().authenticate(zero_rtt_accepted));
-        tokio::spawn(self.clone().heartbeat(heartbeat));
-        tokio::spawn(self.clone().collect_garbage(gc_interval, gc_lifetime));
+        tokio::spawn(
+            self.clone()
+                .authenticate(zero_rtt_accepted)
+                .in_current_span(),
+        );
+        tokio::spawn(
+            self.clone()
+                .heartbeat(heartbeat, heartbeat_max)
+                .in_current_span(),
+        );
+        tokio::spawn(
+            self.clone()
+                .collect_garbage(gc_interval, gc_lifetime)
+                .in_current_span(),
+        );
 
         let err = loop {
             tokio::select! {
                 res = self.accept_uni_stream() => match res {
-                    Ok((recv, reg)) => tokio::spawn(self.clone().handle_uni_stream(recv, reg)),
+                    Ok((recv, reg)) => tokio::spawn(self.clone().handle_uni_stream(recv, reg).in_current_span()),
                     Err(err) => break err,
                 },
                 res = self.accept_bi_stream() => match res {
-                    Ok((send, recv, reg)) => tokio::spawn(self.clone().handle_bi_stream(send, recv, reg)),
+                    Ok((send, recv, reg)) => tokio::spawn(self.clone().handle_bi_stream(send, recv, reg).in_current_span()),
                     Err(err) => break err,
                 },
                 res = self.accept_datagram() => match res {
-                    Ok(dg) => tokio::spawn(self.clone().handle_datagram(dg)),
+                    Ok(dg) => tokio::spawn(self.clone().handle_datagram(dg).in_current_span()),
                     Err(err) => break err,
                 },
             };
         };
 
-        log::warn!("[relay] connection error: {err}");
+        tracing::warn!("[relay] connection error: {err}");
     }
 
     fn is_closed(&self) -> bool {
         self.conn.close_reason().is_some()
     }
 
+    /// Probes the connections relay tasks are using every `interval`, so a
+    /// server that stopped answering is failed over from before the idle
+    /// timeout
+    async fn check_health(interval: Duration) {
+        loop {
+            time::sleep(interval).await;
+
+            for slot in CONNECTIONS.get().unwrap().iter() {
+                let Some(conn) = slot.get() else {
+                    continue;
+                };
+
+                let conn = conn.read().await.clone();
+                // probing idle connections would keep them open
+                if conn.is_closed()
+                    || (!KEEP_OPEN.load(Ordering::Relaxed)
+                        && conn.model.task_connect_count() + conn.model.task_associate_count() == 0)
+                {
+                    continue;
+                }
+
+                let span = conn.span.clone();
+                tokio::spawn(conn.check_alive().instrument(span));
+            }
+        }
+    }
+
+    /// Sends a heartbeat, returning whether the server acknowledged anything
+    /// within the timeout
+    async fn probe(&self) -> bool {
+        let acks = self.conn.stats().frame_rx.acks;
+
+        if let Err(err) = self.model.heartbeat().await {
+            tracing::warn!("[relay] [heartbeat] {err}");
+        }
+
+        time::timeout(TIMEOUT.load(), async {
+            while self.conn.stats().frame_rx.acks == acks && !self.is_closed() {
+                time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    /// Probes the connection. If the probe goes unanswered, it counts as a
+    /// failed connection attempt and the connection is closed to be
+    /// re-established on next use, on the next server once the failover
+    /// threshold is reached
+    async fn check_alive(self) {
+        if self.probe().await {
+            return;
+        }
+
+        tracing::warn!(
+            "[relay] server {} did not answer within {:?}, reconnecting",
+            self.conn.remote_address(),
+            TIMEOUT.load(),
+        );
+        endpoint().await.report_failure();
+        self.conn.close(ERROR_CODE, b"unresponsive");
+    }
+
+    /// Probes the path of the connection after a network change, so the server
+    /// learns the new address right away. If the probe goes unanswered, the
+    /// connection is closed to be re-established on next use, UDP sessions
+    /// following on their next packet
+    async fn probe_path(self) {
+        if self.probe().await {
+            tracing::debug!("[relay] path migrated to {}", self.conn.remote_address());
+        } else {
+            tracing::warn!("[relay] path migration timed out, reconnecting");
+            self.conn.close(ERROR_CODE, b"network changed");
+        }
+    }
+
+    /// Closes a replaced connection once all TCP relay tasks on it are done.
+    /// UDP sessions follow the current connection on their next packet
+    async fn close_when_idle(self) {
+        while !self.is_closed() && self.model.task_connect_count() > 0 {
+            time::sleep(Duration::from_secs(1)).await;
+        }
+
+        self.conn.close(ERROR_CODE, &[]);
+    }
+
     async fn collect_garbage(self, gc_interval: Duration, gc_lifetime: Duration) {
         loop {
             time::sleep(gc_interval).await;
@@ -322,60 +955,360 @@ impl Connection {
                 break;
             }
 
-            log::debug!("[relay] packet fragment garbage collecting event");
+            tracing::debug!("[relay] packet fragment garbage collecting event");
             self.model.collect_garbage(gc_lifetime);
         }
     }
 }
 
+/// Returns the endpoint, without holding its lock, e.g. across handshakes
+async fn endpoint() -> Arc<Endpoint> {
+    ENDPOINT.get().unwrap().read().await.clone()
+}
+
 struct Endpoint {
     ep: QuinnEndpoint,
-    server: ServerAddr,
+    servers: Vec<ServerAddr>,
+    current: AtomicUsize,
+    failures: AtomicU32,
+    failover_threshold: u32,
     uuid: Uuid,
     password: Arc<[u8]>,
     udp_relay_mode: UdpRelayMode,
     zero_rtt_handshake: bool,
     heartbeat: Duration,
+    heartbeat_max: Duration,
     gc_interval: Duration,
     gc_lifetime: Duration,
+    reassembly_limits: ReassemblyLimits,
+    padding: Option<PaddingPolicy>,
+    compression: Option<CompressionPolicy>,
+    connect_status: bool,
+    exporter_labels: ExporterLabels,
+    backoff: Backoff,
+    socket_opts: Option<SocketOptions>,
+    dial_via: Option<DialViaSession>,
 }
 
 impl Endpoint {
     async fn connect(&self) -> Result<Connection, Error> {
+        let server = &self.servers[self.current.load(Ordering::Acquire)];
+        self.connect_to(server).await
+    }
+
+    /// Connects to the current server once the backoff after previous failed
+    /// attempts has elapsed. The timeout only starts after the backoff
+    async fn reconnect(&self) -> Result<Connection, Error> {
+        self.backoff.wait().await;
+
+        if let Some(dial_via) = &self.dial_via {
+            dial_via.ensure_open(&self.ep).await?;
+        }
+
+        time::timeout(TIMEOUT.load(), self.connect())
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+
+    /// Connects to the server, dialing its resolved addresses in the Happy
+    /// Eyeballs fashion (RFC 8305). Address families are interleaved and each
+    /// attempt gets a head start before the next one is raced against it
+    async fn connect_to(&self, server: &ServerAddr) -> Result<Connection, Error> {
+        self.connect_via(&self.ep, server).await
+    }
+
+    /// Connects to the server through `ep`, e.g. the endpoint of the second
+    /// path in multipath mode
+    async fn connect_via(
+        &self,
+        ep: &QuinnEndpoint,
+        server: &ServerAddr,
+    ) -> Result<Connection, Error> {
+        let mut addrs = interleave_families(server.resolve().await?).into_iter();
+        let mut attempts = FuturesUnordered::new();
         let mut last_err = None;
 
-        for addr in self.server.resolve().await? {
-            let connect_to = async {
-                let conn = self.ep.connect(addr, self.server.server_name())?;
-                let (conn, zero_rtt_accepted) = if self.zero_rtt_handshake {
-                    match conn.into_0rtt() {
-                        Ok((conn, zero_rtt_accepted)) => (conn, Some(zero_rtt_accepted)),
-                        Err(conn) => (conn.await?, None),
-                    }
-                } else {
-                    (conn.await?, None)
-                };
+        loop {
+            if let Some(addr) = addrs.next() {
+                tracing::debug!("[relay] connecting to {server} via {addr}");
+                attempts.push(self.handshake(ep, server, addr));
+            }
 
-                Ok((conn, zero_rtt_accepted))
-            };
+            if attempts.is_empty() {
+                break;
+            }
 
-            match connect_to.await {
-                Ok((conn, zero_rtt_accepted)) => {
-                    return Ok(Connection::new(
-                        conn,
-                        zero_rtt_accepted,
-                        self.udp_relay_mode,
-                        self.uuid,
-                        self.password.clone(),
-                        self.heartbeat,
-                        self.gc_interval,
-                        self.gc_lifetime,
-                    ));
-                }
-                Err(err) => last_err = Some(err),
+            tokio::select! {
+                Some(res) = attempts.next() => match res {
+                    Ok((conn, zero_rtt_accepted)) => {
+                        return Ok(Connection::new(
+                            conn,
+                            zero_rtt_accepted,
+                            self.udp_relay_mode,
+                            self.uuid,
+                            self.password.clone(),
+                            self.heartbeat,
+                            self.heartbeat_max,
+                            self.gc_interval,
+                            self.gc_lifetime,
+                            self.reassembly_limits.clone(),
+                            self.padding,
+                            self.compression,
+                            self.connect_status,
+                            self.exporter_labels.clone(),
+                        ));
+                    }
+                    Err(err) => {
+                        tracing::debug!("[relay] failed connecting to {server}: {err}");
+                        last_err = Some(err);
+                    }
+                },
+                _ = time::sleep(CONNECTION_ATTEMPT_DELAY), if addrs.len() > 0 => {}
             }
         }
 
         Err(last_err.unwrap_or(Error::DnsResolve))
     }
+
+    async fn handshake(
+        &self,
+        ep: &QuinnEndpoint,
+        server: &ServerAddr,
+        addr: SocketAddr,
+    ) -> Result<(QuinnConnection, Option<ZeroRttAccepted>), Error> {
+        let conn = ep.connect(addr, server.server_name())?;
+
+        if self.zero_rtt_handshake {
+            match conn.into_0rtt() {
+                Ok((conn, zero_rtt_accepted)) => Ok((conn, Some(zero_rtt_accepted))),
+                Err(conn) => Ok((conn.await?, None)),
+            }
+        } else {
+            Ok((conn.await?, None))
+        }
+    }
+
+    fn report_success(&self) {
+        self.failures.store(0, Ordering::Release);
+    }
+
+    /// Records a failed connection attempt, moving on to the next server once
+    /// the failure threshold is reached
+    fn report_failure(&self) {
+        if self.servers.len() < 2 {
+            return;
+        }
+
+        let failures = self.failures.fetch_add(1, Ordering::AcqRel) + 1;
+        if failures < self.failover_threshold {
+            return;
+        }
+
+        if let Some(next) = self.fail_over() {
+            tracing::warn!(
+                "[relay] failing over to server {next} after {failures} failed attempts"
+            );
+        }
+    }
+
+    /// Moves new connections on to the next server. Returns `None` if there is
+    /// no other server to use
+    fn fail_over(&self) -> Option<&ServerAddr> {
+        if self.servers.len() < 2 {
+            return None;
+        }
+
+        self.failures.store(0, Ordering::Release);
+        let next = (self.current.load(Ordering::Acquire) + 1) % self.servers.len();
+        self.current.store(next, Ordering::Release);
+
+        Some(&self.servers[next])
+    }
+}
+
+/// Options of the endpoint socket, kept to set up a new one after a network
+/// change
+struct SocketOptions {
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+    bind_interface: Option<String>,
+    fwmark: Option<u32>,
+    dscp: Option<u8>,
+}
+
+impl SocketOptions {
+    fn setup(&self, socket: UdpSocket) -> Result<Arc<dyn AsyncUdpSocket>, IoError> {
+        set_buffer_sizes(&socket, self.send_buffer_size, self.recv_buffer_size)?;
+        set_routing(&socket, self.bind_interface.as_deref(), self.fwmark)?;
+
+        if let Some(protect) = PROTECT.get() {
+            protect(&socket)?;
+        }
+
+        if let Some(dscp) = self.dscp {
+            set_dscp(SockRef::from(&socket), dscp)?;
+            Ok(Arc::new(DscpSocket::new(
+                TokioRuntime.wrap_udp_socket(socket)?,
+            )))
+        } else {
+            TokioRuntime.wrap_udp_socket(socket)
+        }
+    }
+}
+
+/// Binds a new socket of the same address family as the one of `ep`
+fn bind_like(ep: &QuinnEndpoint) -> Result<UdpSocket, IoError> {
+    match ep.local_addr()? {
+        SocketAddr::V4(_) => UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))),
+        SocketAddr::V6(_) => bind_dual_stack()
+            .or_else(|_| UdpSocket::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)))),
+    }
+}
+
+/// Returns the local address the system would send packets to `server_addr`
+/// from, or `None` if it is unreachable
+fn route_source(server_addr: SocketAddr, socket_opts: Option<&SocketOptions>) -> Option<IpAddr> {
+    // dual-stack endpoints report IPv4 servers as IPv4-mapped addresses
+    let server_addr = SocketAddr::new(server_addr.ip().to_canonical(), server_addr.port());
+
+    let bind_addr = match server_addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+
+    let socket = UdpSocket::bind(bind_addr).ok()?;
+    if let Some(opts) = socket_opts {
+        set_routing(&socket, opts.bind_interface.as_deref(), opts.fwmark).ok()?;
+    }
+    socket.connect(server_addr).ok()?;
+
+    Some(socket.local_addr().ok()?.ip())
+}
+
+fn bind_dual_stack() -> Result<UdpSocket, IoError> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+    Ok(UdpSocket::from(socket))
+}
+
+/// Takes over the already bound UDP socket `fd`
+#[cfg(unix)]
+fn socket_from_fd(fd: i32) -> Result<UdpSocket, IoError> {
+    use std::os::fd::FromRawFd;
+
+    if fd < 0 {
+        return Err(IoError::new(ErrorKind::InvalidInput, "invalid `socket_fd`"));
+    }
+
+    // SAFETY: the configuration hands the ownership of `fd` over to the client
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    if SockRef::from(&socket).r#type()? != Type::DGRAM {
+        // not ours to close
+        mem::forget(socket);
+        return Err(IoError::new(
+            ErrorKind::InvalidInput,
+            "`socket_fd` is not a UDP socket",
+        ));
+    }
+    socket.set_nonblocking(true)?;
+
+    Ok(socket)
+}
+
+#[cfg(not(unix))]
+fn socket_from_fd(_fd: i32) -> Result<UdpSocket, IoError> {
+    Err(IoError::new(
+        ErrorKind::Unsupported,
+        "`socket_fd` is only supported on Unix",
+    ))
+}
+
+/// Sets the kernel buffer sizes of the endpoint socket. The default receive
+/// buffer commonly drops packets above a few hundred Mbit/s
+fn set_buffer_sizes(
+    socket: &UdpSocket,
+    send: Option<usize>,
+    recv: Option<usize>,
+) -> Result<(), IoError> {
+    let socket = SockRef::from(socket);
+
+    if let Some(size) = send {
+        socket.set_send_buffer_size(size)?;
+        let actual = socket.send_buffer_size()?;
+        if actual < size {
+            tracing::warn!(
+                "[relay] send buffer capped at {actual} bytes instead of {size}, raise \
+                 `net.core.wmem_max` to allow more"
+            );
+        }
+    }
+
+    if let Some(size) = recv {
+        socket.set_recv_buffer_size(size)?;
+        let actual = socket.recv_buffer_size()?;
+        if actual < size {
+            tracing::warn!(
+                "[relay] receive buffer capped at {actual} bytes instead of {size}, raise \
+                 `net.core.rmem_max` to allow more"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Binds the endpoint socket to an interface and sets its firewall mark, for
+/// excluding the traffic to the server from a tunnel
+fn set_routing(
+    socket: &UdpSocket,
+    interface: Option<&str>,
+    fwmark: Option<u32>,
+) -> Result<(), IoError> {
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    {
+        let socket = SockRef::from(socket);
+
+        if let Some(interface) = interface {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+
+        if let Some(mark) = fwmark {
+            socket.set_mark(mark)?;
+        }
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+    {
+        let _ = socket;
+
+        if interface.is_some() || fwmark.is_some() {
+            return Err(IoError::new(
+                ErrorKind::Unsupported,
+                "`bind_interface` and `fwmark` are only supported on Linux",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Reorders resolved addresses so that the two address families alternate,
+/// starting with the family of the first address the resolver returned
+fn interleave_families(addrs: impl Iterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+    let mut addrs = addrs.peekable();
+    let first_v6 = addrs.peek().is_some_and(SocketAddr::is_ipv6);
+    let (mut first, mut second): (Vec<_>, Vec<_>) =
+        addrs.partition(|addr| addr.is_ipv6() == first_v6);
+    first.reverse();
+    second.reverse();
+
+    let mut res = Vec::with_capacity(first.len() + second.len());
+    loop {
+        match (first.pop(), second.pop()) {
+            (None, None) => break,
+            (a, b) => res.extend(a.into_iter().chain(b)),
+        }
+    }
+    res
 }
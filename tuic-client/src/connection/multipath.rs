@@ -0,0 +1,161 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use once_cell::sync::OnceCell;
+use quinn::Endpoint as QuinnEndpoint;
+use tokio::time;
+use tuic::{Address, Capabilities};
+
+use super::{Connection, TIMEOUT, endpoint};
+use crate::{error::Error, utils::MultipathMode};
+
+pub(super) static MULTIPATH: OnceCell<Multipath> = OnceCell::new();
+
+/// The second network path `native` mode UDP packets are sent over. The
+/// connections of both paths join the same bond on the server, which relays
+/// each packet only once
+pub(super) struct Multipath {
+    ep: QuinnEndpoint,
+    mode: MultipathMode,
+    pub(super) bond_id: u64,
+    conn: Mutex<Option<Connection>>,
+    connecting: AtomicBool,
+    /// Packet IDs shared by both paths, by association ID
+    next_pkt_ids: Mutex<HashMap<u16, u16>>,
+    /// Whether the next striped packet is sent over the second path
+    stripe: AtomicBool,
+}
+
+impl Multipath {
+    pub(super) fn new(ep: QuinnEndpoint, mode: MultipathMode) -> Self {
+        Self {
+            ep,
+            mode,
+            bond_id: rand::random(),
+            conn: Mutex::new(None),
+            connecting: AtomicBool::new(false),
+            next_pkt_ids: Mutex::new(HashMap::new()),
+            stripe: AtomicBool::new(false),
+        }
+    }
+
+    /// Sends a UDP packet in `native` mode over `conn`, the connection of the
+    /// first path, and the connection of the second path, either both or in
+    /// turn. Only the first path is used until the second one is established
+    pub(super) fn packet_native(
+        &self,
+        conn: &Connection,
+        pkt: &[u8],
+        addr: Address,
+        assoc_id: u16,
+    ) -> eyre::Result<()> {
+        let pkt_id = {
+            let mut next_pkt_ids = self
+                .next_pkt_ids
+                .lock()
+                .unwrap_or_else(|err| err.into_inner());
+            let next_pkt_id = next_pkt_ids.entry(assoc_id).or_default();
+            let pkt_id = *next_pkt_id;
+            *next_pkt_id = next_pkt_id.wrapping_add(1);
+            pkt_id
+        };
+
+        let Some(second) = self.second_conn() else {
+            return conn.model.packet_native_as(pkt, addr, assoc_id, pkt_id);
+        };
+
+        match self.mode {
+            MultipathMode::Duplicate => {
+                let first = conn
+                    .model
+                    .packet_native_as(pkt, addr.clone(), assoc_id, pkt_id);
+                let second = second.model.packet_native_as(pkt, addr, assoc_id, pkt_id);
+                // the packet is relayed as long as one path takes it
+                first.or(second)
+            }
+            MultipathMode::Stripe => {
+                let conn = if self.stripe.fetch_xor(true, Ordering::Relaxed) {
+                    &second
+                } else {
+                    conn
+                };
+                conn.model.packet_native_as(pkt, addr, assoc_id, pkt_id)
+            }
+        }
+    }
+
+    /// Dissociates the UDP session on the second path as well
+    pub(super) async fn dissociate(&self, assoc_id: u16) {
+        self.next_pkt_ids
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .remove(&assoc_id);
+
+        let second = self
+            .conn
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone();
+
+        if let Some(second) = second
+            && !second.is_closed()
+            && let Err(err) = second.model.dissociate(assoc_id).await
+        {
+            tracing::debug!("[relay] [multipath] [dissociate] [{assoc_id:#06x}] {err}");
+        }
+    }
+
+    /// Returns the connection of the second path once it is bonded, dialing it
+    /// in the background if it is not established
+    fn second_conn(&self) -> Option<Connection> {
+        let conn = self
+            .conn
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+            .filter(|conn| !conn.is_closed());
+
+        if conn.is_none() && !self.connecting.swap(true, Ordering::AcqRel) {
+            tokio::spawn(Self::connect());
+        }
+
+        conn.filter(|conn| {
+            conn.model
+                .negotiated()
+                .is_some_and(|(_, caps)| caps.contains(Capabilities::MULTIPATH))
+        })
+    }
+
+    /// Connects to the current server over the second path. After a failure,
+    /// the next attempt is held off for the connection timeout
+    async fn connect() {
+        let multipath = MULTIPATH.get().unwrap();
+
+        let res = async {
+            let ep = endpoint().await;
+            let server = &ep.servers[ep.current.load(Ordering::Acquire)];
+
+            time::timeout(TIMEOUT.load(), ep.connect_via(&multipath.ep, server))
+                .await
+                .map_err(|_| Error::Timeout)?
+        };
+
+        match res.await {
+            Ok(conn) => {
+                tracing::info!("[relay] [multipath] second path established");
+                *multipath.conn.lock().unwrap_or_else(|err| err.into_inner()) = Some(conn);
+            }
+            Err(err) => {
+                tracing::warn!("[relay] [multipath] failed to establish the second path: {err}");
+                time::sleep(TIMEOUT.load()).await;
+            }
+        }
+
+        multipath.connecting.store(false, Ordering::Release);
+    }
+}
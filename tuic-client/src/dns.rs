@@ -0,0 +1,279 @@
+use std::{
+    io::{Error as IoError, ErrorKind},
+    net::{IpAddr, SocketAddr, TcpListener as StdTcpListener, UdpSocket as StdUdpSocket},
+    sync::Arc,
+    time::Duration,
+};
+
+use hickory_resolver::proto::{
+    op::{Message, MessageType, OpCode},
+    rr::{
+        RData, Record, RecordType,
+        rdata::{A, AAAA},
+    },
+};
+use once_cell::sync::OnceCell;
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time,
+};
+use tuic::Address as TuicAddress;
+use tuic_quinn::BufPool;
+
+use crate::{
+    config::DnsInbound,
+    connection::{Connection as TuicConnection, ERROR_CODE},
+    error::Error,
+    fake_ip::FakeIp,
+};
+
+static SERVER: OnceCell<Server> = OnceCell::new();
+
+/// Largest DNS message, as the length prefix of DNS over TCP is 16 bits
+const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+/// Fake IPs are only valid while the mapping is kept, so they must not be
+/// cached
+const FAKE_IP_TTL: u32 = 1;
+
+/// A local DNS server forwarding the queries to `upstream` through the relay
+///
+/// There is no UDP relaying of the queries. Each one is sent as DNS over TCP on
+/// its own relayed stream, so it works regardless of `udp_relay_mode` and
+/// whether the remote network allows UDP at all.
+///
+/// With `fake_ip` set, `A` or `AAAA` queries are answered locally with an
+/// address from the fake IP range instead, which is turned back into the domain
+/// when relaying a connection to it.
+pub struct Server {
+    udp: Arc<UdpSocket>,
+    tcp: TcpListener,
+    upstream: SocketAddr,
+    timeout: Duration,
+}
+
+impl Server {
+    pub fn set_config(cfg: DnsInbound) -> Result<(), Error> {
+        SERVER
+            .set(Self::new(cfg)?)
+            .map_err(|_| "failed initializing DNS server")
+            .unwrap();
+
+        Ok(())
+    }
+
+    fn new(cfg: DnsInbound) -> Result<Self, Error> {
+        if let Some(range) = cfg.fake_ip {
+            FakeIp::set_config(range)?;
+        }
+
+        let udp = StdUdpSocket::bind(cfg.listen)
+            .and_then(|socket| {
+                socket.set_nonblocking(true)?;
+                UdpSocket::from_std(socket)
+            })
+            .map_err(|err| Error::Socket("failed to bind DNS server UDP socket", err))?;
+
+        let tcp = StdTcpListener::bind(cfg.listen)
+            .and_then(|listener| {
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)
+            })
+            .map_err(|err| Error::Socket("failed to bind DNS server TCP socket", err))?;
+
+        Ok(Self {
+            udp: Arc::new(udp),
+            tcp,
+            upstream: cfg.upstream,
+            timeout: cfg.timeout,
+        })
+    }
+
+    pub async fn start() {
+        let server = SERVER.get().unwrap();
+
+        tracing::warn!(
+            "[dns] server started, listening on {}, forwarding to {}",
+            server.tcp.local_addr().unwrap(),
+            server.upstream
+        );
+
+        tokio::join!(server.serve_udp(), server.serve_tcp());
+    }
+
+    async fn serve_udp(&'static self) {
+        let mut pool = BufPool::new(MAX_MESSAGE_SIZE);
+
+        loop {
+            let (len, addr) = match self.udp.recv_buf_from(&mut pool.buf()).await {
+                Ok(res) => res,
+                Err(err) => {
+                    tracing::warn!("[dns] failed to receive UDP query: {err}");
+                    continue;
+                }
+            };
+
+            let query = pool.take();
+            tracing::debug!("[dns] [{addr}] [udp] query of {len} bytes");
+
+            tokio::spawn(async move {
+                let res = match time::timeout(self.timeout, self.resolve(&query)).await {
+                    Ok(res) => res,
+                    Err(_) => Err(Error::Timeout),
+                };
+
+                match res {
+                    Ok(resp) => {
+                        if let Err(err) = self.udp.send_to(&resp, addr).await {
+                            tracing::warn!("[dns] [{addr}] [udp] failed to send response: {err}");
+                        }
+                    }
+                    Err(err) => tracing::warn!("[dns] [{addr}] [udp] query failed: {err}"),
+                }
+            });
+        }
+    }
+
+    async fn serve_tcp(&'static self) {
+        loop {
+            match self.tcp.accept().await {
+                Ok((conn, addr)) => {
+                    tracing::debug!("[dns] [{addr}] [tcp] connection established");
+
+                    tokio::spawn(async move {
+                        match self.relay_tcp(conn).await {
+                            Ok(()) => tracing::debug!("[dns] [{addr}] [tcp] connection closed"),
+                            Err(err) => {
+                                tracing::warn!("[dns] [{addr}] [tcp] relaying error: {err}")
+                            }
+                        }
+                    });
+                }
+                Err(err) => tracing::warn!("[dns] failed to accept TCP connection: {err}"),
+            }
+        }
+    }
+
+    /// Answers the query with a fake IP if possible, or forwards it upstream
+    async fn resolve(&self, query: &[u8]) -> Result<Vec<u8>, Error> {
+        if let Some(fake_ip) = FakeIp::get()
+            && let Some(resp) = fake_ip_response(fake_ip, query)
+        {
+            return Ok(resp);
+        }
+
+        self.exchange(query).await
+    }
+
+    /// Sends a single query over a new relayed stream and reads the response
+    async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut relay = TuicConnection::get_conn()
+            .await?
+            .connect(TuicAddress::SocketAddress(self.upstream))
+            .await?;
+
+        let res = async {
+            relay.write_u16(query.len() as u16).await?;
+            relay.write_all(query).await?;
+            relay.flush().await?;
+
+            let len = relay.read_u16().await?;
+            let mut resp = vec![0; len as usize];
+            relay.read_exact(&mut resp).await?;
+
+            Ok::<_, IoError>(resp)
+        }
+        .await;
+
+        match res {
+            Ok(resp) => {
+                let _ = relay.shutdown().await;
+                Ok(resp)
+            }
+            Err(err) => {
+                let _ = relay.reset(ERROR_CODE);
+                Err(Error::Io(err))
+            }
+        }
+    }
+
+    /// DNS over TCP is already length-prefixed, so the stream is relayed as is,
+    /// unless the queries have to be answered with fake IPs
+    async fn relay_tcp(&self, mut conn: TcpStream) -> Result<(), Error> {
+        if FakeIp::get().is_some() {
+            return self.serve_tcp_queries(conn).await;
+        }
+
+        let mut relay = TuicConnection::get_conn()
+            .await?
+            .connect(TuicAddress::SocketAddress(self.upstream))
+            .await?;
+
+        match io::copy_bidirectional(&mut conn, &mut relay).await {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                let _ = conn.shutdown().await;
+                let _ = relay.reset(ERROR_CODE);
+                Err(Error::Io(err))
+            }
+        }
+    }
+
+    async fn serve_tcp_queries(&self, mut conn: TcpStream) -> Result<(), Error> {
+        loop {
+            let len = match conn.read_u16().await {
+                Ok(len) => len,
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(Error::Io(err)),
+            };
+
+            let mut query = vec![0; len as usize];
+            conn.read_exact(&mut query).await?;
+
+            let resp = self.resolve(&query).await?;
+            conn.write_u16(resp.len() as u16).await?;
+            conn.write_all(&resp).await?;
+        }
+    }
+}
+
+/// Builds the response to an `A` or `AAAA` query with a fake IP
+///
+/// Queries of the other address family get an empty answer, so clients always
+/// connect to the fake IP. `None` is returned for the queries to be forwarded.
+fn fake_ip_response(fake_ip: &FakeIp, query: &[u8]) -> Option<Vec<u8>> {
+    let req = Message::from_vec(query).ok()?;
+
+    if req.message_type() != MessageType::Query || req.op_code() != OpCode::Query {
+        return None;
+    }
+
+    let [query] = req.queries() else {
+        return None;
+    };
+
+    let query_type = query.query_type();
+    if !matches!(query_type, RecordType::A | RecordType::AAAA) {
+        return None;
+    }
+
+    let mut resp = Message::new();
+    resp.set_id(req.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(req.recursion_desired())
+        .set_recursion_available(true)
+        .add_query(query.clone());
+
+    if (query_type == RecordType::A) == fake_ip.is_ipv4() {
+        let rdata = match fake_ip.lookup(&query.name().to_ascii()) {
+            IpAddr::V4(ip) => RData::A(A(ip)),
+            IpAddr::V6(ip) => RData::AAAA(AAAA(ip)),
+        };
+
+        resp.add_answer(Record::from_rdata(query.name().clone(), FAKE_IP_TTL, rdata));
+    }
+
+    resp.to_vec().ok()
+}
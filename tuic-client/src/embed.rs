@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    io::Error as IoError,
+    net::UdpSocket,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use bytes::Bytes;
+use once_cell::sync::{Lazy, OnceCell};
+use tokio::{
+    runtime::Handle,
+    sync::{RwLock as AsyncRwLock, mpsc},
+};
+use tuic::Address;
+use tuic_quinn::Connect;
+
+use crate::{config::Relay, connection::Connection, error::Error};
+
+/// Packets queued for an association before new ones are dropped
+const QUEUE_SIZE: usize = 256;
+
+/// Queue of the packets received on an association
+pub(crate) type PacketSender = mpsc::Sender<(Bytes, Address)>;
+
+/// Associations opened through [`udp_associate`], by association ID
+pub(crate) static ASSOCIATIONS: Lazy<AsyncRwLock<HashMap<u16, PacketSender>>> =
+    Lazy::new(|| AsyncRwLock::new(HashMap::new()));
+
+/// Association IDs are handed out downwards, away from the ones of the SOCKS5
+/// server counting up from 0
+static NEXT_ASSOC_ID: AtomicU16 = AtomicU16::new(u16::MAX);
+
+/// Callback set by [`protect_socket`]
+pub(crate) type Protect = Box<dyn Fn(&UdpSocket) -> Result<(), IoError> + Send + Sync>;
+
+pub(crate) static PROTECT: OnceCell<Protect> = OnceCell::new();
+
+/// Sets a callback called with the UDP socket connecting to the TUIC server,
+/// before any packet is sent. Must be called before [`init`], and only once
+///
+/// On Android, the callback passes the raw fd of the socket to
+/// `VpnService.protect()` so that the traffic to the server bypasses the VPN.
+/// Returning an error fails [`init`]. Alternatively, a socket created and
+/// protected by the app can be handed over with the `socket_fd` option
+pub fn protect_socket(protect: impl Fn(&UdpSocket) -> Result<(), IoError> + Send + Sync + 'static) {
+    if PROTECT.set(Box::new(protect)).is_err() {
+        tracing::warn!("[embed] socket protect callback already set");
+    }
+}
+
+/// Sets up the relay to the TUIC server. Must be called once, before any
+/// other function of this crate
+///
+/// The configuration is the `relay` section of the client configuration
+/// file, e.g. deserialized with `serde_json::from_value`
+pub async fn init(relay: Relay) -> Result<(), Error> {
+    Connection::set_config(relay).await
+}
+
+/// Opens a TCP stream to `addr` through the TUIC server
+///
+/// The returned stream implements `AsyncRead` and `AsyncWrite`
+pub async fn connect_tcp(addr: Address) -> Result<Connect, Error> {
+    Connection::get_conn().await?.connect(addr).await
+}
+
+/// Opens a UDP association, relaying packets through the TUIC server
+pub async fn udp_associate() -> Result<UdpAssociation, Error> {
+    let assoc_id = NEXT_ASSOC_ID.fetch_sub(1, Ordering::Relaxed);
+    let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+    ASSOCIATIONS.write().await.insert(assoc_id, tx);
+
+    Ok(UdpAssociation { assoc_id, rx })
+}
+
+/// A UDP association opened by [`udp_associate`]. The relaying session on the
+/// server is stopped when dropped
+pub struct UdpAssociation {
+    assoc_id: u16,
+    rx: mpsc::Receiver<(Bytes, Address)>,
+}
+
+impl UdpAssociation {
+    /// Sends a packet to `addr`
+    pub async fn send_to(&self, pkt: Bytes, addr: Address) -> Result<(), Error> {
+        let conn = Connection::get_assoc_conn(self.assoc_id).await?;
+        conn.packet(pkt, addr, self.assoc_id)
+            .await
+            .map_err(|err| Error::Other(anyhow::anyhow!(err)))
+    }
+
+    /// Receives a packet, along with the address it came from. Returns `None`
+    /// once the association is closed
+    pub async fn recv_from(&mut self) -> Option<(Bytes, Address)> {
+        self.rx.recv().await
+    }
+}
+
+/// Returns the packets received for each association opened through
+/// [`udp_associate`] and not read yet
+pub(crate) async fn queued_packets() -> Vec<(u16, usize)> {
+    ASSOCIATIONS
+        .read()
+        .await
+        .iter()
+        .map(|(assoc_id, tx)| (*assoc_id, QUEUE_SIZE - tx.capacity()))
+        .collect()
+}
+
+impl Drop for UdpAssociation {
+    fn drop(&mut self) {
+        let assoc_id = self.assoc_id;
+        let Ok(rt) = Handle::try_current() else {
+            return;
+        };
+
+        rt.spawn(async move {
+            ASSOCIATIONS.write().await.remove(&assoc_id);
+
+            if let Ok(conn) = Connection::get_assoc_conn(assoc_id).await
+                && let Err(err) = conn.dissociate(assoc_id).await
+            {
+                tracing::warn!(
+                    "[embed] [{assoc_id:#06x}] failed stopping UDP relaying session: {err}"
+                );
+            }
+        });
+    }
+}
+
+/// The MTU a TUN interface feeding the relay should have, and the TCP MSS to
+/// clamp the segments crossing it to. Returned by [`mtu_advice`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MtuAdvice {
+    pub mtu: u16,
+    /// For TCP over IPv4. 20 bytes less over IPv6
+    pub tcp_mss: u16,
+}
+
+impl MtuAdvice {
+    fn new(max_datagram_size: usize) -> Self {
+        // the IPv4 and UDP headers of a packet from the interface (28 bytes)
+        // are not relayed, but a `Packet` header to an IPv4 target (17 bytes)
+        // is. Over IPv6 more is saved than added
+        let mtu = (max_datagram_size + 28 - 17).min(u16::MAX as usize) as u16;
+
+        Self {
+            mtu,
+            tcp_mss: mtu.saturating_sub(40),
+        }
+    }
+}
+
+/// Returns the MTU and TCP MSS for a TUN interface in front of the relay, so
+/// UDP packets relayed in `native` mode fit in a single QUIC datagram instead
+/// of being fragmented. `None` if the server does not accept datagrams
+///
+/// Derived from the datagram size of the current connection, following the
+/// path MTU discovered by quinn, so it may grow once the connection is up for
+/// a while. The client has no TUN inbound itself, it is up to the app setting
+/// up the interface, e.g. with `VpnService.Builder.setMtu()` on Android
+pub async fn mtu_advice() -> Result<Option<MtuAdvice>, Error> {
+    let conn = Connection::get_conn().await?;
+    Ok(conn.max_datagram_size().map(MtuAdvice::new))
+}
@@ -3,6 +3,7 @@ use std::io::Error as IoError;
 use quinn::{ConnectError, ConnectionError};
 use rustls::Error as RustlsError;
 use thiserror::Error;
+use tuic::ConnectStatus;
 use tuic_quinn::Error as ModelError;
 
 #[derive(Debug, Error)]
@@ -25,6 +26,16 @@ pub enum Error {
     WrongPacketSource,
     #[error("invalid socks5 authentication")]
     InvalidSocks5Auth,
+    #[error("too many UDP associations, the limit is {0}")]
+    TooManyUdpAssociations(usize),
+    #[error("`max_packet_size` can not exceed {0} bytes")]
+    MaxPacketSizeTooLarge(usize),
+    #[error("`relay.dial_via` username and password must be at most 255 bytes")]
+    DialViaCredentialsTooLong,
+    #[error("`fake_ip` must hold at least 2 addresses besides the network and broadcast ones")]
+    FakeIpRangeTooSmall,
+    #[error("server failed connecting to the target: {0}")]
+    ConnectStatus(ConnectStatus),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
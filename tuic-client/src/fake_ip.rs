@@ -0,0 +1,140 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+};
+
+use once_cell::sync::OnceCell;
+use tuic::Address;
+
+use crate::{error::Error, utils::IpCidr};
+
+static FAKE_IP: OnceCell<FakeIp> = OnceCell::new();
+
+/// Maps domains to addresses in a reserved IP range, so the domain can be
+/// restored when a client connects to the address it was given
+///
+/// The addresses are handed out in order, and once the range is exhausted the
+/// oldest mappings are reused. The range should be large enough to hold all
+/// domains in use at the same time.
+pub struct FakeIp {
+    range: IpCidr,
+    base: u128,
+    size: u128,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    next: u128,
+    by_domain: HashMap<String, IpAddr>,
+    by_ip: HashMap<IpAddr, String>,
+}
+
+impl FakeIp {
+    pub fn set_config(range: IpCidr) -> Result<(), Error> {
+        FAKE_IP
+            .set(Self::new(range)?)
+            .map_err(|_| "failed initializing fake IP pool")
+            .unwrap();
+
+        Ok(())
+    }
+
+    /// Checks that `range` has at least 2 addresses to hand out. Used by
+    /// `--check`
+    pub fn check(range: IpCidr) -> Result<(), Error> {
+        Self::new(range).map(|_| ())
+    }
+
+    pub fn get() -> Option<&'static Self> {
+        FAKE_IP.get()
+    }
+
+    fn new(range: IpCidr) -> Result<Self, Error> {
+        let (bits, addr) = match range.addr() {
+            IpAddr::V4(addr) => (32, u128::from(u32::from(addr))),
+            IpAddr::V6(addr) => (128, u128::from(addr)),
+        };
+
+        let host_bits = bits - range.prefix_len();
+        let host_mask = 1u128.checked_shl(host_bits).map_or(u128::MAX, |n| n - 1);
+
+        // neither the network address nor the all-ones broadcast address is
+        // handed out
+        let size = host_mask.saturating_sub(1);
+        if size < 2 {
+            return Err(Error::FakeIpRangeTooSmall);
+        }
+
+        Ok(Self {
+            range,
+            base: addr & !host_mask,
+            size,
+            inner: Mutex::new(Inner {
+                next: 0,
+                by_domain: HashMap::new(),
+                by_ip: HashMap::new(),
+            }),
+        })
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        self.range.addr().is_ipv4()
+    }
+
+    /// Returns the fake IP of `domain`, allocating one if needed
+    pub fn lookup(&self, domain: &str) -> IpAddr {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(ip) = inner.by_domain.get(&domain) {
+            return *ip;
+        }
+
+        let ip = self.nth(inner.next);
+        inner.next = (inner.next + 1) % self.size;
+
+        if let Some(old) = inner.by_ip.insert(ip, domain.clone()) {
+            inner.by_domain.remove(&old);
+        }
+
+        inner.by_domain.insert(domain, ip);
+        ip
+    }
+
+    /// Returns the domain `ip` was handed out for
+    pub fn restore(&self, ip: IpAddr) -> Option<String> {
+        if !self.range.contains(ip) {
+            return None;
+        }
+
+        self.inner
+            .lock()
+            .unwrap()
+            .by_ip
+            .get(&ip.to_canonical())
+            .cloned()
+    }
+
+    fn nth(&self, idx: u128) -> IpAddr {
+        let ip = self.base + idx + 1;
+
+        if self.is_ipv4() {
+            IpAddr::V4(Ipv4Addr::from(ip as u32))
+        } else {
+            IpAddr::V6(Ipv6Addr::from(ip))
+        }
+    }
+}
+
+/// Replaces a fake IP in `addr` with the domain it stands for, so the domain is
+/// resolved on the server
+pub fn restore(addr: Address) -> Address {
+    match (FakeIp::get(), addr) {
+        (Some(fake_ip), Address::SocketAddress(addr)) => match fake_ip.restore(addr.ip()) {
+            Some(domain) => Address::DomainAddress(domain, addr.port()),
+            None => Address::SocketAddress(addr),
+        },
+        (_, addr) => addr,
+    }
+}
@@ -0,0 +1,42 @@
+//! The TUIC client, embeddable in other applications
+//!
+//! Besides the `tuic-client` binary, the relay to a TUIC server can be used
+//! directly: set it up with [`init`], then open TCP streams with
+//! [`connect_tcp`] and UDP associations with [`udp_associate`]. The other
+//! modules are the internals of the binary, with no stability guarantee.
+
+#![feature(let_chains)]
+
+pub use tuic::Address;
+pub use tuic_quinn::Connect;
+
+pub use self::{
+    config::Relay,
+    embed::{
+        MtuAdvice, UdpAssociation, connect_tcp, init, mtu_advice, protect_socket, udp_associate,
+    },
+    error::Error,
+};
+
+#[doc(hidden)]
+pub mod check;
+#[doc(hidden)]
+pub mod config;
+#[doc(hidden)]
+pub mod connection;
+#[doc(hidden)]
+pub mod dns;
+mod embed;
+mod error;
+#[doc(hidden)]
+pub mod fake_ip;
+#[doc(hidden)]
+pub mod nat_check;
+#[doc(hidden)]
+pub mod socks5;
+#[doc(hidden)]
+pub mod speedtest;
+#[doc(hidden)]
+pub mod stats;
+#[doc(hidden)]
+pub mod utils;
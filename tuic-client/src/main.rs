@@ -1,20 +1,26 @@
 #![feature(let_chains)]
 
-use std::{env, process};
+use std::{env, ffi::OsString, future, io, process};
 
-use env_logger::Builder as LoggerBuilder;
-
-use crate::{
-    config::{Config, ConfigError},
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{
+    filter::Targets, fmt::writer::BoxMakeWriter, layer::SubscriberExt, reload,
+    util::SubscriberInitExt,
+};
+use tuic_client::{
+    check,
+    config::{self, Config, ConfigError},
     connection::Connection,
+    dns::Server as DnsServer,
+    nat_check,
     socks5::Server as Socks5Server,
+    speedtest,
+    stats::{self, Server as StatsServer},
+    utils::LogFormat,
 };
-
-mod config;
-mod connection;
-mod error;
-mod socks5;
-mod utils;
+#[cfg(windows)]
+use tuic_common::service::{self, Service};
+use tuic_common::{log_file::LogFile, runtime};
 
 #[cfg(feature = "jemallocator")]
 use tikv_jemallocator::Jemalloc;
@@ -23,9 +29,40 @@ use tikv_jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
-#[tokio::main]
+#[cfg(windows)]
+static SERVICE: Service = Service {
+    name: "tuic-client",
+    display_name: "TUIC Client",
+    description: "TUIC proxy client",
+};
+
+// only drives the configuration parsing and the shutdown signal, the client
+// itself runs on the runtime built from the `runtime` section
+#[tokio::main(flavor = "current_thread")]
 async fn main() {
-    let cfg = match Config::parse(env::args_os()) {
+    #[cfg(windows)]
+    if let Some(res) = service::handle_command(&SERVICE, |args, shutdown| async move {
+        run(args, async move {
+            _ = shutdown.await;
+        })
+        .await;
+        Ok::<_, std::convert::Infallible>(())
+    })
+    .await
+    {
+        if let Err(err) = res {
+            eprintln!("{err}");
+            process::exit(1);
+        }
+        return;
+    }
+
+    run(env::args_os(), future::pending()).await;
+}
+
+/// Runs the client until `shutdown` completes
+async fn run(args: impl IntoIterator<Item = OsString>, shutdown: impl Future<Output = ()>) {
+    let cfg = match Config::parse(args) {
         Ok(cfg) => cfg,
         Err(ConfigError::Version(msg) | ConfigError::Help(msg)) => {
             println!("{msg}");
@@ -37,11 +74,92 @@ async fn main() {
         }
     };
 
-    LoggerBuilder::new()
-        .filter_level(cfg.log_level)
-        .format_module_path(false)
-        .format_target(false)
-        .init();
+    if cfg.check {
+        match check::check(&cfg).await {
+            Ok(()) => {
+                println!("configuration OK");
+                process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("{err:#}");
+                process::exit(1);
+            }
+        }
+    }
+
+    let runtime = match runtime::build(cfg.runtime.worker_threads, &cfg.runtime.cpu_affinity) {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            eprintln!("failed to build the runtime: {err}");
+            process::exit(1);
+        }
+    };
+
+    tokio::select! {
+        _ = runtime.spawn(serve(cfg)) => {}
+        () = shutdown => {}
+    }
+
+    runtime.shutdown_background();
+}
+
+/// Sets up logging and the relay, then runs the local servers
+async fn serve(cfg: Config) {
+    let configured = cfg.log_level.targets().clone();
+
+    let log_file = match cfg.log_file.as_ref().map(config::LogFile::open).transpose() {
+        Ok(log_file) => log_file,
+        Err(err) => {
+            eprintln!("failed to open log file: {err}");
+            process::exit(1);
+        }
+    };
+
+    let writer = match log_file.clone() {
+        Some(log_file) => BoxMakeWriter::new(move || log_file.clone()),
+        None => BoxMakeWriter::new(io::stderr),
+    };
+
+    let (text, json) = match cfg.log_format {
+        LogFormat::Text => (
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_target(false)
+                    .with_writer(writer),
+            ),
+            None,
+        ),
+        LogFormat::Json => (
+            None,
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_current_span(false)
+                    .with_span_list(true)
+                    .with_writer(writer),
+            ),
+        ),
+    };
+
+    let (filter, reload) = reload::Layer::new(configured.clone());
+
+    if let Err(err) = tracing_subscriber::registry()
+        .with(filter)
+        .with(text)
+        .with(json)
+        .try_init()
+    {
+        eprintln!("{err}");
+        process::exit(1);
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(handle_sigusr1(log_file));
+
+    #[cfg(unix)]
+    tokio::spawn(toggle_debug_on_signal(reload, configured));
+
+    let udp_relay_mode = cfg.relay.udp_relay_mode;
 
     match Connection::set_config(cfg.relay).await {
         Ok(()) => {}
@@ -51,7 +169,33 @@ async fn main() {
         }
     }
 
-    match Socks5Server::set_config(cfg.local) {
+    if let Some(size) = cfg.speedtest {
+        match speedtest::run(size).await {
+            Ok(report) => {
+                println!("{report}");
+                process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("speedtest failed: {err}");
+                process::exit(1);
+            }
+        }
+    }
+
+    if cfg.nat_check {
+        match nat_check::run().await {
+            Ok(report) => {
+                println!("{report}");
+                process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("NAT check failed: {err}");
+                process::exit(1);
+            }
+        }
+    }
+
+    match Socks5Server::set_config(cfg.local, udp_relay_mode) {
         Ok(()) => {}
         Err(err) => {
             eprintln!("{err}");
@@ -59,5 +203,108 @@ async fn main() {
         }
     }
 
-    Socks5Server::start().await;
+    let dns = cfg.dns_inbound.is_some();
+
+    if let Some(dns_inbound) = cfg.dns_inbound {
+        match DnsServer::set_config(dns_inbound) {
+            Ok(()) => {}
+            Err(err) => {
+                eprintln!("{err}");
+                process::exit(1);
+            }
+        }
+    }
+
+    let stats = cfg.stats.is_some();
+
+    if let Some(stats) = cfg.stats {
+        match StatsServer::set_config(stats) {
+            Ok(()) => {}
+            Err(err) => {
+                eprintln!("{err}");
+                process::exit(1);
+            }
+        }
+    }
+
+    let dns_server = async {
+        if dns {
+            DnsServer::start().await;
+        } else {
+            future::pending().await
+        }
+    };
+
+    let stats_server = async {
+        if stats {
+            StatsServer::start().await;
+        } else {
+            future::pending().await
+        }
+    };
+
+    tokio::select! {
+        () = Socks5Server::start() => {}
+        () = dns_server => {}
+        () = stats_server => {}
+    }
+}
+
+/// Re-opens the log file on `SIGUSR1`, so external tools can rotate it, then
+/// logs a dump of the state of the client
+#[cfg(unix)]
+async fn handle_sigusr1(log_file: Option<LogFile>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut sig = match signal(SignalKind::user_defined1()) {
+        Ok(sig) => sig,
+        Err(err) => {
+            tracing::warn!("failed to listen for SIGUSR1: {err}");
+            return;
+        }
+    };
+
+    while sig.recv().await.is_some() {
+        if let Some(log_file) = &log_file {
+            match log_file.reopen() {
+                Ok(()) => tracing::info!("log file re-opened"),
+                Err(err) => tracing::warn!("failed to re-open log file: {err}"),
+            }
+        }
+
+        tracing::warn!("{}", stats::dump().await);
+    }
+}
+
+/// Switches between logging at `debug` and the configured log filter on
+/// `SIGUSR2`, e.g. while reproducing an issue
+#[cfg(unix)]
+async fn toggle_debug_on_signal<S>(reload: reload::Handle<Targets, S>, configured: Targets) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut sig = match signal(SignalKind::user_defined2()) {
+        Ok(sig) => sig,
+        Err(err) => {
+            tracing::warn!("failed to listen for SIGUSR2: {err}");
+            return;
+        }
+    };
+
+    let mut debugging = false;
+
+    while sig.recv().await.is_some() {
+        let filter = if debugging {
+            configured.clone()
+        } else {
+            Targets::new().with_default(LevelFilter::DEBUG)
+        };
+
+        match reload.reload(filter) {
+            Ok(()) => {
+                debugging = !debugging;
+                tracing::warn!("debug logging {}", if debugging { "on" } else { "off" });
+            }
+            Err(err) => tracing::warn!("failed to change log level: {err}"),
+        }
+    }
 }
@@ -0,0 +1,192 @@
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use bytes::{BufMut, BytesMut};
+use tokio::time::{self, Instant};
+use tuic::Address;
+
+use crate::{
+    embed::{self, UdpAssociation},
+    error::Error,
+};
+
+/// STUN servers probed by `--nat-check`. Two targets are needed to tell
+/// whether the public address of the relayed traffic depends on the target
+const STUN_SERVERS: [(&str, u16); 2] =
+    [("stun.l.google.com", 19302), ("stun.cloudflare.com", 3478)];
+
+/// How long to wait for a response before sending the request again
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Binding requests sent to a STUN server before giving up
+const ATTEMPTS: usize = 3;
+
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// How the NAT in front of the server maps the UDP traffic relayed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NatType {
+    /// Every target sees the same public address, as behind a cone NAT or
+    /// no NAT at all
+    EndpointIndependent,
+    /// Each target sees a different public address, as behind a symmetric
+    /// NAT
+    Symmetric,
+}
+
+/// The outcome of `--nat-check`
+pub struct Report {
+    /// The public address seen by each STUN server
+    mapped: Vec<(String, SocketAddr)>,
+    nat_type: NatType,
+}
+
+/// Probes the public address of the relayed UDP traffic with two STUN
+/// servers, through a single association, and tells the NAT type from them.
+/// Used by `--nat-check`
+pub async fn run() -> Result<Report, Error> {
+    let mut assoc = embed::udp_associate().await?;
+    let mut mapped = Vec::with_capacity(STUN_SERVERS.len());
+
+    for (host, port) in STUN_SERVERS {
+        let server = Address::DomainAddress(host.to_owned(), port);
+        let addr = binding(&mut assoc, server).await?;
+        mapped.push((format!("{host}:{port}"), addr));
+    }
+
+    let (a, b) = (mapped[0].1, mapped[1].1);
+    if a.is_ipv4() != b.is_ipv4() {
+        return Err(Error::Other(anyhow!(
+            "the STUN servers were reached over different IP versions, the mappings can not \
+             be compared"
+        )));
+    }
+
+    let nat_type = if a == b {
+        NatType::EndpointIndependent
+    } else {
+        NatType::Symmetric
+    };
+
+    Ok(Report { mapped, nat_type })
+}
+
+/// Sends a binding request to `server`, returning the public address it
+/// reports
+async fn binding(assoc: &mut UdpAssociation, server: Address) -> Result<SocketAddr, Error> {
+    let txn_id: [u8; 12] = rand::random();
+
+    let mut req = BytesMut::with_capacity(20);
+    req.put_u16(BINDING_REQUEST);
+    req.put_u16(0);
+    req.put_u32(MAGIC_COOKIE);
+    req.put_slice(&txn_id);
+    let req = req.freeze();
+
+    for _ in 0..ATTEMPTS {
+        assoc.send_to(req.clone(), server.clone()).await?;
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+
+        // responses to earlier requests are told apart by the transaction ID
+        while let Ok(res) = time::timeout_at(deadline, assoc.recv_from()).await {
+            let Some((pkt, _)) = res else {
+                return Err(Error::Other(anyhow!("the UDP association was closed")));
+            };
+
+            if let Some(addr) = parse_response(&pkt, &txn_id) {
+                return Ok(addr);
+            }
+        }
+    }
+
+    Err(Error::Other(anyhow!(
+        "no response from the STUN server {server}"
+    )))
+}
+
+/// Parses a binding success response to the request `txn_id`, returning the
+/// mapped address in it
+fn parse_response(pkt: &[u8], txn_id: &[u8; 12]) -> Option<SocketAddr> {
+    if pkt.len() < 20
+        || pkt[0..2] != BINDING_SUCCESS.to_be_bytes()
+        || pkt[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || pkt[8..20] != txn_id[..]
+    {
+        return None;
+    }
+
+    let len = u16::from_be_bytes([pkt[2], pkt[3]]) as usize;
+    let mut attrs = pkt.get(20..20 + len)?;
+    let mut mapped = None;
+
+    while attrs.len() >= 4 {
+        let attr = u16::from_be_bytes([attrs[0], attrs[1]]);
+        let len = u16::from_be_bytes([attrs[2], attrs[3]]) as usize;
+        let value = attrs.get(4..4 + len)?;
+
+        match attr {
+            ATTR_XOR_MAPPED_ADDRESS => return parse_address(value, Some(&pkt[4..20])),
+            // only sent by servers predating RFC 5389
+            ATTR_MAPPED_ADDRESS => mapped = parse_address(value, None),
+            _ => {}
+        }
+
+        // attribute values are padded to 4 bytes
+        attrs = attrs
+            .get((4 + len).next_multiple_of(4)..)
+            .unwrap_or_default();
+    }
+
+    mapped
+}
+
+/// Parses the value of a `(XOR-)MAPPED-ADDRESS` attribute, unmasking it with
+/// `xor`, the magic cookie followed by the transaction ID
+fn parse_address(value: &[u8], xor: Option<&[u8]>) -> Option<SocketAddr> {
+    let unmask = |bytes: &[u8]| -> Vec<u8> {
+        match xor {
+            Some(xor) => bytes.iter().zip(xor).map(|(b, x)| b ^ x).collect(),
+            None => bytes.to_vec(),
+        }
+    };
+
+    let port = unmask(value.get(2..4)?);
+    let port = u16::from_be_bytes([port[0], port[1]]);
+
+    let ip = match value.get(1)? {
+        0x01 => IpAddr::from(<[u8; 4]>::try_from(unmask(value.get(4..8)?)).ok()?),
+        0x02 => IpAddr::from(<[u8; 16]>::try_from(unmask(value.get(4..20)?)).ok()?),
+        _ => return None,
+    };
+
+    Some(SocketAddr::new(ip, port))
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        for (server, addr) in &self.mapped {
+            writeln!(f, "mapped address via {server}: {addr}")?;
+        }
+
+        match self.nat_type {
+            NatType::EndpointIndependent => write!(
+                f,
+                "NAT type: endpoint-independent mapping (cone NAT or none), peer-to-peer UDP \
+                 through the relay can work"
+            ),
+            NatType::Symmetric => write!(
+                f,
+                "NAT type: endpoint-dependent mapping (symmetric NAT), peer-to-peer UDP through \
+                 the relay, e.g. in games, is likely to fail"
+            ),
+        }
+    }
+}
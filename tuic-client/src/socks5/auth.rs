@@ -0,0 +1,63 @@
+use std::{collections::HashMap, io::Error as IoError, net::SocketAddr, sync::Mutex};
+
+use async_trait::async_trait;
+use socks5_proto::handshake::{
+    Method as HandshakeMethod,
+    password::{Request as PasswordRequest, Response as PasswordResponse},
+};
+use socks5_server::Auth;
+use tokio::net::TcpStream;
+
+/// Username/password authentication accepting multiple users
+///
+/// The user of each authenticated connection is remembered until taken with
+/// `take_user()`, so the relay logs of the connection can be tagged with it.
+pub struct Users {
+    users: HashMap<Vec<u8>, Vec<u8>>,
+    authenticated: Mutex<HashMap<SocketAddr, String>>,
+}
+
+impl Users {
+    pub fn new(users: HashMap<Vec<u8>, Vec<u8>>) -> Self {
+        Self {
+            users,
+            authenticated: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks a username/password pair
+    pub fn verify(&self, username: &[u8], password: &[u8]) -> bool {
+        self.users
+            .get(username)
+            .is_some_and(|expected| expected == password)
+    }
+
+    /// Returns the user authenticated on the connection from `addr`
+    pub fn take_user(&self, addr: &SocketAddr) -> Option<String> {
+        self.authenticated.lock().unwrap().remove(addr)
+    }
+}
+
+#[async_trait]
+impl Auth for Users {
+    fn as_handshake_method(&self) -> HandshakeMethod {
+        HandshakeMethod::PASSWORD
+    }
+
+    async fn execute(&self, stream: &mut TcpStream) -> Result<bool, IoError> {
+        let req = PasswordRequest::read_from(stream).await?;
+
+        let is_valid = self.verify(&req.username, &req.password);
+
+        PasswordResponse::new(is_valid).write_to(stream).await?;
+
+        if is_valid {
+            self.authenticated.lock().unwrap().insert(
+                stream.peer_addr()?,
+                String::from_utf8_lossy(&req.username).into_owned(),
+            );
+        }
+
+        Ok(is_valid)
+    }
+}
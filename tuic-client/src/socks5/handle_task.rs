@@ -1,38 +1,76 @@
+use std::{
+    future,
+    io::{Error as IoError, ErrorKind},
+    net::SocketAddr,
+};
+
+use quinn::ConnectionError;
 use socks5_proto::{Address, Reply};
 use socks5_server::{
     Associate, Bind, Connect,
     connection::{associate, bind, connect},
 };
-use tokio::io::{self, AsyncWriteExt};
-use tuic::Address as TuicAddress;
+use tokio::{
+    io::{self, AsyncWriteExt},
+    net, time,
+};
+use tuic::{Address as TuicAddress, ConnectStatus};
+use tuic_quinn::Error as ModelError;
 
 use super::{Server, UDP_SESSIONS, udp_session::UdpSession};
-use crate::connection::{Connection as TuicConnection, ERROR_CODE};
+use crate::{
+    connection::{Connection as TuicConnection, ERROR_CODE},
+    error::Error,
+    stats::{self, AssocEnd},
+    utils::{self, Resolve},
+};
 
 impl Server {
-    pub async fn handle_associate(
-        assoc: Associate<associate::NeedReply>,
-        assoc_id: u16,
-        dual_stack: Option<bool>,
-        max_pkt_size: usize,
-    ) {
+    pub async fn handle_associate(&self, assoc: Associate<associate::NeedReply>, assoc_id: u16) {
         let peer_addr = assoc.peer_addr().unwrap();
-        let local_ip = assoc.local_addr().unwrap().ip();
+        let local_ip = self
+            .udp_bind_ip
+            .unwrap_or_else(|| assoc.local_addr().unwrap().ip());
+
+        let slot = self.acquire_udp_assoc();
+        let session = match slot {
+            Some(_) => UdpSession::new(
+                assoc_id,
+                peer_addr,
+                local_ip,
+                self.dual_stack,
+                self.max_pkt_size,
+                self.udp_port_range,
+                self.allowed_ips.clone(),
+                self.udp_fragment_timeout,
+            ),
+            None => {
+                stats::record_assoc_rejected();
+                Err(Error::TooManyUdpAssociations(self.max_udp_assocs))
+            }
+        };
 
-        match UdpSession::new(assoc_id, peer_addr, local_ip, dual_stack, max_pkt_size) {
+        match session {
             Ok(session) => {
                 let local_addr = session.local_addr().unwrap();
-                log::debug!(
+                tracing::debug!(
                     "[socks5] [{peer_addr}] [associate] [{assoc_id:#06x}] bound to {local_addr}"
                 );
 
+                // behind NAT, the client has to be told the external address
+                // instead of the one the socket is bound to
+                let reply_addr = SocketAddr::new(
+                    self.udp_advertise_ip.unwrap_or(local_addr.ip()),
+                    local_addr.port(),
+                );
+
                 let mut assoc = match assoc
-                    .reply(Reply::Succeeded, Address::SocketAddress(local_addr))
+                    .reply(Reply::Succeeded, Address::SocketAddress(reply_addr))
                     .await
                 {
                     Ok(assoc) => assoc,
                     Err(err) => {
-                        log::warn!(
+                        tracing::warn!(
                             "[socks5] [{peer_addr}] [associate] [{assoc_id:#06x}] command reply \
                              error: {err}"
                         );
@@ -47,12 +85,33 @@ impl Server {
                     .await
                     .insert(assoc_id, session.clone());
 
+                stats::record_assoc_opened();
+
+                let idle_timeout = self.udp_idle_timeout;
+                let idle_session = session.clone();
+                let wait_idle = async move {
+                    if idle_timeout.is_zero() {
+                        return future::pending().await;
+                    }
+
+                    loop {
+                        let idle_for = idle_session.idle_for();
+                        if idle_for >= idle_timeout {
+                            break;
+                        }
+                        time::sleep(idle_timeout - idle_for).await;
+                    }
+                };
+
+                // with no idle timeout, the association would never end otherwise
+                let wait_control = self.dissociate_on_close || idle_timeout.is_zero();
+
                 let handle_local_incoming_pkt = async move {
                     loop {
                         let (pkt, target_addr) = match session.recv().await {
                             Ok(res) => res,
                             Err(err) => {
-                                log::warn!(
+                                tracing::warn!(
                                     "[socks5] [{peer_addr}] [associate] [{assoc_id:#06x}] failed \
                                      to receive UDP packet: {err}"
                                 );
@@ -68,7 +127,7 @@ impl Server {
                                 Address::SocketAddress(addr) => TuicAddress::SocketAddress(addr),
                             };
 
-                            match TuicConnection::get_conn().await {
+                            match TuicConnection::get_assoc_conn(assoc_id).await {
                                 Ok(conn) => conn.packet(pkt, target_addr, assoc_id).await,
                                 Err(err) => Err(err)?,
                             }
@@ -78,7 +137,7 @@ impl Server {
                             match forward.await {
                                 Ok(()) => {}
                                 Err(err) => {
-                                    log::warn!(
+                                    tracing::warn!(
                                         "[socks5] [{peer_addr}] [associate] [{assoc_id:#06x}] \
                                          failed relaying UDP packet: {err}"
                                     );
@@ -88,20 +147,29 @@ impl Server {
                     }
                 };
 
-                match tokio::select! {
-                    res = assoc.wait_until_closed() => res,
-                    _ = handle_local_incoming_pkt => unreachable!(),
-                } {
-                    Ok(()) => {}
-                    Err(err) => {
-                        log::warn!(
-                            "[socks5] [{peer_addr}] [associate] [{assoc_id:#06x}] associate \
-                             connection error: {err}"
-                        )
+                let end = tokio::select! {
+                    res = assoc.wait_until_closed(), if wait_control => {
+                        if let Err(err) = res {
+                            tracing::warn!(
+                                "[socks5] [{peer_addr}] [associate] [{assoc_id:#06x}] associate \
+                                 connection error: {err}"
+                            )
+                        }
+                        AssocEnd::ControlClosed
                     }
-                }
+                    () = wait_idle => {
+                        tracing::debug!(
+                            "[socks5] [{peer_addr}] [associate] [{assoc_id:#06x}] idle for \
+                             {idle_timeout:?}"
+                        );
+                        AssocEnd::Idle
+                    }
+                    _ = handle_local_incoming_pkt => unreachable!(),
+                };
+
+                stats::record_assoc_closed(end);
 
-                log::debug!(
+                tracing::debug!(
                     "[socks5] [{peer_addr}] [associate] [{assoc_id:#06x}] stopped associating"
                 );
 
@@ -113,17 +181,17 @@ impl Server {
                     .remove(&assoc_id)
                     .unwrap();
 
-                if let Ok(conn) = TuicConnection::get_conn().await
+                if let Ok(conn) = TuicConnection::get_assoc_conn(assoc_id).await
                     && let Err(err) = conn.dissociate(assoc_id).await
                 {
-                    log::warn!(
+                    tracing::warn!(
                         "[socks5] [{peer_addr}] [associate] [{assoc_id:#06x}] failed stopping UDP \
                          relaying session: {err}"
                     )
                 }
             }
             Err(err) => {
-                log::warn!(
+                tracing::warn!(
                     "[socks5] [{peer_addr}] [associate] [{assoc_id:#06x}] failed setting up UDP \
                      associate session: {err}"
                 );
@@ -136,7 +204,7 @@ impl Server {
                         let _ = assoc.shutdown().await;
                     }
                     Err(err) => {
-                        log::warn!(
+                        tracing::warn!(
                             "[socks5] [{peer_addr}] [associate] [{assoc_id:#06x}] command reply \
                              error: {err}"
                         )
@@ -148,7 +216,7 @@ impl Server {
 
     pub async fn handle_bind(bind: Bind<bind::NeedFirstReply>) {
         let peer_addr = bind.peer_addr().unwrap();
-        log::warn!("[socks5] [{peer_addr}] [bind] command not supported");
+        tracing::warn!("[socks5] [{peer_addr}] [bind] command not supported");
 
         match bind
             .reply(Reply::CommandNotSupported, Address::unspecified())
@@ -157,20 +225,29 @@ impl Server {
             Ok(mut bind) => {
                 let _ = bind.shutdown().await;
             }
-            Err(err) => log::warn!("[socks5] [{peer_addr}] [bind] command reply error: {err}"),
+            Err(err) => tracing::warn!("[socks5] [{peer_addr}] [bind] command reply error: {err}"),
         }
     }
 
-    pub async fn handle_connect(conn: Connect<connect::NeedReply>, addr: Address) {
+    pub async fn handle_connect(&self, conn: Connect<connect::NeedReply>, addr: Address) {
         let peer_addr = conn.peer_addr().unwrap();
         let target_addr = match addr {
             Address::DomainAddress(domain, port) => TuicAddress::DomainAddress(domain, port),
             Address::SocketAddress(addr) => TuicAddress::SocketAddress(addr),
         };
 
-        let relay = match TuicConnection::get_conn().await {
-            Ok(conn) => conn.connect(target_addr.clone()).await,
-            Err(err) => Err(err),
+        let relay = match self.resolve_target(target_addr.clone()).await {
+            Ok(resolved) => match TuicConnection::get_conn().await {
+                Ok(conn) => conn.connect(resolved).await.map_err(|err| {
+                    let reply = connect_reply(&err);
+                    (err, reply)
+                }),
+                Err(err) => {
+                    let reply = server_reply(&err);
+                    Err((err, reply))
+                }
+            },
+            Err(err) => Err((err, Reply::HostUnreachable)),
         };
 
         match relay {
@@ -180,7 +257,7 @@ impl Server {
                     Err(err) => {
                         let _ = conn.shutdown().await;
                         let _ = relay.reset(ERROR_CODE);
-                        log::warn!(
+                        tracing::warn!(
                             "[socks5] [{peer_addr}] [connect] [{target_addr}] TCP stream relaying \
                              error: {err}"
                         );
@@ -188,27 +265,24 @@ impl Server {
                 },
                 Err(err) => {
                     let _ = relay.shutdown().await;
-                    log::warn!(
+                    tracing::warn!(
                         "[socks5] [{peer_addr}] [connect] [{target_addr}] command reply error: \
                          {err}"
                     );
                 }
             },
-            Err(err) => {
-                log::warn!(
+            Err((err, reply)) => {
+                tracing::warn!(
                     "[socks5] [{peer_addr}] [connect] [{target_addr}] unable to relay TCP stream: \
                      {err}"
                 );
 
-                match conn
-                    .reply(Reply::GeneralFailure, Address::unspecified())
-                    .await
-                {
+                match conn.reply(reply, Address::unspecified()).await {
                     Ok(mut conn) => {
                         let _ = conn.shutdown().await;
                     }
                     Err(err) => {
-                        log::warn!(
+                        tracing::warn!(
                             "[socks5] [{peer_addr}] [connect] [{target_addr}] command reply \
                              error: {err}"
                         )
@@ -217,4 +291,83 @@ impl Server {
             }
         }
     }
+
+    /// Resolves the domain of a CONNECT target locally, if the first
+    /// `resolve_rules` entry matching it, or else `resolve`, says so. The
+    /// server resolves it otherwise
+    async fn resolve_target(&self, addr: TuicAddress) -> Result<TuicAddress, Error> {
+        let TuicAddress::DomainAddress(domain, port) = &addr else {
+            return Ok(addr);
+        };
+
+        let resolve = self
+            .resolve_rules
+            .iter()
+            .find(|rule| utils::is_same_or_subdomain(domain, &rule.domain))
+            .map_or(self.resolve, |rule| rule.resolve);
+
+        if resolve == Resolve::Remote {
+            return Ok(addr);
+        }
+
+        let resolved = net::lookup_host((domain.as_str(), *port))
+            .await?
+            .next()
+            .ok_or_else(|| {
+                IoError::new(
+                    ErrorKind::NotFound,
+                    format!("no address found for {domain}"),
+                )
+            })?;
+
+        tracing::debug!("[socks5] [connect] resolved {addr} locally to {resolved}");
+        Ok(TuicAddress::SocketAddress(resolved))
+    }
+}
+
+/// Maps a failure to reach the TUIC server to a SOCKS5 reply
+fn server_reply(err: &Error) -> Reply {
+    match err {
+        Error::Timeout => Reply::TtlExpired,
+        Error::Io(err) if err.kind() == ErrorKind::TimedOut => Reply::TtlExpired,
+        _ => Reply::NetworkUnreachable,
+    }
+}
+
+/// Maps a failure to relay to the target over an established connection to a
+/// SOCKS5 reply
+fn connect_reply(err: &Error) -> Reply {
+    match err {
+        Error::ConnectStatus(status) => status_reply(*status),
+        Error::Timeout => Reply::TtlExpired,
+        Error::Io(err) | Error::Model(ModelError::IoError(err)) => io_reply(err.kind()),
+        Error::Model(ModelError::Connection(err)) => match err {
+            ConnectionError::TimedOut => Reply::TtlExpired,
+            _ => Reply::NetworkUnreachable,
+        },
+        _ => Reply::GeneralFailure,
+    }
+}
+
+fn io_reply(kind: ErrorKind) -> Reply {
+    match kind {
+        ErrorKind::NetworkUnreachable => Reply::NetworkUnreachable,
+        ErrorKind::HostUnreachable => Reply::HostUnreachable,
+        ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+        ErrorKind::TimedOut => Reply::TtlExpired,
+        _ => Reply::GeneralFailure,
+    }
+}
+
+/// Maps the outcome of a `Connect` reported by the server to a SOCKS5 reply
+fn status_reply(status: ConnectStatus) -> Reply {
+    match status {
+        ConnectStatus::Succeeded => Reply::Succeeded,
+        ConnectStatus::Denied | ConnectStatus::QuotaExceeded => Reply::ConnectionNotAllowed,
+        ConnectStatus::NetworkUnreachable => Reply::NetworkUnreachable,
+        ConnectStatus::HostUnreachable | ConnectStatus::DnsFailure => Reply::HostUnreachable,
+        ConnectStatus::ConnectionRefused => Reply::ConnectionRefused,
+        ConnectStatus::TimedOut => Reply::TtlExpired,
+        _ => Reply::GeneralFailure,
+    }
 }
@@ -0,0 +1,254 @@
+use std::{
+    io::{Error as IoError, ErrorKind},
+    net::{IpAddr, SocketAddr},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use httparse::{EMPTY_HEADER, Header, Request, Status};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tracing::Instrument;
+use tuic::Address as TuicAddress;
+
+use super::{Server, auth::Users};
+use crate::connection::{Connection as TuicConnection, ERROR_CODE};
+
+const MAX_HEAD_LEN: usize = 8192;
+const MAX_HEADERS: usize = 64;
+
+/// Headers only meant for the proxy, not forwarded to the target
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+];
+
+impl Server {
+    /// Serves an HTTP proxy connection, either a `CONNECT` tunnel or a plain
+    /// HTTP request with an absolute URI. Plain requests are forwarded with
+    /// `Connection: close`, so each connection carries a single request
+    pub async fn handle_http(&self, mut stream: TcpStream, peer_addr: SocketAddr) {
+        let (head, body) = match read_head(&mut stream).await {
+            Ok(head) => head,
+            Err(err) => {
+                tracing::warn!("[http] [{peer_addr}] failed to read request: {err}");
+                return;
+            }
+        };
+
+        let mut headers = [EMPTY_HEADER; MAX_HEADERS];
+        let mut req = Request::new(&mut headers);
+
+        if !matches!(req.parse(&head), Ok(Status::Complete(_))) {
+            tracing::warn!("[http] [{peer_addr}] invalid request");
+            let _ = write_status(&mut stream, "400 Bad Request").await;
+            return;
+        }
+
+        let method = req.method.unwrap();
+        let uri = req.path.unwrap();
+
+        let user = match &self.users {
+            Some(users) => match authenticate(users, req.headers) {
+                Some(user) => Some(user),
+                None => {
+                    tracing::warn!("[http] [{peer_addr}] proxy authentication failed");
+                    let _ = write_status(
+                        &mut stream,
+                        "407 Proxy Authentication Required\r\nProxy-Authenticate: Basic \
+                         realm=\"tuic\"",
+                    )
+                    .await;
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        let span = tracing::info_span!("http", user = user.as_deref());
+
+        async move {
+            if method.eq_ignore_ascii_case("CONNECT") {
+                let Some(target_addr) = parse_authority(uri, None) else {
+                    tracing::warn!("[http] [{peer_addr}] invalid CONNECT target {uri}");
+                    let _ = write_status(&mut stream, "400 Bad Request").await;
+                    return;
+                };
+
+                tracing::info!("[http] [{peer_addr}] [connect] {target_addr}");
+                relay(
+                    stream,
+                    peer_addr,
+                    target_addr,
+                    b"HTTP/1.1 200 Connection established\r\n\r\n",
+                    &[],
+                    &body,
+                )
+                .await;
+            } else {
+                let Some((target_addr, head)) =
+                    rewrite_request(method, uri, req.version, req.headers)
+                else {
+                    tracing::warn!("[http] [{peer_addr}] unsupported request URI {uri}");
+                    let _ = write_status(&mut stream, "400 Bad Request").await;
+                    return;
+                };
+
+                tracing::info!("[http] [{peer_addr}] [{method}] {target_addr}");
+                relay(stream, peer_addr, target_addr, &[], &head, &body).await;
+            }
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+/// Relays the stream to `target_addr`. `reply` is sent back to the client once
+/// the relay is established, and `head` + `body` are sent to the target first
+async fn relay(
+    mut stream: TcpStream,
+    peer_addr: SocketAddr,
+    target_addr: TuicAddress,
+    reply: &[u8],
+    head: &[u8],
+    body: &[u8],
+) {
+    let relay = match TuicConnection::get_conn().await {
+        Ok(conn) => conn.connect(target_addr.clone()).await,
+        Err(err) => Err(err),
+    };
+
+    let mut relay = match relay {
+        Ok(relay) => relay,
+        Err(err) => {
+            tracing::warn!(
+                "[http] [{peer_addr}] [{target_addr}] unable to relay TCP stream: {err}"
+            );
+            let _ = write_status(&mut stream, "502 Bad Gateway").await;
+            return;
+        }
+    };
+
+    let res = async {
+        stream.write_all(reply).await?;
+        relay.write_all(head).await?;
+        relay.write_all(body).await?;
+        io::copy_bidirectional(&mut stream, &mut relay).await
+    }
+    .await;
+
+    if let Err(err) = res {
+        let _ = stream.shutdown().await;
+        let _ = relay.reset(ERROR_CODE);
+        tracing::warn!("[http] [{peer_addr}] [{target_addr}] TCP stream relaying error: {err}");
+    }
+}
+
+/// Reads the request head, resolving to the head and the bytes read after it
+async fn read_head(stream: &mut TcpStream) -> Result<(Vec<u8>, Vec<u8>), IoError> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+
+    loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let body = buf.split_off(pos + 4);
+            return Ok((buf, body));
+        }
+
+        if buf.len() >= MAX_HEAD_LEN {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                "request head too large",
+            ));
+        }
+
+        let n = stream.read(&mut chunk).await?;
+
+        if n == 0 {
+            return Err(IoError::from(ErrorKind::UnexpectedEof));
+        }
+
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Checks the `Proxy-Authorization` header, resolving to the username
+fn authenticate(users: &Users, headers: &[Header<'_>]) -> Option<String> {
+    let value = headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("proxy-authorization"))?
+        .value;
+    let value = std::str::from_utf8(value).ok()?;
+    let credentials = value.strip_prefix("Basic ")?;
+    let credentials = BASE64.decode(credentials.trim()).ok()?;
+
+    let sep = credentials.iter().position(|byte| *byte == b':')?;
+    let (username, password) = (&credentials[..sep], &credentials[sep + 1..]);
+
+    users
+        .verify(username, password)
+        .then(|| String::from_utf8_lossy(username).into_owned())
+}
+
+/// Rewrites a proxy request with an absolute URI into an origin-form request,
+/// resolving to the target address and the new request head
+fn rewrite_request(
+    method: &str,
+    uri: &str,
+    version: Option<u8>,
+    headers: &[Header<'_>],
+) -> Option<(TuicAddress, Vec<u8>)> {
+    let uri = uri.strip_prefix("http://")?;
+    let (authority, path) = uri.split_at(uri.find('/').unwrap_or(uri.len()));
+    let path = if path.is_empty() { "/" } else { path };
+    let target_addr = parse_authority(authority, Some(80))?;
+
+    let mut head = format!("{method} {path} HTTP/1.{}\r\n", version.unwrap_or(1)).into_bytes();
+
+    for header in headers {
+        if HOP_BY_HOP_HEADERS
+            .iter()
+            .any(|name| header.name.eq_ignore_ascii_case(name))
+        {
+            continue;
+        }
+
+        head.extend_from_slice(header.name.as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(header.value);
+        head.extend_from_slice(b"\r\n");
+    }
+
+    head.extend_from_slice(b"Connection: close\r\n\r\n");
+
+    Some((target_addr, head))
+}
+
+/// Parses `host[:port]`, with the host being a domain, an IPv4 or a bracketed
+/// IPv6 address
+fn parse_authority(authority: &str, default_port: Option<u16>) -> Option<TuicAddress> {
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, port.parse().ok()?),
+        _ => (authority, default_port?),
+    };
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    if host.is_empty() {
+        return None;
+    }
+
+    Some(match host.parse::<IpAddr>() {
+        Ok(ip) => TuicAddress::SocketAddress(SocketAddr::new(ip, port)),
+        Err(_) => TuicAddress::DomainAddress(host.to_owned(), port),
+    })
+}
+
+async fn write_status(stream: &mut TcpStream, status: &str) -> Result<(), IoError> {
+    let resp = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    stream.write_all(resp.as_bytes()).await?;
+    stream.shutdown().await
+}
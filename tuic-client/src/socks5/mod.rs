@@ -1,46 +1,70 @@
 use std::{
     collections::HashMap,
-    net::{SocketAddr, TcpListener as StdTcpListener},
+    net::{IpAddr, SocketAddr, TcpListener as StdTcpListener},
     sync::{
         Arc,
-        atomic::{AtomicU16, Ordering},
+        atomic::{AtomicU16, AtomicUsize, Ordering},
     },
+    time::Duration,
 };
 
+use futures_util::future;
 use once_cell::sync::OnceCell;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
-use socks5_server::{
-    Auth, Connection, Server as Socks5Server,
-    auth::{NoAuth, Password},
-};
+use socks5_server::{Auth, Connection, Server as Socks5Server, auth::NoAuth};
 use tokio::{net::TcpListener, sync::RwLock as AsyncRwLock};
+use tracing::Instrument;
 
-use crate::{config::Local, error::Error};
+use crate::{
+    config::{Local, ResolveRule},
+    error::Error,
+    utils::{self, IpCidr, PortRange, Resolve, UdpRelayMode},
+};
 
+mod auth;
 mod handle_task;
+mod http;
+mod socks4;
 mod udp_session;
 
+use self::auth::Users;
 pub use self::udp_session::UDP_SESSIONS;
 
 static SERVER: OnceCell<Server> = OnceCell::new();
 
+const SOCKS5_VERSION: u8 = 0x05;
+
+/// Default `max_packet_size` in `native` UDP relay mode, fitting the usual MTU
+const DEFAULT_MAX_PKT_SIZE: usize = 1500;
+
+/// Largest `max_packet_size`, the largest UDP datagram. The default in `quic`
+/// and `auto` modes, relaying packets too large for a QUIC datagram on streams
+const MAX_PKT_SIZE: usize = 65535;
+
 pub struct Server {
-    inner: Socks5Server,
+    inner: Vec<Socks5Server>,
     dual_stack: Option<bool>,
     max_pkt_size: usize,
+    udp_bind_ip: Option<IpAddr>,
+    udp_port_range: Option<PortRange>,
+    udp_advertise_ip: Option<IpAddr>,
+    allowed_ips: Arc<[IpCidr]>,
+    users: Option<Arc<Users>>,
+    mixed: bool,
+    max_udp_assocs: usize,
+    udp_idle_timeout: Duration,
+    dissociate_on_close: bool,
+    udp_fragment_timeout: Duration,
+    udp_assocs: AtomicUsize,
+    resolve: Resolve,
+    resolve_rules: Box<[ResolveRule]>,
     next_assoc_id: AtomicU16,
 }
 
 impl Server {
-    pub fn set_config(cfg: Local) -> Result<(), Error> {
+    pub fn set_config(cfg: Local, udp_relay_mode: UdpRelayMode) -> Result<(), Error> {
         SERVER
-            .set(Self::new(
-                cfg.server,
-                cfg.dual_stack,
-                cfg.max_packet_size,
-                cfg.username,
-                cfg.password,
-            )?)
+            .set(Self::new(cfg, udp_relay_mode)?)
             .map_err(|_| "failed initializing socks5 server")
             .unwrap();
 
@@ -52,104 +76,199 @@ impl Server {
         Ok(())
     }
 
-    fn new(
-        addr: SocketAddr,
-        dual_stack: Option<bool>,
-        max_pkt_size: usize,
-        username: Option<Vec<u8>>,
-        password: Option<Vec<u8>>,
-    ) -> Result<Self, Error> {
-        let socket = {
-            let domain = match addr {
-                SocketAddr::V4(_) => Domain::IPV4,
-                SocketAddr::V6(_) => Domain::IPV6,
-            };
-
-            let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))
-                .map_err(|err| Error::Socket("failed to create socks5 server socket", err))?;
-
-            if let Some(dual_stack) = dual_stack {
-                socket.set_only_v6(!dual_stack).map_err(|err| {
-                    Error::Socket("socks5 server dual-stack socket setting error", err)
-                })?;
-            }
-
-            socket.set_reuse_address(true).map_err(|err| {
-                Error::Socket("failed to set socks5 server socket to reuse_address", err)
-            })?;
+    fn new(cfg: Local, udp_relay_mode: UdpRelayMode) -> Result<Self, Error> {
+        let max_pkt_size = cfg.max_packet_size.unwrap_or(match udp_relay_mode {
+            UdpRelayMode::Native => DEFAULT_MAX_PKT_SIZE,
+            UdpRelayMode::Quic | UdpRelayMode::Auto => MAX_PKT_SIZE,
+        });
 
-            socket.set_nonblocking(true).map_err(|err| {
-                Error::Socket("failed setting socks5 server socket as non-blocking", err)
-            })?;
+        if max_pkt_size > MAX_PKT_SIZE {
+            return Err(Error::MaxPacketSizeTooLarge(MAX_PKT_SIZE));
+        }
 
-            socket
-                .bind(&SockAddr::from(addr))
-                .map_err(|err| Error::Socket("failed to bind socks5 server socket", err))?;
+        let mut users = cfg
+            .users
+            .into_iter()
+            .map(|(username, password)| (username.into_bytes(), password.into_bytes()))
+            .collect::<HashMap<_, _>>();
 
-            socket
-                .listen(i32::MAX)
-                .map_err(|err| Error::Socket("failed to listen on socks5 server socket", err))?;
+        match (cfg.username, cfg.password) {
+            (Some(username), Some(password)) => {
+                users.insert(username, password);
+            }
+            (None, None) => {}
+            _ => return Err(Error::InvalidSocks5Auth),
+        }
 
-            TcpListener::from_std(StdTcpListener::from(socket))
-                .map_err(|err| Error::Socket("failed to create socks5 server socket", err))?
+        let users = (!users.is_empty()).then(|| Arc::new(Users::new(users)));
+        let auth: Arc<dyn Auth + Send + Sync> = match &users {
+            Some(users) => users.clone(),
+            None => Arc::new(NoAuth),
         };
 
-        let auth: Arc<dyn Auth + Send + Sync> = match (username, password) {
-            (Some(username), Some(password)) => Arc::new(Password::new(username, password)),
-            (None, None) => Arc::new(NoAuth),
-            _ => return Err(Error::InvalidSocks5Auth),
-        };
+        // an IPv6 listener only accepts IPv4 connections too if `dual_stack`
+        // is set, or if there is no separate IPv4 listener to conflict with
+        let has_ipv4 = cfg.listen.iter().any(SocketAddr::is_ipv4);
+        let only_v6 = cfg.dual_stack.map_or(has_ipv4, |dual_stack| !dual_stack);
+
+        let inner = cfg
+            .listen
+            .into_iter()
+            .map(|addr| {
+                let listener = Self::bind(addr, only_v6)?;
+                Ok(Socks5Server::new(listener, auth.clone()))
+            })
+            .collect::<Result<_, Error>>()?;
 
         Ok(Self {
-            inner: Socks5Server::new(socket, auth),
-            dual_stack,
+            inner,
+            dual_stack: cfg.dual_stack,
             max_pkt_size,
+            udp_bind_ip: cfg.udp_bind_ip,
+            udp_port_range: cfg.udp_port_range,
+            udp_advertise_ip: cfg.udp_advertise_ip,
+            allowed_ips: Arc::from(cfg.allowed_ips),
+            users,
+            mixed: cfg.mixed,
+            max_udp_assocs: cfg.max_udp_associations,
+            udp_idle_timeout: cfg.udp_idle_timeout,
+            dissociate_on_close: cfg.dissociate_on_close,
+            udp_fragment_timeout: cfg.udp_fragment_timeout,
+            udp_assocs: AtomicUsize::new(0),
+            resolve: cfg.resolve,
+            resolve_rules: cfg.resolve_rules.into_boxed_slice(),
             next_assoc_id: AtomicU16::new(0),
         })
     }
 
+    fn bind(addr: SocketAddr, only_v6: bool) -> Result<TcpListener, Error> {
+        let domain = match addr {
+            SocketAddr::V4(_) => Domain::IPV4,
+            SocketAddr::V6(_) => Domain::IPV6,
+        };
+
+        let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))
+            .map_err(|err| Error::Socket("failed to create socks5 server socket", err))?;
+
+        if addr.is_ipv6() {
+            socket.set_only_v6(only_v6).map_err(|err| {
+                Error::Socket("socks5 server dual-stack socket setting error", err)
+            })?;
+        }
+
+        socket.set_reuse_address(true).map_err(|err| {
+            Error::Socket("failed to set socks5 server socket to reuse_address", err)
+        })?;
+
+        socket.set_nonblocking(true).map_err(|err| {
+            Error::Socket("failed setting socks5 server socket as non-blocking", err)
+        })?;
+
+        socket
+            .bind(&SockAddr::from(addr))
+            .map_err(|err| Error::Socket("failed to bind socks5 server socket", err))?;
+
+        socket
+            .listen(i32::MAX)
+            .map_err(|err| Error::Socket("failed to listen on socks5 server socket", err))?;
+
+        TcpListener::from_std(StdTcpListener::from(socket))
+            .map_err(|err| Error::Socket("failed to create socks5 server socket", err))
+    }
+
+    /// Takes one of the `max_udp_associations` slots, given back as the
+    /// returned guard drops. `None` if they are all taken
+    fn acquire_udp_assoc(&self) -> Option<UdpAssocSlot<'_>> {
+        self.udp_assocs
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (self.max_udp_assocs == 0 || n < self.max_udp_assocs).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| UdpAssocSlot(&self.udp_assocs))
+    }
+
     pub async fn start() {
         let server = SERVER.get().unwrap();
+        future::join_all(server.inner.iter().map(|inner| server.serve(inner))).await;
+    }
 
-        log::warn!(
+    async fn serve(&'static self, inner: &'static Socks5Server) {
+        tracing::warn!(
             "[socks5] server started, listening on {}",
-            server.inner.local_addr().unwrap()
+            inner.local_addr().unwrap()
         );
 
         loop {
-            match server.inner.accept().await {
+            match inner.accept().await {
+                Ok((_, addr)) if !utils::is_ip_allowed(&self.allowed_ips, addr.ip()) => {
+                    tracing::warn!("[socks5] [{addr}] connection rejected: not in allowed_ips");
+                }
                 Ok((conn, addr)) => {
-                    log::debug!("[socks5] [{addr}] connection established");
+                    tracing::debug!("[socks5] [{addr}] connection established");
 
                     tokio::spawn(async move {
-                        match conn.handshake().await {
-                            Ok(Connection::Associate(associate, _)) => {
-                                let assoc_id = server.next_assoc_id.fetch_add(1, Ordering::Relaxed);
-                                log::info!("[socks5] [{addr}] [associate] [{assoc_id:#06x}]");
-                                Self::handle_associate(
-                                    associate,
-                                    assoc_id,
-                                    server.dual_stack,
-                                    server.max_pkt_size,
-                                )
-                                .await;
-                            }
-                            Ok(Connection::Bind(bind, _)) => {
-                                log::info!("[socks5] [{addr}] [bind]");
-                                Self::handle_bind(bind).await;
+                        // SOCKS4 and HTTP (with `mixed`) are served on the same port,
+                        // told apart by the first byte
+                        let mut ver = [0; 1];
+                        match conn.get_ref().peek(&mut ver).await {
+                            Ok(1) if ver[0] == socks4::VERSION => {
+                                self.handle_socks4(conn.into_inner(), addr).await;
+                                tracing::debug!("[socks4] [{addr}] connection closed");
+                                return;
                             }
-                            Ok(Connection::Connect(connect, target_addr)) => {
-                                log::info!("[socks5] [{addr}] [connect] {target_addr}");
-                                Self::handle_connect(connect, target_addr).await;
+                            Ok(1) if self.mixed && ver[0] != SOCKS5_VERSION => {
+                                self.handle_http(conn.into_inner(), addr).await;
+                                tracing::debug!("[http] [{addr}] connection closed");
+                                return;
                             }
-                            Err(err) => log::warn!("[socks5] [{addr}] handshake error: {err}"),
-                        };
+                            _ => {}
+                        }
+
+                        let handshake = conn.handshake().await;
+
+                        // tag the logs of the connection with the authenticated user
+                        let user = self.users.as_ref().and_then(|users| users.take_user(&addr));
+                        let span = tracing::info_span!("socks5", user = user.as_deref());
 
-                        log::debug!("[socks5] [{addr}] connection closed");
+                        async move {
+                            match handshake {
+                                Ok(Connection::Associate(associate, _)) => {
+                                    let assoc_id =
+                                        self.next_assoc_id.fetch_add(1, Ordering::Relaxed);
+                                    tracing::info!(
+                                        "[socks5] [{addr}] [associate] [{assoc_id:#06x}]"
+                                    );
+                                    self.handle_associate(associate, assoc_id).await;
+                                }
+                                Ok(Connection::Bind(bind, _)) => {
+                                    tracing::info!("[socks5] [{addr}] [bind]");
+                                    Self::handle_bind(bind).await;
+                                }
+                                Ok(Connection::Connect(connect, target_addr)) => {
+                                    tracing::info!("[socks5] [{addr}] [connect] {target_addr}");
+                                    self.handle_connect(connect, target_addr).await;
+                                }
+                                Err(err) => {
+                                    tracing::warn!("[socks5] [{addr}] handshake error: {err}")
+                                }
+                            };
+
+                            tracing::debug!("[socks5] [{addr}] connection closed");
+                        }
+                        .instrument(span)
+                        .await
                     });
                 }
-                Err(err) => log::warn!("[socks5] failed to establish connection: {err}"),
+                Err(err) => tracing::warn!("[socks5] failed to establish connection: {err}"),
             }
         }
     }
 }
+
+struct UdpAssocSlot<'a>(&'a AtomicUsize);
+
+impl Drop for UdpAssocSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    io::{Error as IoError, ErrorKind},
+    net::{Ipv4Addr, SocketAddr},
+};
+
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tuic::Address as TuicAddress;
+
+use super::Server;
+use crate::connection::{Connection as TuicConnection, ERROR_CODE};
+
+pub const VERSION: u8 = 0x04;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const REPLY_GRANTED: u8 = 0x5a;
+const REPLY_REJECTED: u8 = 0x5b;
+
+const MAX_FIELD_LEN: usize = 255;
+
+impl Server {
+    /// Serves a SOCKS4/4a connection. Only `CONNECT` is supported, and since
+    /// SOCKS4 has no password authentication, it is rejected if socks5
+    /// authentication is configured
+    pub async fn handle_socks4(&self, mut stream: TcpStream, peer_addr: SocketAddr) {
+        let (cmd, target_addr) = match read_request(&mut stream).await {
+            Ok(req) => req,
+            Err(err) => {
+                tracing::warn!("[socks4] [{peer_addr}] handshake error: {err}");
+                return;
+            }
+        };
+
+        if self.users.is_some() {
+            tracing::warn!("[socks4] [{peer_addr}] rejected: socks5 authentication is required");
+            let _ = write_reply(&mut stream, REPLY_REJECTED).await;
+            return;
+        }
+
+        if cmd != CMD_CONNECT {
+            tracing::warn!("[socks4] [{peer_addr}] unsupported command {cmd:#04x}");
+            let _ = write_reply(&mut stream, REPLY_REJECTED).await;
+            return;
+        }
+
+        tracing::info!("[socks4] [{peer_addr}] [connect] {target_addr}");
+
+        let relay = match TuicConnection::get_conn().await {
+            Ok(conn) => conn.connect(target_addr.clone()).await,
+            Err(err) => Err(err),
+        };
+
+        match relay {
+            Ok(mut relay) => match write_reply(&mut stream, REPLY_GRANTED).await {
+                Ok(()) => match io::copy_bidirectional(&mut stream, &mut relay).await {
+                    Ok(_) => {}
+                    Err(err) => {
+                        let _ = stream.shutdown().await;
+                        let _ = relay.reset(ERROR_CODE);
+                        tracing::warn!(
+                            "[socks4] [{peer_addr}] [connect] [{target_addr}] TCP stream relaying \
+                             error: {err}"
+                        );
+                    }
+                },
+                Err(err) => {
+                    let _ = relay.shutdown().await;
+                    tracing::warn!(
+                        "[socks4] [{peer_addr}] [connect] [{target_addr}] command reply error: \
+                         {err}"
+                    );
+                }
+            },
+            Err(err) => {
+                tracing::warn!(
+                    "[socks4] [{peer_addr}] [connect] [{target_addr}] unable to relay TCP stream: \
+                     {err}"
+                );
+                let _ = write_reply(&mut stream, REPLY_REJECTED).await;
+            }
+        }
+    }
+}
+
+/// Reads a SOCKS4 request, resolving to the command and the target address
+async fn read_request(stream: &mut TcpStream) -> Result<(u8, TuicAddress), IoError> {
+    let mut buf = [0; 8];
+    stream.read_exact(&mut buf).await?;
+
+    if buf[0] != VERSION {
+        return Err(IoError::new(
+            ErrorKind::InvalidData,
+            "invalid SOCKS4 version",
+        ));
+    }
+
+    let cmd = buf[1];
+    let port = u16::from_be_bytes([buf[2], buf[3]]);
+    let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+
+    // the user ID is not used
+    read_null_terminated(stream).await?;
+
+    // SOCKS4a: an IP of 0.0.0.x (x != 0) means the domain follows the user ID
+    let addr = match ip.octets() {
+        [0, 0, 0, x] if x != 0 => {
+            let domain = read_null_terminated(stream).await?;
+            let domain = String::from_utf8(domain)
+                .map_err(|_| IoError::new(ErrorKind::InvalidData, "invalid SOCKS4a domain"))?;
+            TuicAddress::DomainAddress(domain, port)
+        }
+        _ => TuicAddress::SocketAddress(SocketAddr::from((ip, port))),
+    };
+
+    Ok((cmd, addr))
+}
+
+async fn read_null_terminated(stream: &mut TcpStream) -> Result<Vec<u8>, IoError> {
+    let mut field = Vec::new();
+
+    loop {
+        match stream.read_u8().await? {
+            0 => return Ok(field),
+            _ if field.len() == MAX_FIELD_LEN => {
+                return Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    "SOCKS4 field too long",
+                ));
+            }
+            byte => field.push(byte),
+        }
+    }
+}
+
+async fn write_reply(stream: &mut TcpStream, reply: u8) -> Result<(), IoError> {
+    stream.write_all(&[0x00, reply, 0, 0, 0, 0, 0, 0]).await
+}
@@ -2,7 +2,8 @@ use std::{
     collections::HashMap,
     io::{Error as IoError, ErrorKind},
     net::{IpAddr, SocketAddr, UdpSocket as StdUdpSocket},
-    sync::Arc,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
@@ -12,7 +13,11 @@ use socks5_proto::Address;
 use socks5_server::AssociatedUdpSocket;
 use tokio::{net::UdpSocket, sync::RwLock as AsyncRwLock};
 
-use crate::error::Error;
+use crate::{
+    error::Error,
+    stats,
+    utils::{self, IpCidr, PortRange},
+};
 
 pub static UDP_SESSIONS: OnceCell<AsyncRwLock<HashMap<u16, UdpSession>>> = OnceCell::new();
 
@@ -21,15 +26,33 @@ pub struct UdpSession {
     socket: Arc<AssociatedUdpSocket>,
     assoc_id: u16,
     ctrl_addr: SocketAddr,
+    max_pkt_size: usize,
+    allowed_ips: Arc<[IpCidr]>,
+    last_active: Arc<Mutex<Instant>>,
+    frag_timeout: Duration,
+    reassembly: Arc<Mutex<Option<Reassembly>>>,
+}
+
+/// The fragments of a socks5 UDP datagram received so far, see RFC 1928
+/// section 7
+struct Reassembly {
+    /// Taken from the first fragment
+    dst_addr: Address,
+    frags: Vec<Bytes>,
+    started: Instant,
 }
 
 impl UdpSession {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         assoc_id: u16,
         ctrl_addr: SocketAddr,
         local_ip: IpAddr,
         dual_stack: Option<bool>,
         max_pkt_size: usize,
+        port_range: Option<PortRange>,
+        allowed_ips: Arc<[IpCidr]>,
+        frag_timeout: Duration,
     ) -> Result<Self, Error> {
         let domain = match local_ip {
             IpAddr::V4(_) => Domain::IPV4,
@@ -56,27 +79,45 @@ impl UdpSession {
             )
         })?;
 
-        socket
-            .bind(&SockAddr::from(SocketAddr::from((local_ip, 0))))
-            .map_err(|err| {
-                Error::Socket("failed to bind socks5 server UDP associate socket", err)
-            })?;
+        let bind = |port| socket.bind(&SockAddr::from(SocketAddr::from((local_ip, port))));
+
+        match port_range {
+            // the first free port in the range is used
+            Some(range) => (range.start..=range.end)
+                .map(bind)
+                .find(Result::is_ok)
+                .unwrap_or_else(|| {
+                    Err(IoError::new(
+                        ErrorKind::AddrInUse,
+                        "no free port in `udp_port_range`",
+                    ))
+                }),
+            None => bind(0),
+        }
+        .map_err(|err| Error::Socket("failed to bind socks5 server UDP associate socket", err))?;
 
         let socket = UdpSocket::from_std(StdUdpSocket::from(socket)).map_err(|err| {
             Error::Socket("failed to create socks5 server UDP associate socket", err)
         })?;
 
         Ok(Self {
-            socket: Arc::new(AssociatedUdpSocket::from((socket, max_pkt_size))),
+            // one byte more than `max_pkt_size`, so larger packets show
+            // filling it as they are truncated
+            socket: Arc::new(AssociatedUdpSocket::from((socket, max_pkt_size + 1))),
             assoc_id,
             ctrl_addr,
+            max_pkt_size,
+            allowed_ips,
+            last_active: Arc::new(Mutex::new(Instant::now())),
+            frag_timeout,
+            reassembly: Arc::new(Mutex::new(None)),
         })
     }
 
     pub async fn send(&self, pkt: Bytes, src_addr: Address) -> Result<(), Error> {
         let src_addr_display = src_addr.to_string();
 
-        log::debug!(
+        tracing::debug!(
             "[socks5] [{ctrl_addr}] [associate] [{assoc_id:#06x}] send packet from \
              {src_addr_display} to {dst_addr}",
             ctrl_addr = self.ctrl_addr,
@@ -85,7 +126,7 @@ impl UdpSession {
         );
 
         if let Err(err) = self.socket.send(pkt, 0, src_addr).await {
-            log::warn!(
+            tracing::warn!(
                 "[socks5] [{ctrl_addr}] [associate] [{assoc_id:#06x}] send packet from \
                  {src_addr_display} to {dst_addr} error: {err}",
                 ctrl_addr = self.ctrl_addr,
@@ -96,12 +137,50 @@ impl UdpSession {
             return Err(Error::Io(err));
         }
 
+        self.touch();
         Ok(())
     }
 
     pub async fn recv(&self) -> Result<(Bytes, Address), Error> {
-        let (pkt, frag, dst_addr, src_addr) = self.socket.recv_from().await?;
+        loop {
+            let (pkt, frag, dst_addr, src_addr) = self.socket.recv_from().await?;
+            self.check_source(src_addr).await?;
+
+            // RSV and FRAG fields, then the address
+            if 3 + dst_addr.serialized_len() + pkt.len() > self.max_pkt_size {
+                stats::record_oversized_dropped();
+                Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "packet larger than max_packet_size ({max_pkt_size} bytes) truncated, \
+                         dropped",
+                        max_pkt_size = self.max_pkt_size
+                    ),
+                ))?;
+            }
+
+            tracing::debug!(
+                "[socks5] [{ctrl_addr}] [associate] [{assoc_id:#06x}] receive packet from \
+                 {src_addr} to {dst_addr}",
+                ctrl_addr = self.ctrl_addr,
+                assoc_id = self.assoc_id
+            );
 
+            self.touch();
+
+            if frag == 0 {
+                return Ok((pkt, dst_addr));
+            }
+
+            if let Some(pkt) = self.reassemble(pkt, frag, dst_addr)? {
+                return Ok(pkt);
+            }
+        }
+    }
+
+    /// Checks that a packet comes from the local client, connecting the
+    /// socket to it on the first packet
+    async fn check_source(&self, src_addr: SocketAddr) -> Result<(), Error> {
         if let Ok(connected_addr) = self.socket.peer_addr() {
             let connected_addr = match connected_addr {
                 SocketAddr::V4(addr) => {
@@ -129,28 +208,111 @@ impl UdpSession {
                     format!("invalid source address: {src_addr}"),
                 ))?;
             }
-        } else {
+        } else if utils::is_ip_allowed(&self.allowed_ips, src_addr.ip()) {
             self.socket.connect(src_addr).await?;
+        } else {
+            Err(IoError::new(
+                ErrorKind::PermissionDenied,
+                format!("source address not in allowed_ips: {src_addr}"),
+            ))?;
         }
 
-        if frag != 0 {
+        Ok(())
+    }
+
+    /// Queues a fragment, returning the datagram once its last fragment is
+    /// received. Fragments are expected in order, the partial datagram is
+    /// abandoned on a gap or once `frag_timeout` passed since its first one
+    fn reassemble(
+        &self,
+        pkt: Bytes,
+        frag: u8,
+        dst_addr: Address,
+    ) -> Result<Option<(Bytes, Address)>, Error> {
+        if self.frag_timeout.is_zero() {
+            stats::record_fragments_dropped(1);
             Err(IoError::new(
                 ErrorKind::Other,
                 "fragmented packet is not supported",
             ))?;
         }
 
-        log::debug!(
-            "[socks5] [{ctrl_addr}] [associate] [{assoc_id:#06x}] receive packet from {src_addr} \
-             to {dst_addr}",
-            ctrl_addr = self.ctrl_addr,
-            assoc_id = self.assoc_id
-        );
+        // the high-order bit marks the last fragment, the others its position
+        let pos = frag & 0x7f;
+        let is_last = frag & 0x80 != 0;
+
+        let mut reassembly = self.reassembly.lock().unwrap();
+
+        if let Some(partial) = reassembly.as_ref()
+            && (partial.started.elapsed() > self.frag_timeout
+                || usize::from(pos) != partial.frags.len() + 1)
+        {
+            tracing::debug!(
+                "[socks5] [{ctrl_addr}] [associate] [{assoc_id:#06x}] abandoned fragmented \
+                 packet to {dst_addr} after {len} fragments",
+                ctrl_addr = self.ctrl_addr,
+                assoc_id = self.assoc_id,
+                dst_addr = partial.dst_addr,
+                len = partial.frags.len(),
+            );
+
+            stats::record_fragments_dropped(partial.frags.len());
+            *reassembly = None;
+        }
+
+        if reassembly.is_none() {
+            if pos != 1 {
+                stats::record_fragments_dropped(1);
+                return Err(Error::Io(IoError::new(
+                    ErrorKind::Other,
+                    format!("fragment {pos} received out of sequence"),
+                )));
+            }
 
-        Ok((pkt, dst_addr))
+            *reassembly = Some(Reassembly {
+                dst_addr,
+                frags: Vec::new(),
+                started: Instant::now(),
+            });
+        }
+
+        let partial = reassembly.as_mut().unwrap();
+        partial.frags.push(pkt);
+
+        if !is_last {
+            return Ok(None);
+        }
+
+        let partial = reassembly.take().unwrap();
+        Ok(Some((
+            Bytes::from(partial.frags.concat()),
+            partial.dst_addr,
+        )))
     }
 
     pub fn local_addr(&self) -> Result<SocketAddr, IoError> {
         self.socket.local_addr()
     }
+
+    pub fn ctrl_addr(&self) -> SocketAddr {
+        self.ctrl_addr
+    }
+
+    /// Returns the number of fragments of a datagram waiting for the rest
+    pub fn buffered_fragments(&self) -> usize {
+        self.reassembly
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |reassembly| reassembly.frags.len())
+    }
+
+    /// Returns how long no packet has been relayed either way
+    pub fn idle_for(&self) -> Duration {
+        self.last_active.lock().unwrap().elapsed()
+    }
+
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
 }
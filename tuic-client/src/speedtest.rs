@@ -0,0 +1,78 @@
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+
+use crate::{connection::Connection, error::Error};
+
+/// How long to wait for the server to offer its extensions
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Empty probes measuring the latency, the median is reported
+const LATENCY_SAMPLES: usize = 5;
+
+/// The outcome of `--speedtest`
+pub struct Report {
+    size: u64,
+    latency: Duration,
+    upload: Duration,
+    download: Duration,
+}
+
+/// Measures the latency and throughput through the relay, uploading then
+/// downloading `size` bytes. Used by `--speedtest`
+pub async fn run(size: u64) -> Result<Report, Error> {
+    let conn = Connection::get_conn().await?;
+
+    if !conn.wait_speedtest(NEGOTIATION_TIMEOUT).await {
+        return Err(Error::Other(anyhow!(
+            "the server does not support speedtests"
+        )));
+    }
+
+    let mut latencies = Vec::with_capacity(LATENCY_SAMPLES);
+    for _ in 0..LATENCY_SAMPLES {
+        latencies.push(conn.speedtest(0, 0).await?);
+    }
+    latencies.sort();
+
+    Ok(Report {
+        size,
+        latency: latencies[LATENCY_SAMPLES / 2],
+        upload: conn.speedtest(size, 0).await?,
+        download: conn.speedtest(0, size).await?,
+    })
+}
+
+impl Report {
+    /// Returns the throughput in Mbps of transferring `size` bytes in
+    /// `elapsed`, the round trip of the request excluded
+    fn mbps(&self, elapsed: Duration) -> f64 {
+        let elapsed = elapsed
+            .saturating_sub(self.latency)
+            .max(Duration::from_micros(1));
+        self.size as f64 * 8.0 / elapsed.as_secs_f64() / 1_000_000.0
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        writeln!(f, "latency:  {:.1} ms", self.latency.as_secs_f64() * 1000.0)?;
+        writeln!(
+            f,
+            "upload:   {:.2} Mbps ({} bytes in {:.2} s)",
+            self.mbps(self.upload),
+            self.size,
+            self.upload.as_secs_f64(),
+        )?;
+        write!(
+            f,
+            "download: {:.2} Mbps ({} bytes in {:.2} s)",
+            self.mbps(self.download),
+            self.size,
+            self.download.as_secs_f64(),
+        )
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    io::{Error as IoError, ErrorKind},
+    net::TcpListener as StdTcpListener,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+};
+
+use httparse::{EMPTY_HEADER, Request, Status};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::Serialize;
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{config::Stats, connection::Connection, embed, error::Error, socks5::UDP_SESSIONS};
+
+static SERVER: OnceCell<Server> = OnceCell::new();
+
+/// Whether the UDP counters are kept, only while the stats API is enabled
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static UDP_COUNTERS: Lazy<Mutex<HashMap<u16, UdpCounters>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static ASSOCS_OPENED: AtomicU64 = AtomicU64::new(0);
+static ASSOCS_REJECTED: AtomicU64 = AtomicU64::new(0);
+static ASSOCS_CLOSED_BY_CONTROL: AtomicU64 = AtomicU64::new(0);
+static ASSOCS_CLOSED_IDLE: AtomicU64 = AtomicU64::new(0);
+static FRAGMENTS_DROPPED: AtomicU64 = AtomicU64::new(0);
+static OVERSIZED_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+const MAX_HEAD_LEN: usize = 8192;
+const MAX_HEADERS: usize = 32;
+
+/// Packets relayed for a UDP association
+#[derive(Clone, Copy, Default, Serialize)]
+struct UdpCounters {
+    tx_packets: u64,
+    tx_bytes: u64,
+    rx_packets: u64,
+    rx_bytes: u64,
+    /// Packets received from the server but not delivered to the local client
+    rx_dropped: u64,
+}
+
+/// Counts a packet sent through the association `assoc_id`
+pub fn record_tx(assoc_id: u16, len: usize) {
+    update(assoc_id, |counters| {
+        counters.tx_packets += 1;
+        counters.tx_bytes += len as u64;
+    });
+}
+
+/// Counts a packet received on the association `assoc_id`
+pub fn record_rx(assoc_id: u16, len: usize) {
+    update(assoc_id, |counters| {
+        counters.rx_packets += 1;
+        counters.rx_bytes += len as u64;
+    });
+}
+
+/// Counts a received packet that could not be delivered
+pub fn record_rx_dropped(assoc_id: u16) {
+    update(assoc_id, |counters| counters.rx_dropped += 1);
+}
+
+/// Forgets the counters of a dissociated association
+pub fn remove(assoc_id: u16) {
+    if ENABLED.load(Ordering::Relaxed) {
+        UDP_COUNTERS.lock().unwrap().remove(&assoc_id);
+    }
+}
+
+/// Why a socks5 UDP association ended
+#[derive(Clone, Copy, Debug)]
+pub enum AssocEnd {
+    /// Its control TCP connection closed
+    ControlClosed,
+    /// No packet was relayed for `udp_idle_timeout`
+    Idle,
+}
+
+/// Counts a socks5 UDP association set up
+pub fn record_assoc_opened() {
+    ASSOCS_OPENED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a socks5 UDP association refused as `max_udp_associations` were
+/// open
+pub fn record_assoc_rejected() {
+    ASSOCS_REJECTED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a socks5 UDP association ended
+pub fn record_assoc_closed(end: AssocEnd) {
+    match end {
+        AssocEnd::ControlClosed => ASSOCS_CLOSED_BY_CONTROL.fetch_add(1, Ordering::Relaxed),
+        AssocEnd::Idle => ASSOCS_CLOSED_IDLE.fetch_add(1, Ordering::Relaxed),
+    };
+}
+
+/// Counts fragments of socks5 UDP datagrams dropped, unsupported or not
+/// reassembled
+pub fn record_fragments_dropped(count: usize) {
+    FRAGMENTS_DROPPED.fetch_add(count as u64, Ordering::Relaxed);
+}
+
+/// Counts socks5 UDP packets dropped as larger than `max_packet_size`
+pub fn record_oversized_dropped() {
+    OVERSIZED_DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+fn update(assoc_id: u16, f: impl FnOnce(&mut UdpCounters)) {
+    if ENABLED.load(Ordering::Relaxed) {
+        f(UDP_COUNTERS.lock().unwrap().entry(assoc_id).or_default());
+    }
+}
+
+/// A local HTTP server answering `GET /stats` with the statistics of the
+/// relay as JSON, and `GET /dump` with a human-readable snapshot of its state
+///
+/// There is no authentication, so it should only listen on a loopback address.
+pub struct Server {
+    listener: TcpListener,
+}
+
+impl Server {
+    pub fn set_config(cfg: Stats) -> Result<(), Error> {
+        SERVER
+            .set(Self::new(cfg)?)
+            .map_err(|_| "failed initializing stats server")
+            .unwrap();
+
+        ENABLED.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    fn new(cfg: Stats) -> Result<Self, Error> {
+        let listener = StdTcpListener::bind(cfg.listen)
+            .and_then(|listener| {
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)
+            })
+            .map_err(|err| Error::Socket("failed to bind stats server socket", err))?;
+
+        Ok(Self { listener })
+    }
+
+    pub async fn start() {
+        let server = SERVER.get().unwrap();
+
+        tracing::warn!(
+            "[stats] server started, listening on {}",
+            server.listener.local_addr().unwrap()
+        );
+
+        loop {
+            match server.listener.accept().await {
+                Ok((stream, addr)) => {
+                    tokio::spawn(async move {
+                        if let Err(err) = serve(stream).await {
+                            tracing::debug!("[stats] [{addr}] {err}");
+                        }
+                    });
+                }
+                Err(err) => tracing::warn!("[stats] failed to accept connection: {err}"),
+            }
+        }
+    }
+}
+
+/// Serves a single request, then closes the connection
+async fn serve(mut stream: TcpStream) -> Result<(), IoError> {
+    let head = read_head(&mut stream).await?;
+    let mut headers = [EMPTY_HEADER; MAX_HEADERS];
+    let mut req = Request::new(&mut headers);
+
+    let (status, content_type, body) = match req.parse(&head) {
+        Ok(Status::Complete(_)) if req.method != Some("GET") => {
+            ("405 Method Not Allowed", "application/json", String::new())
+        }
+        Ok(Status::Complete(_)) if req.path == Some("/stats") => {
+            ("200 OK", "application/json", snapshot().await)
+        }
+        Ok(Status::Complete(_)) if req.path == Some("/dump") => {
+            ("200 OK", "text/plain", dump().await)
+        }
+        Ok(Status::Complete(_)) => ("404 Not Found", "application/json", String::new()),
+        _ => ("400 Bad Request", "application/json", String::new()),
+    };
+
+    let resp = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: \
+         {len}\r\nConnection: close\r\n\r\n{body}",
+        len = body.len(),
+    );
+    stream.write_all(resp.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+async fn snapshot() -> String {
+    let udp = UDP_COUNTERS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(assoc_id, counters)| (format!("{assoc_id:#06x}"), *counters))
+        .collect::<HashMap<_, _>>();
+
+    let mut stats = Connection::stats().await;
+    stats["udp_associations"] = json!(udp);
+
+    let opened = ASSOCS_OPENED.load(Ordering::Relaxed);
+    let closed_by_control = ASSOCS_CLOSED_BY_CONTROL.load(Ordering::Relaxed);
+    let closed_idle = ASSOCS_CLOSED_IDLE.load(Ordering::Relaxed);
+    stats["socks5_associations"] = json!({
+        "active": opened.saturating_sub(closed_by_control + closed_idle),
+        "opened": opened,
+        "rejected": ASSOCS_REJECTED.load(Ordering::Relaxed),
+        "closed_by_control": closed_by_control,
+        "closed_idle": closed_idle,
+        "fragments_dropped": FRAGMENTS_DROPPED.load(Ordering::Relaxed),
+        "oversized_dropped": OVERSIZED_DROPPED.load(Ordering::Relaxed),
+    });
+    stats.to_string()
+}
+
+/// Returns a human-readable snapshot of the pooled connections, the socks5 and
+/// embedded UDP associations, the fragments buffered and the packets queued,
+/// logged on `SIGUSR1` and served on `GET /dump` to debug leaks and stuck
+/// sessions
+pub async fn dump() -> String {
+    let mut out = String::from("state dump\n");
+    Connection::dump(&mut out).await;
+
+    let opened = ASSOCS_OPENED.load(Ordering::Relaxed);
+    let closed_by_control = ASSOCS_CLOSED_BY_CONTROL.load(Ordering::Relaxed);
+    let closed_idle = ASSOCS_CLOSED_IDLE.load(Ordering::Relaxed);
+    let _ = writeln!(
+        out,
+        "socks5 UDP associations: {active} active, {opened} opened, {rejected} rejected, \
+         {fragments} fragments and {oversized} oversized packets dropped",
+        active = opened.saturating_sub(closed_by_control + closed_idle),
+        rejected = ASSOCS_REJECTED.load(Ordering::Relaxed),
+        fragments = FRAGMENTS_DROPPED.load(Ordering::Relaxed),
+        oversized = OVERSIZED_DROPPED.load(Ordering::Relaxed),
+    );
+
+    if let Some(sessions) = UDP_SESSIONS.get() {
+        for (assoc_id, session) in sessions.read().await.iter() {
+            let _ = writeln!(
+                out,
+                "  [{assoc_id:#06x}] {ctrl_addr}: idle for {idle:?}, {frags} fragments buffered",
+                ctrl_addr = session.ctrl_addr(),
+                idle = session.idle_for(),
+                frags = session.buffered_fragments(),
+            );
+        }
+    }
+
+    let queued = embed::queued_packets().await;
+    let _ = write!(out, "embedded UDP associations: {}", queued.len());
+    for (assoc_id, packets) in queued {
+        let _ = write!(out, "\n  [{assoc_id:#06x}] {packets} packets queued");
+    }
+
+    out
+}
+
+async fn read_head(stream: &mut TcpStream) -> Result<Vec<u8>, IoError> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() >= MAX_HEAD_LEN {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                "request head too large",
+            ));
+        }
+
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(IoError::from(ErrorKind::UnexpectedEof));
+        }
+
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    Ok(buf)
+}
@@ -1,15 +1,25 @@
 use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
     fs,
     net::{IpAddr, SocketAddr},
     path::PathBuf,
     str::FromStr,
+    sync::Arc,
 };
 
-use anyhow::Context;
+use anyhow::{Context, anyhow};
+use hickory_resolver::{
+    TokioAsyncResolver,
+    config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+};
 use rustls::{RootCertStore, pki_types::CertificateDer};
 use tokio::net;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::Targets;
+
+use crate::{config::DnsServer, error::Error};
 
-use crate::error::Error;
+pub use tuic_common::log_file::LogRotation;
 
 pub fn load_certs(paths: Vec<PathBuf>, disable_native: bool) -> Result<RootCertStore, Error> {
     let mut certs = RootCertStore::empty();
@@ -39,11 +49,22 @@ pub struct ServerAddr {
     domain: String,
     port: u16,
     ip: Option<IpAddr>,
+    resolver: Option<Arc<TokioAsyncResolver>>,
 }
 
 impl ServerAddr {
-    pub fn new(domain: String, port: u16, ip: Option<IpAddr>) -> Self {
-        Self { domain, port, ip }
+    pub fn new(
+        domain: String,
+        port: u16,
+        ip: Option<IpAddr>,
+        resolver: Option<Arc<TokioAsyncResolver>>,
+    ) -> Self {
+        Self {
+            domain,
+            port,
+            ip,
+            resolver,
+        }
     }
 
     pub fn server_name(&self) -> &str {
@@ -53,6 +74,18 @@ impl ServerAddr {
     pub async fn resolve(&self) -> Result<impl Iterator<Item = SocketAddr>, Error> {
         if let Some(ip) = self.ip {
             Ok(vec![SocketAddr::from((ip, self.port))].into_iter())
+        } else if let Some(resolver) = &self.resolver {
+            let port = self.port;
+            let lookup = resolver
+                .lookup_ip(self.domain.as_str())
+                .await
+                .map_err(|err| Error::Other(anyhow!(err).context("DNS lookup failed")))?;
+
+            Ok(lookup
+                .iter()
+                .map(|ip| SocketAddr::from((ip, port)))
+                .collect::<Vec<_>>()
+                .into_iter())
         } else {
             Ok(net::lookup_host((self.domain.as_str(), self.port))
                 .await?
@@ -62,10 +95,58 @@ impl ServerAddr {
     }
 }
 
-#[derive(Clone, Copy)]
+impl Display for ServerAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}:{}", self.domain, self.port)
+    }
+}
+
+/// Builds a resolver for the server name from the configured DNS servers.
+/// Returns `None` if no server is configured, in which case the system
+/// resolver is used
+pub fn build_resolver(servers: &[DnsServer]) -> Result<Option<TokioAsyncResolver>, Error> {
+    if servers.is_empty() {
+        return Ok(None);
+    }
+
+    let mut config = ResolverConfig::new();
+
+    for server in servers {
+        let protocol = match server.protocol {
+            DnsProtocol::Udp => Protocol::Udp,
+            DnsProtocol::Tcp => Protocol::Tcp,
+            DnsProtocol::Tls => Protocol::Tls,
+            DnsProtocol::Https => Protocol::Https,
+        };
+
+        if matches!(server.protocol, DnsProtocol::Tls | DnsProtocol::Https)
+            && server.tls_name.is_none()
+        {
+            return Err(Error::Other(anyhow!(
+                "DNS server {} requires `tls_name` for DoT / DoH",
+                server.address
+            )));
+        }
+
+        let mut ns = NameServerConfig::new(server.address, protocol);
+        ns.tls_dns_name = server.tls_name.clone();
+        config.add_name_server(ns);
+    }
+
+    Ok(Some(TokioAsyncResolver::tokio(
+        config,
+        ResolverOpts::default(),
+    )))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum UdpRelayMode {
     Native,
     Quic,
+    /// `native` until a packet of the association doesn't fit in a single
+    /// datagram, `quic` from then on or when datagrams are not supported on the
+    /// path
+    Auto,
 }
 
 impl FromStr for UdpRelayMode {
@@ -76,16 +157,64 @@ impl FromStr for UdpRelayMode {
             Ok(Self::Native)
         } else if s.eq_ignore_ascii_case("quic") {
             Ok(Self::Quic)
+        } else if s.eq_ignore_ascii_case("auto") {
+            Ok(Self::Auto)
         } else {
             Err("invalid UDP relay mode")
         }
     }
 }
 
+#[derive(Clone, Copy)]
+pub enum MultipathMode {
+    /// Every packet is sent over both paths
+    Duplicate,
+    /// Packets are sent over each path in turn
+    Stripe,
+}
+
+impl FromStr for MultipathMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("duplicate") {
+            Ok(Self::Duplicate)
+        } else if s.eq_ignore_ascii_case("stripe") {
+            Ok(Self::Stripe)
+        } else {
+            Err("invalid multipath mode")
+        }
+    }
+}
+
+/// Where the domain of a CONNECT target is resolved
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Resolve {
+    /// Sent as a domain, resolved by the server
+    Remote,
+    /// Resolved by the client, sent as an IP
+    Local,
+}
+
+impl FromStr for Resolve {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("remote") {
+            Ok(Self::Remote)
+        } else if s.eq_ignore_ascii_case("local") {
+            Ok(Self::Local)
+        } else {
+            Err("invalid resolve mode")
+        }
+    }
+}
+
 pub enum CongestionControl {
     Cubic,
     NewReno,
     Bbr,
+    Brutal,
 }
 
 impl FromStr for CongestionControl {
@@ -98,8 +227,185 @@ impl FromStr for CongestionControl {
             Ok(Self::NewReno)
         } else if s.eq_ignore_ascii_case("bbr") {
             Ok(Self::Bbr)
+        } else if s.eq_ignore_ascii_case("brutal") {
+            Ok(Self::Brutal)
         } else {
             Err("invalid congestion control")
         }
     }
 }
+
+#[derive(Clone, Copy)]
+pub enum DnsProtocol {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+}
+
+impl FromStr for DnsProtocol {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("udp") {
+            Ok(Self::Udp)
+        } else if s.eq_ignore_ascii_case("tcp") {
+            Ok(Self::Tcp)
+        } else if s.eq_ignore_ascii_case("tls") || s.eq_ignore_ascii_case("dot") {
+            Ok(Self::Tls)
+        } else if s.eq_ignore_ascii_case("https") || s.eq_ignore_ascii_case("doh") {
+            Ok(Self::Https)
+        } else {
+            Err("invalid DNS protocol")
+        }
+    }
+}
+
+/// `log_level`, either a single level for all the crates, or `tracing`
+/// filter directives, e.g. `info,quinn=warn,tuic_quinn=debug`
+#[derive(Clone)]
+pub struct LogFilter(Targets);
+
+impl LogFilter {
+    pub fn targets(&self) -> &Targets {
+        &self.0
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<LevelFilter>() {
+            Ok(level) => Ok(Self(Targets::new().with_default(level))),
+            Err(_) => s
+                .parse()
+                .map(Self)
+                .map_err(|err| format!("invalid log filter `{s}`: {err}")),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("text") {
+            Ok(Self::Text)
+        } else if s.eq_ignore_ascii_case("json") {
+            Ok(Self::Json)
+        } else {
+            Err("invalid log format")
+        }
+    }
+}
+
+/// An inclusive range of ports, `"START-END"` or a single `"PORT"`
+#[derive(Clone, Copy)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let start = start.trim().parse().map_err(|_| "invalid port range")?;
+        let end = end.trim().parse().map_err(|_| "invalid port range")?;
+
+        if start == 0 || start > end {
+            return Err("invalid port range");
+        }
+
+        Ok(Self { start, end })
+    }
+}
+
+/// An IP network in CIDR notation, e.g. `"192.168.0.0/16"`, or a single IP
+#[derive(Clone, Copy)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+impl IpCidr {
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u32 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether `ip` is in one of the `allowed` networks. An empty list allows all
+pub fn is_ip_allowed(allowed: &[IpCidr], ip: IpAddr) -> bool {
+    allowed.is_empty() || allowed.iter().any(|net| net.contains(ip))
+}
+
+impl FromStr for IpCidr {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| "invalid IP network")?
+            .to_canonical();
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| "invalid IP network")?,
+            None => max_prefix_len,
+        };
+
+        if prefix_len > max_prefix_len {
+            return Err("invalid IP network prefix length");
+        }
+
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// Whether `domain` is `parent` or one of its subdomains, ignoring the case
+/// and a trailing dot
+pub fn is_same_or_subdomain(domain: &str, parent: &str) -> bool {
+    let domain = domain.trim_end_matches('.');
+
+    match domain.len().checked_sub(parent.len()) {
+        Some(0) => domain.eq_ignore_ascii_case(parent),
+        Some(idx) => {
+            domain.as_bytes()[idx - 1] == b'.'
+                && domain
+                    .get(idx..)
+                    .is_some_and(|suffix| suffix.eq_ignore_ascii_case(parent))
+        }
+        None => false,
+    }
+}
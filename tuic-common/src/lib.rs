@@ -0,0 +1,7 @@
+//! Code shared by the `tuic-server` and `tuic-client` binaries
+
+pub mod log_file;
+pub mod runtime;
+#[cfg(windows)]
+pub mod service;
+pub mod socket;
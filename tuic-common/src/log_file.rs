@@ -0,0 +1,156 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Error as IoError, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+const SECS_PER_DAY: u64 = 86400;
+
+/// When a [`LogFile`] is rotated
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    #[default]
+    Never,
+    /// At midnight UTC
+    Daily,
+    /// Before a write would grow the file beyond its maximum size
+    Size,
+}
+
+impl FromStr for LogRotation {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("never") {
+            Ok(Self::Never)
+        } else if s.eq_ignore_ascii_case("daily") {
+            Ok(Self::Daily)
+        } else if s.eq_ignore_ascii_case("size") {
+            Ok(Self::Size)
+        } else {
+            Err("invalid log rotation")
+        }
+    }
+}
+
+/// A log file shared by all log writers, rotated by size or at midnight UTC.
+/// Rotated files are renamed to `<path>.1`, `<path>.2`, ... with the most
+/// recent one being `<path>.1`
+#[derive(Clone)]
+pub struct LogFile(Arc<Mutex<Inner>>);
+
+struct Inner {
+    path: PathBuf,
+    rotation: LogRotation,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+    day: u64,
+}
+
+impl LogFile {
+    /// Opens the log file at `path` for appending. `max_size` only applies
+    /// to [`LogRotation::Size`], and at most `max_files` rotated files are
+    /// kept
+    pub fn open(
+        path: &Path,
+        rotation: LogRotation,
+        max_size: u64,
+        max_files: usize,
+    ) -> Result<Self, IoError> {
+        let file = open_append(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self(Arc::new(Mutex::new(Inner {
+            path: path.to_owned(),
+            rotation,
+            max_size,
+            max_files,
+            file,
+            size,
+            day: today(),
+        }))))
+    }
+
+    /// Re-opens the log file at its configured path, e.g. after it has been
+    /// moved away by an external log rotation tool
+    pub fn reopen(&self) -> Result<(), IoError> {
+        let mut inner = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        inner.file = open_append(&inner.path)?;
+        inner.size = inner.file.metadata()?.len();
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let mut inner = self.0.lock().unwrap_or_else(|err| err.into_inner());
+
+        let rotate = match inner.rotation {
+            LogRotation::Never => false,
+            LogRotation::Daily => today() != inner.day,
+            LogRotation::Size => inner.size > 0 && inner.size + buf.len() as u64 > inner.max_size,
+        };
+        if rotate {
+            inner.rotate()?;
+        }
+
+        let n = inner.file.write(buf)?;
+        inner.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.0
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .file
+            .flush()
+    }
+}
+
+impl Inner {
+    fn rotate(&mut self) -> Result<(), IoError> {
+        self.file.flush()?;
+
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for idx in (1..self.max_files).rev() {
+                let from = rotated_path(&self.path, idx);
+                if from.exists() {
+                    fs::rename(from, rotated_path(&self.path, idx + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        self.day = today();
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File, IoError> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, idx: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{idx}"));
+    PathBuf::from(path)
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |dur| dur.as_secs() / SECS_PER_DAY)
+}
@@ -0,0 +1,38 @@
+use std::{
+    io::Error as IoError,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use core_affinity::CoreId;
+use tokio::runtime::{Builder, Runtime};
+
+/// Builds the multi-threaded runtime the server or client runs on, with one
+/// worker thread per CPU core if `worker_threads` is 0
+///
+/// With `cpu_affinity` non-empty, the threads of the runtime are pinned to the
+/// listed cores in turn as they start
+pub fn build(worker_threads: usize, cpu_affinity: &[usize]) -> Result<Runtime, IoError> {
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all();
+
+    if worker_threads != 0 {
+        builder.worker_threads(worker_threads);
+    }
+
+    if !cpu_affinity.is_empty() {
+        let cores: Arc<[usize]> = cpu_affinity.into();
+        let next = AtomicUsize::new(0);
+
+        builder.on_thread_start(move || {
+            let id = cores[next.fetch_add(1, Ordering::Relaxed) % cores.len()];
+            if !core_affinity::set_for_current(CoreId { id }) {
+                tracing::warn!("failed to pin runtime thread to CPU core {id}");
+            }
+        });
+    }
+
+    builder.build()
+}
@@ -0,0 +1,228 @@
+use std::{
+    env,
+    ffi::OsString,
+    fmt::Display,
+    fs,
+    future::Future,
+    io::Error as IoError,
+    pin::Pin,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use thiserror::Error;
+use tokio::{
+    runtime::Handle,
+    sync::oneshot::{self, Receiver},
+    task::JoinError,
+};
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+
+/// How a binary registers itself as a Windows service
+pub struct Service {
+    pub name: &'static str,
+    pub display_name: &'static str,
+    pub description: &'static str,
+}
+
+type RunFuture = Pin<Box<dyn Future<Output = Result<(), String>>>>;
+
+/// Runs the binary with the given command line until the receiver resolves
+type Run = Box<dyn Fn(Vec<OsString>, Receiver<()>) -> RunFuture + Send + Sync>;
+
+struct Dispatch {
+    service: &'static Service,
+    runtime: Handle,
+    run: Run,
+}
+
+static DISPATCH: OnceLock<Dispatch> = OnceLock::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("no config file specified")]
+    NoConfig,
+    #[error("failed to locate the config file: {0}")]
+    Config(IoError),
+    #[error(transparent)]
+    Io(#[from] IoError),
+    #[error(transparent)]
+    Service(#[from] windows_service::Error),
+    #[error("failed to start the service dispatcher: {0}")]
+    Dispatcher(windows_service::Error),
+    #[error(transparent)]
+    Join(#[from] JoinError),
+}
+
+/// Handles the service related command line flags. Returns `None` if the
+/// process is not invoked for any of them
+///
+/// When started by the service control manager, `run` is called with the
+/// process command line, without the `--service` flag, and should return
+/// once the shutdown receiver resolves
+pub async fn handle_command<F, Fut, E>(
+    service: &'static Service,
+    run: F,
+) -> Option<Result<(), Error>>
+where
+    F: Fn(Vec<OsString>, Receiver<()>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), E>> + 'static,
+    E: Display,
+{
+    let mut args = env::args_os().skip(1);
+
+    match args.next()?.to_str()? {
+        "--install-service" => Some(install(service, args.collect())),
+        "--uninstall-service" => Some(uninstall(service)),
+        "--service" => {
+            let run: Run = Box::new(move |args, shutdown| -> RunFuture {
+                let fut = run(args, shutdown);
+                Box::pin(async move { fut.await.map_err(|err| err.to_string()) })
+            });
+            DISPATCH.get_or_init(|| Dispatch {
+                service,
+                runtime: Handle::current(),
+                run,
+            });
+            let dispatch = tokio::task::spawn_blocking(|| {
+                service_dispatcher::start(service.name, ffi_service_main)
+            });
+            Some(match dispatch.await {
+                Ok(res) => res.map_err(Error::Dispatcher),
+                Err(err) => Err(err.into()),
+            })
+        }
+        _ => None,
+    }
+}
+
+fn install(service: &Service, mut args: Vec<OsString>) -> Result<(), Error> {
+    // the service runs with `System32` as its working directory
+    for idx in 1..args.len() {
+        if args[idx - 1] == "-c" || args[idx - 1] == "--config" {
+            args[idx] = fs::canonicalize(&args[idx])
+                .map_err(Error::Config)?
+                .into_os_string();
+        }
+    }
+
+    if !args.iter().any(|arg| arg == "-c" || arg == "--config") {
+        return Err(Error::NoConfig);
+    }
+
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+
+    let mut launch_arguments = vec![OsString::from("--service")];
+    launch_arguments.extend(args);
+
+    let info = ServiceInfo {
+        name: OsString::from(service.name),
+        display_name: OsString::from(service.display_name),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: env::current_exe()?,
+        launch_arguments,
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+
+    let handle = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)?;
+    handle.set_description(service.description)?;
+
+    println!("service `{}` installed", service.name);
+    Ok(())
+}
+
+fn uninstall(service: &Service) -> Result<(), Error> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let handle = manager.open_service(
+        service.name,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    )?;
+
+    if handle.query_status()?.current_state != ServiceState::Stopped {
+        handle.stop()?;
+    }
+    handle.delete()?;
+
+    println!("service `{}` uninstalled", service.name);
+    Ok(())
+}
+
+fn service_main(_args: Vec<OsString>) {
+    if let Err(err) = run_service() {
+        tracing::error!("service error: {err}");
+    }
+}
+
+fn run_service() -> windows_service::Result<()> {
+    let dispatch = DISPATCH.get().unwrap();
+
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let stop_tx = Mutex::new(Some(stop_tx));
+
+    let status_handle =
+        service_control_handler::register(dispatch.service.name, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                if let Some(tx) = stop_tx.lock().unwrap().take() {
+                    _ = tx.send(());
+                }
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?;
+
+    status_handle.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: ServiceState::Running,
+        controls_accepted: ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        exit_code: ServiceExitCode::Win32(0),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    })?;
+
+    // the process command line, without the `--service` flag
+    let args = env::args_os()
+        .enumerate()
+        .filter(|(idx, _)| *idx != 1)
+        .map(|(_, arg)| arg)
+        .collect();
+
+    let res = dispatch.runtime.block_on((dispatch.run)(args, stop_rx));
+
+    let exit_code = match res {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(err) => {
+            tracing::error!("{err}");
+            ServiceExitCode::ServiceSpecific(1)
+        }
+    };
+
+    status_handle.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: ServiceState::Stopped,
+        controls_accepted: ServiceControlAccept::empty(),
+        exit_code,
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    })
+}
@@ -0,0 +1,49 @@
+use std::io::{Error as IoError, ErrorKind};
+
+use socket2::SockRef;
+
+/// Sets the DSCP value of the packets sent through `socket`. Dual-stack sockets
+/// are marked for both address families
+pub fn set_dscp(socket: SockRef<'_>, dscp: u8) -> Result<(), IoError> {
+    if dscp > 63 {
+        return Err(IoError::new(
+            ErrorKind::InvalidInput,
+            format!("invalid DSCP value {dscp}, must be below 64"),
+        ));
+    }
+
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "macos"
+    ))]
+    {
+        // the ECN bits are left to the stack
+        let tclass = u32::from(dscp) << 2;
+
+        if socket.local_addr()?.is_ipv6() {
+            socket.set_tclass_v6(tclass)?;
+            if socket.only_v6()? {
+                return Ok(());
+            }
+        }
+
+        socket.set_tos(tclass)?;
+        Ok(())
+    }
+
+    #[cfg(not(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "macos"
+    )))]
+    {
+        let _ = socket;
+        Err(IoError::new(
+            ErrorKind::Unsupported,
+            "DSCP marking is not supported on this platform",
+        ))
+    }
+}
@@ -0,0 +1,86 @@
+//! Compares receiving UDP packets into a fresh `Vec` per packet with receiving
+//! them through a `BufPool`
+//!
+//! Besides the timings, the number of allocations per packet of each approach
+//! is printed, counted by a wrapping global allocator
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bytes::{BufMut, Bytes};
+use criterion::{Criterion, criterion_group, criterion_main};
+use tuic_quinn::BufPool;
+
+const PACKET_SIZE: usize = 1536;
+const PAYLOAD: [u8; 1200] = [0x5a; 1200];
+/// Number of packets kept alive at once, as if queued for relaying
+const IN_FLIGHT: usize = 64;
+
+struct CountingAlloc;
+
+static ALLOCS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn recv_vec(queue: &mut Vec<Bytes>) {
+    let mut buf = vec![0u8; PACKET_SIZE];
+    buf[..PAYLOAD.len()].copy_from_slice(&PAYLOAD);
+    buf.truncate(PAYLOAD.len());
+    push(queue, Bytes::from(buf));
+}
+
+fn recv_pool(pool: &mut BufPool, queue: &mut Vec<Bytes>) {
+    pool.buf().put_slice(&PAYLOAD);
+    push(queue, pool.take());
+}
+
+fn push(queue: &mut Vec<Bytes>, pkt: Bytes) {
+    if queue.len() == IN_FLIGHT {
+        queue.clear();
+    }
+    queue.push(black_box(pkt));
+}
+
+fn allocs_per_packet(mut recv: impl FnMut()) -> f64 {
+    const PACKETS: u64 = 100_000;
+
+    let start = ALLOCS.load(Ordering::Relaxed);
+    for _ in 0..PACKETS {
+        recv();
+    }
+    (ALLOCS.load(Ordering::Relaxed) - start) as f64 / PACKETS as f64
+}
+
+fn bench(c: &mut Criterion) {
+    let mut queue = Vec::with_capacity(IN_FLIGHT);
+    let mut pool = BufPool::new(PACKET_SIZE);
+
+    println!(
+        "allocations per packet: vec {:.3}, pool {:.3}",
+        allocs_per_packet(|| recv_vec(&mut queue)),
+        allocs_per_packet(|| recv_pool(&mut pool, &mut queue)),
+    );
+
+    let mut group = c.benchmark_group("udp_recv");
+    group.bench_function("vec", |b| b.iter(|| recv_vec(&mut queue)));
+    group.bench_function("pool", |b| b.iter(|| recv_pool(&mut pool, &mut queue)));
+    group.finish();
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
@@ -0,0 +1,149 @@
+use std::{
+    any::Any,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use quinn::congestion::{Controller, ControllerFactory};
+use quinn_proto::RttEstimator;
+
+/// Number of one-second slots the acknowledgement rate is measured over
+const SLOTS: usize = 5;
+/// Minimum number of packets sampled before the measured acknowledgement rate
+/// is trusted
+const MIN_SAMPLES: u64 = 50;
+const MIN_ACK_RATE: f64 = 0.8;
+const WINDOW_GAIN: f64 = 2.0;
+
+/// Configuration of the [`Brutal`] congestion controller
+#[derive(Clone, Debug)]
+pub struct BrutalConfig {
+    bandwidth: u64,
+}
+
+impl BrutalConfig {
+    /// Creates a configuration sending at `bandwidth` bytes per second
+    pub fn new(bandwidth: u64) -> Self {
+        Self { bandwidth }
+    }
+}
+
+impl ControllerFactory for BrutalConfig {
+    fn build(self: Arc<Self>, now: Instant, current_mtu: u16) -> Box<dyn Controller> {
+        Box::new(Brutal {
+            bandwidth: self.bandwidth,
+            start: now,
+            mtu: current_mtu,
+            rtt: None,
+            slots: [Slot::default(); SLOTS],
+            ack_rate: 1.0,
+        })
+    }
+}
+
+/// A congestion controller sending at a fixed, user-declared bandwidth
+///
+/// Losses do not shrink the window. Instead, the window is sized to the
+/// bandwidth-delay product divided by the rate of packets acknowledged over the
+/// last seconds, so that the data delivered stays at the declared bandwidth on
+/// lossy links. Declaring more than the real capacity of the path only adds to
+/// the congestion
+#[derive(Clone, Debug)]
+pub struct Brutal {
+    bandwidth: u64,
+    start: Instant,
+    mtu: u16,
+    rtt: Option<Duration>,
+    slots: [Slot; SLOTS],
+    ack_rate: f64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Slot {
+    second: u64,
+    acked: u64,
+    lost: u64,
+}
+
+impl Brutal {
+    fn record(&mut self, now: Instant, acked: u64, lost: u64) {
+        let second = now.saturating_duration_since(self.start).as_secs();
+        let slot = &mut self.slots[(second % SLOTS as u64) as usize];
+
+        if slot.second != second {
+            *slot = Slot {
+                second,
+                acked: 0,
+                lost: 0,
+            };
+        }
+
+        slot.acked += acked;
+        slot.lost += lost;
+
+        let (acked, lost) = self
+            .slots
+            .iter()
+            .filter(|slot| second.saturating_sub(slot.second) < SLOTS as u64)
+            .fold((0, 0), |(acked, lost), slot| {
+                (acked + slot.acked, lost + slot.lost)
+            });
+
+        self.ack_rate = if acked + lost < MIN_SAMPLES {
+            1.0
+        } else {
+            (acked as f64 / (acked + lost) as f64).max(MIN_ACK_RATE)
+        };
+    }
+}
+
+impl Controller for Brutal {
+    fn on_ack(
+        &mut self,
+        now: Instant,
+        _sent: Instant,
+        _bytes: u64,
+        _app_limited: bool,
+        rtt: &RttEstimator,
+    ) {
+        self.rtt = Some(rtt.get());
+        self.record(now, 1, 0);
+    }
+
+    fn on_congestion_event(
+        &mut self,
+        now: Instant,
+        _sent: Instant,
+        _is_persistent_congestion: bool,
+        lost_bytes: u64,
+    ) {
+        self.record(now, 0, lost_bytes.div_ceil(u64::from(self.mtu)).max(1));
+    }
+
+    fn on_mtu_update(&mut self, new_mtu: u16) {
+        self.mtu = new_mtu;
+    }
+
+    fn window(&self) -> u64 {
+        match self.rtt {
+            Some(rtt) => {
+                let window =
+                    self.bandwidth as f64 * rtt.as_secs_f64() * WINDOW_GAIN / self.ack_rate;
+                (window as u64).max(2 * u64::from(self.mtu))
+            }
+            None => self.initial_window(),
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn Controller> {
+        Box::new(self.clone())
+    }
+
+    fn initial_window(&self) -> u64 {
+        10 * u64::from(self.mtu)
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
@@ -0,0 +1,44 @@
+use bytes::{BufMut, Bytes, BytesMut, buf::Limit};
+
+/// Minimum size of the chunks packets are received into
+const MIN_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A pool of receive buffers, handing out the received packets as [`Bytes`]
+///
+/// Packets are received into a large chunk and split off of it, so that one
+/// allocation is shared by many packets instead of allocating one buffer per
+/// packet. A chunk is reused once all the packets split off of it are dropped
+#[derive(Debug)]
+pub struct BufPool {
+    buf: BytesMut,
+    packet_size: usize,
+    chunk_size: usize,
+}
+
+impl BufPool {
+    /// Creates a pool for packets of at most `packet_size` bytes
+    pub fn new(packet_size: usize) -> Self {
+        let chunk_size = MIN_CHUNK_SIZE.max(packet_size);
+
+        Self {
+            buf: BytesMut::with_capacity(chunk_size),
+            packet_size,
+            chunk_size,
+        }
+    }
+
+    /// Returns a buffer with room for one packet, to receive into. Longer
+    /// packets are truncated
+    pub fn buf(&mut self) -> Limit<&mut BytesMut> {
+        if self.buf.capacity() < self.packet_size {
+            self.buf.reserve(self.chunk_size);
+        }
+
+        (&mut self.buf).limit(self.packet_size)
+    }
+
+    /// Takes the packet received into the buffer
+    pub fn take(&mut self) -> Bytes {
+        self.buf.split().freeze()
+    }
+}
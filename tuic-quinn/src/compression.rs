@@ -0,0 +1,332 @@
+//! Compression of `Connect` streams.
+//!
+//! Once a stream is compressed, the data in each direction starts with a byte
+//! telling whether the rest is a zstd stream or sent as is. The sender decides
+//! by sampling the first chunk written, so traffic that is already compressed
+//! or encrypted is not compressed again.
+
+use std::{
+    io::{Error as IoError, ErrorKind},
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use zstd::{
+    stream::raw::{Decoder, Encoder, InBuffer, Operation, OutBuffer},
+    zstd_safe::{CCtx, DCtx},
+};
+
+const FLAG_RAW: u8 = 0x00;
+const FLAG_ZSTD: u8 = 0x01;
+
+/// Only the start of the first chunk is sampled
+const SAMPLE_SIZE: usize = 4096;
+
+/// Bits of entropy per byte above which data is considered incompressible
+const MAX_ENTROPY: f64 = 7.0;
+
+/// Compression of the streams relayed by `Connect`.
+#[derive(Clone, Copy, Debug)]
+pub struct CompressionPolicy {
+    /// The zstd compression level, from 1 (fastest) to 22. 0 uses the
+    /// default level of zstd.
+    pub level: i32,
+}
+
+/// The state of both directions of a compressed stream.
+pub(crate) struct Codec {
+    compressor: Compressor,
+    decompressor: Decompressor,
+}
+
+impl Codec {
+    /// `level` is `None` if this side sends data as is.
+    pub(crate) fn new(level: Option<i32>) -> Self {
+        Self {
+            compressor: Compressor {
+                level,
+                state: WriteState::Pending,
+                out: Vec::new(),
+                out_pos: 0,
+            },
+            decompressor: Decompressor {
+                state: ReadState::Pending,
+                input: Vec::new(),
+                input_pos: 0,
+            },
+        }
+    }
+
+    pub(crate) fn poll_read(
+        &mut self,
+        recv: &mut (impl AsyncRead + Unpin),
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), IoError>> {
+        self.decompressor.poll_read(recv, cx, buf)
+    }
+
+    pub(crate) fn poll_write(
+        &mut self,
+        send: &mut (impl AsyncWrite + Unpin),
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, IoError>> {
+        self.compressor.poll_write(send, cx, buf)
+    }
+
+    pub(crate) fn poll_flush(
+        &mut self,
+        send: &mut (impl AsyncWrite + Unpin),
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), IoError>> {
+        self.compressor.poll_flush(send, cx)
+    }
+
+    pub(crate) fn poll_shutdown(
+        &mut self,
+        send: &mut (impl AsyncWrite + Unpin),
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), IoError>> {
+        self.compressor.poll_shutdown(send, cx)
+    }
+}
+
+struct Compressor {
+    level: Option<i32>,
+    state: WriteState,
+    /// Compressed data not written to the stream yet
+    out: Vec<u8>,
+    out_pos: usize,
+}
+
+enum WriteState {
+    /// Nothing written yet, the mode is decided on the first write
+    Pending,
+    Raw,
+    Zstd(Encoder<'static>),
+    Finished,
+}
+
+impl Compressor {
+    fn poll_write(
+        &mut self,
+        send: &mut (impl AsyncWrite + Unpin),
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, IoError>> {
+        ready!(drain(&mut self.out, &mut self.out_pos, send, cx))?;
+
+        if let WriteState::Pending = self.state {
+            match self.level {
+                Some(level) if is_compressible(buf) => {
+                    self.state = WriteState::Zstd(Encoder::new(level)?);
+                    self.out.push(FLAG_ZSTD);
+                }
+                _ => {
+                    self.state = WriteState::Raw;
+                    self.out.push(FLAG_RAW);
+                }
+            }
+
+            ready!(drain(&mut self.out, &mut self.out_pos, send, cx))?;
+        }
+
+        match &mut self.state {
+            WriteState::Raw => Pin::new(send).poll_write(cx, buf),
+            WriteState::Zstd(encoder) => {
+                let len = buf.len().min(CCtx::in_size());
+                let mut input = InBuffer::around(&buf[..len]);
+
+                self.out.reserve(CCtx::out_size());
+                let mut output = OutBuffer::around(&mut self.out);
+                encoder.run(&mut input, &mut output)?;
+
+                Poll::Ready(Ok(input.pos()))
+            }
+            WriteState::Pending => unreachable!(),
+            WriteState::Finished => Poll::Ready(Err(IoError::from(ErrorKind::BrokenPipe))),
+        }
+    }
+
+    fn poll_flush(
+        &mut self,
+        send: &mut (impl AsyncWrite + Unpin),
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), IoError>> {
+        ready!(drain(&mut self.out, &mut self.out_pos, send, cx))?;
+
+        if let WriteState::Zstd(encoder) = &mut self.state {
+            loop {
+                self.out.reserve(CCtx::out_size());
+                let remaining = encoder.flush(&mut OutBuffer::around(&mut self.out))?;
+                ready!(drain(&mut self.out, &mut self.out_pos, send, cx))?;
+
+                if remaining == 0 {
+                    break;
+                }
+            }
+        }
+
+        Pin::new(send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        &mut self,
+        send: &mut (impl AsyncWrite + Unpin),
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), IoError>> {
+        ready!(drain(&mut self.out, &mut self.out_pos, send, cx))?;
+
+        if let WriteState::Zstd(encoder) = &mut self.state {
+            loop {
+                self.out.reserve(CCtx::out_size());
+                let remaining = encoder.finish(&mut OutBuffer::around(&mut self.out), true)?;
+                ready!(drain(&mut self.out, &mut self.out_pos, send, cx))?;
+
+                if remaining == 0 {
+                    break;
+                }
+            }
+
+            self.state = WriteState::Finished;
+        }
+
+        Pin::new(send).poll_shutdown(cx)
+    }
+}
+
+/// Writes the buffered data to the stream
+fn drain(
+    out: &mut Vec<u8>,
+    out_pos: &mut usize,
+    send: &mut (impl AsyncWrite + Unpin),
+    cx: &mut Context<'_>,
+) -> Poll<Result<(), IoError>> {
+    while *out_pos < out.len() {
+        let n = ready!(Pin::new(&mut *send).poll_write(cx, &out[*out_pos..]))?;
+
+        if n == 0 {
+            return Poll::Ready(Err(IoError::from(ErrorKind::WriteZero)));
+        }
+
+        *out_pos += n;
+    }
+
+    out.clear();
+    *out_pos = 0;
+    Poll::Ready(Ok(()))
+}
+
+struct Decompressor {
+    state: ReadState,
+    /// Compressed data read from the stream but not decompressed yet
+    input: Vec<u8>,
+    input_pos: usize,
+}
+
+enum ReadState {
+    /// Waiting for the mode of the stream
+    Pending,
+    Raw,
+    Zstd(Decoder<'static>),
+}
+
+impl Decompressor {
+    fn poll_read(
+        &mut self,
+        recv: &mut (impl AsyncRead + Unpin),
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), IoError>> {
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        if let ReadState::Pending = self.state {
+            let mut flag = [0; 1];
+            let mut flag_buf = ReadBuf::new(&mut flag);
+            ready!(Pin::new(&mut *recv).poll_read(cx, &mut flag_buf))?;
+
+            self.state = match flag_buf.filled() {
+                // the peer sent nothing at all
+                [] => return Poll::Ready(Ok(())),
+                [FLAG_RAW] => ReadState::Raw,
+                [FLAG_ZSTD] => ReadState::Zstd(Decoder::new()?),
+                [flag] => {
+                    return Poll::Ready(Err(IoError::new(
+                        ErrorKind::InvalidData,
+                        format!("unknown compression flag {flag:#04x}"),
+                    )));
+                }
+                _ => unreachable!(),
+            };
+        }
+
+        let decoder = match &mut self.state {
+            ReadState::Raw => return Pin::new(recv).poll_read(cx, buf),
+            ReadState::Zstd(decoder) => decoder,
+            ReadState::Pending => unreachable!(),
+        };
+
+        loop {
+            // run even without new input, as the decoder may hold output that
+            // did not fit last time
+            let mut input = InBuffer::around(&self.input[self.input_pos..]);
+            let mut output = OutBuffer::around(buf.initialize_unfilled());
+            decoder.run(&mut input, &mut output)?;
+
+            self.input_pos += input.pos();
+            let n = output.pos();
+            buf.advance(n);
+
+            if n > 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            if self.input_pos < self.input.len() {
+                continue;
+            }
+
+            self.input.resize(DCtx::in_size(), 0);
+            let mut input_buf = ReadBuf::new(&mut self.input);
+            let res = Pin::new(&mut *recv).poll_read(cx, &mut input_buf);
+            let len = input_buf.filled().len();
+            self.input.truncate(len);
+            self.input_pos = 0;
+
+            ready!(res)?;
+
+            if len == 0 {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+/// Whether the data is worth compressing, judging from a sample of it
+fn is_compressible(buf: &[u8]) -> bool {
+    // a TLS record, which is encrypted
+    if let [0x14..=0x17, 0x03, ..] = buf {
+        return false;
+    }
+
+    let sample = &buf[..buf.len().min(SAMPLE_SIZE)];
+    let mut counts = [0usize; 256];
+    for byte in sample {
+        counts[*byte as usize] += 1;
+    }
+
+    let len = sample.len() as f64;
+    let entropy: f64 = counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+
+    entropy <= MAX_ENTROPY
+}
@@ -0,0 +1,70 @@
+use std::{
+    io::{Error as IoError, IoSliceMut},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use quinn::{
+    AsyncUdpSocket, UdpPoller,
+    udp::{RecvMeta, Transmit},
+};
+
+/// An endpoint socket sending packets with the traffic class set on it, e.g.
+/// a DSCP value
+///
+/// quinn attaches the ECN codepoint of every packet as an `IP_TOS` control
+/// message, which replaces the whole traffic class set on the socket. Packets
+/// are sent without an ECN codepoint instead, giving up on ECN congestion
+/// signals
+#[derive(Debug)]
+pub struct DscpSocket(Arc<dyn AsyncUdpSocket>);
+
+impl DscpSocket {
+    /// Wraps `socket`, whose traffic class is already set
+    pub fn new(socket: Arc<dyn AsyncUdpSocket>) -> Self {
+        Self(socket)
+    }
+}
+
+impl AsyncUdpSocket for DscpSocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        self.0.clone().create_io_poller()
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> Result<(), IoError> {
+        self.0.try_send(&Transmit {
+            destination: transmit.destination,
+            ecn: None,
+            contents: transmit.contents,
+            segment_size: transmit.segment_size,
+            src_ip: transmit.src_ip,
+        })
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<Result<usize, IoError>> {
+        self.0.poll_recv(cx, bufs, meta)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, IoError> {
+        self.0.local_addr()
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+        self.0.max_transmit_segments()
+    }
+
+    fn max_receive_segments(&self) -> usize {
+        self.0.max_receive_segments()
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.0.may_fragment()
+    }
+}
@@ -0,0 +1,97 @@
+//! Callbacks on the relay tasks of a connection.
+
+use std::{
+    error::Error as StdError,
+    fmt::{Debug, Formatter, Result as FmtResult},
+    sync::Arc,
+};
+
+/// Callbacks on the relay tasks of a [`Connection`](crate::Connection), for
+/// accounting and metrics without parsing the commands again
+///
+/// Called synchronously from the I/O of the tasks, so implementations should
+/// return quickly. All methods do nothing by default
+pub trait Hooks: Send + Sync {
+    /// A `Connect` was opened or accepted
+    fn task_started(&self, _kind: TaskKind) {}
+
+    /// `len` bytes of the payload of a task were relayed
+    fn bytes_relayed(&self, _kind: TaskKind, _dir: Direction, _len: usize) {}
+
+    /// A `Connect` was dropped, with the bytes relayed through it
+    fn task_finished(&self, _kind: TaskKind, _stats: TaskStats) {}
+
+    /// Accepting a command or the payload of a task failed
+    fn error(&self, _err: &dyn StdError) {}
+}
+
+/// Type of the relay tasks reported to [`Hooks`]
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskKind {
+    Connect,
+    Packet,
+}
+
+/// Direction of the relayed bytes, as seen from the local side
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// The bytes relayed by a task
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TaskStats {
+    pub sent: u64,
+    pub received: u64,
+}
+
+impl TaskStats {
+    pub(crate) fn add(&mut self, dir: Direction, len: usize) {
+        match dir {
+            Direction::Sent => self.sent += len as u64,
+            Direction::Received => self.received += len as u64,
+        }
+    }
+}
+
+/// The hooks set on a connection, if any
+#[derive(Clone, Default)]
+pub(crate) struct HookSet(Option<Arc<dyn Hooks>>);
+
+impl HookSet {
+    pub(crate) fn new(hooks: Arc<dyn Hooks>) -> Self {
+        Self(Some(hooks))
+    }
+
+    pub(crate) fn task_started(&self, kind: TaskKind) {
+        if let Some(hooks) = &self.0 {
+            hooks.task_started(kind);
+        }
+    }
+
+    pub(crate) fn bytes_relayed(&self, kind: TaskKind, dir: Direction, len: usize) {
+        if let Some(hooks) = &self.0 {
+            hooks.bytes_relayed(kind, dir, len);
+        }
+    }
+
+    pub(crate) fn task_finished(&self, kind: TaskKind, stats: TaskStats) {
+        if let Some(hooks) = &self.0 {
+            hooks.task_finished(kind, stats);
+        }
+    }
+
+    pub(crate) fn error(&self, err: &dyn StdError) {
+        if let Some(hooks) = &self.0 {
+            hooks.error(err);
+        }
+    }
+}
+
+impl Debug for HookSet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_tuple("HookSet").field(&self.0.is_some()).finish()
+    }
+}
@@ -2,23 +2,32 @@
 
 use std::{
     fmt::{Debug, Formatter, Result as FmtResult},
-    io::{Cursor, Error as IoError},
+    future::{self, poll_fn},
+    io::{Error as IoError, ErrorKind},
+    ops::RangeInclusive,
     pin::Pin,
-    task::{Context, Poll},
-    time::Duration,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, ready},
+    time::{Duration, Instant},
 };
 
 use bytes::{BufMut, Bytes, BytesMut};
 pub use quinn;
 use quinn::{
-    ClosedStream, Connection as QuinnConnection, ConnectionError, RecvStream, SendDatagramError,
-    SendStream, VarInt,
+    ClosedStream, Connection as QuinnConnection, ConnectionError, SendDatagramError, VarInt,
+    WriteError,
 };
+use rand::Rng;
 use thiserror::Error;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
-use tracing::warn;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    time,
+};
+use tracing::{debug, warn};
+pub use tuic::model::{MemoryBudget, ReassemblyLimits, ReassemblyStats};
 use tuic::{
-    Address, Header, UnmarshalError,
+    Address, Batch, Bond, Capabilities, Compress, ConnectStatus, Header, NoticeKind,
+    Packet as PacketHeader, Padding, Speedtest, Status, UnmarshalError, VERSION,
     model::{
         AssembleError, Authenticate as AuthenticateModel, Connect as ConnectModel,
         Connection as ConnectionModel, KeyingMaterialExporter as KeyingMaterialExporterImpl,
@@ -28,7 +37,29 @@ use tuic::{
 };
 use uuid::Uuid;
 
-use self::side::Side;
+pub use self::{
+    brutal::{Brutal, BrutalConfig},
+    buf_pool::BufPool,
+    compression::CompressionPolicy,
+    dscp::DscpSocket,
+    hooks::{Direction, Hooks, TaskKind, TaskStats},
+    packet_queue::{OverflowPolicy, PacketMode, PacketQueue, PacketQueueConfig},
+    transport::Transport,
+};
+use self::{
+    compression::Codec,
+    hooks::HookSet,
+    side::Side,
+    transport::{RecvStream as _, SendStream as _},
+};
+
+mod brutal;
+mod buf_pool;
+mod compression;
+mod dscp;
+mod hooks;
+mod packet_queue;
+pub mod transport;
 
 pub mod side {
     //! Side marker types for a connection.
@@ -46,55 +77,287 @@ pub mod side {
 
 /// The TUIC Connection.
 ///
-/// This struct takes a clone of `quinn::Connection` for performing TUIC
-/// operations.
+/// This struct takes a clone of the QUIC connection for performing TUIC
+/// operations, a `quinn::Connection` unless another [`Transport`] is used.
 ///
 /// See more details about the TUIC protocol at [SPEC.md](https://github.com/EAimTY/tuic/blob/dev/tuic/SPEC.md)
 #[derive(Clone)]
-pub struct Connection<Side> {
-    conn: QuinnConnection,
+pub struct Connection<Side, T: Transport = QuinnConnection> {
+    conn: T,
     model: ConnectionModel<Bytes>,
+    padding: Arc<Mutex<Option<PaddingPolicy>>>,
+    compression: Arc<Mutex<Option<CompressionPolicy>>>,
+    /// The origin of `Heartbeat` timestamps
+    epoch: Instant,
+    heartbeat_rtt: Arc<Mutex<Option<Duration>>>,
+    timeouts: Arc<Mutex<Timeouts>>,
+    hooks: Arc<Mutex<HookSet>>,
+    extensions: Arc<Mutex<Option<RangeInclusive<u8>>>>,
+    exporter_labels: Arc<Mutex<ExporterLabels>>,
     _marker: Side,
 }
 
-impl<Side> Connection<Side> {
+/// Padding added in front of small `Connect` and `Packet` commands, to hide
+/// their length. Only applied once the `PADDING` extension is negotiated.
+#[derive(Clone, Copy, Debug)]
+pub struct PaddingPolicy {
+    /// Commands shorter than this are padded. For `native` mode packets, the
+    /// payload carried in the datagram counts too.
+    pub threshold: usize,
+    /// The padding is of a random length up to this.
+    pub max_len: u16,
+}
+
+/// Timeouts of the operations of a `Connection`. `None` disables a timeout,
+/// which is the default for all of them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timeouts {
+    /// How long after the creation of the connection
+    /// `Connection::authentication_timeout()` completes.
+    pub authenticate: Option<Duration>,
+    /// How long the `accept_*_stream()` methods wait for the commands on the
+    /// stream. A stream timing out is returned in an `Unmarshal*` error of
+    /// kind `TimedOut`.
+    pub command_read: Option<Duration>,
+    /// How long the fragments of a `native` mode packet are kept for
+    /// reassembly. Overrides `ReassemblyLimits::timeout` if set.
+    pub reassembly: Option<Duration>,
+}
+
+/// Domain separation of the TLS keying material authentication tokens are
+/// exported from. The protocol exports them with the UUID as the label and the
+/// password as the context, which the empty defaults keep.
+///
+/// Both sides must use the same values. Private deployments can set their own
+/// so tokens are only valid within the deployment, and change them to rotate
+/// every token at once.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExporterLabels {
+    /// Put in front of the UUID in the exporter label.
+    pub label: Vec<u8>,
+    /// Put in front of the password in the exporter context.
+    pub context: Vec<u8>,
+}
+
+/// The error code the streams passed to an `accept_*_stream()` method are
+/// reset with if the future is dropped before returning them.
+pub const ACCEPT_CANCELLED_ERROR_CODE: VarInt = VarInt::from_u32(6005);
+
+impl<Side, T: Transport> Connection<Side, T> {
     /// Sends a `Packet` using UDP relay mode `native`.
+    ///
+    /// The packet is fragmented to the maximum datagram size at the time of
+    /// sending, which follows the path MTU discovered by quinn. If the path MTU
+    /// shrinks while the fragments are being sent, the packet is fragmented
+    /// again to the new size and resent under a new packet ID.
     pub fn packet_native(
         &self,
         pkt: impl AsRef<[u8]>,
         addr: Address,
         assoc_id: u16,
+    ) -> eyre::Result<()> {
+        let pkt = pkt.as_ref();
+
+        loop {
+            let Some(max_pkt_size) = self.conn.max_datagram_size() else {
+                return Err(Error::<T>::SendDatagram(SendDatagramError::Disabled))?;
+            };
+
+            let model = self.model.send_packet(assoc_id, addr.clone(), max_pkt_size);
+
+            match self.send_fragments(model, pkt, max_pkt_size) {
+                Err(SendDatagramError::TooLarge)
+                    if self
+                        .conn
+                        .max_datagram_size()
+                        .is_some_and(|size| size < max_pkt_size) =>
+                {
+                    debug!(
+                        "[{assoc_id:#06x}] path MTU shrunk while sending packet, re-fragmenting"
+                    );
+                }
+                res => return Ok(res.map_err(Error::<T>::SendDatagram)?),
+            }
+        }
+    }
+
+    /// Sends a UDP packet using UDP relay mode `native` under `pkt_id`, e.g.
+    /// the same packet ID on all the connections of a bond. Unlike
+    /// `packet_native()`, the packet is not resent if the path MTU shrinks
+    /// meanwhile.
+    pub fn packet_native_as(
+        &self,
+        pkt: impl AsRef<[u8]>,
+        addr: Address,
+        assoc_id: u16,
+        pkt_id: u16,
     ) -> eyre::Result<()> {
         let Some(max_pkt_size) = self.conn.max_datagram_size() else {
-            return Err(Error::SendDatagram(SendDatagramError::Disabled))?;
+            return Err(Error::<T>::SendDatagram(SendDatagramError::Disabled))?;
         };
 
-        let model = self.model.send_packet(assoc_id, addr, max_pkt_size);
+        let model = self
+            .model
+            .send_packet_as(assoc_id, pkt_id, addr, max_pkt_size);
+        self.send_fragments(model, pkt.as_ref(), max_pkt_size)
+            .map_err(Error::<T>::SendDatagram)?;
+        Ok(())
+    }
 
+    fn send_fragments(
+        &self,
+        model: PacketModel<Tx, Bytes>,
+        pkt: &[u8],
+        max_pkt_size: usize,
+    ) -> Result<(), SendDatagramError> {
         for (header, frag) in model.into_fragments(pkt) {
-            let mut buf = BytesMut::with_capacity(header.len() + frag.len());
+            let header = Header::Packet(header);
+            let len = header.len() + frag.len();
+            let padding = self.padding(len, max_pkt_size.saturating_sub(len));
+            let mut buf = BytesMut::with_capacity(padding.as_ref().map_or(0, Header::len) + len);
+            if let Some(padding) = padding {
+                padding.write(&mut buf);
+            }
             header.write(&mut buf);
             buf.put_slice(frag);
             self.conn.send_datagram(Bytes::from(buf))?;
+            self.hooks()
+                .bytes_relayed(TaskKind::Packet, Direction::Sent, frag.len());
         }
 
         Ok(())
     }
 
-    /// Sends a `Packet` using UDP relay mode `quic`.
-    pub async fn packet_quic(
+    /// Sends UDP packets with the same address using UDP relay mode `native`.
+    ///
+    /// Once the `BATCH` extension is negotiated, the packets are packed into
+    /// as few datagrams as possible with `Batch` commands. Otherwise, and for
+    /// packets too large to share a datagram, they are sent one by one as with
+    /// `packet_native()`.
+    pub fn packet_native_batch(
         &self,
-        pkt: impl AsRef<[u8]>,
+        pkts: &[impl AsRef<[u8]>],
         addr: Address,
         assoc_id: u16,
     ) -> eyre::Result<()> {
+        let batching = pkts.len() > 1
+            && self
+                .negotiated()
+                .is_some_and(|(_, caps)| caps.contains(Capabilities::BATCH));
+
+        if !batching {
+            for pkt in pkts {
+                self.packet_native(pkt, addr.clone(), assoc_id)?;
+            }
+            return Ok(());
+        }
+
+        let Some(max_pkt_size) = self.conn.max_datagram_size() else {
+            return Err(Error::<T>::SendDatagram(SendDatagramError::Disabled))?;
+        };
+
+        let header_len = Header::Batch(Batch::new(assoc_id, 0, addr.clone())).len();
+        let mut batch = Vec::new();
+        let mut len = header_len;
+
+        for pkt in pkts {
+            let pkt = pkt.as_ref();
+            let entry_len = Batch::ENTRY_HEADER_LEN + pkt.len();
+
+            if !batch.is_empty()
+                && (len + entry_len > max_pkt_size || batch.len() == u8::MAX as usize)
+            {
+                self.send_batch(&batch, &addr, assoc_id, max_pkt_size)?;
+                batch.clear();
+                len = header_len;
+            }
+
+            if header_len + entry_len > max_pkt_size {
+                self.packet_native(pkt, addr.clone(), assoc_id)?;
+                continue;
+            }
+
+            batch.push(pkt);
+            len += entry_len;
+        }
+
+        if !batch.is_empty() {
+            self.send_batch(&batch, &addr, assoc_id, max_pkt_size)?;
+        }
+
+        Ok(())
+    }
+
+    fn send_batch(
+        &self,
+        pkts: &[&[u8]],
+        addr: &Address,
+        assoc_id: u16,
+        max_pkt_size: usize,
+    ) -> eyre::Result<()> {
+        if let [pkt] = pkts {
+            return self.packet_native(pkt, addr.clone(), assoc_id);
+        }
+
+        let header = Header::Batch(Batch::new(assoc_id, pkts.len() as u8, addr.clone()));
+        let len = header.len()
+            + pkts
+                .iter()
+                .map(|pkt| Batch::ENTRY_HEADER_LEN + pkt.len())
+                .sum::<usize>();
+        let padding = self.padding(len, max_pkt_size.saturating_sub(len));
+
+        let mut buf = BytesMut::with_capacity(padding.as_ref().map_or(0, Header::len) + len);
+        if let Some(padding) = padding {
+            padding.write(&mut buf);
+        }
+        header.write(&mut buf);
+        for pkt in pkts {
+            let model = self.model.send_packet(assoc_id, addr.clone(), max_pkt_size);
+            buf.put_u16(model.pkt_id());
+            buf.put_u16(pkt.len() as u16);
+            buf.put_slice(pkt);
+        }
+
+        match self.conn.send_datagram(buf.freeze()) {
+            // the path MTU shrunk, fall back to fragmenting the packets
+            Err(SendDatagramError::TooLarge) => {
+                debug!("[{assoc_id:#06x}] path MTU shrunk while sending batch, sending one by one");
+                for pkt in pkts {
+                    self.packet_native(pkt, addr.clone(), assoc_id)?;
+                }
+                Ok(())
+            }
+            res => {
+                res.map_err(Error::<T>::SendDatagram)?;
+                for pkt in pkts {
+                    self.hooks()
+                        .bytes_relayed(TaskKind::Packet, Direction::Sent, pkt.len());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Sends a `Packet` using UDP relay mode `quic`.
+    ///
+    /// The header of each fragment is serialized on the stack and written along
+    /// with a slice of `pkt`, so the payload is never copied.
+    pub async fn packet_quic(&self, pkt: Bytes, addr: Address, assoc_id: u16) -> eyre::Result<()> {
         let model = self.model.send_packet(assoc_id, addr, u16::MAX as usize);
 
-        for (header, frag) in model.into_fragments(pkt) {
+        for (header, frag) in model.into_fragments(&pkt) {
+            let header = header.to_header_buf();
             let mut send = self.conn.open_uni().await?;
-            header.async_marshal(&mut send).await?;
-            send.write_all(frag).await?;
+            if let Some(padding) = self.padding(header.as_slice().len() + frag.len(), usize::MAX) {
+                padding.async_marshal(&mut send).await?;
+            }
+
+            AsyncWriteExt::write_all(&mut send, header.as_slice()).await?;
+            send.write_all_chunks(&mut [pkt.slice_ref(frag)]).await?;
             send.finish()?;
+            self.hooks()
+                .bytes_relayed(TaskKind::Packet, Direction::Sent, frag.len());
         }
         Ok(())
     }
@@ -115,17 +378,153 @@ impl<Side> Connection<Side> {
         self.model.collect_garbage(timeout);
     }
 
-    fn keying_material_exporter(&self) -> KeyingMaterialExporter {
-        KeyingMaterialExporter(self.conn.clone())
+    /// Sets the limits on the packet fragments buffered for reassembly
+    pub fn set_reassembly_limits(&self, limits: ReassemblyLimits) {
+        self.model.set_reassembly_limits(limits);
+    }
+
+    /// Sets the timeouts of the operations on the connection.
+    pub fn set_timeouts(&self, timeouts: Timeouts) {
+        if let Some(timeout) = timeouts.reassembly {
+            self.model.set_reassembly_timeout(timeout);
+        }
+        *self.timeouts.lock().unwrap() = timeouts;
+    }
+
+    /// Sets the hooks called on the relay tasks of the connection.
+    pub fn set_hooks(&self, hooks: Arc<dyn Hooks>) {
+        *self.hooks.lock().unwrap() = HookSet::new(hooks);
+    }
+
+    fn hooks(&self) -> HookSet {
+        self.hooks.lock().unwrap().clone()
+    }
+
+    /// Passes the bidirectional streams opened with an extension command in
+    /// `codes` through as `Task::Extension`, instead of failing them as
+    /// invalid. Codes outside `Header::EXTENSION_TYPE_CODES` are never passed
+    /// through.
+    pub fn set_extension_commands(&self, codes: RangeInclusive<u8>) {
+        *self.extensions.lock().unwrap() = Some(codes);
+    }
+
+    fn is_extension(&self, cmd: u8) -> bool {
+        Header::EXTENSION_TYPE_CODES.contains(&cmd)
+            && self
+                .extensions
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_some_and(|codes| codes.contains(&cmd))
+    }
+
+    /// Opens a bidirectional stream with the extension command `code`,
+    /// leaving the rest of the stream to the caller. Either side can open
+    /// one, which the peer only accepts if it passes `code` through.
+    ///
+    /// Fails if `code` is not in `Header::EXTENSION_TYPE_CODES`.
+    pub async fn open_extension(
+        &self,
+        code: u8,
+    ) -> Result<(T::SendStream, T::RecvStream), Error<T>> {
+        if !Header::EXTENSION_TYPE_CODES.contains(&code) {
+            return Err(Error::InvalidExtension(code));
+        }
+
+        let (mut send, recv) = self.conn.open_bi().await?;
+        AsyncWriteExt::write_all(&mut send, &[VERSION, code]).await?;
+        Ok((send, recv))
+    }
+
+    /// Reports the error of accepting a command to the hooks.
+    fn report_error(&self, res: &Result<Task<T>, Error<T>>) {
+        if let Err(err) = res {
+            self.hooks().error(err);
+        }
+    }
+
+    /// Reads a command from a stream, within the `command_read` timeout.
+    async fn read_header(&self, recv: &mut T::RecvStream) -> Result<Header, UnmarshalError> {
+        let timeout = self.timeouts.lock().unwrap().command_read;
+        match timeout {
+            Some(timeout) => time::timeout(timeout, Header::async_unmarshal(recv))
+                .await
+                .unwrap_or_else(|_| Err(UnmarshalError::Io(IoError::from(ErrorKind::TimedOut)))),
+            None => Header::async_unmarshal(recv).await,
+        }
+    }
+
+    /// Sets the padding of small commands. Not padding by default.
+    pub fn set_padding_policy(&self, policy: PaddingPolicy) {
+        *self.padding.lock().unwrap() = Some(policy);
+    }
+
+    /// Sets the compression of `Connect` streams. Not compressing by default.
+    ///
+    /// The client compresses the streams it opens once the `COMPRESSION`
+    /// extension is negotiated. Either side only compresses the data it sends
+    /// if the policy is set.
+    pub fn set_compression_policy(&self, policy: CompressionPolicy) {
+        *self.compression.lock().unwrap() = Some(policy);
+    }
+
+    fn compression_level(&self) -> Option<i32> {
+        self.compression.lock().unwrap().map(|policy| policy.level)
+    }
+
+    /// Returns a `Padding` command to put in front of a command of `len`
+    /// bytes, taking at most `room` bytes, if padding applies to it.
+    fn padding(&self, len: usize, room: usize) -> Option<Header> {
+        let policy = (*self.padding.lock().unwrap())?;
+
+        if len >= policy.threshold
+            || !self
+                .negotiated()
+                .is_some_and(|(_, caps)| caps.contains(Capabilities::PADDING))
+        {
+            return None;
+        }
+
+        // `VER`, `TYPE` and `PAD_LEN` of the `Padding` command itself
+        let room = room.checked_sub(4)?.min(u16::MAX as usize) as u16;
+        let pad_len = rand::thread_rng().gen_range(0..=policy.max_len).min(room);
+        Some(Header::Padding(Padding::new(pad_len)))
+    }
+
+    /// Returns the negotiated protocol version and extensions, or `None` if
+    /// the negotiation is not done, e.g. because the peer does not support it.
+    pub fn negotiated(&self) -> Option<(u8, Capabilities)> {
+        self.model.negotiated()
+    }
+
+    /// Sets the label and context authentication tokens are exported with.
+    /// The ones of the protocol by default.
+    pub fn set_exporter_labels(&self, labels: ExporterLabels) {
+        *self.exporter_labels.lock().unwrap() = labels;
+    }
+
+    fn keying_material_exporter(&self) -> KeyingMaterialExporter<T> {
+        KeyingMaterialExporter {
+            conn: self.conn.clone(),
+            labels: self.exporter_labels.lock().unwrap().clone(),
+        }
     }
 }
 
-impl Connection<side::Client> {
+impl<T: Transport> Connection<side::Client, T> {
     /// Creates a new client side `Connection`.
-    pub fn new(conn: QuinnConnection) -> Self {
+    pub fn new(conn: T) -> Self {
         Self {
             conn,
             model: ConnectionModel::new(),
+            padding: Arc::new(Mutex::new(None)),
+            compression: Arc::new(Mutex::new(None)),
+            epoch: Instant::now(),
+            heartbeat_rtt: Arc::new(Mutex::new(None)),
+            timeouts: Arc::new(Mutex::new(Timeouts::default())),
+            hooks: Arc::new(Mutex::new(HookSet::default())),
+            extensions: Arc::new(Mutex::new(None)),
+            exporter_labels: Arc::new(Mutex::new(ExporterLabels::default())),
             _marker: side::Client,
         }
     }
@@ -143,11 +542,101 @@ impl Connection<side::Client> {
     }
 
     /// Sends a `Connect` command.
-    pub async fn connect(&self, addr: Address) -> Result<Connect, Error> {
+    pub async fn connect(&self, addr: Address) -> Result<Connect<T>, Error<T>> {
+        self.send_connect(addr, false).await
+    }
+
+    /// Sends a `Connect` command, asking the server to report the outcome of
+    /// connecting to the target. The outcome is then available from
+    /// `Connect::status()`.
+    ///
+    /// Same as `Connection::connect()` if the `CONNECT_STATUS` extension is
+    /// not negotiated.
+    pub async fn connect_with_status(&self, addr: Address) -> Result<Connect<T>, Error<T>> {
+        self.send_connect(addr, true).await
+    }
+
+    async fn send_connect(&self, addr: Address, status: bool) -> Result<Connect<T>, Error<T>> {
         let model = self.model.send_connect(addr);
         let (mut send, recv) = self.conn.open_bi().await?;
+        if let Some(padding) = self.padding(model.header().len(), usize::MAX) {
+            padding.async_marshal(&mut send).await?;
+        }
+
+        let status = status
+            && self
+                .negotiated()
+                .is_some_and(|(_, caps)| caps.contains(Capabilities::CONNECT_STATUS));
+
+        if status {
+            Header::Status(Status::new(ConnectStatus::Succeeded))
+                .async_marshal(&mut send)
+                .await?;
+        }
+
+        let codec = match self.compression_level() {
+            Some(level)
+                if self
+                    .negotiated()
+                    .is_some_and(|(_, caps)| caps.contains(Capabilities::COMPRESSION)) =>
+            {
+                Header::Compress(Compress::new())
+                    .async_marshal(&mut send)
+                    .await?;
+                Some(Codec::new(Some(level)))
+            }
+            _ => None,
+        };
+
         model.header().async_marshal(&mut send).await?;
-        Ok(Connect::new(Side::Client(model), send, recv))
+        let mut conn = Connect::new(Side::Client(model), send, recv, codec, self.hooks());
+        if status {
+            conn.status = StatusState::Pending([0; 3], 0);
+        }
+        Ok(conn)
+    }
+
+    /// Sends a `Speedtest` command, asking the server to send back `download`
+    /// bytes once the returned stream is finished. The bytes written to the
+    /// stream before finishing it are the upload.
+    ///
+    /// Fails if the `SPEEDTEST` extension is not negotiated.
+    pub async fn speedtest(
+        &self,
+        download: u64,
+    ) -> Result<(T::SendStream, T::RecvStream), Error<T>> {
+        if !self
+            .negotiated()
+            .is_some_and(|(_, caps)| caps.contains(Capabilities::SPEEDTEST))
+        {
+            return Err(Error::NotNegotiated("SPEEDTEST"));
+        }
+
+        let (mut send, recv) = self.conn.open_bi().await?;
+        Header::Speedtest(Speedtest::new(download))
+            .async_marshal(&mut send)
+            .await?;
+        Ok((send, recv))
+    }
+
+    /// Sends a `Bond` command, joining the connection to the bond `bond_id`,
+    /// whose connections share their UDP sessions on the server.
+    ///
+    /// Fails if the `MULTIPATH` extension is not negotiated.
+    pub async fn bond(&self, bond_id: u64) -> eyre::Result<()> {
+        if !self
+            .negotiated()
+            .is_some_and(|(_, caps)| caps.contains(Capabilities::MULTIPATH))
+        {
+            Err(Error::<T>::NotNegotiated("MULTIPATH"))?;
+        }
+
+        let mut send = self.conn.open_uni().await?;
+        Header::Bond(Bond::new(bond_id))
+            .async_marshal(&mut send)
+            .await?;
+        send.finish()?;
+        Ok(())
     }
 
     /// Sends a `Dissociate` command.
@@ -159,21 +648,56 @@ impl Connection<side::Client> {
         Ok(())
     }
 
-    /// Sends a `Heartbeat` command.
-    pub async fn heartbeat(&self) -> Result<(), Error> {
-        let model = self.model.send_heartbeat();
+    /// Sends a `Heartbeat` command, asking the server to echo a timestamp
+    /// back. Servers not supporting it ignore the timestamp.
+    pub async fn heartbeat(&self) -> Result<(), Error<T>> {
+        let timestamp = self.epoch.elapsed().as_micros() as u64;
+        let model = self.model.send_heartbeat(Some(timestamp));
         let mut buf = Vec::with_capacity(model.header().len());
         model.header().async_marshal(&mut buf).await.unwrap();
         self.conn.send_datagram(Bytes::from(buf))?;
         Ok(())
     }
 
+    /// Returns the round-trip time last measured by a `Heartbeat` echoed by
+    /// the server, or `None` if the server never echoed one.
+    ///
+    /// Unlike the RTT estimated by QUIC, it includes the time taken by both
+    /// sides to process the `Heartbeat`.
+    pub fn heartbeat_rtt(&self) -> Option<Duration> {
+        *self.heartbeat_rtt.lock().unwrap()
+    }
+
+    /// Replies to the `Negotiate` command offered by the server with the
+    /// version and extensions to be used, which must not exceed the offer.
+    pub async fn negotiate(&self, version: u8, capabilities: Capabilities) -> eyre::Result<()> {
+        let model = self.model.send_negotiate(version, capabilities);
+        let mut send = self.conn.open_uni().await?;
+        model.header().async_marshal(&mut send).await?;
+        send.finish()?;
+        self.model.set_negotiated(version, capabilities);
+        Ok(())
+    }
+
     /// Try to parse a `quinn::RecvStream` as a TUIC command.
     ///
     /// The `quinn::RecvStream` should be accepted by
     /// `quinn::Connection::accept_uni()` from the same `quinn::Connection`.
-    pub async fn accept_uni_stream(&self, mut recv: RecvStream) -> Result<Task, Error> {
-        let header = match Header::async_unmarshal(&mut recv).await {
+    ///
+    /// Cancellation safe: dropping the future before it completes stops the
+    /// stream with `ACCEPT_CANCELLED_ERROR_CODE`.
+    pub async fn accept_uni_stream(&self, recv: T::RecvStream) -> Result<Task<T>, Error<T>> {
+        let res = self.recv_uni_stream(recv).await;
+        self.report_error(&res);
+        res
+    }
+
+    async fn recv_uni_stream(&self, recv: T::RecvStream) -> Result<Task<T>, Error<T>> {
+        let mut recv = StreamGuard::new(recv, stop_cancelled);
+        let header = self.read_header(recv.get()).await;
+        let recv = recv.into_inner();
+
+        let header = match header {
             Ok(header) => header,
             Err(err) => return Err(Error::UnmarshalUniStream(err, recv)),
         };
@@ -184,14 +708,32 @@ impl Connection<side::Client> {
             Header::Packet(pkt) => {
                 let assoc_id = pkt.assoc_id();
                 let pkt_id = pkt.pkt_id();
-                self.model
-                    .recv_packet(pkt)
-                    .map_or(Err(Error::InvalidUdpSession(assoc_id, pkt_id)), |pkt| {
-                        Ok(Task::Packet(Packet::new(pkt, PacketSource::Quic(recv))))
-                    })
+                self.model.recv_packet(pkt).map_or(
+                    Err(Error::InvalidUdpSession(assoc_id, pkt_id)),
+                    |pkt| {
+                        Ok(Task::Packet(Packet::new(
+                            pkt,
+                            PacketSource::Quic(recv),
+                            self.hooks(),
+                        )))
+                    },
+                )
             }
             Header::Dissociate(_) => Err(Error::BadCommandUniStream("dissociate", recv)),
             Header::Heartbeat(_) => Err(Error::BadCommandUniStream("heartbeat", recv)),
+            Header::Notice(notice) => {
+                let model = self.model.recv_notice(notice);
+                Ok(Task::Notice(model.kind(), model.message().to_owned()))
+            }
+            Header::Negotiate(negotiate) => {
+                let model = self.model.recv_negotiate(negotiate);
+                Ok(Task::Negotiate(model.version(), model.capabilities()))
+            }
+            Header::Compress(_) => Err(Error::BadCommandUniStream("compress", recv)),
+            Header::Batch(_) => Err(Error::BadCommandUniStream("batch", recv)),
+            Header::Status(_) => Err(Error::BadCommandUniStream("status", recv)),
+            Header::Speedtest(_) => Err(Error::BadCommandUniStream("speedtest", recv)),
+            Header::Bond(_) => Err(Error::BadCommandUniStream("bond", recv)),
             _ => unreachable!(),
         }
     }
@@ -201,13 +743,34 @@ impl Connection<side::Client> {
     ///
     /// The pair of stream should be accepted by
     /// `quinn::Connection::accept_bi()` from the same `quinn::Connection`.
+    ///
+    /// Cancellation safe: dropping the future before it completes resets the
+    /// streams with `ACCEPT_CANCELLED_ERROR_CODE`.
     pub async fn accept_bi_stream(
         &self,
-        send: SendStream,
-        mut recv: RecvStream,
-    ) -> Result<Task, Error> {
-        let header = match Header::async_unmarshal(&mut recv).await {
+        send: T::SendStream,
+        recv: T::RecvStream,
+    ) -> Result<Task<T>, Error<T>> {
+        let res = self.recv_bi_stream(send, recv).await;
+        self.report_error(&res);
+        res
+    }
+
+    async fn recv_bi_stream(
+        &self,
+        send: T::SendStream,
+        recv: T::RecvStream,
+    ) -> Result<Task<T>, Error<T>> {
+        let send = StreamGuard::new(send, reset_cancelled);
+        let mut recv = StreamGuard::new(recv, stop_cancelled);
+        let header = self.read_header(recv.get()).await;
+        let (send, recv) = (send.into_inner(), recv.into_inner());
+
+        let header = match header {
             Ok(header) => header,
+            Err(UnmarshalError::InvalidCommand(cmd)) if self.is_extension(cmd) => {
+                return Ok(Task::Extension(cmd, send, recv));
+            }
             Err(err) => return Err(Error::UnmarshalBiStream(err, send, recv)),
         };
 
@@ -217,6 +780,13 @@ impl Connection<side::Client> {
             Header::Packet(_) => Err(Error::BadCommandBiStream("packet", send, recv)),
             Header::Dissociate(_) => Err(Error::BadCommandBiStream("dissociate", send, recv)),
             Header::Heartbeat(_) => Err(Error::BadCommandBiStream("heartbeat", send, recv)),
+            Header::Notice(_) => Err(Error::BadCommandBiStream("notice", send, recv)),
+            Header::Negotiate(_) => Err(Error::BadCommandBiStream("negotiate", send, recv)),
+            Header::Compress(_) => Err(Error::BadCommandBiStream("compress", send, recv)),
+            Header::Batch(_) => Err(Error::BadCommandBiStream("batch", send, recv)),
+            Header::Status(_) => Err(Error::BadCommandBiStream("status", send, recv)),
+            Header::Speedtest(_) => Err(Error::BadCommandBiStream("speedtest", send, recv)),
+            Header::Bond(_) => Err(Error::BadCommandBiStream("bond", send, recv)),
             _ => unreachable!(),
         }
     }
@@ -225,58 +795,144 @@ impl Connection<side::Client> {
     ///
     /// The Datagram should be accepted by `quinn::Connection::read_datagram()`
     /// from the same `quinn::Connection`.
-    pub fn accept_datagram(&self, dg: Bytes) -> Result<Task, Error> {
-        let mut dg = Cursor::new(dg);
+    pub fn accept_datagram(&self, dg: Bytes) -> Result<Task<T>, Error<T>> {
+        let res = self.recv_datagram(dg);
+        self.report_error(&res);
+        res
+    }
 
-        let header = match Header::unmarshal(&mut dg) {
-            Ok(header) => header,
-            Err(err) => return Err(Error::UnmarshalDatagram(err, dg.into_inner())),
+    fn recv_datagram(&self, dg: Bytes) -> Result<Task<T>, Error<T>> {
+        // the payload keeps referencing the datagram
+        let (header, payload) = match Header::from_bytes(dg.clone()) {
+            Ok(res) => res,
+            Err(err) => return Err(Error::UnmarshalDatagram(err, dg)),
         };
 
         match header {
-            Header::Authenticate(_) => {
-                Err(Error::BadCommandDatagram("authenticate", dg.into_inner()))
-            }
-            Header::Connect(_) => Err(Error::BadCommandDatagram("connect", dg.into_inner())),
+            Header::Authenticate(_) => Err(Error::BadCommandDatagram("authenticate", dg)),
+            Header::Connect(_) => Err(Error::BadCommandDatagram("connect", dg)),
             Header::Packet(pkt) => {
                 let assoc_id = pkt.assoc_id();
                 let pkt_id = pkt.pkt_id();
                 if let Some(pkt) = self.model.recv_packet(pkt) {
-                    let pos = dg.position() as usize;
-                    let mut buf = dg.into_inner();
-                    if (pos + pkt.size() as usize) <= buf.len() {
-                        buf = buf.slice(pos..pos + pkt.size() as usize);
-                        Ok(Task::Packet(Packet::new(pkt, PacketSource::Native(buf))))
-                    } else {
-                        Err(Error::PayloadLength(pkt.size() as usize, buf.len() - pos))
-                    }
+                    let buf = payload_slice::<T>(payload, pkt.size())?;
+                    Ok(Task::Packet(Packet::new(
+                        pkt,
+                        PacketSource::Native(buf),
+                        self.hooks(),
+                    )))
                 } else {
                     Err(Error::InvalidUdpSession(assoc_id, pkt_id))
                 }
             }
-            Header::Dissociate(_) => Err(Error::BadCommandDatagram("dissociate", dg.into_inner())),
-            Header::Heartbeat(_) => Err(Error::BadCommandDatagram("heartbeat", dg.into_inner())),
+            Header::Dissociate(_) => Err(Error::BadCommandDatagram("dissociate", dg)),
+            Header::Heartbeat(hb) => {
+                let model = self.model.recv_heartbeat(hb);
+                match model.timestamp() {
+                    Some(timestamp) if model.is_echo() => {
+                        let now = self.epoch.elapsed().as_micros() as u64;
+                        let rtt = Duration::from_micros(now.saturating_sub(timestamp));
+                        *self.heartbeat_rtt.lock().unwrap() = Some(rtt);
+                        Ok(Task::HeartbeatEcho(rtt))
+                    }
+                    _ => Err(Error::BadCommandDatagram("heartbeat", dg)),
+                }
+            }
+            Header::Notice(_) => Err(Error::BadCommandDatagram("notice", dg)),
+            Header::Negotiate(_) => Err(Error::BadCommandDatagram("negotiate", dg)),
+            Header::Compress(_) => Err(Error::BadCommandDatagram("compress", dg)),
+            Header::Batch(batch) => {
+                let pkts = split_batch::<T>(batch, payload)?
+                    .into_iter()
+                    .map(|(header, buf)| {
+                        let assoc_id = header.assoc_id();
+                        let pkt_id = header.pkt_id();
+                        self.model
+                            .recv_packet(header)
+                            .map(|pkt| Packet::new(pkt, PacketSource::Native(buf), self.hooks()))
+                            .ok_or(Error::<T>::InvalidUdpSession(assoc_id, pkt_id))
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(Task::Batch(pkts))
+            }
+            Header::Status(_) => Err(Error::BadCommandDatagram("status", dg)),
+            Header::Speedtest(_) => Err(Error::BadCommandDatagram("speedtest", dg)),
+            Header::Bond(_) => Err(Error::BadCommandDatagram("bond", dg)),
             _ => unreachable!(),
         }
     }
 }
 
-impl Connection<side::Server> {
+impl<T: Transport> Connection<side::Server, T> {
     /// Creates a new server side `Connection`.
-    pub fn new(conn: QuinnConnection) -> Self {
+    pub fn new(conn: T) -> Self {
         Self {
             conn,
             model: ConnectionModel::new(),
+            padding: Arc::new(Mutex::new(None)),
+            compression: Arc::new(Mutex::new(None)),
+            epoch: Instant::now(),
+            heartbeat_rtt: Arc::new(Mutex::new(None)),
+            timeouts: Arc::new(Mutex::new(Timeouts::default())),
+            hooks: Arc::new(Mutex::new(HookSet::default())),
+            extensions: Arc::new(Mutex::new(None)),
+            exporter_labels: Arc::new(Mutex::new(ExporterLabels::default())),
             _marker: side::Server,
         }
     }
 
+    /// Completes once the `authenticate` timeout elapsed since the creation of
+    /// the connection. Never completes if the timeout is not set.
+    pub async fn authentication_timeout(&self) {
+        let timeout = self.timeouts.lock().unwrap().authenticate;
+        match timeout {
+            Some(timeout) => time::sleep_until((self.epoch + timeout).into()).await,
+            None => future::pending().await,
+        }
+    }
+
+    /// Offers the highest protocol version and the extensions supported to the
+    /// client with a `Negotiate` command.
+    ///
+    /// The client replies with the ones to be used, which are then available
+    /// from `Connection::negotiated()`. Clients not supporting negotiation
+    /// never reply.
+    pub async fn negotiate(&self, capabilities: Capabilities) -> eyre::Result<()> {
+        let model = self.model.send_negotiate(VERSION, capabilities);
+        let mut send = self.conn.open_uni().await?;
+        model.header().async_marshal(&mut send).await?;
+        send.finish()?;
+        Ok(())
+    }
+
+    /// Sends a `Notice` command.
+    pub async fn notice(&self, kind: NoticeKind, message: String) -> eyre::Result<()> {
+        let model = self.model.send_notice(kind, message);
+        let mut send = self.conn.open_uni().await?;
+        model.header().async_marshal(&mut send).await?;
+        send.finish()?;
+        Ok(())
+    }
+
     /// Try to parse a `quinn::RecvStream` as a TUIC command.
     ///
     /// The `quinn::RecvStream` should be accepted by
     /// `quinn::Connection::accept_uni()` from the same `quinn::Connection`.
-    pub async fn accept_uni_stream(&self, mut recv: RecvStream) -> Result<Task, Error> {
-        let header = match Header::async_unmarshal(&mut recv).await {
+    ///
+    /// Cancellation safe: dropping the future before it completes stops the
+    /// stream with `ACCEPT_CANCELLED_ERROR_CODE`.
+    pub async fn accept_uni_stream(&self, recv: T::RecvStream) -> Result<Task<T>, Error<T>> {
+        let res = self.recv_uni_stream(recv).await;
+        self.report_error(&res);
+        res
+    }
+
+    async fn recv_uni_stream(&self, recv: T::RecvStream) -> Result<Task<T>, Error<T>> {
+        let mut recv = StreamGuard::new(recv, stop_cancelled);
+        let header = self.read_header(recv.get()).await;
+        let recv = recv.into_inner();
+
+        let header = match header {
             Ok(header) => header,
             Err(err) => return Err(Error::UnmarshalUniStream(err, recv)),
         };
@@ -292,13 +948,29 @@ impl Connection<side::Server> {
             Header::Connect(_) => Err(Error::BadCommandUniStream("connect", recv)),
             Header::Packet(pkt) => {
                 let model = self.model.recv_packet_unrestricted(pkt);
-                Ok(Task::Packet(Packet::new(model, PacketSource::Quic(recv))))
+                Ok(Task::Packet(Packet::new(
+                    model,
+                    PacketSource::Quic(recv),
+                    self.hooks(),
+                )))
             }
             Header::Dissociate(dissoc) => {
                 let model = self.model.recv_dissociate(dissoc);
                 Ok(Task::Dissociate(model.assoc_id()))
             }
             Header::Heartbeat(_) => Err(Error::BadCommandUniStream("heartbeat", recv)),
+            Header::Notice(_) => Err(Error::BadCommandUniStream("notice", recv)),
+            Header::Negotiate(negotiate) => {
+                let model = self.model.recv_negotiate(negotiate);
+                self.model
+                    .set_negotiated(model.version(), model.capabilities());
+                Ok(Task::Negotiate(model.version(), model.capabilities()))
+            }
+            Header::Compress(_) => Err(Error::BadCommandUniStream("compress", recv)),
+            Header::Batch(_) => Err(Error::BadCommandUniStream("batch", recv)),
+            Header::Status(_) => Err(Error::BadCommandUniStream("status", recv)),
+            Header::Speedtest(_) => Err(Error::BadCommandUniStream("speedtest", recv)),
+            Header::Bond(bond) => Ok(Task::Bond(bond.bond_id())),
             _ => unreachable!(),
         }
     }
@@ -308,25 +980,78 @@ impl Connection<side::Server> {
     ///
     /// The pair of stream should be accepted by
     /// `quinn::Connection::accept_bi()` from the same `quinn::Connection`.
+    ///
+    /// Cancellation safe: dropping the future before it completes resets the
+    /// streams with `ACCEPT_CANCELLED_ERROR_CODE`.
     pub async fn accept_bi_stream(
         &self,
-        send: SendStream,
-        mut recv: RecvStream,
-    ) -> Result<Task, Error> {
-        let header = match Header::async_unmarshal(&mut recv).await {
-            Ok(header) => header,
-            Err(err) => return Err(Error::UnmarshalBiStream(err, send, recv)),
+        send: T::SendStream,
+        recv: T::RecvStream,
+    ) -> Result<Task<T>, Error<T>> {
+        let res = self.recv_bi_stream(send, recv).await;
+        self.report_error(&res);
+        res
+    }
+
+    async fn recv_bi_stream(
+        &self,
+        send: T::SendStream,
+        recv: T::RecvStream,
+    ) -> Result<Task<T>, Error<T>> {
+        let send = StreamGuard::new(send, reset_cancelled);
+        let mut recv = StreamGuard::new(recv, stop_cancelled);
+
+        // `Compress` and `Status` may each prefix a `Connect`, in any order
+        let mut codec = None;
+        let mut status = false;
+
+        let header = loop {
+            let header = match self.read_header(recv.get()).await {
+                Ok(header) => header,
+                Err(UnmarshalError::InvalidCommand(cmd))
+                    if codec.is_none() && !status && self.is_extension(cmd) =>
+                {
+                    return Ok(Task::Extension(cmd, send.into_inner(), recv.into_inner()));
+                }
+                Err(err) => {
+                    return Err(Error::UnmarshalBiStream(
+                        err,
+                        send.into_inner(),
+                        recv.into_inner(),
+                    ));
+                }
+            };
+
+            match header {
+                Header::Compress(_) if codec.is_none() => {
+                    codec = Some(Codec::new(self.compression_level()));
+                }
+                Header::Status(_) if !status => status = true,
+                header => break header,
+            }
         };
+        let (send, recv) = (send.into_inner(), recv.into_inner());
 
         match header {
             Header::Authenticate(_) => Err(Error::BadCommandBiStream("authenticate", send, recv)),
             Header::Connect(conn) => {
                 let model = self.model.recv_connect(conn);
-                Ok(Task::Connect(Connect::new(Side::Server(model), send, recv)))
+                let mut conn = Connect::new(Side::Server(model), send, recv, codec, self.hooks());
+                if status {
+                    conn.status = StatusState::Owed;
+                }
+                Ok(Task::Connect(conn))
             }
+            Header::Compress(_) => Err(Error::BadCommandBiStream("compress", send, recv)),
+            Header::Status(_) => Err(Error::BadCommandBiStream("status", send, recv)),
+            Header::Speedtest(speedtest) => Ok(Task::Speedtest(speedtest.download(), send, recv)),
+            Header::Bond(_) => Err(Error::BadCommandBiStream("bond", send, recv)),
+            Header::Batch(_) => Err(Error::BadCommandBiStream("batch", send, recv)),
             Header::Packet(_) => Err(Error::BadCommandBiStream("packet", send, recv)),
             Header::Dissociate(_) => Err(Error::BadCommandBiStream("dissociate", send, recv)),
             Header::Heartbeat(_) => Err(Error::BadCommandBiStream("heartbeat", send, recv)),
+            Header::Notice(_) => Err(Error::BadCommandBiStream("notice", send, recv)),
+            Header::Negotiate(_) => Err(Error::BadCommandBiStream("negotiate", send, recv)),
             _ => unreachable!(),
         }
     }
@@ -335,53 +1060,88 @@ impl Connection<side::Server> {
     ///
     /// The Datagram should be accepted by `quinn::Connection::read_datagram()`
     /// from the same `quinn::Connection`.
-    pub fn accept_datagram(&self, dg: Bytes) -> Result<Task, Error> {
-        let mut dg = Cursor::new(dg);
+    pub fn accept_datagram(&self, dg: Bytes) -> Result<Task<T>, Error<T>> {
+        let res = self.recv_datagram(dg);
+        self.report_error(&res);
+        res
+    }
 
-        let header = match Header::unmarshal(&mut dg) {
-            Ok(header) => header,
-            Err(err) => return Err(Error::UnmarshalDatagram(err, dg.into_inner())),
+    fn recv_datagram(&self, dg: Bytes) -> Result<Task<T>, Error<T>> {
+        // the payload keeps referencing the datagram
+        let (header, payload) = match Header::from_bytes(dg.clone()) {
+            Ok(res) => res,
+            Err(err) => return Err(Error::UnmarshalDatagram(err, dg)),
         };
 
         match header {
-            Header::Authenticate(_) => {
-                Err(Error::BadCommandDatagram("authenticate", dg.into_inner()))
-            }
-            Header::Connect(_) => Err(Error::BadCommandDatagram("connect", dg.into_inner())),
+            Header::Authenticate(_) => Err(Error::BadCommandDatagram("authenticate", dg)),
+            Header::Connect(_) => Err(Error::BadCommandDatagram("connect", dg)),
             Header::Packet(pkt) => {
                 let model = self.model.recv_packet_unrestricted(pkt);
-                let pos = dg.position() as usize;
-                let buf = dg.into_inner().slice(pos..pos + model.size() as usize);
-                Ok(Task::Packet(Packet::new(model, PacketSource::Native(buf))))
+                let buf = payload_slice::<T>(payload, model.size())?;
+                Ok(Task::Packet(Packet::new(
+                    model,
+                    PacketSource::Native(buf),
+                    self.hooks(),
+                )))
             }
-            Header::Dissociate(_) => Err(Error::BadCommandDatagram("dissociate", dg.into_inner())),
+            Header::Dissociate(_) => Err(Error::BadCommandDatagram("dissociate", dg)),
             Header::Heartbeat(hb) => {
-                let _ = self.model.recv_heartbeat(hb);
+                let model = self.model.recv_heartbeat(hb);
+                if let (Some(timestamp), false) = (model.timestamp(), model.is_echo()) {
+                    let model = self.model.send_heartbeat_echo(timestamp);
+                    let mut buf = BytesMut::with_capacity(model.header().len());
+                    model.header().write(&mut buf);
+                    if let Err(err) = self.conn.send_datagram(buf.freeze()) {
+                        debug!("failed echoing heartbeat: {err}");
+                    }
+                }
                 Ok(Task::Heartbeat)
             }
+            Header::Notice(_) => Err(Error::BadCommandDatagram("notice", dg)),
+            Header::Negotiate(_) => Err(Error::BadCommandDatagram("negotiate", dg)),
+            Header::Compress(_) => Err(Error::BadCommandDatagram("compress", dg)),
+            Header::Batch(batch) => {
+                let pkts = split_batch::<T>(batch, payload)?
+                    .into_iter()
+                    .map(|(header, buf)| {
+                        let pkt = self.model.recv_packet_unrestricted(header);
+                        Packet::new(pkt, PacketSource::Native(buf), self.hooks())
+                    })
+                    .collect();
+                Ok(Task::Batch(pkts))
+            }
+            Header::Status(_) => Err(Error::BadCommandDatagram("status", dg)),
+            Header::Speedtest(_) => Err(Error::BadCommandDatagram("speedtest", dg)),
+            Header::Bond(_) => Err(Error::BadCommandDatagram("bond", dg)),
             _ => unreachable!(),
         }
     }
 }
 
-impl<Side> Debug for Connection<Side> {
+impl<Side, T: Transport> Debug for Connection<Side, T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         f.debug_struct("Connection")
             .field("conn", &self.conn)
             .field("model", &self.model)
+            .field("padding", &self.padding)
+            .field("compression", &self.compression)
+            .field("timeouts", &self.timeouts)
+            .field("hooks", &self.hooks)
+            .field("extensions", &self.extensions)
             .finish()
     }
 }
 
 /// A received `Authenticate` command.
 #[derive(Debug)]
-pub struct Authenticate {
+pub struct Authenticate<T: Transport = QuinnConnection> {
     model: AuthenticateModel<Rx>,
-    exporter: KeyingMaterialExporter,
+    exporter: KeyingMaterialExporter<T>,
 }
 
-impl Authenticate {
-    fn new(model: AuthenticateModel<Rx>, exporter: KeyingMaterialExporter) -> Self {
+impl<T: Transport> Authenticate<T> {
+    fn new(model: AuthenticateModel<Rx>, exporter: KeyingMaterialExporter<T>) -> Self {
         Self { model, exporter }
     }
 
@@ -402,19 +1162,126 @@ impl Authenticate {
 }
 
 /// A received `Connect` command.
-pub struct Connect {
+pub struct Connect<T: Transport = QuinnConnection> {
     model: Side<ConnectModel<Tx>, ConnectModel<Rx>>,
-    pub send: SendStream,
-    pub recv: RecvStream,
+    /// Writing to the streams directly bypasses compression. Relay data
+    /// through the `AsyncRead` and `AsyncWrite` implementations instead.
+    pub send: T::SendStream,
+    pub recv: T::RecvStream,
+    codec: Option<Box<Codec>>,
+    status: StatusState,
+    stats: TaskStats,
+    hooks: HookSet,
+}
+
+/// Where a `Connect` is in reporting its outcome with a `Status` command
+#[derive(Clone, Copy, Debug)]
+enum StatusState {
+    NotRequested,
+    /// The server has yet to reply
+    Owed,
+    Replied,
+    /// The client is reading the reply, with the number of bytes read so far
+    Pending([u8; 3], usize),
+    Received(ConnectStatus),
 }
 
-impl Connect {
+impl<T: Transport> Connect<T> {
     fn new(
         model: Side<ConnectModel<Tx>, ConnectModel<Rx>>,
-        send: SendStream,
-        recv: RecvStream,
+        send: T::SendStream,
+        recv: T::RecvStream,
+        codec: Option<Codec>,
+        hooks: HookSet,
     ) -> Self {
-        Self { model, send, recv }
+        hooks.task_started(TaskKind::Connect);
+
+        Self {
+            model,
+            send,
+            recv,
+            codec: codec.map(Box::new),
+            status: StatusState::NotRequested,
+            stats: TaskStats::default(),
+            hooks,
+        }
+    }
+
+    /// Returns `true` if the stream is compressed
+    pub fn is_compressed(&self) -> bool {
+        self.codec.is_some()
+    }
+
+    /// Returns the bytes relayed through the `AsyncRead` and `AsyncWrite`
+    /// implementations so far, before compression
+    pub fn stats(&self) -> TaskStats {
+        self.stats
+    }
+
+    fn relayed(&mut self, dir: Direction, len: usize) {
+        self.stats.add(dir, len);
+        self.hooks.bytes_relayed(TaskKind::Connect, dir, len);
+    }
+
+    /// Waits for the outcome of connecting to the target reported by the
+    /// server. Returns `None` if it was not asked for with
+    /// `Connection::connect_with_status()`.
+    ///
+    /// Reading from the stream also waits for it, failing if the server could
+    /// not connect to the target.
+    pub async fn status(&mut self) -> Result<Option<ConnectStatus>, Error<T>> {
+        Ok(poll_fn(|cx| self.poll_status(cx)).await?)
+    }
+
+    fn poll_status(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<ConnectStatus>, IoError>> {
+        let (buf, len) = match &mut self.status {
+            StatusState::Pending(buf, len) => (buf, len),
+            StatusState::Received(status) => return Poll::Ready(Ok(Some(*status))),
+            _ => return Poll::Ready(Ok(None)),
+        };
+
+        while *len < buf.len() {
+            let mut read_buf = ReadBuf::new(&mut buf[*len..]);
+            ready!(AsyncRead::poll_read(
+                Pin::new(&mut self.recv),
+                cx,
+                &mut read_buf
+            ))?;
+            if read_buf.filled().is_empty() {
+                return Poll::Ready(Err(IoError::from(ErrorKind::UnexpectedEof)));
+            }
+            *len += read_buf.filled().len();
+        }
+
+        let status = match Header::unmarshal(&mut &buf[..]) {
+            Ok(Header::Status(status)) => status.status(),
+            Ok(_) => {
+                return Poll::Ready(Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    "expecting a `Status` command",
+                )));
+            }
+            Err(err) => return Poll::Ready(Err(IoError::new(ErrorKind::InvalidData, err))),
+        };
+
+        self.status = StatusState::Received(status);
+        Poll::Ready(Ok(Some(status)))
+    }
+
+    /// Reports the outcome of connecting to the target to the client, if it
+    /// asked for it. Must be called before relaying any data, and does
+    /// nothing if the client did not ask or it was already reported.
+    pub async fn reply_status(&mut self, status: ConnectStatus) -> Result<(), Error<T>> {
+        if let StatusState::Owed = self.status {
+            Header::Status(Status::new(status))
+                .async_marshal(&mut self.send)
+                .await?;
+            self.status = StatusState::Replied;
+        }
+        Ok(())
     }
 
     /// Returns the `Connect` address
@@ -443,38 +1310,68 @@ impl Connect {
     }
 }
 
-impl AsyncRead for Connect {
+impl<T: Transport> AsyncRead for Connect<T> {
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
-        AsyncRead::poll_read(Pin::new(&mut self.get_mut().recv), cx, buf)
+        let this = self.get_mut();
+        if let Some(status) = ready!(this.poll_status(cx))? {
+            if !status.is_success() {
+                return Poll::Ready(Err(IoError::new(
+                    status_error_kind(status),
+                    format!("failed connecting to the target: {status}"),
+                )));
+            }
+        }
+
+        let filled = buf.filled().len();
+        ready!(match &mut this.codec {
+            Some(codec) => codec.poll_read(&mut this.recv, cx, buf),
+            None => AsyncRead::poll_read(Pin::new(&mut this.recv), cx, buf),
+        })?;
+        this.relayed(Direction::Received, buf.filled().len() - filled);
+        Poll::Ready(Ok(()))
     }
 }
 
-impl AsyncWrite for Connect {
+impl<T: Transport> AsyncWrite for Connect<T> {
     fn poll_write(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, IoError>> {
-        AsyncWrite::poll_write(Pin::new(&mut self.get_mut().send), cx, buf)
+        let this = self.get_mut();
+        let len = ready!(match &mut this.codec {
+            Some(codec) => codec.poll_write(&mut this.send, cx, buf),
+            None => AsyncWrite::poll_write(Pin::new(&mut this.send), cx, buf),
+        })?;
+        this.relayed(Direction::Sent, len);
+        Poll::Ready(Ok(len))
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
-        AsyncWrite::poll_flush(Pin::new(&mut self.get_mut().send), cx)
+        let this = self.get_mut();
+        match &mut this.codec {
+            Some(codec) => codec.poll_flush(&mut this.send, cx),
+            None => AsyncWrite::poll_flush(Pin::new(&mut this.send), cx),
+        }
     }
 
     fn poll_shutdown(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), std::io::Error>> {
-        AsyncWrite::poll_shutdown(Pin::new(&mut self.get_mut().send), cx)
+        let this = self.get_mut();
+        match &mut this.codec {
+            Some(codec) => codec.poll_shutdown(&mut this.send, cx),
+            None => AsyncWrite::poll_shutdown(Pin::new(&mut this.send), cx),
+        }
     }
 }
 
-impl Debug for Connect {
+impl<T: Transport> Debug for Connect<T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         let model = match &self.model {
             Side::Client(model) => model as &dyn Debug,
@@ -485,26 +1382,45 @@ impl Debug for Connect {
             .field("model", model)
             .field("send", &self.send)
             .field("recv", &self.recv)
+            .field("compressed", &self.is_compressed())
+            .field("status", &self.status)
+            .field("stats", &self.stats)
             .finish()
     }
 }
 
+impl<T: Transport> Drop for Connect<T> {
+    fn drop(&mut self) {
+        self.hooks.task_finished(TaskKind::Connect, self.stats);
+    }
+}
+
+fn status_error_kind(status: ConnectStatus) -> ErrorKind {
+    match status {
+        ConnectStatus::Denied | ConnectStatus::QuotaExceeded => ErrorKind::PermissionDenied,
+        ConnectStatus::ConnectionRefused => ErrorKind::ConnectionRefused,
+        ConnectStatus::TimedOut => ErrorKind::TimedOut,
+        _ => ErrorKind::Other,
+    }
+}
+
 /// A received `Packet` command.
 #[derive(Debug)]
-pub struct Packet {
+pub struct Packet<T: Transport = QuinnConnection> {
     model: PacketModel<Rx, Bytes>,
-    src: PacketSource,
+    src: PacketSource<T>,
+    hooks: HookSet,
 }
 
 #[derive(Debug)]
-enum PacketSource {
-    Quic(RecvStream),
+enum PacketSource<T: Transport> {
+    Quic(T::RecvStream),
     Native(Bytes),
 }
 
-impl Packet {
-    fn new(model: PacketModel<Rx, Bytes>, src: PacketSource) -> Self {
-        Self { src, model }
+impl<T: Transport> Packet<T> {
+    fn new(model: PacketModel<Rx, Bytes>, src: PacketSource<T>, hooks: HookSet) -> Self {
+        Self { src, model, hooks }
     }
 
     /// Returns the UDP session ID
@@ -539,7 +1455,22 @@ impl Packet {
 
     /// Accepts the packet payload. If the packet is fragmented and not yet
     /// fully assembled, `Ok(None)` is returned.
-    pub async fn accept(self) -> Result<Option<(Bytes, Address, u16)>, Error> {
+    pub async fn accept(self) -> Result<Option<(Bytes, Address, u16)>, Error<T>> {
+        let hooks = self.hooks.clone();
+        let res = self.assemble().await;
+
+        match &res {
+            Ok(Some((pkt, _, _))) => {
+                hooks.bytes_relayed(TaskKind::Packet, Direction::Received, pkt.len())
+            }
+            Ok(None) => {}
+            Err(err) => hooks.error(err),
+        }
+
+        res
+    }
+
+    async fn assemble(self) -> Result<Option<(Bytes, Address, u16)>, Error<T>> {
         let pkt = match self.src {
             PacketSource::Quic(mut recv) => {
                 let mut buf = vec![0; self.model.size() as usize];
@@ -559,24 +1490,128 @@ impl Packet {
     }
 }
 
+/// Returns the first `size` bytes of the payload following a `Packet` header
+fn payload_slice<T: Transport>(payload: Bytes, size: u16) -> Result<Bytes, Error<T>> {
+    let size = size as usize;
+    if size <= payload.len() {
+        Ok(payload.slice(..size))
+    } else {
+        Err(Error::PayloadLength(size, payload.len()))
+    }
+}
+
+/// Splits the entries following a `Batch` command in a datagram, as `Packet`
+/// commands with a single fragment and their payloads
+fn split_batch<T: Transport>(
+    batch: Batch,
+    buf: Bytes,
+) -> Result<Vec<(PacketHeader, Bytes)>, Error<T>> {
+    let mut pos = 0;
+    let (assoc_id, count, addr) = batch.into();
+    let mut entries = Vec::with_capacity(count as usize);
+
+    for _ in 0..count {
+        if pos + Batch::ENTRY_HEADER_LEN > buf.len() {
+            return Err(Error::PayloadLength(
+                Batch::ENTRY_HEADER_LEN,
+                buf.len() - pos,
+            ));
+        }
+
+        let pkt_id = u16::from_be_bytes([buf[pos], buf[pos + 1]]);
+        let size = u16::from_be_bytes([buf[pos + 2], buf[pos + 3]]);
+        pos += Batch::ENTRY_HEADER_LEN;
+
+        if pos + size as usize > buf.len() {
+            return Err(Error::PayloadLength(size as usize, buf.len() - pos));
+        }
+
+        let header = PacketHeader::new(assoc_id, pkt_id, 1, 0, size, addr.clone());
+        entries.push((header, buf.slice(pos..pos + size as usize)));
+        pos += size as usize;
+    }
+
+    Ok(entries)
+}
+
 /// Type of tasks that can be received.
 #[non_exhaustive]
 #[derive(Debug)]
-pub enum Task {
-    Authenticate(Authenticate),
-    Connect(Connect),
-    Packet(Packet),
+pub enum Task<T: Transport = QuinnConnection> {
+    Authenticate(Authenticate<T>),
+    Connect(Connect<T>),
+    Packet(Packet<T>),
+    /// Packets received in a `Batch` command, in order
+    Batch(Vec<Packet<T>>),
     Dissociate(u16),
     Heartbeat,
+    /// A `Heartbeat` echoed by the server, with the measured round-trip time
+    HeartbeatEcho(Duration),
+    Notice(NoticeKind, String),
+    Negotiate(u8, Capabilities),
+    /// A `Speedtest` command, with the number of bytes to send back once the
+    /// client finished uploading
+    Speedtest(u64, T::SendStream, T::RecvStream),
+    /// A `Bond` command, with the bond ID
+    Bond(u64),
+    /// A bidirectional stream opened with an extension command passed through
+    /// by `Connection::set_extension_commands()`, with the command type code
+    Extension(u8, T::SendStream, T::RecvStream),
+}
+
+/// A stream handed to an `accept_*_stream()` method, reset or stopped if the
+/// future is dropped before it is returned
+struct StreamGuard<S> {
+    stream: Option<S>,
+    cancel: fn(&mut S),
+}
+
+impl<S> StreamGuard<S> {
+    fn new(stream: S, cancel: fn(&mut S)) -> Self {
+        Self {
+            stream: Some(stream),
+            cancel,
+        }
+    }
+
+    fn get(&mut self) -> &mut S {
+        self.stream.as_mut().unwrap()
+    }
+
+    fn into_inner(mut self) -> S {
+        self.stream.take().unwrap()
+    }
+}
+
+impl<S> Drop for StreamGuard<S> {
+    fn drop(&mut self) {
+        if let Some(stream) = &mut self.stream {
+            (self.cancel)(stream);
+        }
+    }
+}
+
+fn reset_cancelled(send: &mut impl transport::SendStream) {
+    let _ = send.reset(ACCEPT_CANCELLED_ERROR_CODE);
+}
+
+fn stop_cancelled(recv: &mut impl transport::RecvStream) {
+    let _ = recv.stop(ACCEPT_CANCELLED_ERROR_CODE);
 }
 
 #[derive(Debug)]
-struct KeyingMaterialExporter(QuinnConnection);
+struct KeyingMaterialExporter<T> {
+    conn: T,
+    labels: ExporterLabels,
+}
 
-impl KeyingMaterialExporterImpl for KeyingMaterialExporter {
+impl<T: Transport> KeyingMaterialExporterImpl for KeyingMaterialExporter<T> {
     fn export_keying_material(&self, label: &[u8], context: &[u8]) -> [u8; 32] {
+        let label = [self.labels.label.as_slice(), label].concat();
+        let context = [self.labels.context.as_slice(), context].concat();
+
         let mut buf = [0; 32];
-        if let Err(err) = self.0.export_keying_material(&mut buf, label, context) {
+        if let Err(err) = self.conn.export_keying_material(&mut buf, &label, &context) {
             warn!("export keying material error {:#?}", err);
             buf = [0; 32];
         }
@@ -586,7 +1621,7 @@ impl KeyingMaterialExporterImpl for KeyingMaterialExporter {
 
 /// Errors that can occur when processing a task.
 #[derive(Debug, Error)]
-pub enum Error {
+pub enum Error<T: Transport = QuinnConnection> {
     #[error(transparent)]
     IoError(#[from] IoError),
     #[error(transparent)]
@@ -600,17 +1635,21 @@ pub enum Error {
     #[error(transparent)]
     Assemble(#[from] AssembleError),
     #[error("error unmarshalling uni_stream: {0}")]
-    UnmarshalUniStream(UnmarshalError, RecvStream),
+    UnmarshalUniStream(UnmarshalError, T::RecvStream),
     #[error("error unmarshalling bi_stream: {0}")]
-    UnmarshalBiStream(UnmarshalError, SendStream, RecvStream),
+    UnmarshalBiStream(UnmarshalError, T::SendStream, T::RecvStream),
     #[error("error unmarshalling datagram: {0}")]
     UnmarshalDatagram(UnmarshalError, Bytes),
     #[error("bad command `{0}` from uni_stream")]
-    BadCommandUniStream(&'static str, RecvStream),
+    BadCommandUniStream(&'static str, T::RecvStream),
     #[error("bad command `{0}` from bi_stream")]
-    BadCommandBiStream(&'static str, SendStream, RecvStream),
+    BadCommandBiStream(&'static str, T::SendStream, T::RecvStream),
     #[error("bad command `{0}` from datagram")]
     BadCommandDatagram(&'static str, Bytes),
     #[error(transparent)]
-    QuicWriteError(#[from] quinn::WriteError),
+    QuicWriteError(#[from] WriteError),
+    #[error("extension `{0}` is not negotiated")]
+    NotNegotiated(&'static str),
+    #[error("command type code {0:#04x} is not reserved for extensions")]
+    InvalidExtension(u8),
 }
@@ -0,0 +1,340 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+};
+
+use bytes::Bytes;
+use quinn::Connection as QuinnConnection;
+use tokio::sync::Notify;
+use tracing::debug;
+use tuic::Address;
+
+use crate::{Connection, Transport};
+
+/// The UDP relay mode a queued packet is sent with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketMode {
+    /// As datagrams, with `Connection::packet_native()`
+    Native,
+    /// On unidirectional streams, with `Connection::packet_quic()`
+    Quic,
+}
+
+/// What to do with a packet pushed to a full queue
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drops the packet pushed
+    #[default]
+    DropNewest,
+    /// Drops the oldest packet queued in the same mode to make room
+    DropOldest,
+}
+
+/// Limits of a [`PacketQueue`]
+#[derive(Clone, Copy, Debug)]
+pub struct PacketQueueConfig {
+    /// Maximum number of packets queued in each mode
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for PacketQueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+/// A bounded queue of outgoing UDP packets on a connection, on either side
+///
+/// Packets are pushed without waiting and sent by [`PacketQueue::run()`],
+/// `native` mode datagrams first and `quic` mode ones, which open a stream per
+/// fragment, when no datagram is queued. Once a mode has `capacity` packets
+/// queued, packets are dropped following the overflow policy, so heavy UDP
+/// load can not buffer without bound or starve the `Connect` streams sharing
+/// the connection
+#[derive(Clone, Debug)]
+pub struct PacketQueue<Side, T: Transport = QuinnConnection> {
+    conn: Connection<Side, T>,
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    config: PacketQueueConfig,
+    queues: Mutex<Queues>,
+    notify: Notify,
+    closed: AtomicBool,
+    dropped: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Queues {
+    native: VecDeque<QueuedPacket>,
+    quic: VecDeque<QueuedPacket>,
+}
+
+#[derive(Debug)]
+struct QueuedPacket {
+    pkt: Bytes,
+    addr: Address,
+    assoc_id: u16,
+}
+
+impl<Side, T: Transport> PacketQueue<Side, T> {
+    /// Creates a queue sending the packets on `conn`
+    pub fn new(conn: Connection<Side, T>, config: PacketQueueConfig) -> Self {
+        Self {
+            conn,
+            inner: Arc::new(Inner {
+                config,
+                queues: Mutex::new(Queues::default()),
+                notify: Notify::new(),
+                closed: AtomicBool::new(false),
+                dropped: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Queues a packet to be sent in `mode`. Returns `false` if the queue is
+    /// closed, or full and the packet is dropped
+    pub fn push(&self, mode: PacketMode, pkt: Bytes, addr: Address, assoc_id: u16) -> bool {
+        if self.inner.closed.load(Ordering::Acquire) {
+            return false;
+        }
+
+        let mut queues = self.inner.queues.lock().unwrap();
+        let queue = match mode {
+            PacketMode::Native => &mut queues.native,
+            PacketMode::Quic => &mut queues.quic,
+        };
+
+        if queue.len() >= self.inner.config.capacity {
+            self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+            match self.inner.config.overflow {
+                OverflowPolicy::DropNewest => return false,
+                OverflowPolicy::DropOldest => {
+                    if queue.pop_front().is_none() {
+                        return false;
+                    }
+                }
+            }
+        }
+
+        queue.push_back(QueuedPacket {
+            pkt,
+            addr,
+            assoc_id,
+        });
+        drop(queues);
+
+        self.inner.notify.notify_one();
+        true
+    }
+
+    /// Returns the number of packets queued
+    pub fn len(&self) -> usize {
+        let queues = self.inner.queues.lock().unwrap();
+        queues.native.len() + queues.quic.len()
+    }
+
+    /// Returns `true` if no packet is queued
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of packets dropped as the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.inner.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Stops accepting packets. `run()` returns once the packets already
+    /// queued are sent
+    pub fn close(&self) {
+        self.inner.closed.store(true, Ordering::Release);
+        self.inner.notify.notify_one();
+    }
+
+    /// Sends the queued packets until the queue is closed. Should be run by a
+    /// single task
+    pub async fn run(&self) {
+        loop {
+            let next = {
+                let mut queues = self.inner.queues.lock().unwrap();
+                match queues.native.pop_front() {
+                    Some(pkt) => Some((PacketMode::Native, pkt)),
+                    None => queues.quic.pop_front().map(|pkt| (PacketMode::Quic, pkt)),
+                }
+            };
+
+            let Some((mode, pkt)) = next else {
+                if self.inner.closed.load(Ordering::Acquire) {
+                    return;
+                }
+                self.inner.notify.notified().await;
+                continue;
+            };
+
+            let assoc_id = pkt.assoc_id;
+            let res = match mode {
+                PacketMode::Native => self.conn.packet_native(pkt.pkt, pkt.addr, assoc_id),
+                PacketMode::Quic => self.conn.packet_quic(pkt.pkt, pkt.addr, assoc_id).await,
+            };
+
+            if let Err(err) = res {
+                debug!("[{assoc_id:#06x}] failed sending queued packet: {err}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{self, Future};
+
+    use futures_util::FutureExt;
+    use quinn::{ConnectionError, SendDatagramError, crypto::ExportKeyingMaterialError};
+
+    use super::*;
+    use crate::side;
+
+    /// What a [`PacketQueue`] sent on a [`Recorder`]
+    #[derive(Debug, PartialEq, Eq)]
+    enum Sent {
+        Datagram(Bytes),
+        Stream,
+    }
+
+    /// A transport recording the datagrams sent and the streams opened, which
+    /// all fail
+    #[derive(Clone, Debug, Default)]
+    struct Recorder(Arc<Mutex<Vec<Sent>>>);
+
+    impl Transport for Recorder {
+        type SendStream = quinn::SendStream;
+        type RecvStream = quinn::RecvStream;
+
+        fn open_uni(
+            &self,
+        ) -> impl Future<Output = Result<Self::SendStream, ConnectionError>> + Send {
+            self.0.lock().unwrap().push(Sent::Stream);
+            future::ready(Err(ConnectionError::LocallyClosed))
+        }
+
+        fn open_bi(
+            &self,
+        ) -> impl Future<Output = Result<(Self::SendStream, Self::RecvStream), ConnectionError>> + Send
+        {
+            future::ready(Err(ConnectionError::LocallyClosed))
+        }
+
+        fn send_datagram(&self, data: Bytes) -> Result<(), SendDatagramError> {
+            self.0.lock().unwrap().push(Sent::Datagram(data));
+            Ok(())
+        }
+
+        fn max_datagram_size(&self) -> Option<usize> {
+            Some(1200)
+        }
+
+        fn export_keying_material(
+            &self,
+            _output: &mut [u8],
+            _label: &[u8],
+            _context: &[u8],
+        ) -> Result<(), ExportKeyingMaterialError> {
+            Err(ExportKeyingMaterialError)
+        }
+    }
+
+    fn new_queue(overflow: OverflowPolicy) -> (PacketQueue<side::Client, Recorder>, Recorder) {
+        let recorder = Recorder::default();
+        let conn = Connection::<side::Client, _>::new(recorder.clone());
+        let config = PacketQueueConfig {
+            capacity: 2,
+            overflow,
+        };
+        (PacketQueue::new(conn, config), recorder)
+    }
+
+    fn push(queue: &PacketQueue<side::Client, Recorder>, mode: PacketMode, id: u16) -> bool {
+        queue.push(mode, Bytes::from(format!("pkt-{id}")), Address::None, id)
+    }
+
+    /// Returns the associations of the packets queued in `mode`, in order
+    fn queued(queue: &PacketQueue<side::Client, Recorder>, mode: PacketMode) -> Vec<u16> {
+        let queues = queue.inner.queues.lock().unwrap();
+        let queue = match mode {
+            PacketMode::Native => &queues.native,
+            PacketMode::Quic => &queues.quic,
+        };
+        queue.iter().map(|pkt| pkt.assoc_id).collect()
+    }
+
+    #[test]
+    fn drop_newest() {
+        let (queue, _) = new_queue(OverflowPolicy::DropNewest);
+
+        assert!(push(&queue, PacketMode::Native, 1));
+        assert!(push(&queue, PacketMode::Native, 2));
+        assert!(!push(&queue, PacketMode::Native, 3));
+
+        assert_eq!(queued(&queue, PacketMode::Native), [1, 2]);
+        assert_eq!(queue.dropped(), 1);
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let (queue, _) = new_queue(OverflowPolicy::DropOldest);
+
+        assert!(push(&queue, PacketMode::Native, 1));
+        assert!(push(&queue, PacketMode::Native, 2));
+        assert!(push(&queue, PacketMode::Native, 3));
+        assert!(push(&queue, PacketMode::Native, 4));
+
+        assert_eq!(queued(&queue, PacketMode::Native), [3, 4]);
+        assert_eq!(queue.dropped(), 2);
+    }
+
+    #[test]
+    fn capacity_per_mode() {
+        let (queue, _) = new_queue(OverflowPolicy::DropNewest);
+
+        assert!(push(&queue, PacketMode::Native, 1));
+        assert!(push(&queue, PacketMode::Native, 2));
+        assert!(push(&queue, PacketMode::Quic, 3));
+        assert!(push(&queue, PacketMode::Quic, 4));
+        assert!(!push(&queue, PacketMode::Quic, 5));
+
+        assert_eq!(queued(&queue, PacketMode::Native), [1, 2]);
+        assert_eq!(queued(&queue, PacketMode::Quic), [3, 4]);
+        assert_eq!(queue.len(), 4);
+    }
+
+    #[test]
+    fn ordering() {
+        let (queue, recorder) = new_queue(OverflowPolicy::DropNewest);
+
+        assert!(push(&queue, PacketMode::Quic, 1));
+        assert!(push(&queue, PacketMode::Native, 2));
+        assert!(push(&queue, PacketMode::Quic, 3));
+        assert!(push(&queue, PacketMode::Native, 4));
+        queue.close();
+        assert!(!push(&queue, PacketMode::Native, 5));
+
+        // the queued packets are still sent once closed, datagrams first
+        assert_eq!(queue.run().now_or_never(), Some(()));
+        assert!(queue.is_empty());
+
+        let sent = recorder.0.lock().unwrap();
+        assert_eq!(sent.len(), 4);
+        assert!(matches!(&sent[0], Sent::Datagram(data) if data.ends_with(b"pkt-2")));
+        assert!(matches!(&sent[1], Sent::Datagram(data) if data.ends_with(b"pkt-4")));
+        assert_eq!(sent[2..], [Sent::Stream, Sent::Stream]);
+    }
+}
@@ -0,0 +1,128 @@
+//! The QUIC connection TUIC runs over.
+//!
+//! `Connection` is generic over [`Transport`], implemented for
+//! `quinn::Connection` by default. Another QUIC stack, or an in-memory
+//! transport for tests, can host the same TUIC model by implementing it.
+//!
+//! Errors are reported with quinn's error types, which other implementations
+//! map theirs to.
+
+use std::{fmt::Debug, future::Future};
+
+use bytes::Bytes;
+use futures_util::{AsyncRead as FuturesAsyncRead, AsyncWrite as FuturesAsyncWrite};
+use quinn::{
+    ClosedStream, ConnectionError, SendDatagramError, VarInt, WriteError,
+    crypto::ExportKeyingMaterialError,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A QUIC connection, with streams and unreliable datagrams
+pub trait Transport: Clone + Debug + Send + Sync + 'static {
+    type SendStream: SendStream;
+    type RecvStream: RecvStream;
+
+    /// Opens a unidirectional stream
+    fn open_uni(&self) -> impl Future<Output = Result<Self::SendStream, ConnectionError>> + Send;
+
+    /// Opens a bidirectional stream
+    fn open_bi(
+        &self,
+    ) -> impl Future<Output = Result<(Self::SendStream, Self::RecvStream), ConnectionError>> + Send;
+
+    /// Sends an unreliable datagram
+    fn send_datagram(&self, data: Bytes) -> Result<(), SendDatagramError>;
+
+    /// Returns the maximum size of a datagram that can be sent, or `None` if
+    /// datagrams are not supported
+    fn max_datagram_size(&self) -> Option<usize>;
+
+    /// Derives keying material from the TLS session, as in RFC 5705
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> Result<(), ExportKeyingMaterialError>;
+}
+
+/// The sending side of a stream, written to with both the `tokio` and the
+/// `futures` I/O traits
+pub trait SendStream:
+    AsyncWrite + FuturesAsyncWrite + Debug + Unpin + Send + Sync + 'static
+{
+    /// Writes all of `bufs`, in order, to the stream
+    fn write_all_chunks(
+        &mut self,
+        bufs: &mut [Bytes],
+    ) -> impl Future<Output = Result<(), WriteError>> + Send;
+
+    /// Ends the stream once all data written is delivered
+    fn finish(&mut self) -> Result<(), WriteError>;
+
+    /// Abandons the stream with an error code
+    fn reset(&mut self, error_code: VarInt) -> Result<(), ClosedStream>;
+}
+
+/// The receiving side of a stream, read from with both the `tokio` and the
+/// `futures` I/O traits
+pub trait RecvStream: AsyncRead + FuturesAsyncRead + Debug + Unpin + Send + Sync + 'static {
+    /// Asks the peer to stop sending on the stream with an error code
+    fn stop(&mut self, error_code: VarInt) -> Result<(), ClosedStream>;
+}
+
+impl Transport for quinn::Connection {
+    type SendStream = quinn::SendStream;
+    type RecvStream = quinn::RecvStream;
+
+    fn open_uni(&self) -> impl Future<Output = Result<Self::SendStream, ConnectionError>> + Send {
+        quinn::Connection::open_uni(self)
+    }
+
+    fn open_bi(
+        &self,
+    ) -> impl Future<Output = Result<(Self::SendStream, Self::RecvStream), ConnectionError>> + Send
+    {
+        quinn::Connection::open_bi(self)
+    }
+
+    fn send_datagram(&self, data: Bytes) -> Result<(), SendDatagramError> {
+        quinn::Connection::send_datagram(self, data)
+    }
+
+    fn max_datagram_size(&self) -> Option<usize> {
+        quinn::Connection::max_datagram_size(self)
+    }
+
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> Result<(), ExportKeyingMaterialError> {
+        quinn::Connection::export_keying_material(self, output, label, context)
+    }
+}
+
+impl SendStream for quinn::SendStream {
+    fn write_all_chunks(
+        &mut self,
+        bufs: &mut [Bytes],
+    ) -> impl Future<Output = Result<(), WriteError>> + Send {
+        quinn::SendStream::write_all_chunks(self, bufs)
+    }
+
+    fn finish(&mut self) -> Result<(), WriteError> {
+        quinn::SendStream::finish(self).map_err(|_| WriteError::ClosedStream)
+    }
+
+    fn reset(&mut self, error_code: VarInt) -> Result<(), ClosedStream> {
+        quinn::SendStream::reset(self, error_code)
+    }
+}
+
+impl RecvStream for quinn::RecvStream {
+    fn stop(&mut self, error_code: VarInt) -> Result<(), ClosedStream> {
+        quinn::RecvStream::stop(self, error_code)
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    io::{Error as IoError, Write},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use tracing::warn;
+use tuic_common::log_file::LogFile;
+use uuid::Uuid;
+
+use crate::config::LogFileConfig;
+
+/// Writes one JSON line per finished relay task, for auditing and abuse
+/// handling
+pub struct AccessLog(LogFile);
+
+#[derive(Serialize)]
+pub struct AccessLogEntry<'a> {
+    pub conn_id: u32,
+    pub user: Option<Uuid>,
+    pub client: SocketAddr,
+    pub protocol: &'static str,
+    pub destination: &'a str,
+    pub tx: u64,
+    pub rx: u64,
+    #[serde(rename = "duration_ms", serialize_with = "serialize_duration_ms")]
+    pub duration: Duration,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    time: String,
+    #[serde(flatten)]
+    entry: &'a AccessLogEntry<'a>,
+}
+
+impl AccessLog {
+    pub fn open(cfg: &LogFileConfig) -> Result<Self, IoError> {
+        cfg.open().map(Self)
+    }
+
+    #[cfg(unix)]
+    pub fn log_file(&self) -> &LogFile {
+        &self.0
+    }
+
+    pub fn record(&self, entry: &AccessLogEntry) {
+        let line = Line {
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            entry,
+        };
+
+        let mut buf = match serde_json::to_vec(&line) {
+            Ok(buf) => buf,
+            Err(err) => {
+                warn!("failed to serialize access log entry: {err}");
+                return;
+            }
+        };
+        buf.push(b'\n');
+
+        if let Err(err) = self.0.clone().write_all(&buf) {
+            warn!("failed to write access log: {err}");
+        }
+    }
+}
+
+fn serialize_duration_ms<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_u64(duration.as_millis() as u64)
+}
@@ -0,0 +1,329 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use eyre::{Context, eyre};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::config::{BanConfig, RestfulConfig};
+
+/// Tracks authentication failures per source IP, to blunt token brute-forcing
+/// and scanning
+///
+/// Each failure makes the IP wait exponentially longer before it is allowed
+/// to connect again, until it reaches `max_failures` and gets banned for
+/// `ban_duration`. Failures are forgotten after `ban_duration` without a new
+/// one, or on a successful authentication
+pub struct BanList {
+    max_failures: u32,
+    backoff: Duration,
+    ban_duration: Duration,
+    entries: Mutex<Entries>,
+}
+
+struct Entries {
+    ips: HashMap<IpAddr, Entry>,
+    last_pruned: Instant,
+}
+
+struct Entry {
+    failures: u32,
+    last_failure: Instant,
+    /// Connections are refused until then
+    blocked_until: Instant,
+}
+
+#[derive(Serialize)]
+pub struct BanInfo {
+    pub failures: u32,
+    pub banned: bool,
+    /// Seconds left before connections are accepted again
+    pub blocked_secs: u64,
+}
+
+impl BanList {
+    pub fn new(cfg: &BanConfig) -> Self {
+        Self {
+            max_failures: cfg.max_failures.max(1),
+            backoff: cfg.backoff,
+            ban_duration: cfg.ban_duration,
+            entries: Mutex::new(Entries {
+                ips: HashMap::new(),
+                last_pruned: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn ban_duration(&self) -> Duration {
+        self.ban_duration
+    }
+
+    /// Returns `true` if connections from `ip` are currently refused
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        self.is_blocked_at(ip, Instant::now())
+    }
+
+    fn is_blocked_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .ips
+            .get(&ip.to_canonical())
+            .is_some_and(|entry| entry.blocked_until > now)
+    }
+
+    /// Records an authentication failure from `ip`. Returns `true` if it got
+    /// banned by this failure
+    pub fn record_failure(&self, ip: IpAddr) -> bool {
+        self.record_failure_at(ip, Instant::now())
+    }
+
+    fn record_failure_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+
+        if now.duration_since(entries.last_pruned) >= self.ban_duration {
+            let ban_duration = self.ban_duration;
+            entries.ips.retain(|_, entry| {
+                entry.blocked_until > now || now.duration_since(entry.last_failure) < ban_duration
+            });
+            entries.last_pruned = now;
+        }
+
+        let entry = entries.ips.entry(ip.to_canonical()).or_insert(Entry {
+            failures: 0,
+            last_failure: now,
+            blocked_until: now,
+        });
+
+        if now.duration_since(entry.last_failure) >= self.ban_duration {
+            entry.failures = 0;
+        }
+
+        entry.failures += 1;
+        entry.last_failure = now;
+
+        if entry.failures >= self.max_failures {
+            entry.blocked_until = now + self.ban_duration;
+            entry.failures == self.max_failures
+        } else {
+            let backoff = self
+                .backoff
+                .saturating_mul(1u32 << (entry.failures - 1).min(31))
+                .min(self.ban_duration);
+            entry.blocked_until = now + backoff;
+            false
+        }
+    }
+
+    /// Forgets the failures of `ip` after a successful authentication
+    pub fn record_success(&self, ip: IpAddr) {
+        self.entries.lock().unwrap().ips.remove(&ip.to_canonical());
+    }
+
+    /// Returns the IPs with failures not forgotten yet
+    pub fn list(&self) -> HashMap<IpAddr, BanInfo> {
+        self.list_at(Instant::now())
+    }
+
+    fn list_at(&self, now: Instant) -> HashMap<IpAddr, BanInfo> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .ips
+            .iter()
+            .filter(|(_, entry)| {
+                entry.blocked_until > now
+                    || now.duration_since(entry.last_failure) < self.ban_duration
+            })
+            .map(|(ip, entry)| {
+                let info = BanInfo {
+                    failures: entry.failures,
+                    banned: entry.failures >= self.max_failures && entry.blocked_until > now,
+                    blocked_secs: entry.blocked_until.saturating_duration_since(now).as_secs(),
+                };
+                (*ip, info)
+            })
+            .collect()
+    }
+
+    /// Clears the failures and ban of `ip`, or of all IPs if `None`. Returns
+    /// the number of IPs cleared
+    pub fn clear(&self, ip: Option<IpAddr>) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        match ip {
+            Some(ip) => entries.ips.remove(&ip.to_canonical()).map_or(0, |_| 1),
+            None => {
+                let count = entries.ips.len();
+                entries.ips.clear();
+                count
+            }
+        }
+    }
+}
+
+/// What `--bans` and `--unban` ask the running server
+#[derive(Clone, Debug)]
+pub enum BanCommand {
+    List,
+    /// Clears the ban of an IP, or of all IPs if `None`
+    Clear(Option<IpAddr>),
+}
+
+/// Runs a `BanCommand` against the RESTful API of the running server,
+/// returning the response body
+pub async fn run_command(restful: &RestfulConfig, cmd: &BanCommand) -> eyre::Result<String> {
+    let addr = match restful.addr {
+        addr if addr.ip().is_unspecified() => {
+            let ip = match addr {
+                SocketAddr::V4(_) => IpAddr::from([127, 0, 0, 1]),
+                SocketAddr::V6(_) => IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]),
+            };
+            SocketAddr::new(ip, addr.port())
+        }
+        addr => addr,
+    };
+
+    let (method, path, body) = match cmd {
+        BanCommand::List => ("GET", "/bans", String::new()),
+        BanCommand::Clear(ip) => (
+            "POST",
+            "/unban",
+            serde_json::json!({ "ip": ip }).to_string(),
+        ),
+    };
+
+    let mut stream = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("failed connecting to the RESTful API at {addr}"))?;
+
+    let request = format!(
+        "{method} {path} HTTP/1.1\r\nHost: {addr}\r\nAuthorization: Bearer \
+         {secret}\r\nContent-Type: application/json\r\nContent-Length: {len}\r\nConnection: \
+         close\r\n\r\n{body}",
+        secret = restful.secret,
+        len = body.len(),
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| eyre!("invalid response from the RESTful API"))?;
+    let status = head.split(' ').nth(1).unwrap_or_default();
+    if status != "200" {
+        return Err(eyre!("the RESTful API responded with status {status}"));
+    }
+
+    Ok(body.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    fn ban_list() -> BanList {
+        BanList::new(&BanConfig {
+            max_failures: 3,
+            backoff: Duration::from_secs(1),
+            ban_duration: Duration::from_secs(60),
+        })
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn threshold() {
+        let bans = ban_list();
+        let start = Instant::now();
+
+        // the wait doubles on each failure below the threshold
+        assert!(!bans.record_failure_at(IP, start));
+        assert!(bans.is_blocked_at(IP, start));
+        assert!(!bans.is_blocked_at(IP, start + secs(1)));
+
+        assert!(!bans.record_failure_at(IP, start + secs(1)));
+        assert!(bans.is_blocked_at(IP, start + secs(2)));
+        assert!(!bans.is_blocked_at(IP, start + secs(3)));
+
+        let info = &bans.list_at(start + secs(3))[&IP];
+        assert_eq!(info.failures, 2);
+        assert!(!info.banned);
+
+        // only the failure reaching the threshold reports the ban
+        assert!(bans.record_failure_at(IP, start + secs(3)));
+        assert!(!bans.record_failure_at(IP, start + secs(4)));
+        assert!(bans.is_blocked_at(IP, start + secs(63)));
+
+        let info = &bans.list_at(start + secs(4))[&IP];
+        assert_eq!(info.failures, 4);
+        assert!(info.banned);
+        assert_eq!(info.blocked_secs, 60);
+
+        // IPv4-mapped addresses count as the IPv4 address
+        let mapped = IpAddr::V6(Ipv6Addr::from([0, 0, 0, 0, 0, 0xffff, 0xc000, 0x0201]));
+        assert!(bans.is_blocked_at(mapped, start + secs(4)));
+    }
+
+    #[test]
+    fn expiry() {
+        let bans = ban_list();
+        let start = Instant::now();
+
+        for at in 0..3 {
+            bans.record_failure_at(IP, start + secs(at));
+        }
+        assert!(bans.is_blocked_at(IP, start + secs(61)));
+        assert!(!bans.is_blocked_at(IP, start + secs(62)));
+
+        // failures are forgotten `ban_duration` after the last one
+        assert!(bans.list_at(start + secs(62)).is_empty());
+        assert!(!bans.record_failure_at(IP, start + secs(62)));
+        assert_eq!(bans.list_at(start + secs(62))[&IP].failures, 1);
+
+        // and pruned on a later failure, from any IP
+        let other = IpAddr::from([192, 0, 2, 2]);
+        bans.record_failure_at(other, start + secs(200));
+        let entries = bans.entries.lock().unwrap();
+        assert!(!entries.ips.contains_key(&IP));
+        assert!(entries.ips.contains_key(&other));
+    }
+
+    #[test]
+    fn unban() {
+        let bans = ban_list();
+        let start = Instant::now();
+        let other = IpAddr::from([192, 0, 2, 2]);
+
+        for at in 0..3 {
+            bans.record_failure_at(IP, start + secs(at));
+        }
+        bans.record_failure_at(other, start + secs(3));
+
+        assert_eq!(bans.clear(Some(IP)), 1);
+        assert!(!bans.is_blocked_at(IP, start + secs(3)));
+        assert_eq!(bans.clear(Some(IP)), 0);
+
+        // the failures start over
+        assert!(!bans.record_failure_at(IP, start + secs(4)));
+        assert_eq!(bans.list_at(start + secs(4))[&IP].failures, 1);
+
+        bans.record_success(IP);
+        assert!(!bans.is_blocked_at(IP, start + secs(4)));
+
+        assert_eq!(bans.clear(None), 1);
+        assert!(bans.list_at(start + secs(4)).is_empty());
+    }
+}
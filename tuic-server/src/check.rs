@@ -0,0 +1,77 @@
+use eyre::{Context, eyre};
+use quinn::IdleTimeout;
+
+use crate::{
+    config::{Config, UpstreamKind},
+    dns, tls,
+};
+
+/// Validates the parts of the configuration that can only be checked at
+/// runtime, without binding any socket. Used by `--check`
+pub async fn check(cfg: &Config) -> eyre::Result<()> {
+    if cfg.users.is_empty() {
+        return Err(eyre!("no user is configured"));
+    }
+
+    validate(cfg)?;
+
+    if !cfg.tls.self_sign {
+        tls::load_cert_key(&cfg.tls.certificate, &cfg.tls.private_key)
+            .await
+            .with_context(|| {
+                format!(
+                    "invalid certificate `{}` or private key `{}`",
+                    cfg.tls.certificate.display(),
+                    cfg.tls.private_key.display()
+                )
+            })?;
+    }
+
+    IdleTimeout::try_from(cfg.quic.max_idle_time)
+        .map_err(|_| eyre!("invalid `quic.max_idle_time`"))?;
+
+    if cfg.quic.min_mtu > cfg.quic.initial_mtu {
+        return Err(eyre!(
+            "`quic.min_mtu` ({}) is larger than `quic.initial_mtu` ({})",
+            cfg.quic.min_mtu,
+            cfg.quic.initial_mtu
+        ));
+    }
+
+    dns::build_resolver(&cfg.dns).context("invalid `dns` config")?;
+
+    Ok(())
+}
+
+/// The largest `relay_buffer_size` accepted, as two buffers are allocated for
+/// every TCP relay
+const MAX_RELAY_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+
+/// Rejects configured values the server can't run with. Unlike `check`, it
+/// needs no I/O, so it also runs on startup
+pub fn validate(cfg: &Config) -> eyre::Result<()> {
+    if !(1..=MAX_RELAY_BUFFER_SIZE).contains(&cfg.relay_buffer_size) {
+        return Err(eyre!(
+            "`relay_buffer_size` ({}) must be between 1 and {MAX_RELAY_BUFFER_SIZE} bytes",
+            cfg.relay_buffer_size
+        ));
+    }
+
+    if let Some(upstream) = &cfg.outbound.upstream
+        && let Some((username, password)) = upstream.credentials()
+        && matches!(upstream.kind, UpstreamKind::Socks5)
+        && (username.len() > u8::MAX as usize || password.len() > u8::MAX as usize)
+    {
+        return Err(eyre!(
+            "`outbound.upstream` SOCKS5 username and password must be at most 255 bytes"
+        ));
+    }
+
+    if cfg.outbound.io_uring && !cfg!(all(target_os = "linux", feature = "io-uring")) {
+        return Err(eyre!(
+            "`outbound.io_uring` requires Linux and building with the `io-uring` feature"
+        ));
+    }
+
+    Ok(())
+}
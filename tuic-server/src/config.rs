@@ -1,25 +1,46 @@
-use std::{collections::HashMap, env::ArgsOs, net::SocketAddr, path::PathBuf, time::Duration};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fmt::{Display, Formatter, Result as FmtResult},
+    io::Error as IoError,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
 
 use educe::Educe;
 use figment::{
     Figment,
-    providers::{Format, Serialized, Toml},
+    providers::{Format, Serialized, Toml, Yaml},
 };
-use lexopt::{Arg, Parser};
+use lexopt::{Arg, Parser, ValueExt};
 use serde::{Deserialize, Serialize};
 use tracing::{level_filters::LevelFilter, warn};
+use tracing_subscriber::filter::Targets;
+use tuic::ShareLink;
+use tuic_common::log_file::LogFile;
 use uuid::Uuid;
 
 use crate::{
+    ban::BanCommand,
+    log_level,
     old_config::{ConfigError, OldConfig},
-    utils::CongestionController,
+    utils::{CongestionController, PortRange},
 };
 
+pub use tuic_common::log_file::LogRotation;
+
 #[derive(Deserialize, Serialize, Educe)]
 #[educe(Default)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
-    pub log_level: LogLevel,
+    pub log_level: LogFilter,
+    pub log_format: LogFormat,
+    #[educe(Default = None)]
+    pub log_file: Option<LogFileConfig>,
+    #[educe(Default = None)]
+    pub access_log: Option<LogFileConfig>,
     #[educe(Default(expression = "[::]:443".parse().unwrap()))]
     pub server: SocketAddr,
     pub users: HashMap<Uuid, String>,
@@ -28,24 +49,62 @@ pub struct Config {
     #[educe(Default = "./data.toml")]
     pub persistent_data: PathBuf,
 
+    #[educe(Default = None)]
+    pub traffic: Option<TrafficConfig>,
+
     #[educe(Default = None)]
     pub restful: Option<RestfulConfig>,
 
+    #[educe(Default = None)]
+    pub subscription: Option<SubscriptionConfig>,
+
+    #[educe(Default = None)]
+    pub masquerade: Option<MasqueradeConfig>,
+
+    /// How connections negotiating each ALPN protocol are handled, offered
+    /// besides `tls.alpn`
+    pub alpn_routes: HashMap<String, AlpnRoute>,
+
     pub quic: QuicConfig,
 
+    pub outbound: OutboundConfig,
+
+    pub dns: DnsConfig,
+
+    pub runtime: RuntimeConfig,
+
     #[educe(Default = true)]
     pub udp_relay_ipv6: bool,
 
     #[educe(Default = false)]
     pub zero_rtt_handshake: bool,
 
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_secs(60)))]
+    pub zero_rtt_replay_window: Duration,
+
     #[educe(Default = true)]
     pub dual_stack: bool,
 
+    /// How long a client has to authenticate before the connection is
+    /// closed, along with the tasks queued meanwhile
     #[serde(with = "humantime_serde")]
     #[educe(Default(expression = Duration::from_millis(3000)))]
     pub auth_timeout: Duration,
 
+    /// Tasks received before authentication held until it completes, 0 for
+    /// unlimited. The connection is closed once exceeded
+    #[educe(Default = 64)]
+    pub max_pre_auth_tasks: usize,
+
+    /// Bytes of datagrams received before authentication held until it
+    /// completes, 0 for unlimited. The connection is closed once exceeded
+    #[educe(Default = 1048576)]
+    pub max_pre_auth_bytes: usize,
+
+    /// What to do with an authentication on an authenticated connection
+    pub duplicate_auth: DuplicateAuthPolicy,
+
     #[serde(with = "humantime_serde")]
     #[educe(Default(expression = Duration::from_millis(3000)))]
     pub task_negotiation_timeout: Duration,
@@ -58,12 +117,94 @@ pub struct Config {
     #[educe(Default(expression = Duration::from_millis(15000)))]
     pub gc_lifetime: Duration,
 
+    #[educe(Default = 256)]
+    pub max_pending_packets: usize,
+
+    #[educe(Default = 67108864)]
+    pub max_reassembly_memory: usize,
+
+    #[educe(Default = 64)]
+    pub reorder_window: u16,
+
     #[educe(Default = 1500)]
     pub max_external_packet_size: usize,
 
+    #[educe(Default = None)]
+    pub padding: Option<PaddingConfig>,
+
+    #[educe(Default = None)]
+    pub compression: Option<CompressionConfig>,
+
+    #[educe(Default = None)]
+    pub ban: Option<BanConfig>,
+
+    #[educe(Default = None)]
+    pub connection_limit: Option<ConnectionLimitConfig>,
+
+    /// Concurrent connections server-wide, 0 for unlimited
+    #[educe(Default = 0)]
+    pub max_connections: u32,
+
+    /// Concurrent TCP relays and UDP sessions per connection, 0 for unlimited
+    #[educe(Default = 0)]
+    pub max_tasks_per_connection: u32,
+
+    /// Most bytes a client can upload or download in a speedtest, 0 to
+    /// disable speedtests
+    #[educe(Default = 104857600)]
+    pub max_speedtest_size: u64,
+
+    /// How long a TCP relay or a UDP session may go without traffic before
+    /// it is closed, whatever the QUIC keep-alives. 0 disables it
     #[serde(with = "humantime_serde")]
     #[educe(Default(expression = Duration::from_millis(60000)))]
     pub stream_timeout: Duration,
+
+    /// Size of each of the two buffers of a TCP relay, in bytes
+    #[educe(Default = 8192)]
+    pub relay_buffer_size: usize,
+
+    /// Concurrent connections per user, 0 for unlimited
+    #[educe(Default = 0)]
+    pub max_devices: u32,
+
+    pub device_policy: DevicePolicy,
+
+    /// Whether the connections of users are closed once they expire, instead
+    /// of only refusing new ones
+    #[educe(Default = false)]
+    pub terminate_expired: bool,
+
+    /// Whether users can relay TCP
+    #[educe(Default = true)]
+    pub allow_tcp: bool,
+
+    /// Whether users can relay UDP
+    #[educe(Default = true)]
+    pub allow_udp: bool,
+
+    /// Destination ports users can relay to
+    pub ports: PortPolicy,
+
+    /// Per-user overrides of the settings above
+    pub user_settings: HashMap<Uuid, UserSettings>,
+
+    /// Set by `--check`, only validate the configuration and exit
+    #[serde(skip)]
+    #[educe(Default = false)]
+    pub check: bool,
+
+    /// Set by `--share-link`, only print the share link of each user with
+    /// the given host and exit
+    #[serde(skip)]
+    #[educe(Default = None)]
+    pub share_link: Option<String>,
+
+    /// Set by `--bans` and `--unban`, only ask the running server through the
+    /// RESTful API and exit
+    #[serde(skip)]
+    #[educe(Default = None)]
+    pub ban_command: Option<BanCommand>,
 }
 
 #[derive(Deserialize, Serialize, Educe)]
@@ -75,6 +216,13 @@ pub struct TlsConfig {
     pub private_key: PathBuf,
     #[educe(Default(expression = Vec::new()))]
     pub alpn: Vec<String>,
+    /// Put in front of the UUID in the TLS exporter label authentication
+    /// tokens are derived with. Empty keeps the protocol default, must match
+    /// the clients
+    pub exporter_label: String,
+    /// Put in front of the password in the TLS exporter context
+    /// authentication tokens are derived with, like `exporter_label`
+    pub exporter_context: String,
 }
 
 #[derive(Deserialize, Serialize, Educe)]
@@ -95,6 +243,18 @@ pub struct QuicConfig {
     #[educe(Default = true)]
     pub pmtu: bool,
 
+    /// `SO_SNDBUF` of the endpoint socket, `None` keeping the system default
+    #[educe(Default = None)]
+    pub send_buffer_size: Option<usize>,
+
+    /// `SO_RCVBUF` of the endpoint socket, `None` keeping the system default
+    #[educe(Default = None)]
+    pub recv_buffer_size: Option<usize>,
+
+    /// DSCP value of the QUIC packets, `None` leaving them unmarked
+    #[educe(Default = None)]
+    pub dscp: Option<u8>,
+
     #[educe(Default = 16777216)]
     pub send_window: u64,
 
@@ -104,7 +264,253 @@ pub struct QuicConfig {
     #[serde(with = "humantime_serde")]
     #[educe(Default(expression = Duration::from_millis(10000)))]
     pub max_idle_time: Duration,
+
+    pub transport: TransportConfig,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(deny_unknown_fields)]
+pub struct TransportConfig {
+    /// Bytes the client may send without acknowledgement on all streams
+    /// together, unlimited if `None`
+    #[educe(Default = None)]
+    pub connection_receive_window: Option<u64>,
+
+    /// Bidirectional streams the client may open at once, raised on demand
+    #[educe(Default = 32)]
+    pub max_concurrent_bi_streams: u32,
+
+    /// Unidirectional streams the client may open at once, raised on demand
+    #[educe(Default = 32)]
+    pub max_concurrent_uni_streams: u32,
+
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_millis(333)))]
+    pub initial_rtt: Duration,
+
+    /// Disabled if zero
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::ZERO))]
+    pub keep_alive_interval: Duration,
+
+    #[educe(Default = 1250000)]
+    pub datagram_receive_buffer: usize,
+
+    #[educe(Default = 1048576)]
+    pub datagram_send_buffer: usize,
+}
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(deny_unknown_fields)]
+pub struct OutboundConfig {
+    #[educe(Default = None)]
+    pub bind_ipv4: Option<Ipv4Addr>,
+
+    #[educe(Default = None)]
+    pub bind_ipv6: Option<Ipv6Addr>,
+
+    #[educe(Default = None)]
+    pub interface: Option<String>,
+
+    /// DSCP value of the relayed TCP connections and UDP packets
+    #[educe(Default = None)]
+    pub dscp: Option<u8>,
+
+    #[educe(Default = None)]
+    pub upstream: Option<UpstreamConfig>,
+
+    pub strategy: OutboundStrategy,
+
+    /// Destinations whose relayed TCP connections start with a PROXY protocol
+    /// v2 header carrying the address of the client
+    #[educe(Default(expression = Vec::new()))]
+    pub proxy_protocol: Vec<SocketAddr>,
+
+    /// Relay the outbound TCP streams on io_uring runtimes, one per CPU core.
+    /// Requires the `io-uring` feature and Linux
+    #[educe(Default = false)]
+    pub io_uring: bool,
+}
+
+impl OutboundConfig {
+    /// Returns the configured source address matching the family of `addr`
+    pub fn bind_ip(&self, addr: &SocketAddr) -> Option<IpAddr> {
+        match addr {
+            SocketAddr::V4(_) => self.bind_ipv4.map(IpAddr::V4),
+            SocketAddr::V6(_) => self.bind_ipv6.map(IpAddr::V6),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UserSettings {
+    pub max_devices: Option<u32>,
+
+    pub device_policy: Option<DevicePolicy>,
+
+    /// When the user stops being able to authenticate, as an RFC 3339
+    /// timestamp
+    #[serde(default, with = "humantime_serde::option")]
+    pub expires_at: Option<SystemTime>,
+
+    pub allow_tcp: Option<bool>,
+
+    pub allow_udp: Option<bool>,
+
+    pub ports: Option<PortPolicy>,
+
+    /// The password of the user before the one in `users`, still accepted
+    /// until `previous_password_expires_at` so clients can be moved to the
+    /// new one gradually
+    pub previous_password: Option<String>,
+
+    /// When the previous password stops being accepted, as an RFC 3339
+    /// timestamp. Never accepted if not set
+    #[serde(default, with = "humantime_serde::option")]
+    pub previous_password_expires_at: Option<SystemTime>,
+}
+
+/// Destination ports allowed for relaying
+#[derive(Deserialize, Serialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PortPolicy {
+    /// Only these ports are allowed, all ports if empty
+    #[serde(default)]
+    pub allow: Vec<PortRange>,
+
+    /// These ports are denied, even if in `allow`
+    #[serde(default)]
+    pub deny: Vec<PortRange>,
+}
+
+impl PortPolicy {
+    pub fn is_allowed(&self, port: u16) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|range| range.contains(port)))
+            && !self.deny.iter().any(|range| range.contains(port))
+    }
+}
+
+/// What to do with a new connection of a user over `max_devices`
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+#[derive(Educe)]
+#[educe(Default)]
+pub enum DevicePolicy {
+    /// Refuses the new connection
+    #[educe(Default)]
+    Reject,
+    /// Closes the oldest connection of the user
+    KickOldest,
+}
+
+/// What to do with an authentication on an already authenticated connection
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[derive(Educe)]
+#[educe(Default)]
+pub enum DuplicateAuthPolicy {
+    /// Closes the connection
+    #[educe(Default)]
+    Reject,
+    /// Drops the authentication, keeping the connection as it is
+    Ignore,
+    /// Validates the authentication again, switching the connection to its
+    /// user. The connection is closed if it fails
+    Rekey,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+#[derive(Educe)]
+#[educe(Default)]
+pub enum OutboundStrategy {
+    #[educe(Default)]
+    Auto,
+    PreferIpv4,
+    PreferIpv6,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    #[serde(rename = "type")]
+    pub kind: UpstreamKind,
+    #[educe(Default(expression = "127.0.0.1:1080".parse().unwrap()))]
+    pub addr: SocketAddr,
+    #[educe(Default = None)]
+    pub username: Option<String>,
+    #[educe(Default = None)]
+    pub password: Option<String>,
+}
+
+impl UpstreamConfig {
+    /// Returns the username and password pair if both are set
+    pub fn credentials(&self) -> Option<(&str, &str)> {
+        self.username.as_deref().zip(self.password.as_deref())
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+#[derive(Educe)]
+#[educe(Default)]
+pub enum UpstreamKind {
+    #[educe(Default)]
+    Socks5,
+    Http,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(deny_unknown_fields)]
+pub struct DnsConfig {
+    #[educe(Default(expression = Vec::new()))]
+    pub servers: Vec<DnsServerConfig>,
+
+    #[educe(Default = 1024)]
+    pub cache_size: usize,
+
+    #[serde(default, with = "humantime_serde")]
+    #[educe(Default = None)]
+    pub min_ttl: Option<Duration>,
+
+    #[serde(default, with = "humantime_serde")]
+    #[educe(Default = None)]
+    pub max_ttl: Option<Duration>,
+
+    #[serde(default, with = "humantime_serde")]
+    #[educe(Default = None)]
+    pub negative_ttl: Option<Duration>,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(deny_unknown_fields)]
+pub struct DnsServerConfig {
+    #[educe(Default(expression = "1.1.1.1:53".parse().unwrap()))]
+    pub addr: SocketAddr,
+    pub protocol: DnsProtocol,
+    #[educe(Default = None)]
+    pub tls_name: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+#[derive(Educe)]
+#[educe(Default)]
+pub enum DnsProtocol {
+    #[educe(Default)]
+    Udp,
+    Tcp,
+    Tls,
+    Https,
 }
+
 #[derive(Deserialize, Serialize, Educe)]
 #[educe(Default)]
 #[serde(deny_unknown_fields)]
@@ -112,6 +518,12 @@ pub struct CongestionControlConfig {
     pub controller: CongestionController,
     #[educe(Default = 1048576)]
     pub initial_window: u64,
+    /// Only used by `new_reno`
+    #[educe(Default = None)]
+    pub loss_reduction_factor: Option<f32>,
+    /// Sending rate in Mbit/s, required by `brutal`
+    #[educe(Default = 0)]
+    pub bandwidth: u64,
 }
 
 #[derive(Deserialize, Serialize, Educe, Clone)]
@@ -126,7 +538,196 @@ pub struct RestfulConfig {
     pub maximum_clients_per_user: u64,
 }
 
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(deny_unknown_fields)]
+pub struct PaddingConfig {
+    /// Commands shorter than this are padded
+    #[educe(Default = 256)]
+    pub threshold: usize,
+    /// The padding is of a random length up to this
+    #[educe(Default = 128)]
+    pub max_len: u16,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// The zstd compression level, from 1 (fastest) to 22
+    #[educe(Default = 3)]
+    pub level: i32,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(deny_unknown_fields)]
+pub struct TrafficConfig {
+    /// How often the traffic counters are written to `persistent_data`
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_secs(60)))]
+    pub flush_interval: Duration,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(deny_unknown_fields)]
+pub struct BanConfig {
+    /// Authentication failures before an IP gets banned
+    #[educe(Default = 5)]
+    pub max_failures: u32,
+    /// Wait after the first failure, doubled on each following one
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_secs(1)))]
+    pub backoff: Duration,
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_secs(600)))]
+    pub ban_duration: Duration,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionLimitConfig {
+    /// New connections per second per IP, 0 for unlimited
+    #[educe(Default = 10)]
+    pub rate: u32,
+    /// New connections allowed at once per IP, on top of the rate
+    #[educe(Default = 20)]
+    pub burst: u32,
+    /// Concurrent connections per IP, 0 for unlimited
+    #[educe(Default = 64)]
+    pub max_per_ip: u32,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(deny_unknown_fields)]
+pub struct SubscriptionConfig {
+    #[educe(Default(expression = "[::]:8444".parse().unwrap()))]
+    pub addr: SocketAddr,
+    /// The domain (or IP) of the server put in the generated client config
+    #[educe(Default = "example.com")]
+    pub host: String,
+    /// Serve the subscription over HTTPS, with the certificate of the server
+    #[educe(Default = true)]
+    pub tls: bool,
+    /// The name of the outbound in the generated client config
+    #[educe(Default = "tuic")]
+    pub name: String,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(deny_unknown_fields)]
+pub struct MasqueradeConfig {
+    /// The HTTP/3 web server connections of non-TUIC clients are relayed to
+    #[educe(Default(expression = "127.0.0.1:8443".parse().unwrap()))]
+    pub addr: SocketAddr,
+    /// The server name sent to the web server
+    #[educe(Default = "localhost")]
+    pub server_name: String,
+    /// ALPN protocols only served by the web server, offered besides
+    /// `tls.alpn`
+    #[educe(Default(expression = Vec::new()))]
+    pub alpn: Vec<String>,
+}
+
+/// How connections negotiating an ALPN protocol are handled
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum AlpnRoute {
+    /// Handled as TUIC
+    Tuic,
+    /// Relayed to a local QUIC service
+    Forward {
+        addr: SocketAddr,
+        /// The server name sent to the service, `localhost` if not set
+        #[serde(default)]
+        server_name: Option<String>,
+    },
+}
+
 impl Config {
+    /// Returns the device limit and policy of the user `uuid`
+    pub fn device_limit(&self, uuid: &Uuid) -> (u32, DevicePolicy) {
+        let settings = self.user_settings.get(uuid);
+        (
+            settings
+                .and_then(|settings| settings.max_devices)
+                .unwrap_or(self.max_devices),
+            settings
+                .and_then(|settings| settings.device_policy)
+                .unwrap_or(self.device_policy),
+        )
+    }
+
+    /// Returns the previous password of the user `uuid`, if it is still
+    /// accepted
+    pub fn previous_password(&self, uuid: &Uuid) -> Option<&str> {
+        let settings = self.user_settings.get(uuid)?;
+        let expires_at = settings.previous_password_expires_at?;
+
+        if SystemTime::now() < expires_at {
+            settings.previous_password.as_deref()
+        } else {
+            None
+        }
+    }
+
+    /// Returns whether the user can relay TCP
+    pub fn allow_tcp(&self, uuid: &Uuid) -> bool {
+        self.user_settings
+            .get(uuid)
+            .and_then(|settings| settings.allow_tcp)
+            .unwrap_or(self.allow_tcp)
+    }
+
+    /// Returns whether the user can relay UDP
+    pub fn allow_udp(&self, uuid: &Uuid) -> bool {
+        self.user_settings
+            .get(uuid)
+            .and_then(|settings| settings.allow_udp)
+            .unwrap_or(self.allow_udp)
+    }
+
+    /// Returns the destination port policy of the user
+    pub fn port_policy(&self, uuid: &Uuid) -> &PortPolicy {
+        self.user_settings
+            .get(uuid)
+            .and_then(|settings| settings.ports.as_ref())
+            .unwrap_or(&self.ports)
+    }
+
+    /// Returns the share link of each user, for clients connecting to `host`
+    pub fn share_links(&self, host: &str) -> Vec<ShareLink> {
+        self.users
+            .iter()
+            .map(|(uuid, password)| self.share_link(host, *uuid, password))
+            .collect()
+    }
+
+    /// Returns the share link of a user, for clients connecting to `host`
+    pub fn share_link(&self, host: &str, uuid: Uuid, password: &str) -> ShareLink {
+        let mut link = ShareLink::new(
+            uuid,
+            password.to_owned(),
+            host.to_owned(),
+            self.server.port(),
+        );
+        link.alpn = self.tls.alpn.clone();
+        // Brutal needs the client to declare its own bandwidth, so it is left out
+        link.congestion_control = match self.quic.congestion_control.controller {
+            CongestionController::Bbr => Some("bbr"),
+            CongestionController::Cubic => Some("cubic"),
+            CongestionController::NewReno => Some("new_reno"),
+            CongestionController::Brutal => None,
+        }
+        .map(str::to_owned);
+        link.allow_insecure = self.tls.self_sign;
+        link
+    }
+
     pub fn full_example() -> Self {
         Self {
             users: {
@@ -151,6 +752,7 @@ impl From<OldConfig> for Config {
                 certificate: value.certificate,
                 private_key: value.private_key,
                 alpn: value.alpn,
+                ..Default::default()
             },
             udp_relay_ipv6: value.udp_relay_ipv6,
             zero_rtt_handshake: value.zero_rtt_handshake,
@@ -168,11 +770,12 @@ impl From<OldConfig> for Config {
             } else {
                 None
             },
-            log_level: value.log_level.unwrap_or_default(),
+            log_level: value.log_level.map(LogFilter::from).unwrap_or_default(),
             quic: QuicConfig {
                 congestion_control: CongestionControlConfig {
                     controller: value.congestion_control,
                     initial_window: value.initial_window.unwrap_or(1048576),
+                    ..Default::default()
                 },
                 initial_mtu: value.initial_mtu,
                 min_mtu: value.min_mtu,
@@ -181,6 +784,7 @@ impl From<OldConfig> for Config {
                 send_window: value.send_window,
                 receive_window: value.receive_window,
                 max_idle_time: value.max_idle_time,
+                ..Default::default()
             },
             ..Default::default()
         }
@@ -200,6 +804,117 @@ pub enum LogLevel {
     Error,
     Off,
 }
+/// `log_level`, either a single level for the crates of TUIC, the others
+/// being logged at `info`, or `tracing` filter directives, e.g.
+/// `info,quinn=warn,tuic_quinn=debug`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct LogFilter {
+    spec: String,
+    targets: Targets,
+}
+
+impl LogFilter {
+    pub fn targets(&self) -> &Targets {
+        &self.targets
+    }
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        LogLevel::default().into()
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let targets = match s.parse::<LevelFilter>() {
+            Ok(level) => log_level::filter(level),
+            Err(_) => s
+                .parse()
+                .map_err(|err| format!("invalid log filter `{s}`: {err}"))?,
+        };
+
+        Ok(Self {
+            spec: s.to_owned(),
+            targets,
+        })
+    }
+}
+
+impl TryFrom<String> for LogFilter {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<LogFilter> for String {
+    fn from(filter: LogFilter) -> Self {
+        filter.spec
+    }
+}
+
+impl Display for LogFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str(&self.spec)
+    }
+}
+
+impl From<LogLevel> for LogFilter {
+    fn from(level: LogLevel) -> Self {
+        let level = LevelFilter::from(level);
+        Self {
+            spec: level.to_string(),
+            targets: log_level::filter(level),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+#[derive(Educe)]
+#[educe(Default)]
+pub enum LogFormat {
+    #[educe(Default)]
+    Text,
+    Json,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeConfig {
+    /// Number of worker threads, 0 for one per CPU core
+    #[educe(Default = 0)]
+    pub worker_threads: usize,
+    /// CPU cores the runtime threads are pinned to in turn, empty to not pin
+    #[educe(Default(expression = Vec::new()))]
+    pub cpu_affinity: Vec<usize>,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(deny_unknown_fields)]
+pub struct LogFileConfig {
+    #[educe(Default = "./tuic-server.log")]
+    pub path: PathBuf,
+    pub rotation: LogRotation,
+    #[educe(Default = 10485760)]
+    pub max_size: u64,
+    #[educe(Default = 7)]
+    pub max_files: usize,
+}
+
+impl LogFileConfig {
+    pub fn open(&self) -> Result<LogFile, IoError> {
+        LogFile::open(&self.path, self.rotation, self.max_size, self.max_files)
+    }
+}
+
 impl From<LogLevel> for LevelFilter {
     fn from(value: LogLevel) -> Self {
         match value {
@@ -213,9 +928,14 @@ impl From<LogLevel> for LevelFilter {
     }
 }
 
-pub async fn parse_config(args: ArgsOs) -> Result<Config, ConfigError> {
+pub async fn parse_config(args: impl IntoIterator<Item = OsString>) -> Result<Config, ConfigError> {
     let mut parser = Parser::from_iter(args);
     let mut path = None;
+    let mut format = None;
+    let mut overrides = Vec::new();
+    let mut check = false;
+    let mut share_link = None;
+    let mut ban_command = None;
 
     while let Some(arg) = parser.next()? {
         match arg {
@@ -226,6 +946,27 @@ pub async fn parse_config(args: ArgsOs) -> Result<Config, ConfigError> {
                     return Err(ConfigError::Argument(arg.unexpected()));
                 }
             }
+            Arg::Short('f') | Arg::Long("format") => {
+                format = Some(parser.value()?.parse::<ConfigFormat>()?);
+            }
+            Arg::Long("check") => check = true,
+            Arg::Long("share-link") => share_link = Some(parser.value()?.string()?),
+            Arg::Long("bans") => ban_command = Some(BanCommand::List),
+            Arg::Long("unban") => {
+                let value = parser.value()?.string()?;
+                let ip = match value.as_str() {
+                    "all" => None,
+                    ip => Some(ip.parse().map_err(|_| ConfigError::InvalidIp(value))?),
+                };
+                ban_command = Some(BanCommand::Clear(ip));
+            }
+            Arg::Long("set") => {
+                let value = parser.value()?.string()?;
+                match value.split_once('=') {
+                    Some((key, value)) => overrides.push((key.to_owned(), value.to_owned())),
+                    None => return Err(ConfigError::InvalidOverride(value)),
+                }
+            }
             Arg::Short('v') | Arg::Long("version") => {
                 return Err(ConfigError::Version(env!("CARGO_PKG_VERSION")));
             }
@@ -246,16 +987,70 @@ pub async fn parse_config(args: ArgsOs) -> Result<Config, ConfigError> {
     if path.is_none() {
         return Err(ConfigError::NoConfig);
     }
-    let path = path.unwrap().to_string_lossy().to_string();
-    let config = if path.ends_with(".toml") || std::env::var("TUIC_FORCE_TOML").is_ok() {
-        Figment::from(Serialized::defaults(Config::default()))
-            .merge(Toml::file(path))
-            .extract()
-            .unwrap()
-    } else {
-        let config_text = tokio::fs::read(&path).await?;
-        let config: OldConfig = serde_json::from_slice(&config_text)?;
-        config.into()
+    let path = PathBuf::from(path.unwrap());
+    let format = match format {
+        Some(format) => format,
+        None if std::env::var("TUIC_FORCE_TOML").is_ok() => ConfigFormat::Toml,
+        None => ConfigFormat::from_path(&path).unwrap_or(ConfigFormat::Json),
+    };
+    let mut figment = match format {
+        ConfigFormat::Toml => {
+            Figment::from(Serialized::defaults(Config::default())).merge(Toml::file(path))
+        }
+        ConfigFormat::Yaml => {
+            Figment::from(Serialized::defaults(Config::default())).merge(Yaml::file(path))
+        }
+        ConfigFormat::Json => {
+            let config_text = tokio::fs::read(&path).await?;
+            let config: OldConfig = serde_json::from_slice(&config_text)?;
+            Figment::from(Serialized::defaults(Config::from(config)))
+        }
     };
+
+    // values given by `--set` are parsed like environment variables: booleans,
+    // numbers, arrays and dicts are recognized, anything else is a string
+    for (key, value) in overrides {
+        let value = value
+            .parse::<figment::value::Value>()
+            .unwrap_or_else(|never| match never {});
+        figment = figment.merge(Serialized::default(&key, value));
+    }
+
+    let mut config: Config = figment.extract().map_err(Box::new)?;
+    config.check = check;
+    config.share_link = share_link;
+    config.ban_command = ban_command;
     Ok(config)
 }
+
+/// Format of the configuration file
+#[derive(Clone, Copy)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    /// The legacy JSON configuration
+    Json,
+}
+
+impl ConfigFormat {
+    /// Detects the format from the file extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension()?.to_str()?.parse().ok()
+    }
+}
+
+impl FromStr for ConfigFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("toml") {
+            Ok(Self::Toml)
+        } else if s.eq_ignore_ascii_case("yaml") || s.eq_ignore_ascii_case("yml") {
+            Ok(Self::Yaml)
+        } else if s.eq_ignore_ascii_case("json") {
+            Ok(Self::Json)
+        } else {
+            Err("invalid config format, available options: toml, yaml, json")
+        }
+    }
+}
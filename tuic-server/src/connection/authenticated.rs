@@ -1,7 +1,10 @@
 use std::{
     fmt::{Display, Formatter, Result as FmtResult},
     ops::Deref,
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
 };
 
 use arc_swap::ArcSwap;
@@ -15,6 +18,9 @@ struct AuthenticatedInner {
     /// uuid that waiting for auth
     uuid: ArcSwap<Option<Uuid>>,
     tx: AsyncRwLock<Option<Sender<()>>>,
+    /// Tasks waiting for auth, and the bytes they hold
+    queued_tasks: AtomicUsize,
+    queued_bytes: AtomicUsize,
 }
 
 // The whole thing below is just an observable boolean
@@ -25,6 +31,8 @@ impl Authenticated {
         Self(Arc::new(AuthenticatedInner {
             uuid: ArcSwap::new(None.into()),
             tx: AsyncRwLock::new(Some(tx)),
+            queued_tasks: AtomicUsize::new(0),
+            queued_bytes: AtomicUsize::new(0),
         }))
     }
 
@@ -57,6 +65,41 @@ impl Authenticated {
         }
         // If the `tx` already `None`, that's meaning `set` had been invoked
     }
+
+    /// Counts a task holding `bytes` as waiting for auth until the returned
+    /// guard is dropped
+    pub fn enqueue(&self, bytes: usize) -> Queued {
+        self.0.queued_tasks.fetch_add(1, Ordering::Relaxed);
+        self.0.queued_bytes.fetch_add(bytes, Ordering::Relaxed);
+
+        Queued {
+            inner: self.0.clone(),
+            bytes,
+        }
+    }
+
+    /// Returns the number of tasks waiting for auth and the bytes they hold
+    pub fn queued(&self) -> (usize, usize) {
+        (
+            self.0.queued_tasks.load(Ordering::Relaxed),
+            self.0.queued_bytes.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// A task counted as waiting for auth
+pub struct Queued {
+    inner: Arc<AuthenticatedInner>,
+    bytes: usize,
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.inner.queued_tasks.fetch_sub(1, Ordering::Relaxed);
+        self.inner
+            .queued_bytes
+            .fetch_sub(self.bytes, Ordering::Relaxed);
+    }
 }
 
 impl Display for Authenticated {
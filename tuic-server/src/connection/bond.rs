@@ -0,0 +1,108 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+};
+
+use tokio::sync::RwLock as AsyncRwLock;
+use uuid::Uuid;
+
+use super::{Connection, UdpSession};
+
+/// Words of the bit set of the packet IDs seen in a UDP session, covering the
+/// whole packet ID space
+const SEEN_WORDS: usize = (u16::MAX as usize + 1) / 64;
+
+/// The bonds of the clients in multipath mode, by user and bond ID
+#[derive(Default)]
+pub struct Bonds(Mutex<HashMap<(Uuid, u64), Weak<Bond>>>);
+
+impl Bonds {
+    /// Returns the bond `bond_id` of user `uuid`, creating it if none of its
+    /// connections is left
+    pub(super) fn join(&self, uuid: Uuid, bond_id: u64) -> Arc<Bond> {
+        let mut bonds = self.0.lock().unwrap_or_else(|err| err.into_inner());
+
+        if let Some(bond) = bonds.get(&(uuid, bond_id)).and_then(Weak::upgrade) {
+            return bond;
+        }
+
+        bonds.retain(|_, bond| bond.strong_count() > 0);
+
+        let bond = Arc::new(Bond::default());
+        bonds.insert((uuid, bond_id), Arc::downgrade(&bond));
+        bond
+    }
+}
+
+/// Connections of a client over different network paths. They share their UDP
+/// sessions, so a packet sent over several of them is relayed only once
+#[derive(Default)]
+pub struct Bond {
+    pub(super) udp_sessions: AsyncRwLock<HashMap<u16, Weak<UdpSession>>>,
+    /// The packet IDs seen recently in each UDP session, as bit sets
+    seen: Mutex<HashMap<u16, Box<[u64]>>>,
+    /// The connection the latest new packet arrived on, which replies are sent
+    /// through
+    latest: Mutex<Option<Connection>>,
+}
+
+impl Bond {
+    /// Records packet `pkt_id` of UDP session `assoc_id` as seen through
+    /// `conn`, returning `false` if it already was. The IDs half the ID space
+    /// ahead are forgotten along the way, so they can be reused once the
+    /// sequence wraps around
+    pub(super) fn first_seen(&self, conn: &Connection, assoc_id: u16, pkt_id: u16) -> bool {
+        let mut seen = self.seen.lock().unwrap_or_else(|err| err.into_inner());
+        let bits = seen
+            .entry(assoc_id)
+            .or_insert_with(|| vec![0; SEEN_WORDS].into_boxed_slice());
+
+        let word = pkt_id as usize / 64;
+        let bit = 1 << (pkt_id % 64);
+        bits[(word + SEEN_WORDS / 2) % SEEN_WORDS] = 0;
+
+        if bits[word] & bit != 0 {
+            return false;
+        }
+        bits[word] |= bit;
+        drop(seen);
+
+        let mut latest = self.latest.lock().unwrap_or_else(|err| err.into_inner());
+        if latest
+            .as_ref()
+            .is_none_or(|latest| latest.id() != conn.id())
+        {
+            *latest = Some(conn.clone());
+        }
+
+        true
+    }
+
+    /// Returns the connection replies are sent through, if any is still open
+    pub(super) fn latest(&self) -> Option<Connection> {
+        self.latest
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+            .filter(|conn| !conn.is_closed())
+    }
+
+    /// Forgets the packet IDs of a closed UDP session
+    pub(super) fn forget(&self, assoc_id: u16) {
+        self.seen
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .remove(&assoc_id);
+    }
+
+    /// Removes a closed connection, so that it does not keep the bond alive
+    pub(super) fn leave(&self, conn: &Connection) {
+        let mut latest = self.latest.lock().unwrap_or_else(|err| err.into_inner());
+        if latest
+            .as_ref()
+            .is_some_and(|latest| latest.id() == conn.id())
+        {
+            *latest = None;
+        }
+    }
+}
@@ -5,12 +5,51 @@ use quinn::{RecvStream, SendStream, VarInt};
 use register_count::Register;
 use tokio::time;
 use tracing::{debug, warn};
-use tuic_quinn::Task;
+use tuic::{Capabilities, ConnectStatus, UnmarshalError};
+use tuic_quinn::{Error as ModelError, Task};
 
-use super::Connection;
-use crate::{error::Error, utils::UdpRelayMode};
+use super::{Connection, TASK_LIMIT_ERROR_CODE};
+use crate::{config::DuplicateAuthPolicy, error::Error, utils::UdpRelayMode};
 
 impl Connection {
+    /// Waits for the client to authenticate, counting the task, holding
+    /// `bytes` of payload, in the pre-authentication queue meanwhile. Stream
+    /// payloads are left to the QUIC flow control
+    async fn wait_authenticated(&self, bytes: usize) -> Result<(), Error> {
+        if self.auth.get().is_some() {
+            return Ok(());
+        }
+
+        let _queued = self.auth.enqueue(bytes);
+        let (tasks, queued_bytes) = self.auth.queued();
+        let max_tasks = self.ctx.cfg.max_pre_auth_tasks;
+        let max_bytes = self.ctx.cfg.max_pre_auth_bytes;
+
+        if (max_tasks != 0 && tasks > max_tasks) || (max_bytes != 0 && queued_bytes > max_bytes) {
+            return Err(Error::TooManyPreAuthTasks(max_tasks, max_bytes));
+        }
+
+        tokio::select! {
+            () = self.auth.wait() => Ok(()),
+            err = self.inner.closed() => Err(Error::from(err)),
+        }
+    }
+
+    /// Fails on a packet in `mode` if packets of the connection were received in
+    /// the other mode before, unless the client declared mixing both with the
+    /// `MIXED_UDP_RELAY` extension
+    fn check_udp_relay_mode(&self, mode: UdpRelayMode) -> Result<(), Error> {
+        let mixed = self
+            .model
+            .negotiated()
+            .is_some_and(|(_, caps)| caps.contains(Capabilities::MIXED_UDP_RELAY));
+
+        match **self.udp_relay_mode.load() {
+            Some(prev) if prev != mode && !mixed => Err(Error::UnexpectedPacketSource),
+            _ => Ok(()),
+        }
+    }
+
     pub async fn handle_uni_stream(self, recv: RecvStream, _reg: Register) {
         debug!(
             "[{id:#010x}] [{addr}] [{user}] incoming unidirectional stream",
@@ -29,6 +68,10 @@ impl Connection {
                 .set_max_concurrent_uni_streams(VarInt::from(max * 2));
         }
 
+        if self.is_masquerading() {
+            return self.masquerade_uni_stream(&[], recv).await;
+        }
+
         let pre_process = async {
             let task = time::timeout(
                 self.ctx.cfg.task_negotiation_timeout,
@@ -41,15 +84,10 @@ impl Connection {
                 self.authenticate(auth).await?;
             }
 
-            tokio::select! {
-                () = self.auth.wait() => {}
-                err = self.inner.closed() => return Err(Error::from(err)),
-            };
+            self.wait_authenticated(0).await?;
 
-            let same_pkt_src = matches!(task, Task::Packet(_))
-                && matches!(**self.udp_relay_mode.load(), Some(UdpRelayMode::Native));
-            if same_pkt_src {
-                return Err(Error::UnexpectedPacketSource);
+            if let Task::Packet(_) = &task {
+                self.check_udp_relay_mode(UdpRelayMode::Quic)?;
             }
 
             Ok(task)
@@ -59,7 +97,25 @@ impl Connection {
             Ok(Task::Authenticate(auth)) => self.handle_authenticate(auth).await,
             Ok(Task::Packet(pkt)) => self.handle_packet(pkt, UdpRelayMode::Quic).await,
             Ok(Task::Dissociate(assoc_id)) => self.handle_dissociate(assoc_id).await,
+            Ok(Task::Bond(bond_id)) => self.handle_bond(bond_id).await,
+            Ok(Task::Negotiate(version, capabilities)) => {
+                self.handle_negotiate(version, capabilities).await
+            }
             Ok(_) => unreachable!(), // already filtered in `tuic_quinn`
+            Err(Error::Model(ModelError::UnmarshalUniStream(
+                UnmarshalError::InvalidVersion(ver),
+                recv,
+            ))) if self.can_masquerade() => self.masquerade_uni_stream(&[ver], recv).await,
+            Err(Error::DuplicatedAuth)
+                if self.ctx.cfg.duplicate_auth == DuplicateAuthPolicy::Ignore =>
+            {
+                debug!(
+                    "[{id:#010x}] [{addr}] [{user}] ignored duplicated authentication",
+                    id = self.id(),
+                    addr = self.inner.remote_address(),
+                    user = self.auth,
+                );
+            }
             Err(err) => {
                 warn!(
                     "[{id:#010x}] [{addr}] [{user}] handling incoming unidirectional stream \
@@ -91,6 +147,10 @@ impl Connection {
                 .set_max_concurrent_bi_streams(VarInt::from(max * 2));
         }
 
+        if self.is_masquerading() {
+            return self.masquerade_bi_stream(&[], send, recv).await;
+        }
+
         let pre_process = async {
             let task = time::timeout(
                 self.ctx.cfg.task_negotiation_timeout,
@@ -99,17 +159,38 @@ impl Connection {
             .await
             .map_err(|_| Error::TaskNegotiationTimeout)??;
 
-            tokio::select! {
-                () = self.auth.wait() => {}
-                err = self.inner.closed() => return Err(Error::from(err)),
-            };
+            self.wait_authenticated(0).await?;
 
             Ok(task)
         };
 
         match pre_process.await {
-            Ok(Task::Connect(conn)) => self.handle_connect(conn).await,
+            Ok(Task::Connect(mut conn)) if self.task_limit_reached() => {
+                warn!(
+                    "[{id:#010x}] [{addr}] [{user}] [TCP] {target_addr}: {err}",
+                    id = self.id(),
+                    addr = self.inner.remote_address(),
+                    user = self.auth,
+                    target_addr = conn.addr(),
+                    err = Error::TooManyTasks(self.ctx.cfg.max_tasks_per_connection),
+                );
+                let _ = conn.reply_status(ConnectStatus::GeneralFailure).await;
+                let _ = conn.reset(TASK_LIMIT_ERROR_CODE);
+            }
+            Ok(Task::Connect(conn)) => {
+                let _reg = self.relay_task_cnt.reg();
+                self.handle_connect(conn).await
+            }
+            Ok(Task::Speedtest(download, send, recv)) => {
+                let _reg = self.relay_task_cnt.reg();
+                self.handle_speedtest(download, send, recv).await
+            }
             Ok(_) => unreachable!(), // already filtered in `tuic_quinn`
+            Err(Error::Model(ModelError::UnmarshalBiStream(
+                UnmarshalError::InvalidVersion(ver),
+                send,
+                recv,
+            ))) if self.can_masquerade() => self.masquerade_bi_stream(&[ver], send, recv).await,
             Err(err) => {
                 warn!(
                     "[{id:#010x}] [{addr}] [{user}] handling incoming bidirectional stream error: \
@@ -131,18 +212,18 @@ impl Connection {
             user = self.auth,
         );
 
+        if self.is_masquerading() {
+            return self.masquerade_datagram(dg).await;
+        }
+
         let pre_process = async {
+            let len = dg.len();
             let task = self.model.accept_datagram(dg)?;
 
-            tokio::select! {
-                () = self.auth.wait() => {}
-                err = self.inner.closed() => return Err(Error::from(err)),
-            };
+            self.wait_authenticated(len).await?;
 
-            let same_pkt_src = matches!(task, Task::Packet(_))
-                && matches!(**self.udp_relay_mode.load(), Some(UdpRelayMode::Quic));
-            if same_pkt_src {
-                return Err(Error::UnexpectedPacketSource);
+            if let Task::Packet(_) | Task::Batch(_) = &task {
+                self.check_udp_relay_mode(UdpRelayMode::Native)?;
             }
 
             Ok(task)
@@ -150,8 +231,17 @@ impl Connection {
 
         match pre_process.await {
             Ok(Task::Packet(pkt)) => self.handle_packet(pkt, UdpRelayMode::Native).await,
+            Ok(Task::Batch(pkts)) => {
+                for pkt in pkts {
+                    self.handle_packet(pkt, UdpRelayMode::Native).await;
+                }
+            }
             Ok(Task::Heartbeat) => self.handle_heartbeat().await,
             Ok(_) => unreachable!(),
+            Err(Error::Model(ModelError::UnmarshalDatagram(
+                UnmarshalError::InvalidVersion(_),
+                dg,
+            ))) if self.can_masquerade() => self.masquerade_datagram(dg).await,
             Err(err) => {
                 warn!(
                     "[{id:#010x}] [{addr}] [{user}] handling incoming datagram error: {err}",
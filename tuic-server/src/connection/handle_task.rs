@@ -1,21 +1,25 @@
 use std::{
     collections::hash_map::Entry,
     io::{Error as IoError, ErrorKind},
-    net::SocketAddr,
+    time::Instant,
 };
 
 use bytes::Bytes;
 use eyre::{OptionExt, eyre};
+use quinn::{RecvStream, SendStream};
 use tokio::{
-    io::AsyncWriteExt,
-    net::{self, TcpStream},
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
 };
-use tracing::{info, warn};
-use tuic::Address;
+use tracing::{debug, info, warn};
+use tuic::{Address, Capabilities, ConnectStatus};
 use tuic_quinn::{Authenticate, Connect, Packet};
 
-use super::{Connection, ERROR_CODE, UdpSession};
-use crate::{error::Error, io::exchange_tcp, restful, utils::UdpRelayMode};
+use super::{CAPABILITIES, Connection, ERROR_CODE, UdpSession};
+use crate::{
+    access_log::AccessLogEntry, dns, error::Error, io::exchange_tcp, outbound, restful,
+    utils::UdpRelayMode,
+};
 
 impl Connection {
     pub async fn handle_authenticate(&self, auth: Authenticate) {
@@ -26,10 +30,29 @@ impl Connection {
             user = self.auth,
             auth_uuid = auth.uuid(),
         );
+
+        let mut capabilities = CAPABILITIES;
+        if self.ctx.cfg.compression.is_some() {
+            capabilities = capabilities | Capabilities::COMPRESSION;
+        }
+        if self.ctx.cfg.max_speedtest_size != 0 {
+            capabilities = capabilities | Capabilities::SPEEDTEST;
+        }
+
+        if let Err(err) = self.model.negotiate(capabilities).await {
+            warn!(
+                "[{id:#010x}] [{addr}] [{user}] [NEGOTIATE] failed sending offer: {err}",
+                id = self.id(),
+                addr = self.inner.remote_address(),
+                user = self.auth,
+            );
+        }
     }
 
     pub async fn handle_connect(&self, mut conn: Connect) {
         let target_addr = conn.addr().to_string();
+        let start = Instant::now();
+        let mut traffic = (0, 0);
 
         info!(
             "[{id:#010x}] [{addr}] [{user}] [TCP] {target_addr} ",
@@ -39,33 +62,70 @@ impl Connection {
         );
 
         let process = async {
+            if let Some(uuid) = self.auth.get()
+                && !self.ctx.cfg.allow_tcp(&uuid)
+            {
+                let _ = conn.reply_status(ConnectStatus::Denied).await;
+                let _ = conn.shutdown().await;
+                return Err(Error::RelayDisabled("TCP"));
+            }
+
+            if !self.allowed(conn.addr()) {
+                let _ = conn.reply_status(ConnectStatus::Denied).await;
+                let _ = conn.shutdown().await;
+                return Err(Error::Denied(target_addr.clone()));
+            }
+
             let mut stream = None;
             let mut last_err = None;
+            let mut dns_failed = false;
+
+            if let Some(upstream) = &self.ctx.cfg.outbound.upstream {
+                match outbound::connect_upstream(&self.ctx.cfg.outbound, upstream, conn.addr())
+                    .await
+                {
+                    Ok(s) => {
+                        s.set_nodelay(true)?;
+                        stream = Some(s);
+                    }
+                    Err(err) => last_err = Some(err),
+                }
+            } else {
+                match dns::resolve(&self.ctx.resolver, conn.addr()).await {
+                    Ok(addrs) => {
+                        let strategy = self.ctx.cfg.outbound.strategy;
+                        for addr in outbound::apply_strategy(strategy, addrs) {
+                            let connect = async {
+                                let mut s =
+                                    outbound::connect_tcp(&self.ctx.cfg.outbound, addr).await?;
+                                if self.ctx.cfg.outbound.proxy_protocol.contains(&addr) {
+                                    let src = self.inner.remote_address();
+                                    outbound::write_proxy_protocol(&mut s, src, addr).await?;
+                                }
+                                Ok::<_, IoError>(s)
+                            };
 
-            match resolve_dns(conn.addr()).await {
-                Ok(addrs) => {
-                    for addr in addrs {
-                        match TcpStream::connect(addr).await {
-                            Ok(s) => {
-                                s.set_nodelay(true)?;
-                                stream = Some(s);
-                                break;
+                            match connect.await {
+                                Ok(s) => {
+                                    s.set_nodelay(true)?;
+                                    stream = Some(s);
+                                    break;
+                                }
+                                Err(err) => last_err = Some(err),
                             }
-                            Err(err) => last_err = Some(err),
                         }
                     }
+                    Err(err) => {
+                        dns_failed = true;
+                        last_err = Some(err);
+                    }
                 }
-                Err(err) => last_err = Some(err),
             }
 
-            if let Some(mut stream) = stream {
-                // a -> b tx
-                // a <- b rx
-                let (tx, rx, err) =
-                    exchange_tcp(&mut conn, &mut stream, self.ctx.cfg.stream_timeout).await;
-                // let (tx, rx) = tokio::io::copy_bidirectional(&mut conn, &mut stream).await?;
-                _ = conn.reset(ERROR_CODE);
-                _ = stream.shutdown().await;
+            if let Some(stream) = stream {
+                conn.reply_status(ConnectStatus::Succeeded).await?;
+
+                let (tx, rx, err) = self.exchange_tcp(conn, stream).await;
 
                 let uuid = self
                     .auth
@@ -73,28 +133,73 @@ impl Connection {
                     .ok_or_eyre("Unexpected autherization state")?;
                 restful::traffic_tx(&self.ctx, &uuid, tx as u64);
                 restful::traffic_rx(&self.ctx, &uuid, rx as u64);
+                traffic = (tx as u64, rx as u64);
                 if let Some(err) = err {
-                    return Err(err);
+                    return Err(err.into());
                 }
                 Ok(())
             } else {
+                let status = match &last_err {
+                    Some(err) if !dns_failed => connect_status(err),
+                    _ => ConnectStatus::DnsFailure,
+                };
+                let _ = conn.reply_status(status).await;
                 let _ = conn.shutdown().await;
                 Err(last_err
                     .unwrap_or_else(|| IoError::new(ErrorKind::NotFound, "no address resolved")))?
             }
         };
 
-        match process.await {
-            Ok(()) => {}
-            Err(err) => warn!(
+        let res = process.await;
+
+        if let Err(err) = &res {
+            warn!(
                 "[{id:#010x}] [{addr}] [{user}] [TCP] {target_addr}: {err}",
                 id = self.id(),
                 addr = self.inner.remote_address(),
                 user = self.auth,
-            ),
+            );
+        }
+
+        if let Some(access_log) = &self.ctx.access_log {
+            access_log.record(&AccessLogEntry {
+                conn_id: self.id(),
+                user: self.auth.get(),
+                client: self.inner.remote_address(),
+                protocol: "tcp",
+                destination: &target_addr,
+                tx: traffic.0,
+                rx: traffic.1,
+                duration: start.elapsed(),
+                error: res.err().map(|err| err.to_string()),
+            });
         }
     }
 
+    /// Relays the accepted TCP connection, then resets the QUIC stream and
+    /// shuts the outbound stream down
+    async fn exchange_tcp(
+        &self,
+        mut conn: Connect,
+        mut stream: TcpStream,
+    ) -> (usize, usize, Option<eyre::Error>) {
+        let timeout = self.ctx.cfg.stream_timeout;
+        let buffer_size = self.ctx.cfg.relay_buffer_size;
+
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if let Some(uring) = &self.ctx.uring {
+            return uring.exchange_tcp(conn, stream, timeout, buffer_size).await;
+        }
+
+        // a -> b tx
+        // a <- b rx
+        let res = exchange_tcp(&mut conn, &mut stream, timeout, buffer_size).await;
+        // let (tx, rx) = tokio::io::copy_bidirectional(&mut conn, &mut stream).await?;
+        _ = conn.reset(ERROR_CODE);
+        _ = stream.shutdown().await;
+        res
+    }
+
     pub async fn handle_packet(&self, pkt: Packet, mode: UdpRelayMode) {
         let assoc_id = pkt.assoc_id();
         let pkt_id = pkt.pkt_id();
@@ -128,6 +233,23 @@ impl Connection {
             }
         };
 
+        // the client may send the same packet over each path of a bond. Packets
+        // relayed over streams are never duplicated and take their IDs from
+        // another sequence
+        if matches!(mode, UdpRelayMode::Native)
+            && let Some(bond) = self.bond.get()
+            && !bond.first_seen(self, assoc_id, pkt_id)
+        {
+            debug!(
+                "[{id:#010x}] [{addr}] [{user}] [UDP-OUT] [{assoc_id:#06x}] [from-{mode}] \
+                 [{pkt_id:#06x}] duplicate over another path",
+                id = self.id(),
+                addr = self.inner.remote_address(),
+                user = self.auth,
+            );
+            return;
+        }
+
         let process = async {
             info!(
                 "[{id:#010x}] [{addr}] [{user}] [UDP-OUT] [{assoc_id:#06x}] [from-{mode}] \
@@ -138,26 +260,61 @@ impl Connection {
                 src_addr = addr,
             );
 
-            let guard = self.udp_sessions.read().await;
+            if let Some(uuid) = self.auth.get()
+                && !self.ctx.cfg.allow_udp(&uuid)
+            {
+                return Err(Error::RelayDisabled("UDP"));
+            }
+
+            if !self.allowed(&addr) {
+                return Err(Error::Denied(addr.to_string()));
+            }
+
+            let guard = self.udp_sessions().read().await;
             let session = guard.get(&assoc_id).map(|v| v.to_owned());
             drop(guard);
             let session = match session {
                 Some(v) => v,
-                None => match self.udp_sessions.write().await.entry(assoc_id) {
+                None => match self.udp_sessions().write().await.entry(assoc_id) {
                     Entry::Occupied(entry) => entry.get().clone(),
+                    Entry::Vacant(_) if self.task_limit_reached() => {
+                        return Err(Error::TooManyTasks(self.ctx.cfg.max_tasks_per_connection));
+                    }
                     Entry::Vacant(entry) => {
-                        let session = UdpSession::new(self.ctx.clone(), self.clone(), assoc_id)?;
+                        let session = UdpSession::new(
+                            self.ctx.clone(),
+                            self.clone(),
+                            assoc_id,
+                            mode,
+                            self.relay_task_cnt.reg(),
+                        )?;
                         entry.insert(session.clone());
                         session
                     }
                 },
             };
 
-            let Some(socket_addr) = resolve_dns(&addr).await?.next() else {
-                return Err(Error::from(IoError::new(
-                    ErrorKind::NotFound,
-                    "no address resolved",
-                )));
+            let addrs = outbound::apply_strategy(
+                self.ctx.cfg.outbound.strategy,
+                dns::resolve(&self.ctx.resolver, &addr).await?,
+            );
+
+            // fall back to IPv4 if the target also has IPv6 addresses but IPv6
+            // relaying is disabled
+            let socket_addr = match addrs
+                .iter()
+                .find(|addr| addr.is_ipv4() || self.ctx.cfg.udp_relay_ipv6)
+            {
+                Some(addr) => *addr,
+                None => match addrs.first() {
+                    Some(addr) => return Err(Error::UdpRelayIpv6Disabled(*addr)),
+                    None => {
+                        return Err(Error::from(IoError::new(
+                            ErrorKind::NotFound,
+                            "no address resolved",
+                        )));
+                    }
+                },
             };
             let uuid = self
                 .auth
@@ -165,7 +322,10 @@ impl Connection {
                 .ok_or_eyre("Unexpected autherization state")?;
             restful::traffic_tx(&self.ctx, &uuid, pkt.len() as u64);
             if let Some(session) = session.upgrade() {
-                session.send(pkt, socket_addr).await
+                // clients mixing modes pick one per association, so replies
+                // follow the mode of the latest packet of the association
+                session.set_mode(mode);
+                session.send(pkt, socket_addr, &addr).await
             } else {
                 Err(eyre!("UdpSession dropped already").into())
             }
@@ -191,11 +351,102 @@ impl Connection {
             user = self.auth,
         );
 
-        if let Some(session) = self.udp_sessions.write().await.remove(&assoc_id)
+        if let Some(session) = self.udp_sessions().write().await.remove(&assoc_id)
             && let Some(session) = session.upgrade()
         {
             session.close().await;
         }
+
+        if let Some(bond) = self.bond.get() {
+            bond.forget(assoc_id);
+        }
+    }
+
+    /// Joins the connection to a bond, sharing the UDP sessions of the other
+    /// connections of the client over different network paths
+    pub async fn handle_bond(&self, bond_id: u64) {
+        info!(
+            "[{id:#010x}] [{addr}] [{user}] [BOND] {bond_id:#018x}",
+            id = self.id(),
+            addr = self.inner.remote_address(),
+            user = self.auth,
+        );
+
+        let Some(uuid) = self.auth.get() else {
+            return;
+        };
+
+        if self.bond.set(self.ctx.bonds.join(uuid, bond_id)).is_err() {
+            warn!(
+                "[{id:#010x}] [{addr}] [{user}] [BOND] {bond_id:#018x}: already bonded",
+                id = self.id(),
+                addr = self.inner.remote_address(),
+                user = self.auth,
+            );
+        }
+    }
+
+    pub async fn handle_negotiate(&self, version: u8, capabilities: Capabilities) {
+        info!(
+            "[{id:#010x}] [{addr}] [{user}] [NEGOTIATE] version {version:#04x}, capabilities \
+             {caps:#x}",
+            id = self.id(),
+            addr = self.inner.remote_address(),
+            user = self.auth,
+            caps = capabilities.bits(),
+        );
+    }
+
+    /// Discards what the client uploads, then sends `download` bytes back
+    pub async fn handle_speedtest(&self, download: u64, mut send: SendStream, recv: RecvStream) {
+        const CHUNK: [u8; 16384] = [0; 16384];
+
+        info!(
+            "[{id:#010x}] [{addr}] [{user}] [SPEEDTEST] download {download} bytes",
+            id = self.id(),
+            addr = self.inner.remote_address(),
+            user = self.auth,
+        );
+
+        let max = self.ctx.cfg.max_speedtest_size;
+        let (mut upload, mut sent) = (0, 0);
+
+        let process = async {
+            if download > max {
+                return Err(Error::SpeedtestTooLarge(download, max));
+            }
+
+            upload = io::copy(&mut recv.take(max + 1), &mut io::sink()).await?;
+            if upload > max {
+                return Err(Error::SpeedtestTooLarge(upload, max));
+            }
+
+            while sent < download {
+                let len = (download - sent).min(CHUNK.len() as u64) as usize;
+                send.write_all(&CHUNK[..len]).await.map_err(IoError::from)?;
+                sent += len as u64;
+            }
+            send.finish().map_err(IoError::from)?;
+
+            Ok(())
+        };
+
+        let res = process.await;
+
+        if let Some(uuid) = self.auth.get() {
+            restful::traffic_tx(&self.ctx, &uuid, upload);
+            restful::traffic_rx(&self.ctx, &uuid, sent);
+        }
+
+        if let Err(err) = res {
+            warn!(
+                "[{id:#010x}] [{addr}] [{user}] [SPEEDTEST] {err}",
+                id = self.id(),
+                addr = self.inner.remote_address(),
+                user = self.auth,
+            );
+            let _ = send.reset(ERROR_CODE);
+        }
     }
 
     pub async fn handle_heartbeat(&self) {
@@ -207,7 +458,14 @@ impl Connection {
         );
     }
 
-    pub async fn relay_packet(self, pkt: Bytes, addr: Address, assoc_id: u16) -> eyre::Result<()> {
+    /// Relays packets from the same source, batched together if possible
+    pub async fn relay_packets(
+        self,
+        pkts: Vec<Bytes>,
+        addr: Address,
+        assoc_id: u16,
+        mode: UdpRelayMode,
+    ) -> eyre::Result<()> {
         let addr_display = addr.to_string();
 
         info!(
@@ -215,19 +473,27 @@ impl Connection {
             id = self.id(),
             addr = self.inner.remote_address(),
             user = self.auth,
-            mode = self.udp_relay_mode.load().unwrap(),
             src_addr = addr_display,
         );
 
         restful::traffic_rx(
             &self.ctx,
             &self.auth.get().ok_or_eyre("Unreachable")?,
-            pkt.len() as u64,
+            pkts.iter().map(|pkt| pkt.len() as u64).sum(),
         );
 
-        let res = match self.udp_relay_mode.load().unwrap() {
-            UdpRelayMode::Native => self.model.packet_native(pkt, addr, assoc_id),
-            UdpRelayMode::Quic => self.model.packet_quic(pkt, addr, assoc_id).await,
+        let res = match mode {
+            UdpRelayMode::Native => self.model.packet_native_batch(&pkts, addr, assoc_id),
+            UdpRelayMode::Quic => {
+                let mut res = Ok(());
+                for pkt in pkts {
+                    res = self.model.packet_quic(pkt, addr.clone(), assoc_id).await;
+                    if res.is_err() {
+                        break;
+                    }
+                }
+                res
+            }
         };
 
         if let Err(err) = res {
@@ -237,7 +503,6 @@ impl Connection {
                 id = self.id(),
                 addr = self.inner.remote_address(),
                 user = self.auth,
-                mode = self.udp_relay_mode.load().unwrap(),
                 src_addr = addr_display,
             );
         }
@@ -245,13 +510,15 @@ impl Connection {
     }
 }
 
-async fn resolve_dns(addr: &Address) -> Result<impl Iterator<Item = SocketAddr>, IoError> {
-    match addr {
-        Address::None => Err(IoError::new(ErrorKind::InvalidInput, "empty address")),
-        Address::DomainAddress(domain, port) => Ok(net::lookup_host((domain.as_str(), *port))
-            .await?
-            .collect::<Vec<_>>()
-            .into_iter()),
-        Address::SocketAddress(addr) => Ok(vec![*addr].into_iter()),
+/// Maps an error connecting to the target to the outcome reported to the
+/// client
+fn connect_status(err: &IoError) -> ConnectStatus {
+    match err.kind() {
+        ErrorKind::ConnectionRefused => ConnectStatus::ConnectionRefused,
+        ErrorKind::NetworkUnreachable => ConnectStatus::NetworkUnreachable,
+        ErrorKind::HostUnreachable => ConnectStatus::HostUnreachable,
+        ErrorKind::TimedOut => ConnectStatus::TimedOut,
+        ErrorKind::PermissionDenied => ConnectStatus::Denied,
+        _ => ConnectStatus::GeneralFailure,
     }
 }
@@ -0,0 +1,189 @@
+use bytes::Bytes;
+use quinn::{Connection as QuinnConnection, RecvStream, SendStream};
+use tracing::{debug, info, warn};
+
+use super::Connection;
+use crate::{
+    error::Error,
+    masquerade::{self, Backend},
+};
+
+impl Connection {
+    /// Whether the connection is relayed to the masquerade web server, instead
+    /// of being handled as TUIC
+    pub(super) fn is_masquerading(&self) -> bool {
+        self.backend.is_some() || self.masquerade.initialized()
+    }
+
+    /// Whether a stream not speaking TUIC can be relayed to the masquerade web
+    /// server, i.e. the connection is not authenticated as a TUIC client
+    pub(super) fn can_masquerade(&self) -> bool {
+        self.ctx.cfg.masquerade.is_some() && self.auth.get().is_none()
+    }
+
+    /// Returns the connection to the masquerade web server, establishing it
+    /// and relaying its streams and datagrams back on first use
+    async fn masquerade_backend(&self) -> Result<QuinnConnection, Error> {
+        let backend = self
+            .masquerade
+            .get_or_try_init(|| async {
+                let alpn = self.inner.handshake_data().and_then(|data| {
+                    data.downcast::<quinn::crypto::rustls::HandshakeData>()
+                        .ok()
+                        .and_then(|data| data.protocol)
+                });
+
+                let backend = self
+                    .backend
+                    .clone()
+                    .or_else(|| Backend::masquerade(&self.ctx.cfg))
+                    .unwrap();
+                let backend = self
+                    .ctx
+                    .masquerade
+                    .as_ref()
+                    .unwrap()
+                    .connect(&backend, alpn)
+                    .await?;
+
+                info!(
+                    "[{id:#010x}] [{addr}] [unauthenticated] [masquerade] relaying to {backend}",
+                    id = self.id(),
+                    addr = self.inner.remote_address(),
+                    backend = backend.remote_address(),
+                );
+
+                tokio::spawn(relay_backend(self.inner.clone(), backend.clone()));
+
+                Ok::<_, Error>(backend)
+            })
+            .await?;
+
+        Ok(backend.clone())
+    }
+
+    /// Relays a unidirectional stream to the masquerade web server, `prefix`
+    /// being what was already read from it
+    pub(super) async fn masquerade_uni_stream(&self, prefix: &[u8], recv: RecvStream) {
+        let res = async {
+            let send = self.masquerade_backend().await?.open_uni().await?;
+            masquerade::copy(prefix, recv, send).await;
+            Ok::<_, Error>(())
+        };
+
+        if let Err(err) = res.await {
+            self.masquerade_failed(err);
+        }
+    }
+
+    /// Relays a bidirectional stream to the masquerade web server, `prefix`
+    /// being what was already read from it
+    pub(super) async fn masquerade_bi_stream(
+        &self,
+        prefix: &[u8],
+        send: SendStream,
+        recv: RecvStream,
+    ) {
+        let res = async {
+            let (backend_send, backend_recv) = self.masquerade_backend().await?.open_bi().await?;
+            tokio::join!(
+                masquerade::copy(prefix, recv, backend_send),
+                masquerade::copy(&[], backend_recv, send),
+            );
+            Ok::<_, Error>(())
+        };
+
+        if let Err(err) = res.await {
+            self.masquerade_failed(err);
+        }
+    }
+
+    pub(super) async fn masquerade_datagram(&self, dg: Bytes) {
+        let res = async {
+            self.masquerade_backend()
+                .await?
+                .send_datagram(dg)
+                .map_err(|err| Error::Other(err.into()))
+        };
+
+        if let Err(err) = res.await {
+            debug!(
+                "[{id:#010x}] [{addr}] [unauthenticated] [masquerade] {err}",
+                id = self.id(),
+                addr = self.inner.remote_address(),
+            );
+        }
+    }
+
+    fn masquerade_failed(&self, err: Error) {
+        warn!(
+            "[{id:#010x}] [{addr}] [unauthenticated] [masquerade] {err}",
+            id = self.id(),
+            addr = self.inner.remote_address(),
+        );
+        self.close();
+    }
+}
+
+/// Relays the streams and datagrams opened by the masquerade web server back to
+/// the client, until either side closes
+async fn relay_backend(client: QuinnConnection, backend: QuinnConnection) {
+    loop {
+        tokio::select! {
+            res = backend.accept_uni() => match res {
+                Ok(recv) => {
+                    let client = client.clone();
+                    tokio::spawn(async move {
+                        if let Ok(send) = client.open_uni().await {
+                            masquerade::copy(&[], recv, send).await;
+                        }
+                    });
+                }
+                Err(err) => {
+                    close_with(&client, &err);
+                    break;
+                }
+            },
+            res = backend.accept_bi() => match res {
+                Ok((backend_send, backend_recv)) => {
+                    let client = client.clone();
+                    tokio::spawn(async move {
+                        if let Ok((send, recv)) = client.open_bi().await {
+                            tokio::join!(
+                                masquerade::copy(&[], backend_recv, send),
+                                masquerade::copy(&[], recv, backend_send),
+                            );
+                        }
+                    });
+                }
+                Err(err) => {
+                    close_with(&client, &err);
+                    break;
+                }
+            },
+            res = backend.read_datagram() => match res {
+                Ok(dg) => {
+                    let _ = client.send_datagram(dg);
+                }
+                Err(err) => {
+                    close_with(&client, &err);
+                    break;
+                }
+            },
+            err = client.closed() => {
+                close_with(&backend, &err);
+                break;
+            }
+        }
+    }
+}
+
+/// Closes `conn` the way its peer connection was closed
+fn close_with(conn: &QuinnConnection, err: &quinn::ConnectionError) {
+    match err {
+        quinn::ConnectionError::ApplicationClosed(close) => {
+            conn.close(close.error_code, &close.reason)
+        }
+        _ => conn.close(0u32.into(), &[]),
+    }
+}
@@ -1,26 +1,56 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, Weak, atomic::AtomicU32},
-    time::Duration,
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+    },
 };
 
 use arc_swap::ArcSwap;
-use quinn::{Connecting, Connection as QuinnConnection, VarInt};
+use quinn::{Connecting, Connection as QuinnConnection, VarInt, ZeroRttAccepted};
 use register_count::Counter;
-use tokio::{sync::RwLock as AsyncRwLock, time};
-use tracing::{debug, info, warn};
-use tuic_quinn::{Authenticate, Connection as Model, side};
+use tokio::{
+    sync::{OnceCell, RwLock as AsyncRwLock},
+    time,
+};
+use tracing::{Instrument, Span, debug, field, info, info_span, warn};
+use tuic::{Address, Capabilities};
+use tuic_quinn::{
+    Authenticate, CompressionPolicy, Connection as Model, ExporterLabels, PaddingPolicy,
+    ReassemblyLimits, Timeouts, side,
+};
 
-use self::{authenticated::Authenticated, udp_session::UdpSession};
-use crate::{AppContext, error::Error, restful, utils::UdpRelayMode};
+pub use self::bond::Bonds;
+use self::{authenticated::Authenticated, bond::Bond, udp_session::UdpSession};
+use crate::{
+    AppContext,
+    config::DuplicateAuthPolicy,
+    devices,
+    error::Error,
+    expiry,
+    masquerade::Backend,
+    restful,
+    utils::{self, UdpRelayMode},
+};
 
 mod authenticated;
+mod bond;
 mod handle_stream;
 mod handle_task;
+mod masquerade;
 mod udp_session;
 
 pub const ERROR_CODE: VarInt = VarInt::from_u32(6000);
-pub const INIT_CONCURRENT_STREAMS: u32 = 32;
+/// Resets relay streams over `max_tasks_per_connection`
+pub const TASK_LIMIT_ERROR_CODE: VarInt = VarInt::from_u32(6003);
+
+/// Protocol extensions always offered to clients
+pub const CAPABILITIES: Capabilities = Capabilities::NOTICE
+    .union(Capabilities::PADDING)
+    .union(Capabilities::BATCH)
+    .union(Capabilities::CONNECT_STATUS)
+    .union(Capabilities::MULTIPATH)
+    .union(Capabilities::MIXED_UDP_RELAY);
 
 #[derive(Clone)]
 pub struct Connection {
@@ -29,11 +59,27 @@ pub struct Connection {
     model: Model<side::Server>,
     auth: Authenticated,
     udp_sessions: Arc<AsyncRwLock<HashMap<u16, Weak<UdpSession>>>>,
+    /// The mode of the latest packet, for clients not mixing modes
     udp_relay_mode: Arc<ArcSwap<Option<UdpRelayMode>>>,
     remote_uni_stream_cnt: Counter,
     remote_bi_stream_cnt: Counter,
+    /// TCP relays and UDP sessions in progress
+    relay_task_cnt: Counter,
     max_concurrent_uni_streams: Arc<AtomicU32>,
     max_concurrent_bi_streams: Arc<AtomicU32>,
+    /// Whether the handshake is still to be confirmed, i.e. the commands
+    /// received so far may be replayed 0-RTT early data
+    zero_rtt: Arc<AtomicBool>,
+    /// The connection to the masquerade web server, once the client turned out
+    /// not to speak TUIC
+    masquerade: Arc<OnceCell<QuinnConnection>>,
+    /// The service the whole connection is relayed to, by the ALPN protocol
+    /// negotiated
+    backend: Option<Backend>,
+    /// The bond the client joined the connection to, whose UDP sessions are
+    /// used instead of the ones of the connection
+    bond: Arc<OnceCell<Arc<Bond>>>,
+    span: Span,
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -42,62 +88,72 @@ impl Connection {
         let addr = conn.remote_address();
 
         let init = async {
-            let conn = if ctx.cfg.zero_rtt_handshake {
+            let (conn, zero_rtt_accepted) = if ctx.cfg.zero_rtt_handshake {
                 match conn.into_0rtt() {
-                    Ok((conn, _)) => conn,
-                    Err(conn) => conn.await?,
+                    Ok((conn, zero_rtt_accepted)) => (conn, Some(zero_rtt_accepted)),
+                    Err(conn) => (conn.await?, None),
                 }
             } else {
-                conn.await?
+                (conn.await?, None)
             };
 
-            Ok::<_, Error>(Self::new(ctx.clone(), conn))
+            Ok::<_, Error>(Self::new(ctx.clone(), conn, zero_rtt_accepted))
         };
 
         match init.await {
             Ok(conn) => {
-                info!(
-                    "[{id:#010x}] [{addr}] [{user}] connection established",
-                    id = conn.id(),
-                    user = conn.auth,
-                );
-                tokio::spawn(conn.clone().timeout_authenticate(ctx.cfg.auth_timeout));
-                tokio::spawn(conn.clone().collect_garbage());
+                let handle_connection = async {
+                    info!(
+                        "[{id:#010x}] [{addr}] [{user}] connection established",
+                        id = conn.id(),
+                        user = conn.auth,
+                    );
+                    utils::spawn(
+                        || format!("authenticate {:#010x}", conn.id()),
+                        conn.clone().timeout_authenticate().in_current_span(),
+                    );
+                    utils::spawn(
+                        || format!("collect_garbage {:#010x}", conn.id()),
+                        conn.clone().collect_garbage().in_current_span(),
+                    );
 
-                loop {
-                    if conn.is_closed() {
-                        break;
-                    }
+                    loop {
+                        if conn.is_closed() {
+                            break;
+                        }
 
-                    let handle_incoming = async {
-                        tokio::select! {
-                            res = conn.inner.accept_uni() =>
-                                tokio::spawn(conn.clone().handle_uni_stream(res?, conn.remote_uni_stream_cnt.reg())),
-                            res = conn.inner.accept_bi() =>
-                                tokio::spawn(conn.clone().handle_bi_stream(res?, conn.remote_bi_stream_cnt.reg())),
-                            res = conn.inner.read_datagram() =>
-                                tokio::spawn(conn.clone().handle_datagram(res?)),
-                        };
+                        let handle_incoming = async {
+                            tokio::select! {
+                                res = conn.inner.accept_uni() =>
+                                    utils::spawn(|| format!("uni_stream {:#010x}", conn.id()), conn.clone().handle_uni_stream(res?, conn.remote_uni_stream_cnt.reg()).in_current_span()),
+                                res = conn.inner.accept_bi() =>
+                                    utils::spawn(|| format!("bi_stream {:#010x}", conn.id()), conn.clone().handle_bi_stream(res?, conn.remote_bi_stream_cnt.reg()).in_current_span()),
+                                res = conn.inner.read_datagram() =>
+                                    utils::spawn(|| format!("datagram {:#010x}", conn.id()), conn.clone().handle_datagram(res?).in_current_span()),
+                            };
 
-                        Ok::<_, Error>(())
-                    };
+                            Ok::<_, Error>(())
+                        };
 
-                    match handle_incoming.await {
-                        Ok(()) => {}
-                        Err(err) if err.is_trivial() => {
-                            debug!(
-                                "[{id:#010x}] [{addr}] [{user}] {err}",
+                        match handle_incoming.await {
+                            Ok(()) => {}
+                            Err(err) if err.is_trivial() => {
+                                debug!(
+                                    "[{id:#010x}] [{addr}] [{user}] {err}",
+                                    id = conn.id(),
+                                    user = conn.auth,
+                                );
+                            }
+                            Err(err) => warn!(
+                                "[{id:#010x}] [{addr}] [{user}] connection error: {err}",
                                 id = conn.id(),
                                 user = conn.auth,
-                            );
+                            ),
                         }
-                        Err(err) => warn!(
-                            "[{id:#010x}] [{addr}] [{user}] connection error: {err}",
-                            id = conn.id(),
-                            user = conn.auth,
-                        ),
                     }
-                }
+                };
+
+                handle_connection.instrument(conn.span.clone()).await;
             }
             Err(err) if err.is_trivial() => {
                 debug!(
@@ -114,51 +170,244 @@ impl Connection {
         }
     }
 
-    fn new(ctx: Arc<AppContext>, conn: QuinnConnection) -> Self {
+    fn new(
+        ctx: Arc<AppContext>,
+        conn: QuinnConnection,
+        zero_rtt_accepted: Option<ZeroRttAccepted>,
+    ) -> Self {
+        let span = info_span!(
+            "conn",
+            id = conn.stable_id() as u32,
+            addr = %conn.remote_address(),
+            user = field::Empty,
+        );
+
+        let model = Model::<side::Server>::new(conn.clone());
+        model.set_reassembly_limits(ReassemblyLimits {
+            timeout: ctx.cfg.gc_lifetime,
+            max_pending_packets: ctx.cfg.max_pending_packets,
+            memory: ctx.reassembly_memory.clone(),
+            reorder_window: ctx.cfg.reorder_window,
+            stats: ctx.reassembly_stats.clone(),
+        });
+        model.set_timeouts(Timeouts {
+            authenticate: Some(ctx.cfg.auth_timeout),
+            ..Default::default()
+        });
+
+        if let Some(padding) = &ctx.cfg.padding {
+            model.set_padding_policy(PaddingPolicy {
+                threshold: padding.threshold,
+                max_len: padding.max_len,
+            });
+        }
+
+        if let Some(compression) = &ctx.cfg.compression {
+            model.set_compression_policy(CompressionPolicy {
+                level: compression.level,
+            });
+        }
+
+        model.set_exporter_labels(ExporterLabels {
+            label: ctx.cfg.tls.exporter_label.clone().into_bytes(),
+            context: ctx.cfg.tls.exporter_context.clone().into_bytes(),
+        });
+
+        let max_concurrent_uni_streams = ctx.cfg.quic.transport.max_concurrent_uni_streams;
+        let max_concurrent_bi_streams = ctx.cfg.quic.transport.max_concurrent_bi_streams;
+
+        let zero_rtt = Arc::new(AtomicBool::new(zero_rtt_accepted.is_some()));
+        if let Some(zero_rtt_accepted) = zero_rtt_accepted {
+            let zero_rtt = zero_rtt.clone();
+            tokio::spawn(async move {
+                zero_rtt_accepted.await;
+                zero_rtt.store(false, Ordering::Relaxed);
+            });
+        }
+
+        let backend = conn
+            .handshake_data()
+            .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+            .and_then(|data| data.protocol)
+            .and_then(|alpn| Backend::route(&ctx.cfg, &alpn));
+
         Self {
             ctx,
-            inner: conn.clone(),
-            model: Model::<side::Server>::new(conn),
+            inner: conn,
+            model,
             auth: Authenticated::new(),
             udp_sessions: Arc::new(AsyncRwLock::new(HashMap::new())),
             udp_relay_mode: Arc::new(ArcSwap::new(None.into())),
             remote_uni_stream_cnt: Counter::new(),
             remote_bi_stream_cnt: Counter::new(),
-            max_concurrent_uni_streams: Arc::new(AtomicU32::new(INIT_CONCURRENT_STREAMS)),
-            max_concurrent_bi_streams: Arc::new(AtomicU32::new(INIT_CONCURRENT_STREAMS)),
+            relay_task_cnt: Counter::new(),
+            max_concurrent_uni_streams: Arc::new(AtomicU32::new(max_concurrent_uni_streams)),
+            max_concurrent_bi_streams: Arc::new(AtomicU32::new(max_concurrent_bi_streams)),
+            zero_rtt,
+            masquerade: Arc::new(OnceCell::new()),
+            backend,
+            bond: Arc::new(OnceCell::new()),
+            span,
         }
     }
 
     async fn authenticate(&self, auth: &Authenticate) -> Result<(), Error> {
-        if self.auth.get().is_some() {
+        let prev = self.auth.get();
+
+        if prev.is_some() && self.ctx.cfg.duplicate_auth != DuplicateAuthPolicy::Rekey {
             Err(Error::DuplicatedAuth)
         } else if self
             .ctx
-            .cfg
-            .users
-            .get(&auth.uuid())
-            .is_some_and(|password| auth.validate(password))
+            .hooks
+            .as_ref()
+            .and_then(|hooks| hooks.password(&auth.uuid()))
+            .or_else(|| self.ctx.cfg.users.get(&auth.uuid()).cloned())
+            .is_some_and(|password| auth.validate(&password))
+            || self.validate_previous_password(auth)
         {
+            if self.ctx.cfg.zero_rtt_handshake
+                && !self
+                    .ctx
+                    .replay_cache
+                    .check(auth.token(), self.zero_rtt.load(Ordering::Relaxed))
+            {
+                return Err(Error::ReplayedAuth(auth.uuid()));
+            }
+
+            if let Some(bans) = &self.ctx.bans {
+                bans.record_success(self.inner.remote_address().ip());
+            }
+
+            if self.ctx.expiry.is_expired(&auth.uuid()) {
+                self.inner.close(expiry::ERROR_CODE, b"User expired");
+                return Err(Error::Expired(auth.uuid()));
+            }
+
+            // a re-key as the same user keeps the device registered
+            if prev != Some(auth.uuid()) {
+                if let Some(prev) = prev {
+                    self.ctx.devices.unregister(&prev, &self.inner);
+                }
+
+                if !self
+                    .ctx
+                    .devices
+                    .register(&self.ctx.cfg, auth.uuid(), &self.inner, &self.model)
+                {
+                    self.inner.close(
+                        devices::REJECT_ERROR_CODE,
+                        b"Reached maximum devices limitation",
+                    );
+                    return Err(Error::TooManyDevices(auth.uuid()));
+                }
+            }
+
+            if let Some(hooks) = &self.ctx.hooks {
+                hooks.authenticated(&auth.uuid(), self.inner.remote_address());
+            }
+
             self.auth.set(auth.uuid()).await;
+            self.span.record("user", field::display(auth.uuid()));
             Ok(())
         } else {
+            self.record_auth_failure();
             Err(Error::AuthFailed(auth.uuid()))
         }
     }
 
-    async fn timeout_authenticate(self, timeout: Duration) {
-        time::sleep(timeout).await;
+    /// Returns whether `auth` is valid with the previous password of the user,
+    /// still accepted while clients are moved to the new one
+    fn validate_previous_password(&self, auth: &Authenticate) -> bool {
+        let valid = self
+            .ctx
+            .cfg
+            .previous_password(&auth.uuid())
+            .is_some_and(|password| auth.validate(password));
+
+        if valid {
+            info!(
+                "[{id:#010x}] [{addr}] [{user}] authenticated with the previous password",
+                id = self.id(),
+                addr = self.inner.remote_address(),
+                user = auth.uuid(),
+            );
+        }
+
+        valid
+    }
+
+    /// Counts an authentication failure against the client IP, possibly
+    /// banning it
+    fn record_auth_failure(&self) {
+        let Some(bans) = &self.ctx.bans else {
+            return;
+        };
+
+        let addr = self.inner.remote_address();
+        if bans.record_failure(addr.ip()) {
+            warn!(
+                "[{id:#010x}] [{addr}] [unauthenticated] [authenticate] too many failures, \
+                 banning {ip} for {duration}",
+                id = self.id(),
+                ip = addr.ip(),
+                duration = humantime::format_duration(bans.ban_duration()),
+            );
+        }
+    }
+
+    /// Returns `true` if the hooks, if any, allow the user to relay to `target`
+    fn allowed(&self, target: &Address) -> bool {
+        let Some(uuid) = self.auth.get() else {
+            return true;
+        };
+
+        let port = match target {
+            Address::None => None,
+            Address::DomainAddress(_, port) => Some(*port),
+            Address::SocketAddress(addr) => Some(addr.port()),
+        };
+        if let Some(port) = port
+            && !self.ctx.cfg.port_policy(&uuid).is_allowed(port)
+        {
+            return false;
+        }
+
+        match &self.ctx.hooks {
+            Some(hooks) => hooks.allow(&uuid, target),
+            None => true,
+        }
+    }
+
+    /// Returns the UDP sessions of the bond of the connection, if any, or of
+    /// the connection itself
+    fn udp_sessions(&self) -> &AsyncRwLock<HashMap<u16, Weak<UdpSession>>> {
+        match self.bond.get() {
+            Some(bond) => &bond.udp_sessions,
+            None => &self.udp_sessions,
+        }
+    }
+
+    /// Returns `true` if no more relay task can be started
+    fn task_limit_reached(&self) -> bool {
+        let max = self.ctx.cfg.max_tasks_per_connection;
+        max != 0 && self.relay_task_cnt.count() as u32 >= max
+    }
+
+    async fn timeout_authenticate(self) {
+        self.model.authentication_timeout().await;
 
         match self.auth.get() {
             Some(uuid) => {
-                restful::client_connect(&self.ctx, &uuid, self.inner).await;
+                restful::client_connect(&self.ctx, &uuid, self.inner, self.model).await;
             }
+            None if self.is_masquerading() => {}
             None => {
                 warn!(
                     "[{id:#010x}] [{addr}] [unauthenticated] [authenticate] timeout",
                     id = self.id(),
                     addr = self.inner.remote_address(),
                 );
+                self.record_auth_failure();
                 self.close();
             }
         }
@@ -169,7 +418,11 @@ impl Connection {
             time::sleep(self.ctx.cfg.gc_interval).await;
 
             if self.is_closed() {
+                if let Some(bond) = self.bond.get() {
+                    bond.leave(&self);
+                }
                 if let Some(uuid) = self.auth.get() {
+                    self.ctx.devices.unregister(&uuid, &self.inner);
                     restful::client_disconnect(&self.ctx, &uuid, self.inner).await;
                 }
                 break;
@@ -1,20 +1,34 @@
 use std::{
+    collections::HashMap,
     io::Error as IoError,
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket as StdUdpSocket},
-    sync::{Arc, Weak},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
+use register_count::Register;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use tokio::{
     net::UdpSocket,
-    sync::{RwLock as AsyncRwLock, oneshot},
+    sync::{Notify, RwLock as AsyncRwLock, oneshot},
 };
-use tracing::warn;
+use tracing::{Instrument, warn};
 use tuic::Address;
+use tuic_quinn::BufPool;
 
 use super::Connection;
-use crate::{AppContext, error::Error, utils::FutResultExt};
+use crate::{
+    AppContext,
+    access_log::AccessLogEntry,
+    error::Error,
+    io::RelayIdleTimer,
+    outbound,
+    utils::{self, FutResultExt, UdpRelayMode},
+};
+
+/// The most packets received at once, to be relayed together
+const MAX_BATCH: usize = 16;
 
 pub struct UdpSession {
     ctx: Arc<AppContext>,
@@ -23,11 +37,31 @@ pub struct UdpSession {
     socket_v4: UdpSocket,
     socket_v6: Option<UdpSocket>,
     close: AsyncRwLock<Option<oneshot::Sender<()>>>,
+    /// Notified on each packet sent out, restarting the idle timer
+    sent: Notify,
+    /// The mode replies are sent in, i.e. the one of the latest packet of the
+    /// client
+    mode: Mutex<UdpRelayMode>,
+    // per-destination traffic, only tracked when the access log is enabled
+    traffic: Option<Mutex<HashMap<SocketAddr, DestTraffic>>>,
+    _task: Register,
+}
+
+struct DestTraffic {
+    name: String,
+    tx: u64,
+    rx: u64,
 }
 
 impl UdpSession {
     // spawn a task which actually owns itself, then return its wake reference.
-    pub fn new(ctx: Arc<AppContext>, conn: Connection, assoc_id: u16) -> Result<Weak<Self>, Error> {
+    pub fn new(
+        ctx: Arc<AppContext>,
+        conn: Connection,
+        assoc_id: u16,
+        mode: UdpRelayMode,
+        task: Register,
+    ) -> Result<Weak<Self>, Error> {
         let socket_v4 = {
             let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
                 .map_err(|err| Error::Socket("failed to create UDP associate IPv4 socket", err))?;
@@ -39,9 +73,13 @@ impl UdpSession {
                 )
             })?;
 
+            outbound::bind_device(&ctx.cfg.outbound, &socket).map_err(|err| {
+                Error::Socket("failed to bind UDP associate IPv4 socket to interface", err)
+            })?;
+
             socket
                 .bind(&SockAddr::from(SocketAddr::from((
-                    Ipv4Addr::UNSPECIFIED,
+                    ctx.cfg.outbound.bind_ipv4.unwrap_or(Ipv4Addr::UNSPECIFIED),
                     0,
                 ))))
                 .map_err(|err| Error::Socket("failed to bind UDP associate IPv4 socket", err))?;
@@ -64,9 +102,13 @@ impl UdpSession {
                 Error::Socket("failed setting UDP associate IPv6 socket as IPv6-only", err)
             })?;
 
+            outbound::bind_device(&ctx.cfg.outbound, &socket).map_err(|err| {
+                Error::Socket("failed to bind UDP associate IPv6 socket to interface", err)
+            })?;
+
             socket
                 .bind(&SockAddr::from(SocketAddr::from((
-                    Ipv6Addr::UNSPECIFIED,
+                    ctx.cfg.outbound.bind_ipv6.unwrap_or(Ipv6Addr::UNSPECIFIED),
                     0,
                 ))))
                 .map_err(|err| Error::Socket("failed to bind UDP associate IPv6 socket", err))?;
@@ -85,35 +127,46 @@ impl UdpSession {
             socket_v4,
             socket_v6,
             close: AsyncRwLock::new(Some(tx)),
+            sent: Notify::new(),
+            mode: Mutex::new(mode),
+            traffic: ctx.access_log.as_ref().map(|_| Mutex::new(HashMap::new())),
+            _task: task,
         });
 
         let session_listening = session.clone();
         // UdpSession's real owner.
         let listen = async move {
+            let start = Instant::now();
             let mut rx = rx;
-            let mut timeout = tokio::time::interval(ctx.cfg.stream_timeout);
-            timeout.reset();
+            let mut idle = RelayIdleTimer::new(ctx.cfg.stream_timeout);
+            let mut pools = [(); 2].map(|_| BufPool::new(ctx.cfg.max_external_packet_size));
 
             loop {
                 let next;
                 tokio::select! {
-                    recv = session_listening.recv() => next = recv,
+                    recv = session_listening.recv(&mut pools) => next = recv,
+                    () = session_listening.sent.notified() => {
+                        idle.reset();
+                        continue;
+                    },
                     // Avoid client didn't send `UDP-DROP` properly
-                    _ = timeout.tick() => {
+                    () = idle.elapsed() => {
                         session_listening.close().await;
                         warn!(
-                            "[{id:#010x}] [{addr}] [{user}] [packet] [{assoc_id:#06x}] UDP session timeout",
+                            "[{id:#010x}] [{addr}] [{user}] [packet] [{assoc_id:#06x}] UDP session \
+                             idle for {timeout:?}, closed",
                             id = session_listening.conn.id(),
                             addr = session_listening.conn.inner.remote_address(),
                             user = session_listening.conn.auth,
+                            timeout = ctx.cfg.stream_timeout,
                         );
                         continue;
                     },
                     // `UDP-DROP`
                     _ = &mut rx => break
                 }
-                timeout.reset();
-                let (pkt, addr) = match next {
+                idle.reset();
+                let pkts = match next {
                     Ok(v) => v,
                     Err(err) => {
                         warn!(
@@ -127,31 +180,78 @@ impl UdpSession {
                     }
                 };
 
-                tokio::spawn(
-                    session_listening
-                        .conn
-                        .clone()
-                        .relay_packet(
-                            pkt,
-                            Address::SocketAddress(addr),
-                            session_listening.assoc_id,
-                        )
-                        .log_err(),
-                );
+                // packets from the same source are relayed together
+                let mut pkts = pkts.into_iter().peekable();
+                while let Some((pkt, addr)) = pkts.next() {
+                    let mut batch = vec![pkt];
+                    while let Some((pkt, _)) = pkts.next_if(|(_, next)| *next == addr) {
+                        batch.push(pkt);
+                    }
+
+                    for pkt in &batch {
+                        session_listening.count_traffic(addr, None, 0, pkt.len());
+                    }
+
+                    utils::spawn(
+                        || {
+                            format!(
+                                "udp_relay {:#010x} {assoc_id:#06x}",
+                                session_listening.conn.id()
+                            )
+                        },
+                        session_listening
+                            .reply_conn()
+                            .relay_packets(
+                                batch,
+                                Address::SocketAddress(addr),
+                                session_listening.assoc_id,
+                                session_listening.mode(),
+                            )
+                            .log_err()
+                            .in_current_span(),
+                    );
+                }
             }
             session_listening
                 .conn
-                .udp_sessions
+                .udp_sessions()
                 .write()
                 .await
                 .remove(&assoc_id);
+            if let Some(bond) = session_listening.conn.bond.get() {
+                bond.forget(assoc_id);
+            }
+
+            session_listening.record_access(start.elapsed());
         };
 
-        tokio::spawn(listen);
+        utils::spawn(
+            || format!("udp_session {:#010x} {assoc_id:#06x}", session.conn.id()),
+            listen.instrument(session.conn.span.clone()),
+        );
         Ok(Arc::downgrade(&session))
     }
 
-    pub async fn send(&self, pkt: Bytes, addr: SocketAddr) -> Result<(), Error> {
+    /// Returns the connection replies are sent through. In a bond, it is the
+    /// one the latest new packet arrived on, i.e. the fastest path
+    fn reply_conn(&self) -> Connection {
+        self.conn
+            .bond
+            .get()
+            .and_then(|bond| bond.latest())
+            .unwrap_or_else(|| self.conn.clone())
+    }
+
+    fn mode(&self) -> UdpRelayMode {
+        *self.mode.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub fn set_mode(&self, mode: UdpRelayMode) {
+        *self.mode.lock().unwrap_or_else(|err| err.into_inner()) = mode;
+    }
+
+    /// `name` is the destination as requested by the client, e.g. a domain name
+    pub async fn send(&self, pkt: Bytes, addr: SocketAddr, name: &Address) -> Result<(), Error> {
         let socket = match addr {
             SocketAddr::V4(_) => &self.socket_v4,
             SocketAddr::V6(_) => self
@@ -161,24 +261,76 @@ impl UdpSession {
         };
 
         socket.send_to(&pkt, addr).await?;
+        self.sent.notify_one();
+        self.count_traffic(addr, Some(name), pkt.len(), 0);
         Ok(())
     }
 
-    async fn recv(&self) -> Result<(Bytes, SocketAddr), IoError> {
-        let recv = async |socket: &UdpSocket| -> Result<(Bytes, SocketAddr), IoError> {
-            let mut buf = vec![0u8; self.ctx.cfg.max_external_packet_size];
-            let (n, addr) = socket.recv_from(&mut buf).await?;
-            buf.truncate(n);
-            Ok((Bytes::from(buf), addr))
+    fn count_traffic(&self, addr: SocketAddr, name: Option<&Address>, tx: usize, rx: usize) {
+        let Some(traffic) = &self.traffic else {
+            return;
+        };
+
+        let mut traffic = traffic.lock().unwrap_or_else(|err| err.into_inner());
+        let dest = traffic.entry(addr).or_insert_with(|| DestTraffic {
+            name: name.map_or_else(|| addr.to_string(), |name| name.to_string()),
+            tx: 0,
+            rx: 0,
+        });
+        dest.tx += tx as u64;
+        dest.rx += rx as u64;
+    }
+
+    fn record_access(&self, duration: Duration) {
+        let (Some(access_log), Some(traffic)) = (&self.ctx.access_log, &self.traffic) else {
+            return;
+        };
+
+        let traffic = traffic.lock().unwrap_or_else(|err| err.into_inner());
+        for dest in traffic.values() {
+            access_log.record(&AccessLogEntry {
+                conn_id: self.conn.id(),
+                user: self.conn.auth.get(),
+                client: self.conn.inner.remote_address(),
+                protocol: "udp",
+                destination: &dest.name,
+                tx: dest.tx,
+                rx: dest.rx,
+                duration,
+                error: None,
+            });
+        }
+    }
+
+    /// Receives a packet, along with the packets already queued on the same
+    /// socket, up to `MAX_BATCH` in total. `pools` holds the buffers of the
+    /// IPv4 and IPv6 sockets
+    async fn recv(&self, pools: &mut [BufPool; 2]) -> Result<Vec<(Bytes, SocketAddr)>, IoError> {
+        let recv = async |socket: &UdpSocket,
+                          pool: &mut BufPool|
+               -> Result<Vec<(Bytes, SocketAddr)>, IoError> {
+            let (_, addr) = socket.recv_buf_from(&mut pool.buf()).await?;
+            let mut pkts = vec![(pool.take(), addr)];
+
+            while pkts.len() < MAX_BATCH {
+                match socket.try_recv_buf_from(&mut pool.buf()) {
+                    Ok((_, addr)) => pkts.push((pool.take(), addr)),
+                    // other errors are surfaced on the next receive
+                    Err(_) => break,
+                }
+            }
+
+            Ok(pkts)
         };
 
+        let [pool_v4, pool_v6] = pools;
         if let Some(socket_v6) = &self.socket_v6 {
             tokio::select! {
-                res = recv(&self.socket_v4) => res,
-                res = recv(socket_v6) => res,
+                res = recv(&self.socket_v4, pool_v4) => res,
+                res = recv(socket_v6, pool_v6) => res,
             }
         } else {
-            recv(&self.socket_v4).await
+            recv(&self.socket_v4, pool_v4).await
         }
     }
 
@@ -0,0 +1,110 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    sync::Mutex,
+};
+
+use quinn::{Connection as QuinnConnection, VarInt};
+use tuic_quinn::{Connection as Model, side};
+use uuid::Uuid;
+
+use crate::config::{Config, DevicePolicy};
+
+/// Closes the connections refused over `max_devices`
+pub const REJECT_ERROR_CODE: VarInt = VarInt::from_u32(6001);
+/// Closes the connections kicked out by a newer one over `max_devices`
+pub const KICK_ERROR_CODE: VarInt = VarInt::from_u32(6002);
+
+/// The authenticated connections of each user, oldest first
+#[derive(Default)]
+pub struct Devices {
+    conns: Mutex<HashMap<Uuid, VecDeque<Device>>>,
+}
+
+struct Device {
+    conn: QuinnConnection,
+    model: Model<side::Server>,
+}
+
+impl Devices {
+    /// Registers a connection authenticated as `uuid`. Returns `false` if it
+    /// is over the device limit of the user and must be rejected
+    pub fn register(
+        &self,
+        cfg: &Config,
+        uuid: Uuid,
+        conn: &QuinnConnection,
+        model: &Model<side::Server>,
+    ) -> bool {
+        let (max, policy) = cfg.device_limit(&uuid);
+        let mut all = self.conns.lock().unwrap();
+        let conns = all.entry(uuid).or_default();
+        conns.retain(|device| device.conn.close_reason().is_none());
+
+        if max != 0 && conns.len() >= max as usize {
+            match policy {
+                DevicePolicy::Reject => return false,
+                DevicePolicy::KickOldest => {
+                    while conns.len() >= max as usize {
+                        let oldest = conns.pop_front().unwrap();
+                        oldest
+                            .conn
+                            .close(KICK_ERROR_CODE, b"Kicked by a newer device");
+                    }
+                }
+            }
+        }
+
+        conns.push_back(Device {
+            conn: conn.clone(),
+            model: model.clone(),
+        });
+        true
+    }
+
+    /// Closes all connections of the user `uuid`, returning how many there were
+    pub fn close(&self, uuid: &Uuid, code: VarInt, reason: &[u8]) -> usize {
+        let conns = self.conns.lock().unwrap().remove(uuid).unwrap_or_default();
+        for device in &conns {
+            device.conn.close(code, reason);
+        }
+        conns.len()
+    }
+
+    pub fn unregister(&self, uuid: &Uuid, conn: &QuinnConnection) {
+        let mut all = self.conns.lock().unwrap();
+        if let Some(conns) = all.get_mut(uuid) {
+            conns.retain(|device| device.conn.stable_id() != conn.stable_id());
+            if conns.is_empty() {
+                all.remove(uuid);
+            }
+        }
+    }
+
+    /// Writes a line per connection of each user to `out`, with its relay
+    /// tasks, for the state dump
+    pub fn dump(&self, out: &mut String) {
+        let all = self.conns.lock().unwrap();
+        let _ = writeln!(out, "users online: {}", all.len());
+
+        for (uuid, conns) in all.iter() {
+            let _ = writeln!(out, "  user {uuid}: {} connections", conns.len());
+
+            for device in conns {
+                let stats = device.conn.stats();
+                let _ = writeln!(
+                    out,
+                    "    [{id:#010x}] {addr}: rtt {rtt:?}, {tcp} TCP relays, {udp} UDP sessions, \
+                     {lost} of {sent} packets lost",
+                    id = device.conn.stable_id() as u32,
+                    addr = device.conn.remote_address(),
+                    rtt = stats.path.rtt,
+                    tcp = device.model.task_connect_count(),
+                    udp = device.model.task_associate_count(),
+                    lost = stats.path.lost_packets,
+                    sent = stats.path.sent_packets,
+                );
+            }
+        }
+    }
+}
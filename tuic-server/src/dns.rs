@@ -0,0 +1,91 @@
+use std::{
+    io::{Error as IoError, ErrorKind},
+    net::SocketAddr,
+};
+
+use hickory_resolver::{
+    TokioAsyncResolver,
+    config::{NameServerConfig, Protocol, ResolverConfig},
+    system_conf,
+};
+use tracing::warn;
+use tuic::Address;
+
+use crate::config::{DnsConfig, DnsProtocol};
+
+/// Builds the resolver used for relayed hostnames. The system resolver
+/// configuration is used if no upstream server is configured, or the default
+/// one of hickory if the system configuration can't be read
+pub fn build_resolver(cfg: &DnsConfig) -> Result<TokioAsyncResolver, IoError> {
+    let (config, mut opts) = if cfg.servers.is_empty() {
+        // e.g. no `/etc/resolv.conf` in a minimal container
+        system_conf::read_system_conf().unwrap_or_else(|err| {
+            warn!(
+                "failed to read the system DNS configuration, falling back to the default: {err}"
+            );
+            (ResolverConfig::default(), Default::default())
+        })
+    } else {
+        let mut config = ResolverConfig::new();
+
+        for server in &cfg.servers {
+            let protocol = match server.protocol {
+                DnsProtocol::Udp => Protocol::Udp,
+                DnsProtocol::Tcp => Protocol::Tcp,
+                DnsProtocol::Tls => Protocol::Tls,
+                DnsProtocol::Https => Protocol::Https,
+            };
+
+            if matches!(server.protocol, DnsProtocol::Tls | DnsProtocol::Https)
+                && server.tls_name.is_none()
+            {
+                return Err(IoError::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "DNS server {} requires `tls_name` for DoT / DoH",
+                        server.addr
+                    ),
+                ));
+            }
+
+            let mut ns = NameServerConfig::new(server.addr, protocol);
+            ns.tls_dns_name = server.tls_name.clone();
+            config.add_name_server(ns);
+        }
+
+        (config, Default::default())
+    };
+
+    opts.cache_size = cfg.cache_size;
+    opts.positive_min_ttl = cfg.min_ttl;
+    opts.positive_max_ttl = cfg.max_ttl;
+    opts.negative_min_ttl = cfg.negative_ttl;
+    opts.negative_max_ttl = cfg.negative_ttl;
+
+    Ok(TokioAsyncResolver::tokio(config, opts))
+}
+
+/// Resolves a relay target address. Lookups go through the resolver cache, so
+/// UDP packets to the same hostname do not trigger a query each
+pub async fn resolve(
+    resolver: &TokioAsyncResolver,
+    addr: &Address,
+) -> Result<impl Iterator<Item = SocketAddr>, IoError> {
+    match addr {
+        Address::None => Err(IoError::new(ErrorKind::InvalidInput, "empty address")),
+        Address::DomainAddress(domain, port) => {
+            let port = *port;
+            let lookup = resolver
+                .lookup_ip(domain.as_str())
+                .await
+                .map_err(IoError::other)?;
+
+            Ok(lookup
+                .iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect::<Vec<_>>()
+                .into_iter())
+        }
+        Address::SocketAddress(addr) => Ok(vec![*addr].into_iter()),
+    }
+}
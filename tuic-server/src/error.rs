@@ -14,6 +14,8 @@ pub enum Error {
     Rustls(#[from] RustlsError),
     #[error("invalid max idle time")]
     InvalidMaxIdleTime,
+    #[error("brutal congestion control requires a bandwidth")]
+    MissingBandwidth,
     #[error("connection timed out")]
     TimedOut,
     #[error("connection locally closed")]
@@ -26,10 +28,26 @@ pub enum Error {
     AuthFailed(Uuid),
     #[error("received packet from unexpected source")]
     UnexpectedPacketSource,
+    #[error("replayed authentication: {0}")]
+    ReplayedAuth(Uuid),
+    #[error("user {0} expired")]
+    Expired(Uuid),
+    #[error("too many devices of user {0}")]
+    TooManyDevices(Uuid),
     #[error("{0}: {1}")]
     Socket(&'static str, IoError),
     #[error("task negotiation timed out")]
     TaskNegotiationTimeout,
+    #[error("relaying to {0} is denied")]
+    Denied(String),
+    #[error("{0} relay is disabled for the user")]
+    RelayDisabled(&'static str),
+    #[error("speedtest of {0} bytes over the limit of {1} bytes")]
+    SpeedtestTooLarge(u64, u64),
+    #[error("too many relay tasks on the connection, the limit is {0}")]
+    TooManyTasks(u32),
+    #[error("too many tasks queued before authentication, the limits are {0} tasks and {1} bytes")]
+    TooManyPreAuthTasks(usize, usize),
     #[error("failed sending packet to {0}: relaying IPv6 UDP packet is disabled")]
     UdpRelayIpv6Disabled(SocketAddr),
     #[error(transparent)]
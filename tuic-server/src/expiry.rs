@@ -0,0 +1,81 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use quinn::VarInt;
+use tokio::time;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{AppContext, config::Config};
+
+/// Closes the connections of expired users
+pub const ERROR_CODE: VarInt = VarInt::from_u32(6004);
+
+/// How often the connections of expired users are looked for
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Expiry times of the users, taken from `user_settings` and updated through
+/// the RESTful API
+pub struct Expiry {
+    times: RwLock<HashMap<Uuid, SystemTime>>,
+}
+
+impl Expiry {
+    pub fn new(cfg: &Config) -> Self {
+        let times = cfg
+            .user_settings
+            .iter()
+            .filter_map(|(uuid, settings)| Some((*uuid, settings.expires_at?)))
+            .collect();
+
+        Self {
+            times: RwLock::new(times),
+        }
+    }
+
+    pub fn is_expired(&self, uuid: &Uuid) -> bool {
+        self.times
+            .read()
+            .unwrap()
+            .get(uuid)
+            .is_some_and(|at| *at <= SystemTime::now())
+    }
+
+    /// Sets the expiry time of the user `uuid`, `None` for never
+    pub fn set(&self, uuid: Uuid, at: Option<SystemTime>) {
+        let mut times = self.times.write().unwrap();
+        match at {
+            Some(at) => times.insert(uuid, at),
+            None => times.remove(&uuid),
+        };
+    }
+
+    /// Returns the users expired by now
+    pub fn expired(&self) -> Vec<Uuid> {
+        let now = SystemTime::now();
+        self.times
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(uuid, _)| *uuid)
+            .collect()
+    }
+}
+
+/// Closes the connections of users as they expire, with `terminate_expired`
+pub async fn enforce(ctx: Arc<AppContext>) {
+    loop {
+        time::sleep(CHECK_INTERVAL).await;
+
+        for uuid in ctx.expiry.expired() {
+            let closed = ctx.devices.close(&uuid, ERROR_CODE, b"User expired");
+            if closed != 0 {
+                info!("[{uuid}] closed {closed} connections of expired user");
+            }
+        }
+    }
+}
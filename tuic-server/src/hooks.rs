@@ -0,0 +1,37 @@
+use std::net::SocketAddr;
+
+use tuic::Address;
+use uuid::Uuid;
+
+/// Callbacks for applications hosting the server in-process, e.g. panels
+/// managing the users in their own database
+///
+/// All methods have a default implementation keeping the behavior of the
+/// standalone server. They are called on the relay tasks, so they should
+/// return quickly
+pub trait Hooks: Send + Sync + 'static {
+    /// Returns the password of the user `uuid`. Users it returns `None` for
+    /// are looked up in the `users` table of the configuration
+    fn password(&self, uuid: &Uuid) -> Option<String> {
+        let _ = uuid;
+        None
+    }
+
+    /// Called when a client at `addr` has authenticated as `uuid`
+    fn authenticated(&self, uuid: &Uuid, addr: SocketAddr) {
+        let _ = (uuid, addr);
+    }
+
+    /// Returns whether the user `uuid` may relay to `target`. Denied TCP
+    /// relays are refused, and denied UDP packets dropped
+    fn allow(&self, uuid: &Uuid, target: &Address) -> bool {
+        let _ = (uuid, target);
+        true
+    }
+
+    /// Accounts the traffic relayed for the user `uuid`, in bytes. `tx` is
+    /// sent by the client to targets, `rx` received from targets
+    fn traffic(&self, uuid: &Uuid, tx: u64, rx: u64) {
+        let _ = (uuid, tx, rx);
+    }
+}
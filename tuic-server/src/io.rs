@@ -1,42 +1,110 @@
-use std::time::Duration;
+use std::{
+    future,
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+    time::Duration,
+};
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use bytes::BytesMut;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::{self, Interval},
+};
 
-const BUFFER_SIZE: usize = 8 * 1024;
+/// The most memory kept in relay buffers for reuse once their relays ended
+const MAX_POOLED_BYTES: usize = 16 * 1024 * 1024;
 
+static BUFFER_POOL: Mutex<Vec<BytesMut>> = Mutex::new(Vec::new());
+
+/// A relay buffer taken from the pool, given back once dropped, so relays
+/// don't allocate their buffers each time under connection churn
+struct PooledBuffer(BytesMut);
+
+impl PooledBuffer {
+    fn take(size: usize) -> Self {
+        let buf = BUFFER_POOL
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .pop()
+            .unwrap_or_default();
+        let mut buf = Self(buf);
+        buf.reclaim(size);
+        buf
+    }
+
+    /// Empties the buffer and makes room for reading `size` more bytes,
+    /// reusing its allocation once everything read was written out
+    fn reclaim(&mut self, size: usize) {
+        self.0.clear();
+        self.0.reserve(size);
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut pool = BUFFER_POOL.lock().unwrap_or_else(|err| err.into_inner());
+        if (pool.len() + 1) * self.0.capacity() <= MAX_POOLED_BYTES {
+            self.0.clear();
+            pool.push(std::mem::take(&mut self.0));
+        }
+    }
+}
+
+/// Relays between the QUIC stream and the outbound TCP stream, with a buffer
+/// of at least `buffer_size` bytes in each direction taken from a pool shared
+/// by all the relays
+///
+/// Reads fill the spare capacity of the buffers directly, and writes to the
+/// TCP stream are vectored. No splice-style zero-copy is possible here, as
+/// QUIC streams are implemented in userspace
 pub async fn exchange_tcp(
     a: &mut tuic_quinn::Connect,
     b: &mut tokio::net::TcpStream,
     timeout: Duration,
+    buffer_size: usize,
 ) -> (usize, usize, Option<eyre::Error>) {
-    let mut a2b = [0u8; BUFFER_SIZE];
-    let mut b2a = [0u8; BUFFER_SIZE];
+    let mut a2b = PooledBuffer::take(buffer_size);
+    let mut b2a = PooledBuffer::take(buffer_size);
 
     let mut a2b_num = 0;
     let mut b2a_num = 0;
 
     let mut last_err = None;
-    let mut timeout = tokio::time::interval(timeout);
-    timeout.reset();
+    let mut idle = RelayIdleTimer::new(timeout);
 
     loop {
         tokio::select! {
-            _ = timeout.tick() => {
-                last_err = Some(eyre::eyre!("TCP stream timeout"));
+            () = idle.elapsed() => {
+                last_err = Some(eyre::eyre!("TCP relay idle for {timeout:?}, closed"));
                 break;
             },
-            a2b_res = a.recv.read(&mut a2b) => match a2b_res {
-                Ok(Some(num)) => {
+            a2b_res = a.read_buf(&mut *a2b) => match a2b_res {
+                Ok(num) => {
+                    // EOF
+                    if num == 0 {
+                        break;
+                    }
                     a2b_num += num;
-                    timeout.reset();
-                    if let Err(err) = b.write_all(&a2b[..num]).await {
+                    idle.reset();
+                    if let Err(err) = b.write_all_buf(&mut *a2b).await {
                         last_err = Some(err.into());
                         break;
                     }
-                },
-                // EOF
-                Ok(None) => {
-                    break;
+                    a2b.reclaim(buffer_size);
                 },
                 Err(err) => {
                     last_err = Some(err.into());
@@ -44,18 +112,20 @@ pub async fn exchange_tcp(
                 }
             },
 
-            b2a_res = b.read(&mut b2a) => match b2a_res {
+            b2a_res = b.read_buf(&mut *b2a) => match b2a_res {
                 Ok(num) => {
                     // EOF
                     if num == 0 {
                         break;
                     }
                     b2a_num += num;
-                    timeout.reset();
-                    if let Err(err) = a.send.write_all(&b2a[..num]).await {
+                    idle.reset();
+                    // compressed data is buffered until flushed
+                    if let Err(err) = write_flush(a, &b2a).await {
                         last_err = Some(err.into());
                         break;
                     }
+                    b2a.reclaim(buffer_size);
                 },
                 Err(err) => {
                     last_err = Some(err.into());
@@ -68,3 +138,39 @@ pub async fn exchange_tcp(
 
     (a2b_num, b2a_num, last_err)
 }
+
+/// Fires once a relay task went `stream_timeout` without traffic, however
+/// long the QUIC connection is kept alive. Never fires if the timeout is 0
+pub struct RelayIdleTimer(Option<Interval>);
+
+impl RelayIdleTimer {
+    pub fn new(timeout: Duration) -> Self {
+        Self((!timeout.is_zero()).then(|| {
+            let mut interval = time::interval(timeout);
+            interval.reset();
+            interval
+        }))
+    }
+
+    /// Restarts the timeout, on traffic
+    pub fn reset(&mut self) {
+        if let Some(interval) = &mut self.0 {
+            interval.reset();
+        }
+    }
+
+    /// Waits for the timeout to elapse
+    pub async fn elapsed(&mut self) {
+        match &mut self.0 {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => future::pending().await,
+        }
+    }
+}
+
+pub async fn write_flush(a: &mut tuic_quinn::Connect, buf: &[u8]) -> std::io::Result<()> {
+    a.write_all(buf).await?;
+    a.flush().await
+}
@@ -0,0 +1,165 @@
+//! The TUIC server, embeddable in other applications
+//!
+//! Besides the `tuic-server` binary, the server can be hosted in-process, e.g.
+//! by panels managing the users in their own database. Build it with
+//! [`Server::builder`], optionally with [`Hooks`] for authentication, access
+//! control and accounting. The other modules are the internals of the binary,
+//! with no stability guarantee.
+
+#![feature(let_chains, trivial_bounds)]
+
+#[cfg(all(feature = "console", not(tokio_unstable)))]
+compile_error!("the `console` feature requires building with RUSTFLAGS=\"--cfg tokio_unstable\"");
+
+use std::{fmt::Write, sync::Arc};
+
+use eyre::Context;
+use hickory_resolver::TokioAsyncResolver;
+use register_count::Counter;
+use tuic_quinn::{MemoryBudget, ReassemblyStats};
+
+use self::{
+    access_log::AccessLog, ban::BanList, connection::Bonds, devices::Devices, expiry::Expiry,
+    limit::ConnectionLimiter, masquerade::Masquerade, replay::ReplayCache, traffic::Traffic,
+};
+pub use self::{
+    config::Config,
+    hooks::Hooks,
+    server::{Server, ServerBuilder},
+};
+
+#[doc(hidden)]
+pub mod access_log;
+#[doc(hidden)]
+pub mod ban;
+#[doc(hidden)]
+pub mod check;
+pub mod config;
+#[doc(hidden)]
+pub mod connection;
+#[doc(hidden)]
+pub mod devices;
+#[doc(hidden)]
+pub mod dns;
+#[doc(hidden)]
+pub mod error;
+#[doc(hidden)]
+pub mod expiry;
+mod hooks;
+#[doc(hidden)]
+pub mod io;
+#[doc(hidden)]
+pub mod limit;
+#[doc(hidden)]
+pub mod log_level;
+#[doc(hidden)]
+pub mod masquerade;
+#[doc(hidden)]
+pub mod old_config;
+#[doc(hidden)]
+pub mod outbound;
+#[doc(hidden)]
+pub mod replay;
+#[doc(hidden)]
+pub mod restful;
+mod server;
+#[doc(hidden)]
+pub mod subscription;
+#[cfg(target_os = "linux")]
+#[doc(hidden)]
+pub mod systemd;
+#[doc(hidden)]
+pub mod tls;
+#[doc(hidden)]
+pub mod traffic;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+#[doc(hidden)]
+pub mod uring;
+#[doc(hidden)]
+pub mod utils;
+
+#[doc(hidden)]
+pub struct AppContext {
+    pub cfg: Config,
+    pub resolver: TokioAsyncResolver,
+    pub access_log: Option<AccessLog>,
+    pub reassembly_memory: Arc<MemoryBudget>,
+    pub reassembly_stats: Arc<ReassemblyStats>,
+    pub replay_cache: ReplayCache,
+    pub bans: Option<BanList>,
+    pub limiter: Option<Arc<ConnectionLimiter>>,
+    pub connections: Counter,
+    pub devices: Devices,
+    pub bonds: Bonds,
+    pub expiry: Expiry,
+    pub traffic: Traffic,
+    pub masquerade: Option<Masquerade>,
+    pub hooks: Option<Arc<dyn Hooks>>,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub uring: Option<uring::UringPool>,
+}
+
+impl AppContext {
+    pub fn new(cfg: Config, hooks: Option<Arc<dyn Hooks>>) -> eyre::Result<Self> {
+        check::validate(&cfg)?;
+        let resolver = dns::build_resolver(&cfg.dns)?;
+        let access_log = cfg.access_log.as_ref().map(AccessLog::open).transpose()?;
+        let reassembly_memory = Arc::new(MemoryBudget::new(cfg.max_reassembly_memory));
+        let replay_cache = ReplayCache::new(cfg.zero_rtt_replay_window);
+        let bans = cfg.ban.as_ref().map(BanList::new);
+        let expiry = Expiry::new(&cfg);
+        let traffic = Traffic::new(&cfg).context("failed to load traffic data")?;
+        let masquerade = Masquerade::new(&cfg)?;
+        let limiter = cfg
+            .connection_limit
+            .as_ref()
+            .map(|limit| Arc::new(ConnectionLimiter::new(limit)));
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        let uring = cfg
+            .outbound
+            .io_uring
+            .then(uring::UringPool::new)
+            .transpose()
+            .map_err(|err| eyre::eyre!("failed to start the io_uring runtimes: {err}"))?;
+
+        Ok(Self {
+            cfg,
+            resolver,
+            access_log,
+            reassembly_memory,
+            reassembly_stats: Arc::new(ReassemblyStats::default()),
+            replay_cache,
+            bans,
+            limiter,
+            connections: Counter::new(),
+            devices: Devices::default(),
+            bonds: Bonds::default(),
+            expiry,
+            traffic,
+            masquerade,
+            hooks,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            uring,
+        })
+    }
+
+    /// Returns a human-readable snapshot of the connections, the relay tasks
+    /// of each user and the memory used by UDP reassembly, logged on `SIGUSR1`
+    /// and served on the `/dump` RESTful endpoint to debug leaks and stuck
+    /// sessions
+    pub fn dump(&self) -> String {
+        let mut out = String::from("state dump\n");
+        let _ = writeln!(out, "connections: {}", self.connections.count());
+        self.devices.dump(&mut out);
+        let _ = write!(
+            out,
+            "UDP reassembly: {used} of {limit} bytes buffered, {duplicated} duplicated and \
+             {out_of_window} out-of-window packets dropped",
+            used = self.reassembly_memory.used(),
+            limit = self.cfg.max_reassembly_memory,
+            duplicated = self.reassembly_stats.duplicated(),
+            out_of_window = self.reassembly_stats.out_of_window(),
+        );
+        out
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::config::ConnectionLimitConfig;
+
+/// Limits the new and concurrent QUIC connections per source IP, checked
+/// before the TLS handshake to survive UDP connection floods
+///
+/// New connections are rate limited with a token bucket refilled by `rate`
+/// tokens per second, holding up to `burst` tokens
+pub struct ConnectionLimiter {
+    rate: f64,
+    burst: f64,
+    max_per_ip: u32,
+    ips: Mutex<Ips>,
+}
+
+struct Ips {
+    states: HashMap<IpAddr, IpState>,
+    last_pruned: Instant,
+}
+
+struct IpState {
+    tokens: f64,
+    last_refill: Instant,
+    active: u32,
+}
+
+/// Why a connection was refused
+#[derive(Clone, Copy, Debug)]
+pub enum LimitExceeded {
+    Rate,
+    Concurrent,
+}
+
+/// Counts a connection as active until dropped
+pub struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl ConnectionLimiter {
+    pub fn new(cfg: &ConnectionLimitConfig) -> Self {
+        Self {
+            rate: cfg.rate as f64,
+            burst: cfg.burst.max(cfg.rate).max(1) as f64,
+            max_per_ip: cfg.max_per_ip,
+            ips: Mutex::new(Ips {
+                states: HashMap::new(),
+                last_pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Admits a new connection from `ip`, if within the limits
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionPermit, LimitExceeded> {
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        let mut ips = self.ips.lock().unwrap();
+
+        if now.duration_since(ips.last_pruned) >= Duration::from_secs(1) {
+            let (rate, burst) = (self.rate, self.burst);
+            ips.states.retain(|_, state| {
+                state.active > 0
+                    || state.tokens + now.duration_since(state.last_refill).as_secs_f64() * rate
+                        < burst
+            });
+            ips.last_pruned = now;
+        }
+
+        let state = ips.states.entry(ip).or_insert(IpState {
+            tokens: self.burst,
+            last_refill: now,
+            active: 0,
+        });
+
+        if self.max_per_ip != 0 && state.active >= self.max_per_ip {
+            return Err(LimitExceeded::Concurrent);
+        }
+
+        if self.rate > 0.0 {
+            let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * self.rate).min(self.burst);
+            state.last_refill = now;
+
+            if state.tokens < 1.0 {
+                return Err(LimitExceeded::Rate);
+            }
+            state.tokens -= 1.0;
+        }
+
+        state.active += 1;
+        Ok(ConnectionPermit {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut ips = self.limiter.ips.lock().unwrap();
+        if let Some(state) = ips.states.get_mut(&self.ip) {
+            state.active = state.active.saturating_sub(1);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Changing the log filter of the running server, through the RESTful API or
+//! on `SIGUSR2`, without restarting it and losing the state of its
+//! connections
+
+use std::sync::Mutex;
+
+use eyre::eyre;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{filter::Targets, reload::Error as ReloadError};
+
+type Reload = Box<dyn Fn(Targets) -> Result<(), ReloadError> + Send + Sync>;
+
+struct State {
+    reload: Reload,
+    /// The filter of the configuration
+    configured: Targets,
+    /// Whether `toggle_debug()` switched to `DEBUG`
+    debug: bool,
+}
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+/// Returns the filter logging the crates of TUIC at `level` and the others at
+/// `INFO`, for a `log_level` that is a single level
+pub fn filter(level: LevelFilter) -> Targets {
+    Targets::new()
+        .with_targets(vec![
+            ("tuic", level),
+            ("tuic_quinn", level),
+            ("tuic_server", level),
+        ])
+        .with_default(LevelFilter::INFO)
+}
+
+/// Sets how the filter of the log layers is replaced, once they are set up
+/// with `configured`
+pub fn init(
+    configured: Targets,
+    reload: impl Fn(Targets) -> Result<(), ReloadError> + Send + Sync + 'static,
+) {
+    *STATE.lock().unwrap() = Some(State {
+        reload: Box::new(reload),
+        configured,
+        debug: false,
+    });
+}
+
+/// Filters the logs with `filter` from now on. Fails if the log layers are
+/// not set up by the `tuic-server` binary, e.g. when embedded
+pub fn set(filter: Targets) -> eyre::Result<()> {
+    let mut state = STATE.lock().unwrap();
+    let state = state.as_mut().ok_or_else(not_managed)?;
+
+    (state.reload)(filter)?;
+    state.debug = false;
+    Ok(())
+}
+
+/// Switches between logging the crates of TUIC at `DEBUG` and the filter of
+/// the configuration, returning whether `DEBUG` is on
+pub fn toggle_debug() -> eyre::Result<bool> {
+    let mut state = STATE.lock().unwrap();
+    let state = state.as_mut().ok_or_else(not_managed)?;
+
+    let next = if state.debug {
+        state.configured.clone()
+    } else {
+        filter(LevelFilter::DEBUG)
+    };
+
+    (state.reload)(next)?;
+    state.debug = !state.debug;
+    Ok(state.debug)
+}
+
+fn not_managed() -> eyre::Report {
+    eyre!("the log filter is not managed by tuic-server")
+}
@@ -1,23 +1,18 @@
-#![feature(let_chains, trivial_bounds)]
-
-use std::{env, process, sync::Arc};
+use std::{env, ffi::OsString, process, sync::Arc};
 
 use chrono::{Local, Offset, TimeZone};
-use config::{Config, parse_config};
-use tracing::level_filters::LevelFilter;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-
-use crate::{old_config::ConfigError, server::Server};
-
-mod config;
-mod connection;
-mod error;
-mod io;
-mod old_config;
-mod restful;
-mod server;
-mod tls;
-mod utils;
+use tracing_subscriber::{
+    Layer, fmt::writer::BoxMakeWriter, layer::SubscriberExt, reload, util::SubscriberInitExt,
+};
+#[cfg(windows)]
+use tuic_common::service::{self, Service};
+use tuic_common::{log_file::LogFile, runtime};
+use tuic_server::{
+    AppContext, Server, ban, check,
+    config::{Config, LogFileConfig, LogFormat, parse_config},
+    log_level,
+    old_config::ConfigError,
+};
 
 #[cfg(feature = "jemallocator")]
 use tikv_jemallocator::Jemalloc;
@@ -26,14 +21,44 @@ use tikv_jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
-struct AppContext {
-    pub cfg: Config,
-}
+#[cfg(windows)]
+static SERVICE: Service = Service {
+    name: "tuic-server",
+    display_name: "TUIC Server",
+    description: "TUIC proxy server",
+};
 
-#[tokio::main]
+// only drives the configuration parsing and the shutdown signal, the server
+// itself runs on the runtime built from the `runtime` section
+#[tokio::main(flavor = "current_thread")]
 async fn main() -> eyre::Result<()> {
     std::env::set_var("RUST_BACKTRACE", "1");
-    let cfg = match parse_config(env::args_os()).await {
+
+    #[cfg(windows)]
+    if let Some(res) = service::handle_command(&SERVICE, |args, shutdown| {
+        run(args, async move {
+            _ = shutdown.await;
+        })
+    })
+    .await
+    {
+        return res.map_err(Into::into);
+    }
+
+    run(env::args_os(), async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for event");
+    })
+    .await
+}
+
+/// Runs the server until `shutdown` completes
+async fn run(
+    args: impl IntoIterator<Item = OsString>,
+    shutdown: impl Future<Output = ()>,
+) -> eyre::Result<()> {
+    let cfg = match parse_config(args).await {
         Ok(cfg) => cfg,
         Err(ConfigError::Version(msg) | ConfigError::Help(msg)) => {
             println!("{msg}");
@@ -44,48 +69,180 @@ async fn main() -> eyre::Result<()> {
             process::exit(1);
         }
     };
-    let ctx = Arc::new(AppContext { cfg });
-
-    let filter = tracing_subscriber::filter::Targets::new()
-        .with_targets(vec![
-            ("tuic", ctx.cfg.log_level),
-            ("tuic_quinn", ctx.cfg.log_level),
-            ("tuic_server", ctx.cfg.log_level),
-        ])
-        .with_default(LevelFilter::INFO);
-    let registry = tracing_subscriber::registry();
-    registry
-        .with(filter)
-        .with(
-            tracing_subscriber::fmt::layer()
-                .with_target(true)
-                .with_timer(tracing_subscriber::fmt::time::OffsetTime::new(
-                    time::UtcOffset::from_whole_seconds(
-                        Local
-                            .timestamp_opt(0, 0)
-                            .unwrap()
-                            .offset()
-                            .fix()
-                            .local_minus_utc(),
-                    )
-                    .unwrap_or(time::UtcOffset::UTC),
-                    time::macros::format_description!(
-                        "[year repr:last_two]-[month]-[day] [hour]:[minute]:[second]"
-                    ),
-                )),
-        )
-        .try_init()?;
-    tokio::spawn(async move {
-        match Server::init(ctx.clone()).await {
-            Ok(server) => server.start().await,
+    if cfg.check {
+        match check::check(&cfg).await {
+            Ok(()) => {
+                println!("configuration OK");
+                process::exit(0);
+            }
             Err(err) => {
-                eprintln!("{err}");
+                eprintln!("{err:#}");
                 process::exit(1);
             }
         }
-    });
-    tokio::signal::ctrl_c()
-        .await
-        .expect("failed to listen for event");
+    }
+
+    if let Some(cmd) = &cfg.ban_command {
+        let Some(restful) = &cfg.restful else {
+            eprintln!("the `restful` section is required to query the running server");
+            process::exit(1);
+        };
+        match ban::run_command(restful, cmd).await {
+            Ok(body) => {
+                println!("{body}");
+                process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("{err:#}");
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(host) = &cfg.share_link {
+        for link in cfg.share_links(host) {
+            println!("{link}");
+        }
+        process::exit(0);
+    }
+
+    let runtime = runtime::build(cfg.runtime.worker_threads, &cfg.runtime.cpu_affinity)?;
+    let serve = runtime.spawn(serve(cfg));
+    let res = tokio::select! {
+        res = serve => res.map_err(eyre::Report::from).and_then(|res| res),
+        () = shutdown => Ok(()),
+    };
+    runtime.shutdown_background();
+    res
+}
+
+/// Sets up logging and the application context, then runs the server
+async fn serve(cfg: Config) -> eyre::Result<()> {
+    let ctx = Arc::new(AppContext::new(cfg, None)?);
+
+    let configured = ctx.cfg.log_level.targets().clone();
+    let (filter, reload) = reload::Layer::new(configured.clone());
+    let timer = tracing_subscriber::fmt::time::OffsetTime::new(
+        time::UtcOffset::from_whole_seconds(
+            Local
+                .timestamp_opt(0, 0)
+                .unwrap()
+                .offset()
+                .fix()
+                .local_minus_utc(),
+        )
+        .unwrap_or(time::UtcOffset::UTC),
+        time::macros::format_description!(
+            "[year repr:last_two]-[month]-[day] [hour]:[minute]:[second]"
+        ),
+    );
+    let log_file = ctx
+        .cfg
+        .log_file
+        .as_ref()
+        .map(LogFileConfig::open)
+        .transpose()?;
+    let writer = match log_file.clone() {
+        Some(log_file) => BoxMakeWriter::new(move || log_file.clone()),
+        None => BoxMakeWriter::new(std::io::stdout),
+    };
+    let (text, json) = match ctx.cfg.log_format {
+        LogFormat::Text => (
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_target(true)
+                    .with_timer(timer)
+                    .with_writer(writer),
+            ),
+            None,
+        ),
+        LogFormat::Json => (
+            None,
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_current_span(false)
+                    .with_span_list(true)
+                    .with_timer(timer)
+                    .with_writer(writer),
+            ),
+        ),
+    };
+    let registry = tracing_subscriber::registry();
+    // tokio-console needs the task spans of tokio, so the filter only applies
+    // to the log layers
+    #[cfg(feature = "console")]
+    let registry = registry.with(console_subscriber::spawn());
+    // `Layer::and_then`, not the one of `Option`
+    registry
+        .with(Layer::and_then(text, json).with_filter(filter))
+        .try_init()?;
+    log_level::init(configured, move |filter| reload.reload(filter));
+    #[cfg(unix)]
+    tokio::spawn(toggle_debug_on_signal());
+    #[cfg(unix)]
+    {
+        let log_files = log_file
+            .into_iter()
+            .chain(ctx.access_log.as_ref().map(|log| log.log_file().clone()))
+            .collect::<Vec<_>>();
+        tokio::spawn(handle_sigusr1(ctx.clone(), log_files));
+    }
+    match Server::init(ctx.clone()).await {
+        Ok(server) => server.start().await,
+        Err(err) => {
+            eprintln!("{err}");
+            process::exit(1);
+        }
+    }
     Ok(())
 }
+
+/// Re-opens the log files on `SIGUSR1`, so external tools can rotate them,
+/// then logs a dump of the state of the server
+#[cfg(unix)]
+async fn handle_sigusr1(ctx: Arc<AppContext>, log_files: Vec<LogFile>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut sig = match signal(SignalKind::user_defined1()) {
+        Ok(sig) => sig,
+        Err(err) => {
+            tracing::warn!("failed to listen for SIGUSR1: {err}");
+            return;
+        }
+    };
+
+    while sig.recv().await.is_some() {
+        for log_file in &log_files {
+            match log_file.reopen() {
+                Ok(()) => tracing::info!("log file re-opened"),
+                Err(err) => tracing::warn!("failed to re-open log file: {err}"),
+            }
+        }
+
+        tracing::warn!("{}", ctx.dump());
+    }
+}
+
+/// Switches between the `debug` log level and the configured one on
+/// `SIGUSR2`, e.g. while reproducing an issue
+#[cfg(unix)]
+async fn toggle_debug_on_signal() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut sig = match signal(SignalKind::user_defined2()) {
+        Ok(sig) => sig,
+        Err(err) => {
+            tracing::warn!("failed to listen for SIGUSR2: {err}");
+            return;
+        }
+    };
+
+    while sig.recv().await.is_some() {
+        match log_level::toggle_debug() {
+            Ok(true) => tracing::warn!("debug logging on"),
+            Ok(false) => tracing::warn!("debug logging off, back to the configured log level"),
+            Err(err) => tracing::warn!("failed to change log level: {err}"),
+        }
+    }
+}
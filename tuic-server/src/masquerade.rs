@@ -0,0 +1,217 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use quinn::{
+    ClientConfig, Connection as QuinnConnection, Endpoint, RecvStream, SendStream,
+    crypto::rustls::QuicClientConfig,
+};
+use rustls::{
+    ClientConfig as RustlsClientConfig,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, ServerName, UnixTime},
+};
+use tokio::io;
+
+use crate::{
+    config::{AlpnRoute, Config},
+    error::Error,
+};
+
+/// A local QUIC service that connections are relayed to
+#[derive(Clone)]
+pub struct Backend {
+    pub addr: SocketAddr,
+    pub server_name: String,
+}
+
+impl Backend {
+    /// Returns the masquerade web server
+    pub fn masquerade(cfg: &Config) -> Option<Self> {
+        cfg.masquerade.as_ref().map(|masquerade| Self {
+            addr: masquerade.addr,
+            server_name: masquerade.server_name.clone(),
+        })
+    }
+
+    /// Returns the service connections negotiating `alpn` are relayed to as a
+    /// whole, or `None` if they are handled as TUIC
+    pub fn route(cfg: &Config, alpn: &[u8]) -> Option<Self> {
+        let route = cfg
+            .alpn_routes
+            .iter()
+            .find(|(proto, _)| proto.as_bytes() == alpn)
+            .map(|(_, route)| route);
+
+        match route {
+            Some(AlpnRoute::Tuic) => None,
+            Some(AlpnRoute::Forward { addr, server_name }) => Some(Self {
+                addr: *addr,
+                server_name: server_name.as_deref().unwrap_or("localhost").to_owned(),
+            }),
+            None if cfg.masquerade.as_ref().is_some_and(|masquerade| {
+                masquerade.alpn.iter().any(|proto| proto.as_bytes() == alpn)
+                    && !cfg.tls.alpn.iter().any(|proto| proto.as_bytes() == alpn)
+            }) =>
+            {
+                Self::masquerade(cfg)
+            }
+            None => None,
+        }
+    }
+
+    fn all(cfg: &Config) -> impl Iterator<Item = SocketAddr> + '_ {
+        cfg.masquerade
+            .iter()
+            .map(|masquerade| masquerade.addr)
+            .chain(cfg.alpn_routes.values().filter_map(|route| match route {
+                AlpnRoute::Tuic => None,
+                AlpnRoute::Forward { addr, .. } => Some(*addr),
+            }))
+    }
+}
+
+/// The QUIC client towards the local services that connections of non-TUIC
+/// clients are relayed to, i.e. the masquerade web server and the services of
+/// `alpn_routes`
+pub struct Masquerade {
+    v4: Option<Endpoint>,
+    v6: Option<Endpoint>,
+    crypto: RustlsClientConfig,
+}
+
+impl Masquerade {
+    /// Returns `None` if no connection is ever relayed
+    pub fn new(cfg: &Config) -> Result<Option<Self>, Error> {
+        let (mut v4, mut v6) = (None, None);
+
+        for addr in Backend::all(cfg) {
+            if addr.is_ipv4() && v4.is_none() {
+                v4 = Some(Endpoint::client(SocketAddr::from((
+                    Ipv4Addr::UNSPECIFIED,
+                    0,
+                )))?);
+            } else if addr.is_ipv6() && v6.is_none() {
+                v6 = Some(Endpoint::client(SocketAddr::from((
+                    Ipv6Addr::UNSPECIFIED,
+                    0,
+                )))?);
+            }
+        }
+
+        if v4.is_none() && v6.is_none() {
+            return Ok(None);
+        }
+
+        // the services are local ones, so their certificates are not verified
+        let crypto = RustlsClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(SkipServerVerification::new())
+            .with_no_client_auth();
+
+        Ok(Some(Self { v4, v6, crypto }))
+    }
+
+    /// Connects to `backend`, negotiating the same ALPN protocol as the client
+    pub async fn connect(
+        &self,
+        backend: &Backend,
+        alpn: Option<Vec<u8>>,
+    ) -> Result<QuinnConnection, Error> {
+        let mut crypto = self.crypto.clone();
+        crypto.alpn_protocols = alpn.into_iter().collect();
+
+        let crypto = QuicClientConfig::try_from(crypto).map_err(|err| Error::Other(err.into()))?;
+        let cfg = ClientConfig::new(Arc::new(crypto));
+
+        let endpoint = if backend.addr.is_ipv4() {
+            &self.v4
+        } else {
+            &self.v6
+        };
+
+        let conn = endpoint
+            .as_ref()
+            .unwrap()
+            .connect_with(cfg, backend.addr, &backend.server_name)
+            .map_err(|err| Error::Other(err.into()))?
+            .await?;
+
+        Ok(conn)
+    }
+}
+
+/// Copies `recv` to `send`, starting with `prefix`
+pub async fn copy(prefix: &[u8], mut recv: RecvStream, mut send: SendStream) {
+    let res = async {
+        send.write_all(prefix).await?;
+        io::copy(&mut recv, &mut send).await?;
+        send.finish()?;
+        Ok::<_, io::Error>(())
+    };
+
+    if res.await.is_err() {
+        let _ = send.reset(0u32.into());
+        let _ = recv.stop(0u32.into());
+    }
+}
+
+#[derive(Debug)]
+struct SkipServerVerification(Arc<CryptoProvider>);
+
+impl SkipServerVerification {
+    fn new() -> Arc<Self> {
+        Arc::new(Self(
+            CryptoProvider::get_default()
+                .expect("Crypto not found")
+                .clone(),
+        ))
+    }
+}
+
+impl ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
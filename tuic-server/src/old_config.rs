@@ -17,9 +17,22 @@ Usage tuic-server [arguments]
 
 Arguments:
     -c, --config <path>     Path to the config file (required)
+    -f, --format <format>   Format of the config file: toml, yaml or json (legacy)
+                            Default: detected from the file extension
+    --set <key>=<value>     Override a config field, can be repeated
+                            e.g. --set server=[::]:8443 --set quic.congestion_control.controller=bbr
     -v, --version           Print the version
     -h, --help              Print this help message
     -i, --init              Generate a example configuration (config.toml)
+    --check                 Validate the configuration, certificate and key, then exit
+    --share-link <host>     Print a tuic:// share link for each user, connecting to <host>, then exit
+    --bans                  Print the IPs with authentication failures or banned by the running server, then exit
+    --unban <ip|all>        Lift the ban of an IP, or of all IPs, on the running server, then exit
+                            Both go through the RESTful API, which must be enabled
+
+Windows only:
+    --install-service       Install as a Windows service, using the given config file
+    --uninstall-service     Stop and uninstall the Windows service
 "#;
 
 #[derive(Deserialize)]
@@ -232,6 +245,10 @@ pub enum ConfigError {
     Argument(#[from] ArgumentError),
     #[error("no config file specified")]
     NoConfig,
+    #[error("invalid config override `{0}`, expected `--set <key>=<value>`")]
+    InvalidOverride(String),
+    #[error("invalid IP address `{0}`, expected an IP or `all`")]
+    InvalidIp(String),
     #[error("{0}")]
     Version(&'static str),
     #[error("{0}")]
@@ -240,4 +257,6 @@ pub enum ConfigError {
     Io(#[from] IoError),
     #[error(transparent)]
     Serde(#[from] SerdeError),
+    #[error(transparent)]
+    Figment(#[from] Box<figment::Error>),
 }
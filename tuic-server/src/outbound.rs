@@ -0,0 +1,287 @@
+use std::{
+    io::{Error as IoError, ErrorKind},
+    net::{IpAddr, SocketAddr},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use bytes::{BufMut, BytesMut};
+use socket2::{SockRef, Socket};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpSocket, TcpStream},
+};
+use tuic::Address;
+use tuic_common::socket::set_dscp;
+
+use crate::config::{OutboundConfig, OutboundStrategy, UpstreamConfig, UpstreamKind};
+
+/// Filters and orders resolved addresses by the configured IP family
+/// strategy. The relative order within a family is kept
+pub fn apply_strategy(
+    strategy: OutboundStrategy,
+    addrs: impl Iterator<Item = SocketAddr>,
+) -> Vec<SocketAddr> {
+    let mut addrs = addrs.collect::<Vec<_>>();
+
+    match strategy {
+        OutboundStrategy::Auto => {}
+        OutboundStrategy::PreferIpv4 => addrs.sort_by_key(SocketAddr::is_ipv6),
+        OutboundStrategy::PreferIpv6 => addrs.sort_by_key(SocketAddr::is_ipv4),
+        OutboundStrategy::Ipv4Only => addrs.retain(SocketAddr::is_ipv4),
+        OutboundStrategy::Ipv6Only => addrs.retain(SocketAddr::is_ipv6),
+    }
+
+    addrs
+}
+
+/// Establishes a TCP connection to `addr`, honoring the configured outbound
+/// bind address and interface
+pub async fn connect_tcp(cfg: &OutboundConfig, addr: SocketAddr) -> Result<TcpStream, IoError> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    if let Some(interface) = &cfg.interface {
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        socket.bind_device(Some(interface.as_bytes()))?;
+        #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+        return Err(unsupported_interface(interface));
+    }
+
+    if let Some(dscp) = cfg.dscp {
+        set_dscp(SockRef::from(&socket), dscp)?;
+    }
+
+    if let Some(ip) = cfg.bind_ip(&addr) {
+        socket.bind(SocketAddr::new(ip, 0))?;
+    }
+
+    socket.connect(addr).await
+}
+
+/// Establishes a TCP connection to `target` through the upstream proxy. The
+/// target address is passed to the proxy as-is, so domain names are resolved
+/// by the proxy
+pub async fn connect_upstream(
+    cfg: &OutboundConfig,
+    upstream: &UpstreamConfig,
+    target: &Address,
+) -> Result<TcpStream, IoError> {
+    let mut stream = connect_tcp(cfg, upstream.addr).await?;
+
+    match upstream.kind {
+        UpstreamKind::Socks5 => socks5_handshake(&mut stream, upstream, target).await?,
+        UpstreamKind::Http => http_handshake(&mut stream, upstream, target).await?,
+    }
+
+    Ok(stream)
+}
+
+async fn socks5_handshake(
+    stream: &mut TcpStream,
+    upstream: &UpstreamConfig,
+    target: &Address,
+) -> Result<(), IoError> {
+    const VERSION: u8 = 0x05;
+    const METHOD_NONE: u8 = 0x00;
+    const METHOD_PASSWORD: u8 = 0x02;
+
+    let credentials = upstream.credentials();
+    let method = if credentials.is_some() {
+        METHOD_PASSWORD
+    } else {
+        METHOD_NONE
+    };
+
+    stream.write_all(&[VERSION, 1, method]).await?;
+
+    let mut buf = [0; 2];
+    stream.read_exact(&mut buf).await?;
+    if buf[0] != VERSION || buf[1] != method {
+        return Err(upstream_err(
+            "upstream SOCKS5 proxy rejected authentication method",
+        ));
+    }
+
+    if let Some((username, password)) = credentials {
+        // lengths are sent as single bytes
+        if username.len() > u8::MAX as usize || password.len() > u8::MAX as usize {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "upstream SOCKS5 username or password longer than 255 bytes",
+            ));
+        }
+
+        let mut req = BytesMut::with_capacity(3 + username.len() + password.len());
+        req.put_u8(0x01);
+        req.put_u8(username.len() as u8);
+        req.put_slice(username.as_bytes());
+        req.put_u8(password.len() as u8);
+        req.put_slice(password.as_bytes());
+        stream.write_all(&req).await?;
+
+        stream.read_exact(&mut buf).await?;
+        if buf[1] != 0x00 {
+            return Err(upstream_err("upstream SOCKS5 proxy authentication failed"));
+        }
+    }
+
+    let mut req = BytesMut::with_capacity(3 + target.len());
+    req.put_slice(&[VERSION, 0x01, 0x00]);
+    match target {
+        Address::None => return Err(IoError::new(ErrorKind::InvalidInput, "empty address")),
+        Address::DomainAddress(domain, port) => {
+            req.put_u8(0x03);
+            req.put_u8(domain.len() as u8);
+            req.put_slice(domain.as_bytes());
+            req.put_u16(*port);
+        }
+        Address::SocketAddress(SocketAddr::V4(addr)) => {
+            req.put_u8(0x01);
+            req.put_slice(&addr.ip().octets());
+            req.put_u16(addr.port());
+        }
+        Address::SocketAddress(SocketAddr::V6(addr)) => {
+            req.put_u8(0x04);
+            req.put_slice(&addr.ip().octets());
+            req.put_u16(addr.port());
+        }
+    }
+    stream.write_all(&req).await?;
+
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).await?;
+    if buf[1] != 0x00 {
+        return Err(upstream_err(format!(
+            "upstream SOCKS5 proxy replied with error code {:#04x}",
+            buf[1]
+        )));
+    }
+
+    // skip the bound address
+    let addr_len = match buf[3] {
+        0x01 => 4,
+        0x03 => stream.read_u8().await? as usize,
+        0x04 => 16,
+        atyp => {
+            return Err(upstream_err(format!(
+                "upstream SOCKS5 proxy replied with invalid address type {atyp:#04x}"
+            )));
+        }
+    };
+    let mut buf = vec![0; addr_len + 2];
+    stream.read_exact(&mut buf).await?;
+
+    Ok(())
+}
+
+async fn http_handshake(
+    stream: &mut TcpStream,
+    upstream: &UpstreamConfig,
+    target: &Address,
+) -> Result<(), IoError> {
+    if target.is_none() {
+        return Err(IoError::new(ErrorKind::InvalidInput, "empty address"));
+    }
+
+    let mut req = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some((username, password)) = upstream.credentials() {
+        let token = BASE64.encode(format!("{username}:{password}"));
+        req.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+    }
+    req.push_str("\r\n");
+    stream.write_all(req.as_bytes()).await?;
+
+    // read byte by byte so no tunneled data is consumed
+    let mut resp = Vec::new();
+    while !resp.ends_with(b"\r\n\r\n") {
+        if resp.len() >= 8192 {
+            return Err(upstream_err(
+                "upstream HTTP proxy response header too large",
+            ));
+        }
+        resp.push(stream.read_u8().await?);
+    }
+
+    let resp = String::from_utf8_lossy(&resp);
+    let status = resp.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(upstream_err(format!(
+            "upstream HTTP proxy refused to connect: {status}"
+        ))),
+    }
+}
+
+/// Writes a PROXY protocol v2 header, telling the destination `dst` that the
+/// connection comes from `src`
+pub async fn write_proxy_protocol(
+    stream: &mut TcpStream,
+    src: SocketAddr,
+    dst: SocketAddr,
+) -> Result<(), IoError> {
+    const SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+    let mut buf = BytesMut::with_capacity(SIGNATURE.len() + 4 + 36);
+    buf.put_slice(SIGNATURE);
+    // version 2, PROXY command
+    buf.put_u8(0x21);
+
+    match (src.ip().to_canonical(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            // TCP over IPv4
+            buf.put_u8(0x11);
+            buf.put_u16(12);
+            buf.put_slice(&src_ip.octets());
+            buf.put_slice(&dst_ip.octets());
+        }
+        (src_ip, dst_ip) => {
+            // TCP over IPv6, with IPv4 addresses mapped
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            buf.put_u8(0x21);
+            buf.put_u16(36);
+            buf.put_slice(&to_v6(src_ip).octets());
+            buf.put_slice(&to_v6(dst_ip).octets());
+        }
+    }
+
+    buf.put_u16(src.port());
+    buf.put_u16(dst.port());
+
+    stream.write_all(&buf).await
+}
+
+/// Binds a raw socket to the configured outbound interface, if any
+pub fn bind_device(cfg: &OutboundConfig, socket: &Socket) -> Result<(), IoError> {
+    if let Some(interface) = &cfg.interface {
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        socket.bind_device(Some(interface.as_bytes()))?;
+        #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+        {
+            let _ = socket;
+            return Err(unsupported_interface(interface));
+        }
+    }
+
+    if let Some(dscp) = cfg.dscp {
+        set_dscp(SockRef::from(socket), dscp)?;
+    }
+
+    Ok(())
+}
+
+fn upstream_err(msg: impl Into<String>) -> IoError {
+    IoError::new(ErrorKind::ConnectionRefused, msg.into())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn unsupported_interface(interface: &str) -> IoError {
+    IoError::new(
+        ErrorKind::Unsupported,
+        format!("binding to interface `{interface}` is not supported on this platform"),
+    )
+}
@@ -0,0 +1,80 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// Remembers the authentication tokens seen recently, to reject 0-RTT early
+/// data replayed by an attacker
+///
+/// A token is derived from the TLS keying material of its connection, so an
+/// honest client never sends the same token twice. Replays arriving after the
+/// window are not caught, which is the residual risk counted by
+/// `zero_rtt_authentications`
+pub struct ReplayCache {
+    window: Duration,
+    seen: Mutex<Seen>,
+    zero_rtt: AtomicU64,
+    replayed: AtomicU64,
+}
+
+struct Seen {
+    tokens: HashMap<[u8; 32], Instant>,
+    last_pruned: Instant,
+}
+
+impl ReplayCache {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: Mutex::new(Seen {
+                tokens: HashMap::new(),
+                last_pruned: Instant::now(),
+            }),
+            zero_rtt: AtomicU64::new(0),
+            replayed: AtomicU64::new(0),
+        }
+    }
+
+    /// Records an authentication token, returning `false` if it was already
+    /// seen within the window. `zero_rtt` tells if it arrived in early data
+    pub fn check(&self, token: [u8; 32], zero_rtt: bool) -> bool {
+        if zero_rtt {
+            self.zero_rtt.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+
+        if now.duration_since(seen.last_pruned) >= self.window {
+            let window = self.window;
+            seen.tokens
+                .retain(|_, time| now.duration_since(*time) < window);
+            seen.last_pruned = now;
+        }
+
+        match seen.tokens.get(&token) {
+            Some(time) if now.duration_since(*time) < self.window => {
+                self.replayed.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            _ => {
+                seen.tokens.insert(token, now);
+                true
+            }
+        }
+    }
+
+    /// Returns the number of authentications received in 0-RTT early data
+    pub fn zero_rtt(&self) -> u64 {
+        self.zero_rtt.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of authentications rejected as replays
+    pub fn replayed(&self) -> u64 {
+        self.replayed.load(Ordering::Relaxed)
+    }
+}
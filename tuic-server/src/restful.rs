@@ -1,11 +1,12 @@
 use std::{
     collections::{HashMap, HashSet},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     ops::Deref,
     sync::{
         Arc, LazyLock,
         atomic::{AtomicU64, Ordering},
     },
+    time::SystemTime,
 };
 
 use axum::{
@@ -21,18 +22,20 @@ use axum_extra::{
 use chashmap::CHashMap;
 use lateinit::LateInit;
 use quinn::{Connection as QuinnConnection, VarInt};
+use serde::Deserialize;
 use serde_json::json;
 use tracing::warn;
+use tuic::{Capabilities, NoticeKind};
+use tuic_quinn::{Connection as Model, side};
 use uuid::Uuid;
 
-use crate::AppContext;
+use crate::{AppContext, ban::BanList, config::LogFilter, expiry, log_level, traffic::Record};
 
 static ONLINE_COUNTER: LateInit<HashMap<Uuid, AtomicU64>> = LateInit::new();
 static ONLINE_CLIENTS: LazyLock<CHashMap<Uuid, HashSet<QuicClient>>> = LazyLock::new(CHashMap::new);
-static TRAFFIC_STATS: LateInit<HashMap<Uuid, (AtomicU64, AtomicU64)>> = LateInit::new(); // (tx, rx)
 
 #[derive(Clone)]
-struct QuicClient(QuinnConnection);
+struct QuicClient(QuinnConnection, Model<side::Server>);
 impl Deref for QuicClient {
     type Target = QuinnConnection;
 
@@ -40,11 +43,6 @@ impl Deref for QuicClient {
         &self.0
     }
 }
-impl From<QuinnConnection> for QuicClient {
-    fn from(value: QuinnConnection) -> Self {
-        Self(value)
-    }
-}
 impl std::hash::Hash for QuicClient {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.0.stable_id().hash(state);
@@ -62,15 +60,8 @@ pub async fn start(ctx: Arc<AppContext>) {
     for (user, _) in ctx.cfg.users.iter() {
         online.insert(user.to_owned(), AtomicU64::new(0));
     }
-
-    let mut traffic = HashMap::new();
-    for (user, _) in ctx.cfg.users.iter() {
-        // TODO use persist
-        traffic.insert(user.to_owned(), (AtomicU64::new(0), AtomicU64::new(0)));
-    }
     unsafe {
         ONLINE_COUNTER.init(online);
-        TRAFFIC_STATS.init(traffic);
     }
 
     let restful = ctx.cfg.restful.as_ref().unwrap();
@@ -81,22 +72,37 @@ pub async fn start(ctx: Arc<AppContext>) {
         .route("/detailed_online", get(list_detailed_online))
         .route("/traffic", get(list_traffic))
         .route("/reset_traffic", get(reset_traffic))
+        .route("/udp_stats", get(udp_stats))
+        .route("/auth_stats", get(auth_stats))
+        .route("/dump", get(dump))
+        .route("/bans", get(list_bans))
+        .route("/unban", post(unban))
+        .route("/notice", post(notice))
+        .route("/expiry", post(set_expiry))
+        .route("/log_level", post(set_log_level))
         .with_state(ctx);
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
     warn!("RESTful server started, listening on {addr}");
     axum::serve(listener, app).await.unwrap();
 }
 
+/// Whether the request carries the bearer token set as `restful.secret`. Any
+/// request is authorized if no secret is set
+fn authorized(ctx: &AppContext, token: Option<TypedHeader<Authorization<Bearer>>>) -> bool {
+    match &ctx.cfg.restful {
+        Some(restful) if !restful.secret.is_empty() => {
+            token.is_some_and(|TypedHeader(token)| token.token() == restful.secret)
+        }
+        _ => true,
+    }
+}
+
 async fn kick(
     State(ctx): State<Arc<AppContext>>,
     token: Option<TypedHeader<Authorization<Bearer>>>,
     Json(users): Json<Vec<Uuid>>,
 ) -> StatusCode {
-    if let Some(restful) = &ctx.cfg.restful
-        && !restful.secret.is_empty()
-        && let Some(TypedHeader(token)) = token
-        && restful.secret != token.token()
-    {
+    if !authorized(&ctx, token) {
         return StatusCode::UNAUTHORIZED;
     }
     for user in users {
@@ -113,11 +119,7 @@ async fn list_online(
     State(ctx): State<Arc<AppContext>>,
     token: Option<TypedHeader<Authorization<Bearer>>>,
 ) -> (StatusCode, Json<HashMap<Uuid, u64>>) {
-    if let Some(restful) = &ctx.cfg.restful
-        && !restful.secret.is_empty()
-        && let Some(TypedHeader(token)) = token
-        && restful.secret != token.token()
-    {
+    if !authorized(&ctx, token) {
         return (StatusCode::UNAUTHORIZED, Json(HashMap::new()));
     }
     let mut result = HashMap::new();
@@ -135,11 +137,7 @@ async fn list_detailed_online(
     State(ctx): State<Arc<AppContext>>,
     token: Option<TypedHeader<Authorization<Bearer>>>,
 ) -> (StatusCode, Json<HashMap<Uuid, Vec<SocketAddr>>>) {
-    if let Some(restful) = &ctx.cfg.restful
-        && !restful.secret.is_empty()
-        && let Some(TypedHeader(token)) = token
-        && restful.secret != token.token()
-    {
+    if !authorized(&ctx, token) {
         return (StatusCode::UNAUTHORIZED, Json(HashMap::new()));
     }
     let mut result = HashMap::new();
@@ -156,68 +154,230 @@ async fn list_detailed_online(
 async fn list_traffic(
     State(ctx): State<Arc<AppContext>>,
     token: Option<TypedHeader<Authorization<Bearer>>>,
-) -> (StatusCode, Json<HashMap<Uuid, serde_json::Value>>) {
-    if let Some(restful) = &ctx.cfg.restful
-        && !restful.secret.is_empty()
-        && let Some(TypedHeader(token)) = token
-        && restful.secret != token.token()
-    {
+) -> (StatusCode, Json<HashMap<Uuid, Record>>) {
+    if !authorized(&ctx, token) {
         return (StatusCode::UNAUTHORIZED, Json(HashMap::new()));
     }
-    let mut result = HashMap::new();
-    for (uuid, (tx, rx)) in TRAFFIC_STATS.iter() {
-        let tx = tx.load(Ordering::Relaxed);
-        let rx = rx.load(Ordering::Relaxed);
-        if tx != 0 || rx != 0 {
-            result.insert(*uuid, json!({"tx": tx, "rx":rx}));
-        }
-    }
-
-    (StatusCode::OK, Json(result))
+    (StatusCode::OK, Json(ctx.traffic.get()))
 }
 
 async fn reset_traffic(
     State(ctx): State<Arc<AppContext>>,
     token: Option<TypedHeader<Authorization<Bearer>>>,
-) -> (StatusCode, Json<HashMap<Uuid, serde_json::Value>>) {
-    if let Some(restful) = &ctx.cfg.restful
-        && !restful.secret.is_empty()
-        && let Some(TypedHeader(token)) = token
-        && restful.secret != token.token()
-    {
+) -> (StatusCode, Json<HashMap<Uuid, Record>>) {
+    if !authorized(&ctx, token) {
         return (StatusCode::UNAUTHORIZED, Json(HashMap::new()));
     }
-    let mut result = HashMap::new();
-    for (uuid, (tx, rx)) in TRAFFIC_STATS.iter() {
-        let tx = tx.swap(0, Ordering::Relaxed);
-        let rx = rx.swap(0, Ordering::Relaxed);
-        if tx != 0 || rx != 0 {
-            result.insert(*uuid, json!({"tx": tx, "rx":rx}));
+    (StatusCode::OK, Json(ctx.traffic.reset()))
+}
+
+async fn udp_stats(
+    State(ctx): State<Arc<AppContext>>,
+    token: Option<TypedHeader<Authorization<Bearer>>>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if !authorized(&ctx, token) {
+        return (StatusCode::UNAUTHORIZED, Json(json!({})));
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "duplicated_packets": ctx.reassembly_stats.duplicated(),
+            "out_of_window_packets": ctx.reassembly_stats.out_of_window(),
+            "reassembly_memory": ctx.reassembly_memory.used(),
+        })),
+    )
+}
+
+async fn auth_stats(
+    State(ctx): State<Arc<AppContext>>,
+    token: Option<TypedHeader<Authorization<Bearer>>>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if !authorized(&ctx, token) {
+        return (StatusCode::UNAUTHORIZED, Json(json!({})));
+    }
+
+    (
+        StatusCode::OK,
+        Json(json!({
+            "zero_rtt_authentications": ctx.replay_cache.zero_rtt(),
+            "replayed_authentications": ctx.replay_cache.replayed(),
+        })),
+    )
+}
+
+async fn dump(
+    State(ctx): State<Arc<AppContext>>,
+    token: Option<TypedHeader<Authorization<Bearer>>>,
+) -> (StatusCode, String) {
+    if !authorized(&ctx, token) {
+        return (StatusCode::UNAUTHORIZED, String::new());
+    }
+
+    (StatusCode::OK, ctx.dump())
+}
+
+async fn list_bans(
+    State(ctx): State<Arc<AppContext>>,
+    token: Option<TypedHeader<Authorization<Bearer>>>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if !authorized(&ctx, token) {
+        return (StatusCode::UNAUTHORIZED, Json(json!({})));
+    }
+
+    let bans = ctx.bans.as_ref().map(BanList::list).unwrap_or_default();
+    (StatusCode::OK, Json(json!(bans)))
+}
+
+#[derive(Deserialize)]
+struct UnbanRequest {
+    /// Clears all IPs if absent
+    ip: Option<IpAddr>,
+}
+
+async fn unban(
+    State(ctx): State<Arc<AppContext>>,
+    token: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(req): Json<UnbanRequest>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if !authorized(&ctx, token) {
+        return (StatusCode::UNAUTHORIZED, Json(json!({})));
+    }
+
+    let cleared = ctx.bans.as_ref().map_or(0, |bans| bans.clear(req.ip));
+    (StatusCode::OK, Json(json!({ "cleared": cleared })))
+}
+
+#[derive(Deserialize)]
+struct ExpiryRequest {
+    user: Uuid,
+    /// Never expires if absent
+    #[serde(default, with = "humantime_serde::option")]
+    expires_at: Option<SystemTime>,
+}
+
+async fn set_expiry(
+    State(ctx): State<Arc<AppContext>>,
+    token: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(req): Json<ExpiryRequest>,
+) -> StatusCode {
+    if !authorized(&ctx, token) {
+        return StatusCode::UNAUTHORIZED;
+    }
+    ctx.expiry.set(req.user, req.expires_at);
+    if ctx.cfg.terminate_expired && ctx.expiry.is_expired(&req.user) {
+        ctx.devices
+            .close(&req.user, expiry::ERROR_CODE, b"User expired");
+    }
+    StatusCode::OK
+}
+
+#[derive(Deserialize)]
+struct LogLevelRequest {
+    level: LogFilter,
+}
+
+async fn set_log_level(
+    State(ctx): State<Arc<AppContext>>,
+    token: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(req): Json<LogLevelRequest>,
+) -> StatusCode {
+    if !authorized(&ctx, token) {
+        return StatusCode::UNAUTHORIZED;
+    }
+    match log_level::set(req.level.targets().clone()) {
+        Ok(()) => {
+            warn!("log level set to {level}", level = req.level);
+            StatusCode::OK
+        }
+        Err(err) => {
+            warn!("failed to change log level: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR
         }
     }
+}
 
-    (StatusCode::OK, Json(result))
+#[derive(Deserialize)]
+struct NoticeRequest {
+    kind: u8,
+    #[serde(default)]
+    message: String,
+    /// Sends to all online clients if absent
+    users: Option<Vec<Uuid>>,
 }
 
-pub async fn client_connect(ctx: &AppContext, uuid: &Uuid, conn: QuinnConnection) {
+async fn notice(
+    State(ctx): State<Arc<AppContext>>,
+    token: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(req): Json<NoticeRequest>,
+) -> StatusCode {
+    if !authorized(&ctx, token) {
+        return StatusCode::UNAUTHORIZED;
+    }
+    let kind = NoticeKind::from(req.kind);
+    let clients = match req.users {
+        Some(users) => {
+            let mut clients = Vec::new();
+            for user in users {
+                if let Some(list) = ONLINE_CLIENTS.get(&user).await {
+                    clients.extend(list.iter().cloned());
+                }
+            }
+            clients
+        }
+        None => ONLINE_CLIENTS
+            .clone_locking()
+            .await
+            .into_values()
+            .flatten()
+            .collect(),
+    };
+    for client in clients {
+        // clients not accepting notices would only log an unknown command
+        if !client
+            .1
+            .negotiated()
+            .is_some_and(|(_, caps)| caps.contains(Capabilities::NOTICE))
+        {
+            continue;
+        }
+        let message = req.message.clone();
+        tokio::spawn(async move {
+            if let Err(err) = client.1.notice(kind, message).await {
+                warn!(
+                    "[{addr}] failed to send notice: {err}",
+                    addr = client.remote_address()
+                );
+            }
+        });
+    }
+    StatusCode::OK
+}
+
+pub async fn client_connect(
+    ctx: &AppContext,
+    uuid: &Uuid,
+    conn: QuinnConnection,
+    model: Model<side::Server>,
+) {
     if ctx.cfg.restful.is_none() {
         return;
     }
     let cfg = ctx.cfg.restful.as_ref().unwrap();
-    let current = ONLINE_COUNTER
-        .get(uuid)
-        .expect("Authorized UUID not present in users table")
-        .fetch_add(1, Ordering::Release);
-    if cfg.maximum_clients_per_user != 0 && current > cfg.maximum_clients_per_user {
-        conn.close(
-            VarInt::from_u32(6001),
-            "Reached maximum clients limitation".as_bytes(),
-        );
-        return;
+    // users authenticated through `Hooks` may be missing from the table
+    if let Some(counter) = ONLINE_COUNTER.get(uuid) {
+        let current = counter.fetch_add(1, Ordering::Release);
+        if cfg.maximum_clients_per_user != 0 && current > cfg.maximum_clients_per_user {
+            conn.close(
+                VarInt::from_u32(6001),
+                "Reached maximum clients limitation".as_bytes(),
+            );
+            return;
+        }
     }
     ONLINE_CLIENTS
         .upsert(*uuid, HashSet::new, |v| {
-            v.insert(conn.into());
+            v.insert(QuicClient(conn, model));
         })
         .await;
 }
@@ -225,29 +385,24 @@ pub async fn client_disconnect(ctx: &AppContext, uuid: &Uuid, conn: QuinnConnect
     if ctx.cfg.restful.is_none() {
         return;
     }
-    ONLINE_COUNTER
-        .get(uuid)
-        .expect("Authorized UUID not present in users table")
-        .fetch_sub(1, Ordering::SeqCst);
+    if let Some(counter) = ONLINE_COUNTER.get(uuid) {
+        counter.fetch_sub(1, Ordering::SeqCst);
+    }
     if let Some(mut pair) = ONLINE_CLIENTS.get_mut(uuid).await {
-        pair.remove(&conn.into());
+        pair.retain(|client| client.stable_id() != conn.stable_id());
     }
 }
 
 pub fn traffic_tx(ctx: &AppContext, uuid: &Uuid, size: u64) {
-    if ctx.cfg.restful.is_none() {
-        return;
-    }
-    if let Some((tx, _)) = TRAFFIC_STATS.get(uuid) {
-        tx.fetch_add(size, Ordering::SeqCst);
+    if let Some(hooks) = &ctx.hooks {
+        hooks.traffic(uuid, size, 0);
     }
+    ctx.traffic.add_tx(uuid, size);
 }
 
 pub fn traffic_rx(ctx: &AppContext, uuid: &Uuid, size: u64) {
-    if ctx.cfg.restful.is_none() {
-        return;
-    }
-    if let Some((__, rx)) = TRAFFIC_STATS.get(uuid) {
-        rx.fetch_add(size, Ordering::SeqCst);
+    if let Some(hooks) = &ctx.hooks {
+        hooks.traffic(uuid, 0, size);
     }
+    ctx.traffic.add_rx(uuid, size);
 }
@@ -1,58 +1,79 @@
 use std::{
+    io::Error as IoError,
     net::{SocketAddr, UdpSocket as StdUdpSocket},
     sync::Arc,
 };
 
 use eyre::Context;
 use quinn::{
-    Endpoint, EndpointConfig, IdleTimeout, ServerConfig, TokioRuntime, TransportConfig, VarInt,
+    Endpoint, EndpointConfig, IdleTimeout, Runtime, ServerConfig, TokioRuntime, TransportConfig,
+    VarInt,
     congestion::{BbrConfig, CubicConfig, NewRenoConfig},
     crypto::rustls::QuicServerConfig,
 };
-use rustls::{
-    ServerConfig as RustlsServerConfig,
-    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
-};
-use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, Type};
 use tracing::{debug, warn};
+use tuic_common::socket::set_dscp;
+use tuic_quinn::{BrutalConfig, DscpSocket};
 
 use crate::{
-    AppContext,
-    connection::{Connection, INIT_CONCURRENT_STREAMS},
+    AppContext, Config, Hooks,
+    connection::Connection,
     error::Error,
-    tls::CertResolver,
-    utils::CongestionController,
+    limit::LimitExceeded,
+    tls,
+    utils::{self, CongestionController},
 };
 
+/// A TUIC server endpoint
 pub struct Server {
     ep: Endpoint,
     ctx: Arc<AppContext>,
 }
 
+/// Builds a [`Server`] hosted in-process
+pub struct ServerBuilder {
+    cfg: Config,
+    hooks: Option<Arc<dyn Hooks>>,
+}
+
+impl ServerBuilder {
+    /// Sets the hooks for authentication, access control and accounting
+    pub fn hooks(mut self, hooks: impl Hooks) -> Self {
+        self.hooks = Some(Arc::new(hooks));
+        self
+    }
+
+    /// Binds the endpoint. Connections are accepted once [`Server::start`] is
+    /// awaited
+    pub async fn build(self) -> eyre::Result<Server> {
+        let ctx = Arc::new(AppContext::new(self.cfg, self.hooks)?);
+        Ok(Server::init(ctx).await?)
+    }
+}
+
 impl Server {
-    pub async fn init(ctx: Arc<AppContext>) -> Result<Self, Error> {
-        let mut crypto: RustlsServerConfig;
-        if ctx.cfg.tls.self_sign {
-            let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
-            let cert_der = CertificateDer::from(cert.cert);
-            let priv_key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
-            crypto = RustlsServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
-                .with_no_client_auth()
-                .with_single_cert(vec![cert_der], PrivateKeyDer::Pkcs8(priv_key))?;
-        } else {
-            let cert_resolver =
-                CertResolver::new(&ctx.cfg.tls.certificate, &ctx.cfg.tls.private_key).await?;
+    /// Returns a builder of a server running with `cfg`
+    pub fn builder(cfg: Config) -> ServerBuilder {
+        ServerBuilder { cfg, hooks: None }
+    }
 
-            crypto = RustlsServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
-                .with_no_client_auth()
-                .with_cert_resolver(cert_resolver);
-        }
+    #[doc(hidden)]
+    pub async fn init(ctx: Arc<AppContext>) -> Result<Self, Error> {
+        let mut crypto = tls::server_config(&ctx.cfg.tls).await?;
 
         crypto.alpn_protocols = ctx
             .cfg
             .tls
             .alpn
             .iter()
+            .chain(
+                ctx.cfg
+                    .masquerade
+                    .iter()
+                    .flat_map(|masquerade| &masquerade.alpn),
+            )
+            .chain(ctx.cfg.alpn_routes.keys())
             .cloned()
             .map(|alpn| alpn.into_bytes())
             .collect();
@@ -64,12 +85,25 @@ impl Server {
             QuicServerConfig::try_from(crypto).context("no initial cipher suite found")?,
         ));
         let mut tp_cfg = TransportConfig::default();
+        let transport = &ctx.cfg.quic.transport;
 
         tp_cfg
-            .max_concurrent_bidi_streams(VarInt::from(INIT_CONCURRENT_STREAMS))
-            .max_concurrent_uni_streams(VarInt::from(INIT_CONCURRENT_STREAMS))
+            .max_concurrent_bidi_streams(VarInt::from(transport.max_concurrent_bi_streams))
+            .max_concurrent_uni_streams(VarInt::from(transport.max_concurrent_uni_streams))
             .send_window(ctx.cfg.quic.send_window)
             .stream_receive_window(VarInt::from_u32(ctx.cfg.quic.receive_window))
+            .receive_window(
+                transport
+                    .connection_receive_window
+                    .and_then(|window| VarInt::from_u64(window).ok())
+                    .unwrap_or(VarInt::MAX),
+            )
+            .initial_rtt(transport.initial_rtt)
+            .keep_alive_interval(
+                (!transport.keep_alive_interval.is_zero()).then_some(transport.keep_alive_interval),
+            )
+            .datagram_receive_buffer_size(Some(transport.datagram_receive_buffer))
+            .datagram_send_buffer_size(transport.datagram_send_buffer)
             .max_idle_timeout(Some(
                 IdleTimeout::try_from(ctx.cfg.quic.max_idle_time)
                     .map_err(|_| Error::InvalidMaxIdleTime)?,
@@ -83,27 +117,47 @@ impl Server {
                 Some(Default::default())
             });
 
-        match ctx.cfg.quic.congestion_control.controller {
+        let cc = &ctx.cfg.quic.congestion_control;
+        match cc.controller {
             CongestionController::Bbr => {
                 let mut bbr_config = BbrConfig::default();
-                bbr_config.initial_window(ctx.cfg.quic.congestion_control.initial_window);
+                bbr_config.initial_window(cc.initial_window);
                 tp_cfg.congestion_controller_factory(Arc::new(bbr_config))
             }
             CongestionController::Cubic => {
                 let mut cubic_config = CubicConfig::default();
-                cubic_config.initial_window(ctx.cfg.quic.congestion_control.initial_window);
+                cubic_config.initial_window(cc.initial_window);
                 tp_cfg.congestion_controller_factory(Arc::new(cubic_config))
             }
             CongestionController::NewReno => {
                 let mut new_reno = NewRenoConfig::default();
-                new_reno.initial_window(ctx.cfg.quic.congestion_control.initial_window);
+                new_reno.initial_window(cc.initial_window);
+                if let Some(factor) = cc.loss_reduction_factor {
+                    new_reno.loss_reduction_factor(factor);
+                }
                 tp_cfg.congestion_controller_factory(Arc::new(new_reno))
             }
+            CongestionController::Brutal => {
+                if cc.bandwidth == 0 {
+                    return Err(Error::MissingBandwidth);
+                }
+                let brutal = BrutalConfig::new(cc.bandwidth * 1_000_000 / 8);
+                tp_cfg.congestion_controller_factory(Arc::new(brutal))
+            }
         };
 
         config.transport_config(Arc::new(tp_cfg));
 
-        let socket = {
+        #[cfg(target_os = "linux")]
+        let inherited = crate::systemd::listen_udp_socket()
+            .map_err(|err| Error::Socket("failed to take socket from systemd", err))?;
+        #[cfg(not(target_os = "linux"))]
+        let inherited = None;
+
+        let socket = if let Some(socket) = inherited {
+            debug!("using the UDP socket passed by systemd");
+            socket
+        } else {
             let domain = match ctx.cfg.server {
                 SocketAddr::V4(_) => Domain::IPV4,
                 SocketAddr::V6(_) => Domain::IPV6,
@@ -125,35 +179,130 @@ impl Server {
             StdUdpSocket::from(socket)
         };
 
-        let ep = Endpoint::new(
-            EndpointConfig::default(),
-            Some(config),
-            socket,
-            Arc::new(TokioRuntime),
-        )?;
+        set_buffer_sizes(
+            &socket,
+            ctx.cfg.quic.send_buffer_size,
+            ctx.cfg.quic.recv_buffer_size,
+        )
+        .map_err(|err| Error::Socket("endpoint socket buffer size setting error", err))?;
+
+        let ep = if let Some(dscp) = ctx.cfg.quic.dscp {
+            set_dscp(SockRef::from(&socket), dscp)
+                .map_err(|err| Error::Socket("endpoint socket DSCP setting error", err))?;
+
+            Endpoint::new_with_abstract_socket(
+                EndpointConfig::default(),
+                Some(config),
+                Arc::new(DscpSocket::new(TokioRuntime.wrap_udp_socket(socket)?)),
+                Arc::new(TokioRuntime),
+            )?
+        } else {
+            Endpoint::new(
+                EndpointConfig::default(),
+                Some(config),
+                socket,
+                Arc::new(TokioRuntime),
+            )?
+        };
 
         Ok(Self { ep, ctx })
     }
 
+    /// Returns the address the endpoint is bound to, e.g. to find the port
+    /// picked for a `server` address with port 0
+    pub fn local_addr(&self) -> Result<SocketAddr, IoError> {
+        self.ep.local_addr()
+    }
+
+    /// Accepts and serves connections until the endpoint is closed
     pub async fn start(&self) {
         warn!(
             "server started, listening on {}",
             self.ep.local_addr().unwrap()
         );
+        #[cfg(target_os = "linux")]
+        crate::systemd::notify_ready();
         if self.ctx.cfg.restful.is_some() {
             tokio::spawn(crate::restful::start(self.ctx.clone()));
         }
+        if self.ctx.cfg.subscription.is_some() {
+            tokio::spawn(crate::subscription::start(self.ctx.clone()));
+        }
+        if self.ctx.cfg.traffic.is_some() {
+            tokio::spawn(crate::traffic::flush_periodically(self.ctx.clone()));
+        }
+        if self.ctx.cfg.terminate_expired {
+            tokio::spawn(crate::expiry::enforce(self.ctx.clone()));
+        }
 
         loop {
             match self.ep.accept().await {
-                Some(conn) => match conn.accept() {
-                    Ok(conn) => {
-                        tokio::spawn(Connection::handle(self.ctx.clone(), conn));
-                    }
-                    Err(e) => {
-                        debug!("[Incoming] Failed to accept connection: {e}");
+                Some(conn)
+                    if self
+                        .ctx
+                        .bans
+                        .as_ref()
+                        .is_some_and(|bans| bans.is_blocked(conn.remote_address().ip())) =>
+                {
+                    debug!(
+                        "[Incoming] refused connection from banned {addr}",
+                        addr = conn.remote_address()
+                    );
+                    conn.refuse();
+                }
+                Some(conn)
+                    if self.ctx.cfg.max_connections != 0
+                        && self.ctx.connections.count() as u32 >= self.ctx.cfg.max_connections =>
+                {
+                    debug!(
+                        "[Incoming] refused connection from {addr}: server-wide connection limit \
+                         reached",
+                        addr = conn.remote_address()
+                    );
+                    conn.refuse();
+                }
+                Some(conn) => {
+                    let permit = match &self.ctx.limiter {
+                        Some(limiter) => match limiter.acquire(conn.remote_address().ip()) {
+                            Ok(permit) => Some(permit),
+                            Err(LimitExceeded::Rate) => {
+                                debug!(
+                                    "[Incoming] dropped connection from {addr}: rate limit \
+                                     exceeded",
+                                    addr = conn.remote_address()
+                                );
+                                // answering a flood would only amplify it
+                                conn.ignore();
+                                continue;
+                            }
+                            Err(LimitExceeded::Concurrent) => {
+                                debug!(
+                                    "[Incoming] refused connection from {addr}: too many \
+                                     concurrent connections",
+                                    addr = conn.remote_address()
+                                );
+                                conn.refuse();
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+
+                    match conn.accept() {
+                        Ok(conn) => {
+                            let ctx = self.ctx.clone();
+                            let reg = self.ctx.connections.reg();
+                            let remote = conn.remote_address();
+                            utils::spawn(|| format!("connection {remote}"), async move {
+                                Connection::handle(ctx, conn).await;
+                                drop((permit, reg));
+                            });
+                        }
+                        Err(e) => {
+                            debug!("[Incoming] Failed to accept connection: {e}");
+                        }
                     }
-                },
+                }
                 None => {
                     debug!("[Incoming] the endpoint is closed");
                     return;
@@ -162,3 +311,37 @@ impl Server {
         }
     }
 }
+
+/// Sets the kernel buffer sizes of the endpoint socket. The default receive
+/// buffer commonly drops packets above a few hundred Mbit/s
+fn set_buffer_sizes(
+    socket: &StdUdpSocket,
+    send: Option<usize>,
+    recv: Option<usize>,
+) -> Result<(), IoError> {
+    let socket = SockRef::from(socket);
+
+    if let Some(size) = send {
+        socket.set_send_buffer_size(size)?;
+        let actual = socket.send_buffer_size()?;
+        if actual < size {
+            warn!(
+                "endpoint send buffer capped at {actual} bytes instead of {size}, raise \
+                 `net.core.wmem_max` to allow more"
+            );
+        }
+    }
+
+    if let Some(size) = recv {
+        socket.set_recv_buffer_size(size)?;
+        let actual = socket.recv_buffer_size()?;
+        if actual < size {
+            warn!(
+                "endpoint receive buffer capped at {actual} bytes instead of {size}, raise \
+                 `net.core.rmem_max` to allow more"
+            );
+        }
+    }
+
+    Ok(())
+}
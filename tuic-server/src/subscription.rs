@@ -0,0 +1,182 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+    serve::Listener,
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+use tracing::{debug, warn};
+use tuic::ShareLink;
+use uuid::Uuid;
+
+use crate::{AppContext, tls};
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+struct SubscriptionQuery {
+    token: String,
+    #[serde(default)]
+    format: SubscriptionFormat,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+enum SubscriptionFormat {
+    #[default]
+    Clash,
+    SingBox,
+    Link,
+}
+
+pub async fn start(ctx: Arc<AppContext>) {
+    let subscription = ctx.cfg.subscription.as_ref().unwrap();
+    let addr = subscription.addr;
+    let app = Router::new()
+        .route("/subscription/{uuid}", get(subscribe))
+        .with_state(ctx.clone());
+
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!("failed to bind subscription server on {addr}: {err}");
+            return;
+        }
+    };
+
+    if subscription.tls {
+        let mut crypto = match tls::server_config(&ctx.cfg.tls).await {
+            Ok(crypto) => crypto,
+            Err(err) => {
+                warn!("failed to load TLS config for subscription server: {err}");
+                return;
+            }
+        };
+        crypto.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let listener = TlsListener {
+            listener,
+            acceptor: TlsAcceptor::from(Arc::new(crypto)),
+        };
+        warn!("subscription server started, listening on https://{addr}");
+        axum::serve(listener, app).await.unwrap();
+    } else {
+        warn!("subscription server started, listening on http://{addr}");
+        axum::serve(listener, app).await.unwrap();
+    }
+}
+
+async fn subscribe(
+    State(ctx): State<Arc<AppContext>>,
+    Path(uuid): Path<Uuid>,
+    Query(query): Query<SubscriptionQuery>,
+) -> Response {
+    let Some(password) = ctx.cfg.users.get(&uuid) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    if *password != query.token {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let subscription = ctx.cfg.subscription.as_ref().unwrap();
+    let link = ctx.cfg.share_link(&subscription.host, uuid, password);
+
+    match query.format {
+        SubscriptionFormat::Clash => {
+            let proxies = json!({ "proxies": [clash_proxy(&ctx, &link)] });
+            match serde_yaml::to_string(&proxies) {
+                Ok(yaml) => {
+                    ([(header::CONTENT_TYPE, "text/yaml; charset=utf-8")], yaml).into_response()
+                }
+                Err(err) => {
+                    warn!("failed to generate Clash subscription: {err}");
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            }
+        }
+        SubscriptionFormat::SingBox => {
+            Json(json!({ "outbounds": [sing_box_outbound(&ctx, &link)] })).into_response()
+        }
+        SubscriptionFormat::Link => link.to_string().into_response(),
+    }
+}
+
+/// Generates the Clash.Meta proxy of a user
+fn clash_proxy(ctx: &AppContext, link: &ShareLink) -> Value {
+    json!({
+        "name": ctx.cfg.subscription.as_ref().unwrap().name,
+        "type": "tuic",
+        "server": link.host,
+        "port": link.port,
+        "uuid": link.uuid,
+        "password": link.password,
+        "alpn": link.alpn,
+        "congestion-controller": link.congestion_control,
+        "udp-relay-mode": "native",
+        "reduce-rtt": ctx.cfg.zero_rtt_handshake,
+        "skip-cert-verify": link.allow_insecure,
+    })
+}
+
+/// Generates the sing-box outbound of a user
+fn sing_box_outbound(ctx: &AppContext, link: &ShareLink) -> Value {
+    json!({
+        "type": "tuic",
+        "tag": ctx.cfg.subscription.as_ref().unwrap().name,
+        "server": link.host,
+        "server_port": link.port,
+        "uuid": link.uuid,
+        "password": link.password,
+        "congestion_control": link.congestion_control,
+        "udp_relay_mode": "native",
+        "zero_rtt_handshake": ctx.cfg.zero_rtt_handshake,
+        "tls": {
+            "enabled": true,
+            "server_name": link.host,
+            "alpn": link.alpn,
+            "insecure": link.allow_insecure,
+        },
+    })
+}
+
+/// Accepts TLS connections for the subscription server
+struct TlsListener {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+}
+
+impl Listener for TlsListener {
+    type Addr = SocketAddr;
+    type Io = TlsStream<TcpStream>;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            let (stream, addr) = match self.listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    debug!("[subscription] failed to accept connection: {err}");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, self.acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => return (stream, addr),
+                Ok(Err(err)) => debug!("[subscription] TLS handshake with {addr} failed: {err}"),
+                Err(_) => debug!("[subscription] TLS handshake with {addr} timed out"),
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+}
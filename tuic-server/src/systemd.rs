@@ -0,0 +1,62 @@
+use std::{
+    io::{Error as IoError, ErrorKind},
+    net::UdpSocket as StdUdpSocket,
+    os::fd::FromRawFd,
+    time::Duration,
+};
+
+use sd_notify::NotifyState;
+use socket2::{Socket, Type};
+use tracing::{debug, warn};
+
+/// Takes the UDP socket passed by systemd socket activation, if any
+///
+/// Only the first inherited file descriptor is used, and it must be a datagram
+/// socket that is already bound (`ListenDatagram=` in the `.socket` unit).
+pub fn listen_udp_socket() -> Result<Option<StdUdpSocket>, IoError> {
+    let Some(fd) = sd_notify::listen_fds()?.next() else {
+        return Ok(None);
+    };
+
+    // SAFETY: systemd hands the ownership of the listening fds over to this
+    // process, and this is the only place they are taken
+    let socket = unsafe { Socket::from_raw_fd(fd) };
+
+    if socket.r#type()? != Type::DGRAM {
+        return Err(IoError::new(
+            ErrorKind::InvalidInput,
+            "inherited socket from systemd is not a UDP socket",
+        ));
+    }
+
+    Ok(Some(StdUdpSocket::from(socket)))
+}
+
+/// Tells systemd that the server is ready, and starts sending watchdog
+/// keep-alive pings if `WatchdogSec=` is set for the service
+pub fn notify_ready() {
+    if let Err(err) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        warn!("failed to notify systemd: {err}");
+        return;
+    }
+
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) {
+        // ping twice per timeout, as systemd recommends
+        let interval = Duration::from_micros(usec) / 2;
+        debug!("systemd watchdog enabled, pinging every {interval:?}");
+        tokio::spawn(watchdog(interval));
+    }
+}
+
+async fn watchdog(interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(err) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+            warn!("failed to send watchdog ping to systemd: {err}");
+        }
+    }
+}
@@ -7,13 +7,36 @@ use std::{
 use eyre::Context;
 use notify::{RecursiveMode, Watcher as _};
 use rustls::{
+    ServerConfig as RustlsServerConfig,
     pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
     server::{ClientHello, ResolvesServerCert},
     sign::CertifiedKey,
 };
 use tracing::warn;
 
-use crate::utils::{self, FutResultExt};
+use crate::{
+    config::TlsConfig,
+    error::Error,
+    utils::{self, FutResultExt},
+};
+
+/// Builds the TLS config with the certificate of the server, either self-signed
+/// or loaded (and reloaded on change) from the configured files. ALPN is left
+/// for the caller to set
+pub async fn server_config(cfg: &TlsConfig) -> Result<RustlsServerConfig, Error> {
+    let builder = RustlsServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
+        .with_no_client_auth();
+
+    if cfg.self_sign {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let cert_der = CertificateDer::from(cert.cert);
+        let priv_key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+        Ok(builder.with_single_cert(vec![cert_der], PrivateKeyDer::Pkcs8(priv_key))?)
+    } else {
+        let cert_resolver = CertResolver::new(&cfg.certificate, &cfg.private_key).await?;
+        Ok(builder.with_cert_resolver(cert_resolver))
+    }
+}
 
 #[derive(Debug)]
 pub struct CertResolver {
@@ -56,7 +79,7 @@ impl ResolvesServerCert for CertResolver {
     }
 }
 
-async fn load_cert_key(cert_path: &Path, key_path: &Path) -> eyre::Result<Arc<CertifiedKey>> {
+pub async fn load_cert_key(cert_path: &Path, key_path: &Path) -> eyre::Result<Arc<CertifiedKey>> {
     let cert_chain = load_cert_chain(cert_path).await?;
     let der = load_priv_key(key_path).await?;
 
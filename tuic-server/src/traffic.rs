@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Error as IoError, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::{AppContext, config::Config};
+
+/// Bytes relayed for each user of the `users` table
+///
+/// With a `traffic` section, the counters are loaded from `persistent_data` at
+/// startup and flushed back to it periodically, so they survive restarts.
+pub struct Traffic {
+    counters: HashMap<Uuid, (AtomicU64, AtomicU64)>,
+    path: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+pub struct Record {
+    /// Bytes sent by the user to targets
+    pub tx: u64,
+    /// Bytes received by the user from targets
+    pub rx: u64,
+}
+
+impl Traffic {
+    pub fn new(cfg: &Config) -> Result<Self, IoError> {
+        let path = cfg.traffic.as_ref().map(|_| cfg.persistent_data.clone());
+
+        let mut saved = match &path {
+            Some(path) => load(path)?,
+            None => HashMap::new(),
+        };
+
+        let counters = cfg
+            .users
+            .keys()
+            .map(|uuid| {
+                let record = saved.remove(uuid).unwrap_or_default();
+                (
+                    *uuid,
+                    (AtomicU64::new(record.tx), AtomicU64::new(record.rx)),
+                )
+            })
+            .collect();
+
+        Ok(Self { counters, path })
+    }
+
+    pub fn add_tx(&self, uuid: &Uuid, size: u64) {
+        if let Some((tx, _)) = self.counters.get(uuid) {
+            tx.fetch_add(size, Ordering::Relaxed);
+        }
+    }
+
+    pub fn add_rx(&self, uuid: &Uuid, size: u64) {
+        if let Some((_, rx)) = self.counters.get(uuid) {
+            rx.fetch_add(size, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the counters of the users with any traffic
+    pub fn get(&self) -> HashMap<Uuid, Record> {
+        self.collect(|counter| counter.load(Ordering::Relaxed))
+    }
+
+    /// Resets the counters, returning their previous values
+    pub fn reset(&self) -> HashMap<Uuid, Record> {
+        self.collect(|counter| counter.swap(0, Ordering::Relaxed))
+    }
+
+    fn collect(&self, read: impl Fn(&AtomicU64) -> u64) -> HashMap<Uuid, Record> {
+        self.counters
+            .iter()
+            .map(|(uuid, (tx, rx))| {
+                (
+                    *uuid,
+                    Record {
+                        tx: read(tx),
+                        rx: read(rx),
+                    },
+                )
+            })
+            .filter(|(_, record)| record.tx != 0 || record.rx != 0)
+            .collect()
+    }
+
+    /// Writes the counters to `persistent_data`, through a temporary file so a
+    /// crash never leaves it truncated
+    pub fn flush(&self) -> Result<(), IoError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let records = self.get();
+        let data = if is_json(path) {
+            serde_json::to_string_pretty(&records).map_err(IoError::other)?
+        } else {
+            toml::to_string(&records).map_err(IoError::other)?
+        };
+
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+fn load(path: &Path) -> Result<HashMap<Uuid, Record>, IoError> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(err) => return Err(err),
+    };
+
+    if is_json(path) {
+        serde_json::from_str(&data).map_err(|err| IoError::new(ErrorKind::InvalidData, err))
+    } else {
+        toml::from_str(&data).map_err(|err| IoError::new(ErrorKind::InvalidData, err))
+    }
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+}
+
+/// Flushes the counters every `flush_interval`
+pub async fn flush_periodically(ctx: Arc<AppContext>) {
+    let interval = ctx.cfg.traffic.as_ref().unwrap().flush_interval;
+
+    loop {
+        time::sleep(interval).await;
+
+        match ctx.traffic.flush() {
+            Ok(()) => debug!("traffic data flushed"),
+            Err(err) => warn!("failed to flush traffic data: {err}"),
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    future::Future,
+    io::Error as IoError,
+    net::Shutdown,
+    pin::{Pin, pin},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::sync_channel,
+    },
+    thread,
+    time::Duration,
+};
+
+use eyre::eyre;
+use tokio::{
+    io::AsyncReadExt,
+    net::TcpStream,
+    sync::{
+        mpsc::{self, UnboundedSender},
+        oneshot,
+    },
+};
+use tokio_uring::{buf::BoundedBuf, net::TcpStream as UringTcpStream};
+use tuic_quinn::Connect;
+
+use crate::{
+    connection::ERROR_CODE,
+    io::{RelayIdleTimer, write_flush},
+};
+
+type Job = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()>>> + Send>;
+
+/// io_uring runtimes, one per CPU core and each on its own thread, the
+/// outbound TCP streams are relayed on when `outbound.io_uring` is enabled
+///
+/// Relayed streams are handed to the runtimes in turn. Connecting, including
+/// the upstream proxy handshake and the PROXY protocol header, stays on the
+/// main runtime
+pub struct UringPool {
+    workers: Vec<UnboundedSender<Job>>,
+    next: AtomicUsize,
+}
+
+impl UringPool {
+    /// Starts the runtimes. Fails if the kernel doesn't support io_uring, or
+    /// it is blocked, e.g. by a seccomp profile
+    pub fn new() -> Result<Self, IoError> {
+        let workers = (0..thread::available_parallelism()?.get())
+            .map(|idx| {
+                let (tx, mut rx) = mpsc::unbounded_channel::<Job>();
+                let (init_tx, init_rx) = sync_channel(1);
+
+                thread::Builder::new()
+                    .name(format!("io-uring-{idx}"))
+                    .spawn(move || {
+                        let runtime = match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+                            Ok(runtime) => {
+                                _ = init_tx.send(Ok(()));
+                                runtime
+                            }
+                            Err(err) => {
+                                _ = init_tx.send(Err(err));
+                                return;
+                            }
+                        };
+
+                        // stops once the pool is dropped
+                        runtime.block_on(async move {
+                            while let Some(job) = rx.recv().await {
+                                tokio_uring::spawn(job());
+                            }
+                        });
+                    })?;
+
+                init_rx
+                    .recv()
+                    .map_err(|_| IoError::other("io_uring runtime thread exited"))??;
+                Ok(tx)
+            })
+            .collect::<Result<_, IoError>>()?;
+
+        Ok(Self {
+            workers,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Relays between the QUIC stream and the outbound TCP stream on one of
+    /// the runtimes, like [`crate::io::exchange_tcp`], then resets the QUIC
+    /// stream and shuts the TCP stream down
+    pub async fn exchange_tcp(
+        &self,
+        mut a: Connect,
+        b: TcpStream,
+        timeout: Duration,
+        buffer_size: usize,
+    ) -> (usize, usize, Option<eyre::Error>) {
+        // io_uring waits for readiness itself
+        let b = match b
+            .into_std()
+            .and_then(|b| b.set_nonblocking(false).map(|()| b))
+        {
+            Ok(b) => b,
+            Err(err) => {
+                _ = a.reset(ERROR_CODE);
+                return (0, 0, Some(err.into()));
+            }
+        };
+
+        let (res_tx, res_rx) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            Box::pin(async move {
+                let b = UringTcpStream::from_std(b);
+                let res = exchange(&mut a, &b, timeout, buffer_size).await;
+                _ = a.reset(ERROR_CODE);
+                _ = b.shutdown(Shutdown::Write);
+                _ = res_tx.send(res);
+            })
+        });
+
+        let idx = self.next.fetch_add(1, Ordering::Relaxed) % self.workers.len();
+        if self.workers[idx].send(job).is_err() {
+            return (0, 0, Some(eyre!("io_uring runtime stopped")));
+        }
+
+        res_rx
+            .await
+            .unwrap_or_else(|_| (0, 0, Some(eyre!("io_uring relay task aborted"))))
+    }
+}
+
+async fn exchange(
+    a: &mut Connect,
+    b: &UringTcpStream,
+    timeout: Duration,
+    buffer_size: usize,
+) -> (usize, usize, Option<eyre::Error>) {
+    let mut a2b = vec![0u8; buffer_size];
+
+    let mut a2b_num = 0;
+    let mut b2a_num = 0;
+
+    let mut last_err = None;
+    let mut idle = RelayIdleTimer::new(timeout);
+
+    // the buffer is owned by the submitted read until it completes, so the
+    // read is kept across iterations instead of being cancelled by `select!`
+    let mut b_read = pin!(b.read(Vec::with_capacity(buffer_size)));
+
+    loop {
+        tokio::select! {
+            () = idle.elapsed() => {
+                last_err = Some(eyre!("TCP relay idle for {timeout:?}, closed"));
+                break;
+            },
+            a2b_res = a.read(&mut a2b) => match a2b_res {
+                Ok(num) => {
+                    // EOF
+                    if num == 0 {
+                        break;
+                    }
+                    a2b_num += num;
+                    idle.reset();
+                    let (res, buf) = b.write_all(a2b.slice(..num)).await;
+                    a2b = buf.into_inner();
+                    if let Err(err) = res {
+                        last_err = Some(err.into());
+                        break;
+                    }
+                },
+                Err(err) => {
+                    last_err = Some(err.into());
+                    break;
+                }
+            },
+
+            (b2a_res, mut b2a) = &mut b_read => match b2a_res {
+                Ok(num) => {
+                    // EOF
+                    if num == 0 {
+                        break;
+                    }
+                    b2a_num += num;
+                    idle.reset();
+                    // compressed data is buffered until flushed
+                    if let Err(err) = write_flush(a, &b2a[..num]).await {
+                        last_err = Some(err.into());
+                        break;
+                    }
+                    b2a.clear();
+                    b_read.set(b.read(b2a));
+                },
+                Err(err) => {
+                    last_err = Some(err.into());
+                    break;
+                },
+            }
+        }
+    }
+
+    (a2b_num, b2a_num, last_err)
+}
@@ -1,14 +1,37 @@
 use std::{
     fmt::{Display, Formatter, Result as FmtResult},
+    future::Future,
     str::FromStr,
 };
 
 use educe::Educe;
 use notify::{EventKind, RecommendedWatcher, Watcher};
 use serde::{Deserialize, Serialize};
-use tokio::sync::broadcast;
+use tokio::{sync::broadcast, task::JoinHandle};
 
-#[derive(Clone, Copy)]
+/// Spawns a task, named by `name` in tokio-console when built with the
+/// `console` feature
+#[track_caller]
+pub fn spawn<F>(name: impl FnOnce() -> String, fut: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(feature = "console")]
+    {
+        tokio::task::Builder::new()
+            .name(&name())
+            .spawn(fut)
+            .expect("failed to spawn task")
+    }
+    #[cfg(not(feature = "console"))]
+    {
+        _ = name;
+        tokio::spawn(fut)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum UdpRelayMode {
     Native,
     Quic,
@@ -32,6 +55,7 @@ pub enum CongestionController {
     Bbr,
     Cubic,
     NewReno,
+    Brutal,
 }
 
 // TODO remove in 2.0.0
@@ -45,6 +69,8 @@ impl FromStr for CongestionController {
             Ok(Self::NewReno)
         } else if s.eq_ignore_ascii_case("bbr") {
             Ok(Self::Bbr)
+        } else if s.eq_ignore_ascii_case("brutal") {
+            Ok(Self::Brutal)
         } else {
             Err("invalid congestion control")
         }
@@ -88,3 +114,71 @@ pub async fn async_watcher() -> eyre::Result<(RecommendedWatcher, broadcast::Rec
 
     Ok((watcher, rx))
 }
+
+/// An inclusive range of ports, `"START-END"` or a single port
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(try_from = "PortRangeRepr", into = "String")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let start = start.trim().parse().map_err(|_| "invalid port range")?;
+        let end = end.trim().parse().map_err(|_| "invalid port range")?;
+
+        if start == 0 || start > end {
+            return Err("invalid port range");
+        }
+
+        Ok(Self { start, end })
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(range: PortRange) -> Self {
+        range.to_string()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortRangeRepr {
+    Port(u16),
+    Range(String),
+}
+
+impl TryFrom<PortRangeRepr> for PortRange {
+    type Error = &'static str;
+
+    fn try_from(repr: PortRangeRepr) -> Result<Self, Self::Error> {
+        match repr {
+            PortRangeRepr::Port(0) => Err("invalid port range"),
+            PortRangeRepr::Port(port) => Ok(Self {
+                start: port,
+                end: port,
+            }),
+            PortRangeRepr::Range(range) => range.parse(),
+        }
+    }
+}
@@ -0,0 +1,25 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tuic::Header;
+
+fuzz_target!(|data: &[u8]| {
+    // the lenient parser must fail cleanly too
+    let _ = Header::decode(&mut &data[..]);
+
+    let Ok((header, len)) = Header::parse_strict(data) else {
+        return;
+    };
+    assert!(len <= data.len());
+
+    // a header accepted once is accepted again after encoding it, and encodes
+    // to the same bytes
+    let mut buf = Vec::new();
+    header.encode(&mut buf);
+    let (again, again_len) = Header::parse_strict(&buf).unwrap();
+    assert_eq!(again_len, buf.len());
+
+    let mut again_buf = Vec::new();
+    again.encode(&mut again_buf);
+    assert_eq!(buf, again_buf);
+});
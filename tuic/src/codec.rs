@@ -0,0 +1,151 @@
+//! Synchronous encoding and decoding of the wire format from `bytes` buffers,
+//! for consumers not driven by an async runtime, e.g. tests and fuzzers
+
+use bytes::BufMut;
+#[cfg(feature = "marshal")]
+use bytes::{Buf, Bytes};
+
+#[cfg(feature = "marshal")]
+use crate::UnmarshalError;
+use crate::{
+    Address, Authenticate, Batch, Bond, Compress, Connect, Dissociate, Header, Heartbeat,
+    Negotiate, Notice, Packet, Padding, Speedtest, Status, VERSION,
+};
+
+impl Header {
+    /// Encodes the header, version and command type included, into a `BufMut`
+    pub fn encode(&self, buf: &mut impl BufMut) {
+        self.write(buf);
+    }
+
+    /// Decodes a header from a `Buf`, which is advanced past it. `Padding`
+    /// commands are skipped, returning the command following them
+    ///
+    /// A `buf` ending early fails with an `UnexpectedEof` I/O error, leaving
+    /// it consumed
+    #[cfg(feature = "marshal")]
+    pub fn decode(buf: &mut impl Buf) -> Result<Self, UnmarshalError> {
+        Self::unmarshal(&mut buf.reader())
+    }
+
+    /// Decodes a header from the front of `buf`, returning it along with the
+    /// rest of `buf`, e.g. the payload of a `Packet` in a datagram. The rest
+    /// references the memory of `buf`, so the payload is never copied
+    #[cfg(feature = "marshal")]
+    pub fn from_bytes(mut buf: Bytes) -> Result<(Self, Bytes), UnmarshalError> {
+        let header = Self::decode(&mut buf)?;
+        Ok((header, buf))
+    }
+}
+
+/// A header serialized on the stack, to be sent along with a payload kept in
+/// its own buffer, without joining them into a new one first
+#[derive(Clone)]
+pub struct HeaderBuf {
+    buf: [u8; HeaderBuf::CAPACITY],
+    len: usize,
+}
+
+impl HeaderBuf {
+    /// The longest header fitting, enough for any `Packet` or `Batch` header
+    pub const CAPACITY: usize = 2 + 8 + Address::MAX_LEN;
+
+    /// Returns the serialized header
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl AsRef<[u8]> for HeaderBuf {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl Packet {
+    /// Encodes the header of the `Packet` command on the stack. Unlike
+    /// `Header::to_header_buf()`, it can't fail, as `HeaderBuf::CAPACITY` fits
+    /// any `Packet` header
+    pub fn to_header_buf(&self) -> HeaderBuf {
+        let mut buf = [0; HeaderBuf::CAPACITY];
+        let mut rest = &mut buf[..];
+        rest.put_u8(VERSION);
+        rest.put_u8(Header::TYPE_CODE_PACKET);
+        self.write(&mut rest);
+        let len = HeaderBuf::CAPACITY - rest.len();
+
+        HeaderBuf { buf, len }
+    }
+}
+
+impl Header {
+    /// Encodes the header on the stack. Returns `None` if it is longer than
+    /// `HeaderBuf::CAPACITY`, e.g. a `Notice` with a long message
+    pub fn to_header_buf(&self) -> Option<HeaderBuf> {
+        if self.len() > HeaderBuf::CAPACITY {
+            return None;
+        }
+
+        let mut buf = [0; HeaderBuf::CAPACITY];
+        let mut rest = &mut buf[..];
+        self.write(&mut rest);
+        let len = HeaderBuf::CAPACITY - rest.len();
+
+        Some(HeaderBuf { buf, len })
+    }
+}
+
+impl Address {
+    /// The serialized length of the longest address, a domain name of 255
+    /// bytes
+    const MAX_LEN: usize = 1 + 1 + 255 + 2;
+
+    /// Encodes the address into a `BufMut`
+    pub fn encode(&self, buf: &mut impl BufMut) {
+        self.write(buf);
+    }
+
+    /// Decodes an address from a `Buf`, which is advanced past it
+    #[cfg(feature = "marshal")]
+    pub fn decode(buf: &mut impl Buf) -> Result<Self, UnmarshalError> {
+        Self::read(&mut buf.reader())
+    }
+}
+
+macro_rules! impl_codec {
+    ($($cmd:ident),* $(,)?) => {
+        $(
+            impl $cmd {
+                /// Encodes the command-specific data, without the version and
+                /// command type of the header, into a `BufMut`
+                pub fn encode(&self, buf: &mut impl BufMut) {
+                    self.write(buf);
+                }
+
+                /// Decodes the command-specific data, without the version and
+                /// command type of the header, from a `Buf`, which is advanced
+                /// past it
+                #[cfg(feature = "marshal")]
+                pub fn decode(buf: &mut impl Buf) -> Result<Self, UnmarshalError> {
+                    Self::read(&mut buf.reader())
+                }
+            }
+        )*
+    };
+}
+
+impl_codec!(
+    Authenticate,
+    Connect,
+    Packet,
+    Dissociate,
+    Heartbeat,
+    Notice,
+    Negotiate,
+    Padding,
+    Compress,
+    Batch,
+    Status,
+    Speedtest,
+    Bond,
+);
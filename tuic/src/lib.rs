@@ -3,9 +3,13 @@
 mod protocol;
 
 pub use self::protocol::{
-    Address, Authenticate, Connect, Dissociate, Header, Heartbeat, Packet, VERSION,
+    Address, Authenticate, Batch, Bond, Capabilities, Compress, Connect, ConnectStatus, Dissociate,
+    Header, Heartbeat, Negotiate, Notice, NoticeKind, Packet, Padding, Speedtest, Status, VERSION,
 };
 
+#[cfg(any(feature = "async_marshal", feature = "marshal"))]
+mod codec;
+
 #[cfg(any(feature = "async_marshal", feature = "marshal"))]
 mod marshal;
 
@@ -13,7 +17,22 @@ mod marshal;
 mod unmarshal;
 
 #[cfg(any(feature = "async_marshal", feature = "marshal"))]
-pub use self::unmarshal::UnmarshalError;
+pub use self::{codec::HeaderBuf, unmarshal::UnmarshalError};
+
+#[cfg(feature = "marshal")]
+mod strict;
+
+#[cfg(feature = "marshal")]
+pub use self::strict::{ParseError, ParseErrorKind};
 
 #[cfg(feature = "model")]
 pub mod model;
+
+#[cfg(feature = "test-util")]
+pub mod test_util;
+
+#[cfg(feature = "share_link")]
+mod share_link;
+
+#[cfg(feature = "share_link")]
+pub use self::share_link::{ShareLink, ShareLinkError};
@@ -6,7 +6,10 @@ use std::{
 use bytes::{BufMut, BytesMut};
 use futures_util::{AsyncWrite, AsyncWriteExt};
 
-use crate::{Address, Authenticate, Connect, Dissociate, Header, Heartbeat, Packet, VERSION};
+use crate::{
+    Address, Authenticate, Batch, Bond, Compress, Connect, Dissociate, Header, Heartbeat,
+    Negotiate, Notice, Packet, Padding, Speedtest, Status, VERSION,
+};
 
 impl Header {
     /// Marshals the header into an `AsyncWrite` stream
@@ -36,12 +39,20 @@ impl Header {
             Self::Packet(packet) => packet.write(buf),
             Self::Dissociate(dissociate) => dissociate.write(buf),
             Self::Heartbeat(heartbeat) => heartbeat.write(buf),
+            Self::Notice(notice) => notice.write(buf),
+            Self::Negotiate(negotiate) => negotiate.write(buf),
+            Self::Padding(padding) => padding.write(buf),
+            Self::Compress(compress) => compress.write(buf),
+            Self::Batch(batch) => batch.write(buf),
+            Self::Status(status) => status.write(buf),
+            Self::Speedtest(speedtest) => speedtest.write(buf),
+            Self::Bond(bond) => bond.write(buf),
         }
     }
 }
 
 impl Address {
-    fn write(&self, buf: &mut impl BufMut) {
+    pub(crate) fn write(&self, buf: &mut impl BufMut) {
         buf.put_u8(self.type_code());
 
         match self {
@@ -66,20 +77,20 @@ impl Address {
 }
 
 impl Authenticate {
-    fn write(&self, buf: &mut impl BufMut) {
+    pub(crate) fn write(&self, buf: &mut impl BufMut) {
         buf.put_slice(self.uuid().as_ref());
         buf.put_slice(&self.token());
     }
 }
 
 impl Connect {
-    fn write(&self, buf: &mut impl BufMut) {
+    pub(crate) fn write(&self, buf: &mut impl BufMut) {
         self.addr().write(buf);
     }
 }
 
 impl Packet {
-    fn write(&self, buf: &mut impl BufMut) {
+    pub(crate) fn write(&self, buf: &mut impl BufMut) {
         buf.put_u16(self.assoc_id());
         buf.put_u16(self.pkt_id());
         buf.put_u8(self.frag_total());
@@ -89,12 +100,73 @@ impl Packet {
     }
 }
 
+impl Batch {
+    pub(crate) fn write(&self, buf: &mut impl BufMut) {
+        buf.put_u16(self.assoc_id());
+        buf.put_u8(self.count());
+        self.addr().write(buf);
+    }
+}
+
 impl Dissociate {
-    fn write(&self, buf: &mut impl BufMut) {
+    pub(crate) fn write(&self, buf: &mut impl BufMut) {
         buf.put_u16(self.assoc_id());
     }
 }
 
 impl Heartbeat {
-    fn write(&self, _buf: &mut impl BufMut) {}
+    pub(crate) fn write(&self, buf: &mut impl BufMut) {
+        if let Some(timestamp) = self.timestamp() {
+            buf.put_u8(if self.is_echo() {
+                Self::KIND_ECHO
+            } else {
+                Self::KIND_REQUEST
+            });
+            buf.put_u64(timestamp);
+        }
+    }
+}
+
+impl Compress {
+    pub(crate) fn write(&self, _buf: &mut impl BufMut) {}
+}
+
+impl Padding {
+    pub(crate) fn write(&self, buf: &mut impl BufMut) {
+        buf.put_u16(self.pad_len());
+        buf.put_bytes(0, self.pad_len() as usize);
+    }
+}
+
+impl Negotiate {
+    pub(crate) fn write(&self, buf: &mut impl BufMut) {
+        buf.put_u8(self.version());
+        buf.put_u64(self.capabilities().bits());
+    }
+}
+
+impl Bond {
+    pub(crate) fn write(&self, buf: &mut impl BufMut) {
+        buf.put_u64(self.bond_id());
+    }
+}
+
+impl Speedtest {
+    pub(crate) fn write(&self, buf: &mut impl BufMut) {
+        buf.put_u64(self.download());
+    }
+}
+
+impl Status {
+    pub(crate) fn write(&self, buf: &mut impl BufMut) {
+        buf.put_u8(self.status().code());
+    }
+}
+
+impl Notice {
+    pub(crate) fn write(&self, buf: &mut impl BufMut) {
+        buf.put_u8(self.kind().code());
+        buf.put_u16(self.message().len() as u16);
+        buf.put_slice(self.message().as_bytes());
+    }
 }
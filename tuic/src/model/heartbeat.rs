@@ -13,10 +13,10 @@ struct Tx {
 }
 
 impl Heartbeat<side::Tx> {
-    pub(super) fn new() -> Self {
+    pub(super) fn new(header: HeartbeatHeader) -> Self {
         Self {
             inner: Side::Tx(Tx {
-                header: Header::Heartbeat(HeartbeatHeader::new()),
+                header: Header::Heartbeat(header),
             }),
             _marker: side::Tx,
         }
@@ -42,19 +42,44 @@ impl Debug for Heartbeat<side::Tx> {
     }
 }
 
-struct Rx;
+struct Rx {
+    timestamp: Option<u64>,
+    echo: bool,
+}
 
 impl Heartbeat<side::Rx> {
-    pub(super) fn new() -> Self {
+    pub(super) fn new(timestamp: Option<u64>, echo: bool) -> Self {
         Self {
-            inner: Side::Rx(Rx),
+            inner: Side::Rx(Rx { timestamp, echo }),
             _marker: side::Rx,
         }
     }
+
+    /// Returns the timestamp, if any
+    pub fn timestamp(&self) -> Option<u64> {
+        let Side::Rx(rx) = &self.inner else {
+            unreachable!()
+        };
+        rx.timestamp
+    }
+
+    /// Returns `true` if the `Heartbeat` is an echo of a timestamp sent before
+    pub fn is_echo(&self) -> bool {
+        let Side::Rx(rx) = &self.inner else {
+            unreachable!()
+        };
+        rx.echo
+    }
 }
 
 impl Debug for Heartbeat<side::Rx> {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        f.debug_struct("Heartbeat").finish()
+        let Side::Rx(rx) = &self.inner else {
+            unreachable!()
+        };
+        f.debug_struct("Heartbeat")
+            .field("timestamp", &rx.timestamp)
+            .field("echo", &rx.echo)
+            .finish()
     }
 }
@@ -1,31 +1,39 @@
 //! An abstraction of a TUIC connection, with packet fragmentation management
 //! and task counters. No I/O operation is involved internally
 
+#[cfg(not(target_family = "wasm"))]
+use std::time::Instant;
 use std::{
     collections::HashMap,
     fmt::{Debug, Formatter, Result as FmtResult},
     mem,
     sync::{
         Arc,
-        atomic::{AtomicU16, Ordering},
+        atomic::{AtomicU16, AtomicU64, AtomicUsize, Ordering},
     },
-    time::{Duration, Instant},
+    time::Duration,
 };
 
 use parking_lot::Mutex;
 use register_count::{Counter, Register};
 use thiserror::Error;
 use uuid::Uuid;
+// `std::time::Instant` panics on `wasm32-unknown-unknown`
+#[cfg(target_family = "wasm")]
+use web_time::Instant;
 
 use crate::{
-    Address, Authenticate as AuthenticateHeader, Connect as ConnectHeader,
-    Dissociate as DissociateHeader, Heartbeat as HeartbeatHeader, Packet as PacketHeader,
+    Address, Authenticate as AuthenticateHeader, Capabilities, Connect as ConnectHeader,
+    Dissociate as DissociateHeader, Heartbeat as HeartbeatHeader, Negotiate as NegotiateHeader,
+    Notice as NoticeHeader, NoticeKind, Packet as PacketHeader, VERSION,
 };
 
 mod authenticate;
 mod connect;
 mod dissociate;
 mod heartbeat;
+mod negotiate;
+mod notice;
 mod packet;
 
 pub use self::{
@@ -33,6 +41,8 @@ pub use self::{
     connect::Connect,
     dissociate::Dissociate,
     heartbeat::Heartbeat,
+    negotiate::Negotiate,
+    notice::Notice,
     packet::{Fragments, Packet},
 };
 
@@ -43,6 +53,7 @@ pub struct Connection<B> {
     udp_sessions: Arc<Mutex<UdpSessions<B>>>,
     task_connect_count: Counter,
     task_associate_count: Counter,
+    negotiated: Arc<Mutex<Option<(u8, Capabilities)>>>,
 }
 
 impl<B> Connection<B>
@@ -58,6 +69,7 @@ where
             udp_sessions: Arc::new(Mutex::new(UdpSessions::new(task_associate_count.clone()))),
             task_connect_count: Counter::new(),
             task_associate_count,
+            negotiated: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -100,6 +112,20 @@ where
             .send_packet(assoc_id, addr, max_pkt_size)
     }
 
+    /// Sends a `Packet` under `pkt_id` instead of the next packet ID of the
+    /// association, e.g. to send the same packet over several connections
+    pub fn send_packet_as(
+        &self,
+        assoc_id: u16,
+        pkt_id: u16,
+        addr: Address,
+        max_pkt_size: usize,
+    ) -> Packet<side::Tx, B> {
+        self.udp_sessions
+            .lock()
+            .send_packet_as(assoc_id, pkt_id, addr, max_pkt_size)
+    }
+
     /// Receives a `Packet`. If the association ID is not found, returns `None`
     pub fn recv_packet(&self, header: PacketHeader) -> Option<Packet<side::Rx, B>> {
         let (assoc_id, pkt_id, frag_total, frag_id, size, addr) = header.into();
@@ -139,15 +165,58 @@ where
         self.udp_sessions.lock().recv_dissociate(assoc_id)
     }
 
-    /// Sends a `Heartbeat`
-    pub fn send_heartbeat(&self) -> Heartbeat<side::Tx> {
-        Heartbeat::<side::Tx>::new()
+    /// Sends a `Heartbeat`, asking the peer to echo `timestamp` back if any
+    pub fn send_heartbeat(&self, timestamp: Option<u64>) -> Heartbeat<side::Tx> {
+        let header = match timestamp {
+            Some(timestamp) => HeartbeatHeader::with_timestamp(timestamp),
+            None => HeartbeatHeader::new(),
+        };
+        Heartbeat::<side::Tx>::new(header)
+    }
+
+    /// Sends a `Heartbeat` echoing `timestamp` back
+    pub fn send_heartbeat_echo(&self, timestamp: u64) -> Heartbeat<side::Tx> {
+        Heartbeat::<side::Tx>::new(HeartbeatHeader::echo(timestamp))
     }
 
     /// Receives a `Heartbeat`
     pub fn recv_heartbeat(&self, header: HeartbeatHeader) -> Heartbeat<side::Rx> {
-        let () = header.into();
-        Heartbeat::<side::Rx>::new()
+        let (timestamp, echo) = header.into();
+        Heartbeat::<side::Rx>::new(timestamp, echo)
+    }
+
+    /// Sends a `Notice`
+    pub fn send_notice(&self, kind: NoticeKind, message: String) -> Notice<side::Tx> {
+        Notice::<side::Tx>::new(kind, message)
+    }
+
+    /// Receives a `Notice`
+    pub fn recv_notice(&self, header: NoticeHeader) -> Notice<side::Rx> {
+        let (kind, message) = header.into();
+        Notice::<side::Rx>::new(kind, message)
+    }
+
+    /// Sends a `Negotiate`
+    pub fn send_negotiate(&self, version: u8, capabilities: Capabilities) -> Negotiate<side::Tx> {
+        Negotiate::<side::Tx>::new(version, capabilities)
+    }
+
+    /// Receives a `Negotiate`
+    pub fn recv_negotiate(&self, header: NegotiateHeader) -> Negotiate<side::Rx> {
+        let (version, capabilities) = header.into();
+        Negotiate::<side::Rx>::new(version, capabilities)
+    }
+
+    /// Records the outcome of the negotiation. The version is capped to the
+    /// one implemented here
+    pub fn set_negotiated(&self, version: u8, capabilities: Capabilities) {
+        *self.negotiated.lock() = Some((version.min(VERSION), capabilities));
+    }
+
+    /// Returns the negotiated protocol version and extensions, or `None` if
+    /// the peer does not support negotiation (yet)
+    pub fn negotiated(&self) -> Option<(u8, Capabilities)> {
+        *self.negotiated.lock()
     }
 
     /// Returns the number of `Connect` tasks
@@ -165,6 +234,109 @@ where
     pub fn collect_garbage(&self, timeout: Duration) {
         self.udp_sessions.lock().collect_garbage(timeout);
     }
+
+    /// Sets the limits on the fragments buffered for reassembly
+    pub fn set_reassembly_limits(&self, limits: ReassemblyLimits) {
+        self.udp_sessions.lock().limits = limits;
+    }
+
+    /// Sets how long the fragments of a packet are kept, leaving the other
+    /// reassembly limits unchanged
+    pub fn set_reassembly_timeout(&self, timeout: Duration) {
+        self.udp_sessions.lock().limits.timeout = timeout;
+    }
+}
+
+/// Limits on the fragments of `native` mode packets buffered for reassembly
+#[derive(Clone, Debug)]
+pub struct ReassemblyLimits {
+    /// How long the fragments of a packet are kept. Expired packets are
+    /// evicted when a new packet arrives in the same UDP session, in addition
+    /// to `collect_garbage()`
+    pub timeout: Duration,
+    /// Maximum number of packets pending reassembly in a UDP session. The
+    /// oldest one is evicted to make room for a new packet
+    pub max_pending_packets: usize,
+    /// Memory budget for the buffered fragments, which can be shared by
+    /// multiple connections
+    pub memory: Arc<MemoryBudget>,
+    /// How many packet IDs behind the latest reassembled packet of a UDP
+    /// session are still accepted. Older packets, and packets already
+    /// reassembled in this window, are dropped. `0` disables the check. At
+    /// most 128
+    pub reorder_window: u16,
+    /// Counters of the dropped packets, which can be shared by multiple
+    /// connections
+    pub stats: Arc<ReassemblyStats>,
+}
+
+impl Default for ReassemblyLimits {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(15),
+            max_pending_packets: 256,
+            memory: Arc::new(MemoryBudget::new(usize::MAX)),
+            reorder_window: 64,
+            stats: Arc::new(ReassemblyStats::default()),
+        }
+    }
+}
+
+/// Counters of the packets dropped before reassembly
+#[derive(Debug, Default)]
+pub struct ReassemblyStats {
+    duplicated: AtomicU64,
+    out_of_window: AtomicU64,
+}
+
+impl ReassemblyStats {
+    /// Returns the number of packets dropped as already reassembled
+    pub fn duplicated(&self) -> u64 {
+        self.duplicated.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of packets dropped as too far behind the latest one
+    pub fn out_of_window(&self) -> u64 {
+        self.out_of_window.load(Ordering::Relaxed)
+    }
+}
+
+/// A limit on the bytes buffered for reassembly
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl MemoryBudget {
+    /// Creates a budget of `limit` bytes
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of bytes currently buffered
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    fn has_room(&self, size: usize) -> bool {
+        self.used().saturating_add(size) <= self.limit
+    }
+
+    fn try_reserve(&self, size: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(size).filter(|used| *used <= self.limit)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, size: usize) {
+        self.used.fetch_sub(size, Ordering::AcqRel);
+    }
 }
 
 impl<B> Debug for Connection<B>
@@ -176,6 +348,7 @@ where
             .field("udp_sessions", &self.udp_sessions)
             .field("task_connect_count", &self.task_connect_count())
             .field("task_associate_count", &self.task_associate_count())
+            .field("negotiated", &self.negotiated())
             .finish()
     }
 }
@@ -196,6 +369,7 @@ pub mod side {
 struct UdpSessions<B> {
     sessions: HashMap<u16, UdpSession<B>>,
     task_associate_count: Counter,
+    limits: ReassemblyLimits,
 }
 
 impl<B> UdpSessions<B>
@@ -206,6 +380,7 @@ where
         Self {
             sessions: HashMap::new(),
             task_associate_count,
+            limits: ReassemblyLimits::default(),
         }
     }
 
@@ -221,6 +396,19 @@ where
             .send_packet(assoc_id, addr, max_pkt_size)
     }
 
+    fn send_packet_as(
+        &mut self,
+        assoc_id: u16,
+        pkt_id: u16,
+        addr: Address,
+        max_pkt_size: usize,
+    ) -> Packet<side::Tx, B> {
+        self.sessions
+            .entry(assoc_id)
+            .or_insert_with(|| UdpSession::new(self.task_associate_count.reg()));
+        Packet::<side::Tx, B>::new(assoc_id, pkt_id, addr, max_pkt_size)
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn recv_packet(
         &mut self,
@@ -278,7 +466,16 @@ where
         self.sessions
             .entry(assoc_id)
             .or_insert_with(|| UdpSession::new(self.task_associate_count.reg()))
-            .insert(assoc_id, pkt_id, frag_total, frag_id, size, addr, data)
+            .insert(
+                &self.limits,
+                assoc_id,
+                pkt_id,
+                frag_total,
+                frag_id,
+                size,
+                addr,
+                data,
+            )
     }
 
     fn collect_garbage(&mut self, timeout: Duration) {
@@ -302,6 +499,7 @@ where
 struct UdpSession<B> {
     pkt_buf: HashMap<u16, PacketBuffer<B>>,
     next_pkt_id: AtomicU16,
+    recv_window: RecvWindow,
     _task_reg: Register,
 }
 
@@ -313,6 +511,7 @@ where
         Self {
             pkt_buf: HashMap::new(),
             next_pkt_id: AtomicU16::new(0),
+            recv_window: RecvWindow::new(),
             _task_reg: task_reg,
         }
     }
@@ -348,6 +547,7 @@ where
     #[allow(clippy::too_many_arguments)]
     fn insert(
         &mut self,
+        limits: &ReassemblyLimits,
         assoc_id: u16,
         pkt_id: u16,
         frag_total: u8,
@@ -356,14 +556,45 @@ where
         addr: Address,
         data: B,
     ) -> Result<Option<Assemblable<B>>, AssembleError> {
+        // checked before a buffer is set up for the packet
+        if frag_id >= frag_total {
+            return Err(AssembleError::InvalidFragmentId(frag_total, frag_id));
+        }
+
+        match self.recv_window.check(pkt_id, limits.reorder_window) {
+            Ok(()) => {}
+            Err(err @ AssembleError::DuplicatedPacket(_)) => {
+                limits.stats.duplicated.fetch_add(1, Ordering::Relaxed);
+                return Err(err);
+            }
+            Err(err) => {
+                limits.stats.out_of_window.fetch_add(1, Ordering::Relaxed);
+                return Err(err);
+            }
+        }
+
+        // a packet in a single fragment is never buffered
+        if frag_total > 1 {
+            if !self.pkt_buf.contains_key(&pkt_id) {
+                self.collect_garbage(limits.timeout);
+
+                while self.pkt_buf.len() >= limits.max_pending_packets.max(1)
+                    && self.evict_oldest(pkt_id)
+                {}
+            }
+
+            while !limits.memory.has_room(size as usize) && self.evict_oldest(pkt_id) {}
+        }
+
         let res = self
             .pkt_buf
             .entry(pkt_id)
-            .or_insert_with(|| PacketBuffer::new(frag_total))
+            .or_insert_with(|| PacketBuffer::new(frag_total, limits.memory.clone()))
             .insert(assoc_id, frag_total, frag_id, size, addr, data)?;
 
         if res.is_some() {
             self.pkt_buf.remove(&pkt_id);
+            self.recv_window.mark(pkt_id, limits.reorder_window);
         }
 
         Ok(res)
@@ -372,6 +603,25 @@ where
     fn collect_garbage(&mut self, timeout: Duration) {
         self.pkt_buf.retain(|_, buf| buf.c_time.elapsed() < timeout);
     }
+
+    /// Evicts the oldest packet pending reassembly other than `pkt_id`,
+    /// returns `false` if there is none
+    fn evict_oldest(&mut self, pkt_id: u16) -> bool {
+        let oldest = self
+            .pkt_buf
+            .iter()
+            .filter(|(id, _)| **id != pkt_id)
+            .min_by_key(|(_, buf)| buf.c_time)
+            .map(|(id, _)| *id);
+
+        match oldest {
+            Some(id) => {
+                self.pkt_buf.remove(&id);
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 impl<B> Debug for UdpSession<B>
@@ -386,6 +636,70 @@ where
     }
 }
 
+/// Tracks the IDs of the recently reassembled packets of a UDP session, in the
+/// way of an anti-replay window
+struct RecvWindow {
+    latest: Option<u16>,
+    /// Bit `n` is set if packet `latest - n` was reassembled
+    seen: u128,
+}
+
+impl RecvWindow {
+    const MAX_SIZE: u16 = u128::BITS as u16;
+
+    fn new() -> Self {
+        Self {
+            latest: None,
+            seen: 0,
+        }
+    }
+
+    /// Returns how far `pkt_id` is behind the latest packet, `None` if it is
+    /// ahead. Packet IDs wrap around, so half of the ID space counts as ahead
+    fn behind(&self, pkt_id: u16) -> Option<u16> {
+        let latest = self.latest?;
+        let behind = latest.wrapping_sub(pkt_id);
+        (behind < 0x8000).then_some(behind)
+    }
+
+    fn check(&self, pkt_id: u16, size: u16) -> Result<(), AssembleError> {
+        let size = size.min(Self::MAX_SIZE);
+
+        if size == 0 {
+            return Ok(());
+        }
+
+        match self.behind(pkt_id) {
+            Some(behind) if behind >= size => Err(AssembleError::OutOfWindow(pkt_id)),
+            Some(behind) if self.seen & (1 << behind) != 0 => {
+                Err(AssembleError::DuplicatedPacket(pkt_id))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn mark(&mut self, pkt_id: u16, size: u16) {
+        if size == 0 {
+            return;
+        }
+
+        match self.behind(pkt_id) {
+            Some(behind) => {
+                if behind < Self::MAX_SIZE {
+                    self.seen |= 1 << behind;
+                }
+            }
+            None => {
+                let ahead = self
+                    .latest
+                    .map_or(u128::BITS, |latest| pkt_id.wrapping_sub(latest) as u32);
+                self.seen = self.seen.checked_shl(ahead).unwrap_or(0) | 1;
+                self.latest = Some(pkt_id);
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 struct PacketBuffer<B> {
     buf: Vec<Option<B>>,
@@ -393,13 +707,15 @@ struct PacketBuffer<B> {
     frag_received: u8,
     addr: Address,
     c_time: Instant,
+    size: usize,
+    memory: Arc<MemoryBudget>,
 }
 
 impl<B> PacketBuffer<B>
 where
     B: AsRef<[u8]>,
 {
-    fn new(frag_total: u8) -> Self {
+    fn new(frag_total: u8, memory: Arc<MemoryBudget>) -> Self {
         let mut buf = Vec::with_capacity(frag_total as usize);
         buf.resize_with(frag_total as usize, || None);
 
@@ -409,6 +725,8 @@ where
             frag_received: 0,
             addr: Address::None,
             c_time: Instant::now(),
+            size: 0,
+            memory,
         }
     }
 
@@ -421,9 +739,13 @@ where
         addr: Address,
         data: B,
     ) -> Result<Option<Assemblable<B>>, AssembleError> {
-        assert_eq!(data.as_ref().len(), size as usize);
+        if data.as_ref().len() != size as usize {
+            return Err(AssembleError::SizeMismatch(size, data.as_ref().len()));
+        }
 
-        if frag_id >= frag_total {
+        // fragments of the same packet disagreeing on `FRAG_TOTAL` must not
+        // index past the buffer allocated for the first one
+        if frag_id >= frag_total || frag_total != self.frag_total {
             return Err(AssembleError::InvalidFragmentId(frag_total, frag_id));
         }
 
@@ -443,6 +765,13 @@ where
             return Err(AssembleError::DuplicatedFragment(frag_id));
         }
 
+        if frag_total > 1 {
+            if !self.memory.try_reserve(size as usize) {
+                return Err(AssembleError::MemoryBudgetExceeded);
+            }
+            self.size += size as usize;
+        }
+
         self.buf[frag_id as usize] = Some(data);
         self.frag_received += 1;
 
@@ -462,6 +791,12 @@ where
     }
 }
 
+impl<B> Drop for PacketBuffer<B> {
+    fn drop(&mut self) {
+        self.memory.release(self.size);
+    }
+}
+
 /// A complete packet that can be assembled
 #[derive(Debug)]
 pub struct Assemblable<B> {
@@ -521,4 +856,12 @@ pub enum AssembleError {
     InvalidAddress(&'static str),
     #[error("duplicated fragment: {0}")]
     DuplicatedFragment(u8),
+    #[error("reassembly memory budget exceeded")]
+    MemoryBudgetExceeded,
+    #[error("duplicated packet: {0:#06x}")]
+    DuplicatedPacket(u16),
+    #[error("packet {0:#06x} is too far behind the latest one")]
+    OutOfWindow(u16),
+    #[error("fragment size {0} does not match its payload of {1} bytes")]
+    SizeMismatch(u16, usize),
 }
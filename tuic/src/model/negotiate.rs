@@ -0,0 +1,89 @@
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+
+use super::side::{self, Side};
+use crate::{Capabilities, Header, Negotiate as NegotiateHeader};
+
+/// The model of the `Negotiate` command
+pub struct Negotiate<M> {
+    inner: Side<Tx, Rx>,
+    _marker: M,
+}
+
+struct Tx {
+    header: Header,
+}
+
+impl Negotiate<side::Tx> {
+    pub(super) fn new(version: u8, capabilities: Capabilities) -> Self {
+        Self {
+            inner: Side::Tx(Tx {
+                header: Header::Negotiate(NegotiateHeader::new(version, capabilities)),
+            }),
+            _marker: side::Tx,
+        }
+    }
+
+    /// Returns the header of the `Negotiate` command
+    pub fn header(&self) -> &Header {
+        let Side::Tx(tx) = &self.inner else {
+            unreachable!()
+        };
+        &tx.header
+    }
+}
+
+impl Debug for Negotiate<side::Tx> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let Side::Tx(tx) = &self.inner else {
+            unreachable!()
+        };
+        f.debug_struct("Negotiate")
+            .field("header", &tx.header)
+            .finish()
+    }
+}
+
+struct Rx {
+    version: u8,
+    capabilities: Capabilities,
+}
+
+impl Negotiate<side::Rx> {
+    pub(super) fn new(version: u8, capabilities: Capabilities) -> Self {
+        Self {
+            inner: Side::Rx(Rx {
+                version,
+                capabilities,
+            }),
+            _marker: side::Rx,
+        }
+    }
+
+    /// Returns the protocol version
+    pub fn version(&self) -> u8 {
+        let Side::Rx(rx) = &self.inner else {
+            unreachable!()
+        };
+        rx.version
+    }
+
+    /// Returns the protocol extensions
+    pub fn capabilities(&self) -> Capabilities {
+        let Side::Rx(rx) = &self.inner else {
+            unreachable!()
+        };
+        rx.capabilities
+    }
+}
+
+impl Debug for Negotiate<side::Rx> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let Side::Rx(rx) = &self.inner else {
+            unreachable!()
+        };
+        f.debug_struct("Negotiate")
+            .field("version", &rx.version)
+            .field("capabilities", &rx.capabilities)
+            .finish()
+    }
+}
@@ -0,0 +1,86 @@
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+
+use super::side::{self, Side};
+use crate::{Header, Notice as NoticeHeader, NoticeKind};
+
+/// The model of the `Notice` command
+pub struct Notice<M> {
+    inner: Side<Tx, Rx>,
+    _marker: M,
+}
+
+struct Tx {
+    header: Header,
+}
+
+impl Notice<side::Tx> {
+    pub(super) fn new(kind: NoticeKind, message: String) -> Self {
+        Self {
+            inner: Side::Tx(Tx {
+                header: Header::Notice(NoticeHeader::new(kind, message)),
+            }),
+            _marker: side::Tx,
+        }
+    }
+
+    /// Returns the header of the `Notice` command
+    pub fn header(&self) -> &Header {
+        let Side::Tx(tx) = &self.inner else {
+            unreachable!()
+        };
+        &tx.header
+    }
+}
+
+impl Debug for Notice<side::Tx> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let Side::Tx(tx) = &self.inner else {
+            unreachable!()
+        };
+        f.debug_struct("Notice")
+            .field("header", &tx.header)
+            .finish()
+    }
+}
+
+struct Rx {
+    kind: NoticeKind,
+    message: String,
+}
+
+impl Notice<side::Rx> {
+    pub(super) fn new(kind: NoticeKind, message: String) -> Self {
+        Self {
+            inner: Side::Rx(Rx { kind, message }),
+            _marker: side::Rx,
+        }
+    }
+
+    /// Returns the kind of the notice
+    pub fn kind(&self) -> NoticeKind {
+        let Side::Rx(rx) = &self.inner else {
+            unreachable!()
+        };
+        rx.kind
+    }
+
+    /// Returns the message
+    pub fn message(&self) -> &str {
+        let Side::Rx(rx) = &self.inner else {
+            unreachable!()
+        };
+        &rx.message
+    }
+}
+
+impl Debug for Notice<side::Rx> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let Side::Rx(rx) = &self.inner else {
+            unreachable!()
+        };
+        f.debug_struct("Notice")
+            .field("kind", &rx.kind)
+            .field("message", &rx.message)
+            .finish()
+    }
+}
@@ -209,7 +209,7 @@ impl<B> Debug for Packet<side::Rx, B> {
     }
 }
 
-/// Iterator over fragments of a packet
+/// Iterator over fragments of a packet, along with their `Packet` headers
 #[derive(Debug)]
 pub struct Fragments<'a, P> {
     assoc_id: u16,
@@ -240,7 +240,7 @@ where
         let (_, _, _, _, _, addr) = pkt.into();
 
         let frag_total = if first_frag_size < payload.as_ref().len() {
-            (1 + (payload.as_ref().len() - first_frag_size) / frag_size_addr_none + 1) as u8
+            (1 + (payload.as_ref().len() - first_frag_size).div_ceil(frag_size_addr_none)) as u8
         } else {
             1u8
         };
@@ -263,7 +263,7 @@ impl<'a, P> Iterator for Fragments<'a, P>
 where
     P: AsRef<[u8]> + 'a,
 {
-    type Item = (Header, &'a [u8]);
+    type Item = (PacketHeader, &'a [u8]);
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.next_frag_id < self.frag_total {
@@ -278,14 +278,14 @@ where
             };
             let (_, _, _, _, _, addr) = pkt.into();
 
-            let header = Header::Packet(PacketHeader::new(
+            let header = PacketHeader::new(
                 self.assoc_id,
                 self.pkt_id,
                 self.frag_total,
                 self.next_frag_id,
                 (next_frag_end - self.next_frag_start) as u16,
                 addr,
-            ));
+            );
 
             let payload_ptr = &(self.payload.as_ref()[self.next_frag_start]) as *const u8;
             let payload =
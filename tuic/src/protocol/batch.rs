@@ -0,0 +1,84 @@
+use super::Address;
+
+/// Command `Batch`
+/// ```plain
+/// +----------+-------+----------+
+/// | ASSOC_ID | COUNT |   ADDR   |
+/// +----------+-------+----------+
+/// |    2     |   1   | Variable |
+/// +----------+-------+----------+
+/// ```
+///
+/// where:
+///
+/// - `ASSOC_ID` - UDP relay session ID
+/// - `COUNT` - number of UDP packets in the batch
+/// - `ADDR` - target (from client) or source (from server) address of all
+///   packets in the batch
+///
+/// Followed by `COUNT` entries, each carrying a whole UDP packet:
+///
+/// ```plain
+/// +--------+------+----------+
+/// | PKT_ID | SIZE | PAYLOAD  |
+/// +--------+------+----------+
+/// |   2    |  2   | Variable |
+/// +--------+------+----------+
+/// ```
+///
+/// Only sent in a datagram, once the `BATCH` extension is negotiated. Each
+/// entry is handled as a `Packet` command with a single fragment
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Batch {
+    assoc_id: u16,
+    count: u8,
+    addr: Address,
+}
+
+impl Batch {
+    /// The serialized length of an entry, without its payload
+    pub const ENTRY_HEADER_LEN: usize = 2 + 2;
+    const TYPE_CODE: u8 = 0x09;
+
+    /// Creates a new `Batch` command
+    pub const fn new(assoc_id: u16, count: u8, addr: Address) -> Self {
+        Self {
+            assoc_id,
+            count,
+            addr,
+        }
+    }
+
+    /// Returns the UDP relay session ID
+    pub fn assoc_id(&self) -> u16 {
+        self.assoc_id
+    }
+
+    /// Returns the number of UDP packets in the batch
+    pub fn count(&self) -> u8 {
+        self.count
+    }
+
+    /// Returns the target (from client) or source (from server) address
+    pub fn addr(&self) -> &Address {
+        &self.addr
+    }
+
+    /// Returns the command type code
+    pub const fn type_code() -> u8 {
+        Self::TYPE_CODE
+    }
+
+    /// Returns the serialized length of the command, without the entries
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        2 + 1 + self.addr.len()
+    }
+}
+
+impl From<Batch> for (u16, u8, Address) {
+    fn from(batch: Batch) -> Self {
+        (batch.assoc_id, batch.count, batch.addr)
+    }
+}
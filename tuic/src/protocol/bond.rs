@@ -0,0 +1,55 @@
+/// Command `Bond`
+/// ```plain
+/// +---------+
+/// | BOND_ID |
+/// +---------+
+/// |    8    |
+/// +---------+
+/// ```
+///
+/// where:
+///
+/// - `BOND_ID` - an identifier chosen by the client, the same on all the
+///   connections of the bond
+///
+/// Sent by the client in a unidirectional stream to join the connection to a
+/// bond of connections over different network paths. The UDP sessions are
+/// shared across the bond, so the same packet may be sent over several of its
+/// connections, under the same packet ID, and only the first copy is relayed.
+/// Only sent once the `MULTIPATH` extension is negotiated
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Bond {
+    bond_id: u64,
+}
+
+impl Bond {
+    const TYPE_CODE: u8 = 0x0c;
+
+    /// Creates a new `Bond` command
+    pub const fn new(bond_id: u64) -> Self {
+        Self { bond_id }
+    }
+
+    /// Returns the bond ID
+    pub fn bond_id(&self) -> u64 {
+        self.bond_id
+    }
+
+    /// Returns the command type code
+    pub const fn type_code() -> u8 {
+        Self::TYPE_CODE
+    }
+
+    /// Returns the serialized length of the command
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        8
+    }
+}
+
+impl From<Bond> for (u64,) {
+    fn from(bond: Bond) -> Self {
+        (bond.bond_id,)
+    }
+}
@@ -0,0 +1,48 @@
+/// Command `Compress`
+///
+/// ```plain
+/// +-+
+/// | |
+/// +-+
+/// | |
+/// +-+
+/// ```
+///
+/// Sent by the client right before a `Connect` command, in the same stream, to
+/// enable compression on the stream. The data relayed in each direction then
+/// starts with a byte telling whether it is compressed with zstd (`0x01`) or
+/// sent as is (`0x00`), decided by the sender. Only sent once the
+/// `COMPRESSION` extension is negotiated
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Compress;
+
+impl Compress {
+    const TYPE_CODE: u8 = 0x08;
+
+    /// Creates a new `Compress` command
+    pub const fn new() -> Self {
+        Self
+    }
+
+    /// Returns the command type code
+    pub const fn type_code() -> u8 {
+        Self::TYPE_CODE
+    }
+
+    /// Returns the serialized length of the command
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        0
+    }
+}
+
+impl From<Compress> for () {
+    fn from(_: Compress) -> Self {}
+}
+
+impl Default for Compress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -13,6 +13,7 @@ use super::Address;
 ///
 /// - `ADDR` - target address
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Connect {
     addr: Address,
 }
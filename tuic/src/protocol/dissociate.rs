@@ -12,6 +12,7 @@
 ///
 /// - `ASSOC_ID` - UDP relay session ID
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Dissociate {
     assoc_id: u16,
 }
@@ -1,20 +1,66 @@
 /// Command `Heartbeat`
 /// ```plain
-/// +-+
-/// | |
-/// +-+
-/// | |
-/// +-+
+/// +------+-----------+
+/// | KIND | TIMESTAMP |
+/// +------+-----------+
+/// |  1   |     8     |
+/// +------+-----------+
 /// ```
+///
+/// where:
+///
+/// - `KIND` - `0x00` if the peer should echo the timestamp back, `0x01` if this
+///   is an echo
+/// - `TIMESTAMP` - an opaque timestamp chosen by the side requesting the echo
+///
+/// Both fields are optional, a `Heartbeat` can be empty. As it is always sent
+/// alone in a datagram, the receiver tells them apart by what is left in the
+/// datagram. Peers not knowing about the fields ignore them
 #[derive(Clone, Debug)]
-pub struct Heartbeat;
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Heartbeat {
+    timestamp: Option<u64>,
+    echo: bool,
+}
 
 impl Heartbeat {
+    pub(crate) const KIND_ECHO: u8 = 0x01;
+    pub(crate) const KIND_REQUEST: u8 = 0x00;
     const TYPE_CODE: u8 = 0x04;
 
-    /// Creates a new `Heartbeat` command
+    /// Creates a new empty `Heartbeat` command
     pub const fn new() -> Self {
-        Self
+        Self {
+            timestamp: None,
+            echo: false,
+        }
+    }
+
+    /// Creates a new `Heartbeat` command asking the peer to echo `timestamp`
+    /// back
+    pub const fn with_timestamp(timestamp: u64) -> Self {
+        Self {
+            timestamp: Some(timestamp),
+            echo: false,
+        }
+    }
+
+    /// Creates a new `Heartbeat` command echoing `timestamp` back
+    pub const fn echo(timestamp: u64) -> Self {
+        Self {
+            timestamp: Some(timestamp),
+            echo: true,
+        }
+    }
+
+    /// Returns the timestamp, if any
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    /// Returns `true` if the command is an echo of a timestamp
+    pub fn is_echo(&self) -> bool {
+        self.echo
     }
 
     /// Returns the command type code
@@ -25,12 +71,14 @@ impl Heartbeat {
     /// Returns the serialized length of the command
     #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
-        0
+        if self.timestamp.is_some() { 1 + 8 } else { 0 }
     }
 }
 
-impl From<Heartbeat> for () {
-    fn from(_: Heartbeat) -> Self {}
+impl From<Heartbeat> for (Option<u64>, bool) {
+    fn from(hb: Heartbeat) -> Self {
+        (hb.timestamp, hb.echo)
+    }
 }
 
 impl Default for Heartbeat {
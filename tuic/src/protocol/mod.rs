@@ -2,17 +2,37 @@ use std::{
     fmt::{Display, Formatter, Result as FmtResult},
     mem,
     net::SocketAddr,
+    ops::RangeInclusive,
 };
 
 mod authenticate;
+mod batch;
+mod bond;
+mod compress;
 mod connect;
 mod dissociate;
 mod heartbeat;
+mod negotiate;
+mod notice;
 mod packet;
+mod padding;
+mod speedtest;
+mod status;
 
 pub use self::{
-    authenticate::Authenticate, connect::Connect, dissociate::Dissociate, heartbeat::Heartbeat,
+    authenticate::Authenticate,
+    batch::Batch,
+    bond::Bond,
+    compress::Compress,
+    connect::Connect,
+    dissociate::Dissociate,
+    heartbeat::Heartbeat,
+    negotiate::{Capabilities, Negotiate},
+    notice::{Notice, NoticeKind},
     packet::Packet,
+    padding::Padding,
+    speedtest::Speedtest,
+    status::{ConnectStatus, Status},
 };
 
 /// The TUIC protocol version
@@ -35,31 +55,61 @@ pub const VERSION: u8 = 0x05;
 ///
 /// ## Command Types
 ///
-/// There are five types of command:
+/// There are thirteen types of command:
 ///
 /// - `0x00` - `Authenticate` - for authenticating the multiplexed stream
 /// - `0x01` - `Connect` - for establishing a TCP relay
 /// - `0x02` - `Packet` - for relaying (fragmented part of) a UDP packet
 /// - `0x03` - `Dissociate` - for terminating a UDP relaying session
 /// - `0x04` - `Heartbeat` - for keeping the QUIC connection alive
+/// - `0x05` - `Notice` - for the server to notify the client of events
+/// - `0x06` - `Negotiate` - for agreeing on the protocol version and extensions
+/// - `0x07` - `Padding` - for hiding the length of the command following it
+/// - `0x08` - `Compress` - for compressing the stream of the `Connect`
+///   following it
+/// - `0x09` - `Batch` - for relaying several small UDP packets at once
+/// - `0x0a` - `Status` - for reporting the outcome of a `Connect`
+/// - `0x0b` - `Speedtest` - for probing the bandwidth of the connection
+/// - `0x0c` - `Bond` - for sharing UDP sessions across connections
 ///
-/// Command `Connect` and `Packet` carry payload (stream / packet fragment)
+/// Command `Connect`, `Packet` and `Batch` carry payload (stream / packet
+/// fragment / packets)
 #[non_exhaustive]
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Header {
     Authenticate(Authenticate),
     Connect(Connect),
     Packet(Packet),
     Dissociate(Dissociate),
     Heartbeat(Heartbeat),
+    Notice(Notice),
+    Negotiate(Negotiate),
+    Padding(Padding),
+    Compress(Compress),
+    Batch(Batch),
+    Status(Status),
+    Speedtest(Speedtest),
+    Bond(Bond),
 }
 
 impl Header {
     pub const TYPE_CODE_AUTHENTICATE: u8 = Authenticate::type_code();
+    pub const TYPE_CODE_BATCH: u8 = Batch::type_code();
+    pub const TYPE_CODE_BOND: u8 = Bond::type_code();
+    pub const TYPE_CODE_COMPRESS: u8 = Compress::type_code();
     pub const TYPE_CODE_CONNECT: u8 = Connect::type_code();
     pub const TYPE_CODE_DISSOCIATE: u8 = Dissociate::type_code();
     pub const TYPE_CODE_HEARTBEAT: u8 = Heartbeat::type_code();
+    pub const TYPE_CODE_NEGOTIATE: u8 = Negotiate::type_code();
+    pub const TYPE_CODE_NOTICE: u8 = Notice::type_code();
     pub const TYPE_CODE_PACKET: u8 = Packet::type_code();
+    pub const TYPE_CODE_PADDING: u8 = Padding::type_code();
+    pub const TYPE_CODE_SPEEDTEST: u8 = Speedtest::type_code();
+    pub const TYPE_CODE_STATUS: u8 = Status::type_code();
+    /// Type codes reserved for experimental extensions, which open a
+    /// bidirectional stream with just `VER` and `TYPE`
+    pub const EXTENSION_TYPE_CODES: RangeInclusive<u8> = 0xe0..=0xff;
 
     /// Returns the command type code
     pub const fn type_code(&self) -> u8 {
@@ -69,6 +119,14 @@ impl Header {
             Self::Packet(_) => Packet::type_code(),
             Self::Dissociate(_) => Dissociate::type_code(),
             Self::Heartbeat(_) => Heartbeat::type_code(),
+            Self::Notice(_) => Notice::type_code(),
+            Self::Negotiate(_) => Negotiate::type_code(),
+            Self::Padding(_) => Padding::type_code(),
+            Self::Compress(_) => Compress::type_code(),
+            Self::Batch(_) => Batch::type_code(),
+            Self::Status(_) => Status::type_code(),
+            Self::Speedtest(_) => Speedtest::type_code(),
+            Self::Bond(_) => Bond::type_code(),
         }
     }
 
@@ -81,6 +139,14 @@ impl Header {
             Self::Packet(packet) => packet.len(),
             Self::Dissociate(dissociate) => dissociate.len(),
             Self::Heartbeat(heartbeat) => heartbeat.len(),
+            Self::Notice(notice) => notice.len(),
+            Self::Negotiate(negotiate) => negotiate.len(),
+            Self::Padding(padding) => padding.len(),
+            Self::Compress(compress) => compress.len(),
+            Self::Batch(batch) => batch.len(),
+            Self::Status(status) => status.len(),
+            Self::Speedtest(speedtest) => speedtest.len(),
+            Self::Bond(bond) => bond.len(),
         }
     }
 }
@@ -114,6 +180,7 @@ impl Header {
 ///
 /// The port number is encoded in 2 bytes after the Domain name / IP address.
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Address {
     None,
     DomainAddress(String, u16),
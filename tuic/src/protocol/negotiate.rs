@@ -0,0 +1,149 @@
+use std::{
+    fmt::{Debug, Formatter, Result as FmtResult},
+    ops::{BitAnd, BitOr},
+};
+
+/// Command `Negotiate`
+///
+/// ```plain
+/// +---------+------+
+/// | VERSION | CAPS |
+/// +---------+------+
+/// |    1    |  8   |
+/// +---------+------+
+/// ```
+///
+/// where:
+///
+/// - `VERSION` - the protocol version
+/// - `CAPS` - the protocol extensions, see [`Capabilities`]
+///
+/// The server offers the highest version and all extensions it supports right
+/// after the client is authenticated. The client replies with the version and
+/// extensions to be used on the connection, which must not exceed the offer
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Negotiate {
+    version: u8,
+    capabilities: Capabilities,
+}
+
+impl Negotiate {
+    const TYPE_CODE: u8 = 0x06;
+
+    /// Creates a new `Negotiate` command
+    pub const fn new(version: u8, capabilities: Capabilities) -> Self {
+        Self {
+            version,
+            capabilities,
+        }
+    }
+
+    /// Returns the protocol version
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Returns the protocol extensions
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Returns the command type code
+    pub const fn type_code() -> u8 {
+        Self::TYPE_CODE
+    }
+
+    /// Returns the serialized length of the command
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        1 + 8
+    }
+}
+
+impl From<Negotiate> for (u8, Capabilities) {
+    fn from(negotiate: Negotiate) -> Self {
+        (negotiate.version, negotiate.capabilities)
+    }
+}
+
+/// A set of protocol extensions
+///
+/// - `0x01` - `NOTICE` - the client accepts `Notice` commands
+/// - `0x02` - `PADDING` - both sides accept `Padding` commands
+/// - `0x04` - `COMPRESSION` - the server accepts `Compress` commands
+/// - `0x08` - `BATCH` - both sides accept `Batch` commands
+/// - `0x10` - `CONNECT_STATUS` - the server accepts `Status` commands
+/// - `0x20` - `SPEEDTEST` - the server accepts `Speedtest` commands
+/// - `0x40` - `MULTIPATH` - the server accepts `Bond` commands
+/// - `0x80` - `MIXED_UDP_RELAY` - the server accepts packets of one connection
+///   in both `native` and `quic` modes
+///
+/// Unknown bits are kept, so an extension unknown to one side is simply left
+/// out of the intersection
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    pub const BATCH: Self = Self(1 << 3);
+    pub const COMPRESSION: Self = Self(1 << 2);
+    pub const CONNECT_STATUS: Self = Self(1 << 4);
+    pub const MIXED_UDP_RELAY: Self = Self(1 << 7);
+    pub const MULTIPATH: Self = Self(1 << 6);
+    pub const NOTICE: Self = Self(1 << 0);
+    pub const PADDING: Self = Self(1 << 1);
+    pub const SPEEDTEST: Self = Self(1 << 5);
+
+    /// Returns an empty set, i.e. no extension
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Creates a set from its raw bits
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the raw bits
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Returns the set of extensions in either set
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Returns `true` if all extensions in `other` are in the set
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns `true` if the set is empty
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl Debug for Capabilities {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "Capabilities({:#x})", self.0)
+    }
+}
@@ -0,0 +1,141 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// Command `Notice`
+///
+/// ```plain
+/// +------+---------+----------+
+/// | KIND | MSG_LEN |   MSG    |
+/// +------+---------+----------+
+/// |  1   |    2    | Variable |
+/// +------+---------+----------+
+/// ```
+///
+/// where:
+///
+/// - `KIND` - the kind of the notice, see [`NoticeKind`]
+/// - `MSG_LEN` - length of the message
+/// - `MSG` - human-readable message in UTF-8, can be empty
+///
+/// Sent by the server only, on a unidirectional stream
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Notice {
+    kind: NoticeKind,
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "deserialize_message"))]
+    message: String,
+}
+
+impl Notice {
+    const TYPE_CODE: u8 = 0x05;
+
+    /// Creates a new `Notice` command. The message is truncated to fit in
+    /// `MSG_LEN`
+    pub fn new(kind: NoticeKind, mut message: String) -> Self {
+        truncate_message(&mut message);
+        Self { kind, message }
+    }
+
+    /// Returns the kind of the notice
+    pub fn kind(&self) -> NoticeKind {
+        self.kind
+    }
+
+    /// Returns the message
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Returns the command type code
+    pub const fn type_code() -> u8 {
+        Self::TYPE_CODE
+    }
+
+    /// Returns the serialized length of the command
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        1 + 2 + self.message.len()
+    }
+}
+
+impl From<Notice> for (NoticeKind, String) {
+    fn from(notice: Notice) -> Self {
+        (notice.kind, notice.message)
+    }
+}
+
+/// Truncates the message to fit in `MSG_LEN`
+fn truncate_message(message: &mut String) {
+    if message.len() > u16::MAX as usize {
+        let mut len = u16::MAX as usize;
+        while !message.is_char_boundary(len) {
+            len -= 1;
+        }
+        message.truncate(len);
+    }
+}
+
+#[cfg(feature = "serde")]
+fn deserialize_message<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let mut message = <String as serde::Deserialize>::deserialize(deserializer)?;
+    truncate_message(&mut message);
+    Ok(message)
+}
+
+/// The kind of a `Notice`
+///
+/// - `0x00` - `Info` - informational only
+/// - `0x01` - `QuotaExhausted` - the traffic quota of the user is used up
+/// - `0x02` - `Shutdown` - the server is going down, e.g. for maintenance
+/// - `0x03` - `BandwidthDowngrade` - the bandwidth of the user is reduced
+///
+/// Kinds unknown to the receiver are kept as `Other`, so new kinds can be added
+/// without breaking older clients
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NoticeKind {
+    Info,
+    QuotaExhausted,
+    Shutdown,
+    BandwidthDowngrade,
+    Other(u8),
+}
+
+impl NoticeKind {
+    /// Returns the kind code
+    pub const fn code(self) -> u8 {
+        match self {
+            Self::Info => 0x00,
+            Self::QuotaExhausted => 0x01,
+            Self::Shutdown => 0x02,
+            Self::BandwidthDowngrade => 0x03,
+            Self::Other(code) => code,
+        }
+    }
+}
+
+impl From<u8> for NoticeKind {
+    fn from(code: u8) -> Self {
+        match code {
+            0x00 => Self::Info,
+            0x01 => Self::QuotaExhausted,
+            0x02 => Self::Shutdown,
+            0x03 => Self::BandwidthDowngrade,
+            code => Self::Other(code),
+        }
+    }
+}
+
+impl Display for NoticeKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Info => write!(f, "info"),
+            Self::QuotaExhausted => write!(f, "quota exhausted"),
+            Self::Shutdown => write!(f, "shutdown"),
+            Self::BandwidthDowngrade => write!(f, "bandwidth downgrade"),
+            Self::Other(code) => write!(f, "unknown ({code:#04x})"),
+        }
+    }
+}
@@ -18,6 +18,7 @@ use super::Address;
 /// - `SIZE` - length of the (fragmented) UDP packet
 /// - `ADDR` - target (from client) or source (from server) address
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Packet {
     assoc_id: u16,
     pkt_id: u16,
@@ -0,0 +1,54 @@
+/// Command `Padding`
+///
+/// ```plain
+/// +---------+----------+
+/// | PAD_LEN |   PAD    |
+/// +---------+----------+
+/// |    2    | Variable |
+/// +---------+----------+
+/// ```
+///
+/// where:
+///
+/// - `PAD_LEN` - length of the padding
+/// - `PAD` - the padding, filled with zeros
+///
+/// Prefixes another command in the same stream or datagram to hide its length.
+/// It is skipped when unmarshalling, so the command following it is returned
+/// instead. Only sent once the `PADDING` extension is negotiated
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Padding {
+    pad_len: u16,
+}
+
+impl Padding {
+    const TYPE_CODE: u8 = 0x07;
+
+    /// Creates a new `Padding` command
+    pub const fn new(pad_len: u16) -> Self {
+        Self { pad_len }
+    }
+
+    /// Returns the length of the padding
+    pub fn pad_len(&self) -> u16 {
+        self.pad_len
+    }
+
+    /// Returns the command type code
+    pub const fn type_code() -> u8 {
+        Self::TYPE_CODE
+    }
+
+    /// Returns the serialized length of the command
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        2 + self.pad_len as usize
+    }
+}
+
+impl From<Padding> for (u16,) {
+    fn from(padding: Padding) -> Self {
+        (padding.pad_len,)
+    }
+}
@@ -0,0 +1,54 @@
+/// Command `Speedtest`
+/// ```plain
+/// +----------+
+/// | DOWNLOAD |
+/// +----------+
+/// |    8     |
+/// +----------+
+/// ```
+///
+/// where:
+///
+/// - `DOWNLOAD` - the number of bytes the server sends back
+///
+/// Sent by the client in a bidirectional stream to probe the bandwidth of the
+/// connection. The client follows it with the bytes to upload and finishes its
+/// side of the stream, then the server sends `DOWNLOAD` bytes of arbitrary
+/// data and finishes the stream. Only sent once the `SPEEDTEST` extension is
+/// negotiated
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Speedtest {
+    download: u64,
+}
+
+impl Speedtest {
+    const TYPE_CODE: u8 = 0x0b;
+
+    /// Creates a new `Speedtest` command
+    pub const fn new(download: u64) -> Self {
+        Self { download }
+    }
+
+    /// Returns the number of bytes the server sends back
+    pub fn download(&self) -> u64 {
+        self.download
+    }
+
+    /// Returns the command type code
+    pub const fn type_code() -> u8 {
+        Self::TYPE_CODE
+    }
+
+    /// Returns the serialized length of the command
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        8
+    }
+}
+
+impl From<Speedtest> for (u64,) {
+    fn from(speedtest: Speedtest) -> Self {
+        (speedtest.download,)
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// Command `Status`
+/// ```plain
+/// +--------+
+/// | STATUS |
+/// +--------+
+/// |   1    |
+/// +--------+
+/// ```
+///
+/// where:
+///
+/// - `STATUS` - the outcome of a `Connect`, see [`ConnectStatus`]
+///
+/// Sent by the client right before a `Connect` command, in the same stream and
+/// with `STATUS` set to `Succeeded`, to ask for the outcome of connecting to
+/// the target. The server then answers with a `Status` command before any
+/// relayed data. Only sent once the `CONNECT_STATUS` extension is negotiated
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Status {
+    status: ConnectStatus,
+}
+
+impl Status {
+    const TYPE_CODE: u8 = 0x0a;
+
+    /// Creates a new `Status` command
+    pub const fn new(status: ConnectStatus) -> Self {
+        Self { status }
+    }
+
+    /// Returns the outcome of the `Connect`
+    pub fn status(&self) -> ConnectStatus {
+        self.status
+    }
+
+    /// Returns the command type code
+    pub const fn type_code() -> u8 {
+        Self::TYPE_CODE
+    }
+
+    /// Returns the serialized length of the command
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        1
+    }
+}
+
+impl From<Status> for (ConnectStatus,) {
+    fn from(status: Status) -> Self {
+        (status.status,)
+    }
+}
+
+/// The outcome of a `Connect`
+///
+/// - `0x00` - `Succeeded` - connected to the target
+/// - `0x01` - `GeneralFailure` - failed for another reason
+/// - `0x02` - `Denied` - the target is not allowed for the user
+/// - `0x03` - `NetworkUnreachable` - the network of the target is unreachable
+/// - `0x04` - `HostUnreachable` - the target is unreachable
+/// - `0x05` - `ConnectionRefused` - the target refused the connection
+/// - `0x06` - `TimedOut` - connecting to the target timed out
+/// - `0x07` - `DnsFailure` - the domain name of the target can not be resolved
+/// - `0x08` - `QuotaExceeded` - the traffic quota of the user is used up
+///
+/// Codes unknown to the receiver are kept as `Other` and are failures
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ConnectStatus {
+    Succeeded,
+    GeneralFailure,
+    Denied,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TimedOut,
+    DnsFailure,
+    QuotaExceeded,
+    Other(u8),
+}
+
+impl ConnectStatus {
+    /// Returns the status code
+    pub const fn code(self) -> u8 {
+        match self {
+            Self::Succeeded => 0x00,
+            Self::GeneralFailure => 0x01,
+            Self::Denied => 0x02,
+            Self::NetworkUnreachable => 0x03,
+            Self::HostUnreachable => 0x04,
+            Self::ConnectionRefused => 0x05,
+            Self::TimedOut => 0x06,
+            Self::DnsFailure => 0x07,
+            Self::QuotaExceeded => 0x08,
+            Self::Other(code) => code,
+        }
+    }
+
+    /// Returns `true` if connected to the target
+    pub const fn is_success(self) -> bool {
+        matches!(self, Self::Succeeded)
+    }
+}
+
+impl From<u8> for ConnectStatus {
+    fn from(code: u8) -> Self {
+        match code {
+            0x00 => Self::Succeeded,
+            0x01 => Self::GeneralFailure,
+            0x02 => Self::Denied,
+            0x03 => Self::NetworkUnreachable,
+            0x04 => Self::HostUnreachable,
+            0x05 => Self::ConnectionRefused,
+            0x06 => Self::TimedOut,
+            0x07 => Self::DnsFailure,
+            0x08 => Self::QuotaExceeded,
+            code => Self::Other(code),
+        }
+    }
+}
+
+impl Display for ConnectStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Succeeded => write!(f, "succeeded"),
+            Self::GeneralFailure => write!(f, "general failure"),
+            Self::Denied => write!(f, "denied"),
+            Self::NetworkUnreachable => write!(f, "network unreachable"),
+            Self::HostUnreachable => write!(f, "host unreachable"),
+            Self::ConnectionRefused => write!(f, "connection refused"),
+            Self::TimedOut => write!(f, "timed out"),
+            Self::DnsFailure => write!(f, "DNS failure"),
+            Self::QuotaExceeded => write!(f, "quota exceeded"),
+            Self::Other(code) => write!(f, "unknown failure ({code:#04x})"),
+        }
+    }
+}
@@ -0,0 +1,412 @@
+//! A strict parser of the wire format from byte slices, reporting where and
+//! in which field malformed input fails, e.g. for fuzzing
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    str,
+};
+
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::{
+    Address, Authenticate, Batch, Bond, Capabilities, Compress, Connect, ConnectStatus, Dissociate,
+    Header, Heartbeat, Negotiate, Notice, NoticeKind, Packet, Speedtest, Status, VERSION,
+};
+
+impl Header {
+    /// Parses a header from the front of `buf`, returning it along with the
+    /// number of bytes it takes. `Padding` commands are skipped, returning the
+    /// command following them
+    ///
+    /// Unlike `Header::unmarshal()`, never reads past `buf` or allocates from
+    /// a length field, and also rejects values that are well-formed but can
+    /// never be valid: a `Packet` with no fragments or a fragment ID out of
+    /// range, an empty `Batch`, an empty domain name, a `Notice` message not in
+    /// UTF-8 and a `Heartbeat` with partial optional fields
+    pub fn parse_strict(buf: &[u8]) -> Result<(Self, usize), ParseError> {
+        let mut c = Cursor::new(buf);
+
+        let cmd = loop {
+            let at = c.pos;
+            let ver = c.u8("VER")?;
+            if ver != VERSION {
+                return Err(ParseError::new(
+                    at,
+                    "VER",
+                    ParseErrorKind::InvalidVersion(ver),
+                ));
+            }
+
+            let cmd = c.u8("TYPE")?;
+            if cmd != Header::TYPE_CODE_PADDING {
+                break cmd;
+            }
+
+            let pad_len = c.u16("PAD_LEN")?;
+            c.take(pad_len as usize, "PAD")?;
+        };
+
+        let header = match cmd {
+            Header::TYPE_CODE_AUTHENTICATE => {
+                let uuid = Uuid::from_slice(c.take(16, "UUID")?).unwrap();
+                let token = c.take(32, "TOKEN")?.try_into().unwrap();
+                Self::Authenticate(Authenticate::new(uuid, token))
+            }
+            Header::TYPE_CODE_CONNECT => Self::Connect(Connect::new(c.address()?)),
+            Header::TYPE_CODE_PACKET => {
+                let assoc_id = c.u16("ASSOC_ID")?;
+                let pkt_id = c.u16("PKT_ID")?;
+
+                let at = c.pos;
+                let frag_total = c.u8("FRAG_TOTAL")?;
+                if frag_total == 0 {
+                    return Err(ParseError::new(
+                        at,
+                        "FRAG_TOTAL",
+                        ParseErrorKind::OutOfRange,
+                    ));
+                }
+
+                let at = c.pos;
+                let frag_id = c.u8("FRAG_ID")?;
+                if frag_id >= frag_total {
+                    return Err(ParseError::new(at, "FRAG_ID", ParseErrorKind::OutOfRange));
+                }
+
+                let size = c.u16("SIZE")?;
+                let addr = c.address()?;
+                Self::Packet(Packet::new(
+                    assoc_id, pkt_id, frag_total, frag_id, size, addr,
+                ))
+            }
+            Header::TYPE_CODE_DISSOCIATE => Self::Dissociate(Dissociate::new(c.u16("ASSOC_ID")?)),
+            Header::TYPE_CODE_HEARTBEAT => {
+                // the optional fields take the rest of the datagram
+                let heartbeat = match c.remaining() {
+                    0 => Heartbeat::new(),
+                    9 => {
+                        let kind = c.u8("KIND")?;
+                        let timestamp = c.u64("TIMESTAMP")?;
+                        match kind {
+                            Heartbeat::KIND_REQUEST => Heartbeat::with_timestamp(timestamp),
+                            Heartbeat::KIND_ECHO => Heartbeat::echo(timestamp),
+                            // unknown kinds are left for future use
+                            _ => Heartbeat::new(),
+                        }
+                    }
+                    1..=8 => {
+                        return Err(ParseError::new(
+                            c.pos,
+                            "TIMESTAMP",
+                            ParseErrorKind::Truncated,
+                        ));
+                    }
+                    _ => return Err(ParseError::new(c.pos, "KIND", ParseErrorKind::OutOfRange)),
+                };
+                Self::Heartbeat(heartbeat)
+            }
+            Header::TYPE_CODE_NOTICE => {
+                let kind = NoticeKind::from(c.u8("KIND")?);
+                let len = c.u16("MSG_LEN")?;
+                let at = c.pos;
+                let message = str::from_utf8(c.take(len as usize, "MSG")?)
+                    .map_err(|_| ParseError::new(at, "MSG", ParseErrorKind::InvalidUtf8))?;
+                Self::Notice(Notice::new(kind, message.to_owned()))
+            }
+            Header::TYPE_CODE_NEGOTIATE => {
+                let version = c.u8("VERSION")?;
+                let caps = Capabilities::from_bits(c.u64("CAPS")?);
+                Self::Negotiate(Negotiate::new(version, caps))
+            }
+            Header::TYPE_CODE_COMPRESS => Self::Compress(Compress::new()),
+            Header::TYPE_CODE_BATCH => {
+                let assoc_id = c.u16("ASSOC_ID")?;
+
+                let at = c.pos;
+                let count = c.u8("COUNT")?;
+                if count == 0 {
+                    return Err(ParseError::new(at, "COUNT", ParseErrorKind::OutOfRange));
+                }
+
+                Self::Batch(Batch::new(assoc_id, count, c.address()?))
+            }
+            Header::TYPE_CODE_STATUS => {
+                Self::Status(Status::new(ConnectStatus::from(c.u8("STATUS")?)))
+            }
+            Header::TYPE_CODE_SPEEDTEST => Self::Speedtest(Speedtest::new(c.u64("DOWNLOAD")?)),
+            Header::TYPE_CODE_BOND => Self::Bond(Bond::new(c.u64("BOND_ID")?)),
+            _ => {
+                return Err(ParseError::new(
+                    c.pos - 1,
+                    "TYPE",
+                    ParseErrorKind::InvalidCommand(cmd),
+                ));
+            }
+        };
+
+        Ok((header, c.pos))
+    }
+}
+
+/// A read position in the input of the strict parser
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    fn take(&mut self, len: usize, field: &'static str) -> Result<&'a [u8], ParseError> {
+        if self.remaining() < len {
+            return Err(ParseError::new(self.pos, field, ParseErrorKind::Truncated));
+        }
+
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self, field: &'static str) -> Result<u8, ParseError> {
+        Ok(self.take(1, field)?[0])
+    }
+
+    fn u16(&mut self, field: &'static str) -> Result<u16, ParseError> {
+        Ok(u16::from_be_bytes(self.take(2, field)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self, field: &'static str) -> Result<u64, ParseError> {
+        Ok(u64::from_be_bytes(self.take(8, field)?.try_into().unwrap()))
+    }
+
+    fn address(&mut self) -> Result<Address, ParseError> {
+        let at = self.pos;
+        let type_code = self.u8("ADDR")?;
+
+        let ip = match type_code {
+            Address::TYPE_CODE_NONE => return Ok(Address::None),
+            Address::TYPE_CODE_DOMAIN => {
+                let at = self.pos;
+                let len = self.u8("ADDR")?;
+                if len == 0 {
+                    return Err(ParseError::new(at, "ADDR", ParseErrorKind::OutOfRange));
+                }
+
+                let at = self.pos;
+                let domain = str::from_utf8(self.take(len as usize, "ADDR")?)
+                    .map_err(|_| ParseError::new(at, "ADDR", ParseErrorKind::InvalidUtf8))?;
+                let port = self.u16("PORT")?;
+                return Ok(Address::DomainAddress(domain.to_owned(), port));
+            }
+            Address::TYPE_CODE_IPV4 => {
+                let ip: [u8; 4] = self.take(4, "ADDR")?.try_into().unwrap();
+                Ipv4Addr::from(ip).into()
+            }
+            Address::TYPE_CODE_IPV6 => {
+                let ip: [u8; 16] = self.take(16, "ADDR")?.try_into().unwrap();
+                Ipv6Addr::from(ip).into()
+            }
+            _ => {
+                return Err(ParseError::new(
+                    at,
+                    "ADDR",
+                    ParseErrorKind::InvalidAddressType(type_code),
+                ));
+            }
+        };
+
+        let port = self.u16("PORT")?;
+        Ok(Address::SocketAddress(SocketAddr::new(ip, port)))
+    }
+}
+
+/// Malformed input found by `Header::parse_strict()`
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("malformed `{field}` at byte {offset}: {kind}")]
+pub struct ParseError {
+    /// Offset in the input of the first byte of the field
+    pub offset: usize,
+    /// Name of the field in the specification, e.g. `FRAG_TOTAL`
+    pub field: &'static str,
+    pub kind: ParseErrorKind,
+}
+
+impl ParseError {
+    fn new(offset: usize, field: &'static str, kind: ParseErrorKind) -> Self {
+        Self {
+            offset,
+            field,
+            kind,
+        }
+    }
+}
+
+/// What is wrong with the field a `ParseError` is found in
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    #[error("input ends within the field")]
+    Truncated,
+    #[error("invalid version: {0}")]
+    InvalidVersion(u8),
+    #[error("invalid command: {0}")]
+    InvalidCommand(u8),
+    #[error("invalid address type: {0}")]
+    InvalidAddressType(u8),
+    #[error("invalid UTF-8")]
+    InvalidUtf8,
+    #[error("value out of range")]
+    OutOfRange,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(header: &Header) -> Vec<u8> {
+        let mut buf = Vec::new();
+        header.encode(&mut buf);
+        buf
+    }
+
+    fn packet(frag_total: u8, frag_id: u8) -> Vec<u8> {
+        let addr = Address::SocketAddress(SocketAddr::from(([192, 0, 2, 1], 443)));
+        encode(&Header::Packet(Packet::new(
+            1, 2, frag_total, frag_id, 1200, addr,
+        )))
+    }
+
+    fn err(buf: &[u8]) -> ParseError {
+        Header::parse_strict(buf).unwrap_err()
+    }
+
+    #[test]
+    fn truncated() {
+        let addr = Address::DomainAddress(String::from("example.com"), 443);
+        let headers = [
+            Header::Authenticate(Authenticate::new(Uuid::nil(), [0; 32])),
+            Header::Connect(Connect::new(addr.clone())),
+            Header::Packet(Packet::new(1, 2, 3, 0, 1200, addr.clone())),
+            Header::Batch(Batch::new(1, 2, addr)),
+            Header::Notice(Notice::new(NoticeKind::Info, String::from("message"))),
+            Header::Bond(Bond::new(1)),
+        ];
+
+        for header in &headers {
+            let buf = encode(header);
+            for len in 0..buf.len() {
+                let err = err(&buf[..len]);
+                assert_eq!(
+                    err.kind,
+                    ParseErrorKind::Truncated,
+                    "{header:?} cut at {len}"
+                );
+                assert!(err.offset <= len, "{header:?} cut at {len}");
+            }
+        }
+
+        // the error points at the field the input ends in
+        let buf = encode(&headers[1]);
+        assert_eq!(
+            err(&buf[..5]),
+            ParseError::new(4, "ADDR", ParseErrorKind::Truncated)
+        );
+        assert_eq!(
+            err(&buf[..buf.len() - 1]),
+            ParseError::new(buf.len() - 2, "PORT", ParseErrorKind::Truncated)
+        );
+    }
+
+    #[test]
+    fn frag_id_out_of_range() {
+        assert!(Header::parse_strict(&packet(3, 2)).is_ok());
+
+        for (frag_total, frag_id) in [(3, 3), (3, 4), (1, u8::MAX)] {
+            assert_eq!(
+                err(&packet(frag_total, frag_id)),
+                ParseError::new(7, "FRAG_ID", ParseErrorKind::OutOfRange)
+            );
+        }
+
+        assert_eq!(
+            err(&packet(0, 0)),
+            ParseError::new(6, "FRAG_TOTAL", ParseErrorKind::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn oversized_domain() {
+        // a domain length beyond the input is never read past
+        let mut buf = vec![
+            VERSION,
+            Header::TYPE_CODE_CONNECT,
+            Address::TYPE_CODE_DOMAIN,
+            255,
+        ];
+        buf.extend_from_slice(b"example.com");
+        assert_eq!(
+            err(&buf),
+            ParseError::new(4, "ADDR", ParseErrorKind::Truncated)
+        );
+
+        let buf = [
+            VERSION,
+            Header::TYPE_CODE_CONNECT,
+            Address::TYPE_CODE_DOMAIN,
+            0,
+        ];
+        assert_eq!(
+            err(&buf),
+            ParseError::new(3, "ADDR", ParseErrorKind::OutOfRange)
+        );
+
+        let mut buf = vec![
+            VERSION,
+            Header::TYPE_CODE_CONNECT,
+            Address::TYPE_CODE_DOMAIN,
+            2,
+        ];
+        buf.extend_from_slice(&[0xff, 0xfe, 0x01, 0xbb]);
+        assert_eq!(
+            err(&buf),
+            ParseError::new(4, "ADDR", ParseErrorKind::InvalidUtf8)
+        );
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        // the payload following a header is left to the caller
+        let mut buf = encode(&Header::Connect(Connect::new(Address::None)));
+        let len = buf.len();
+        buf.extend_from_slice(b"payload");
+        let (header, parsed) = Header::parse_strict(&buf).unwrap();
+        assert!(matches!(header, Header::Connect(connect) if connect.addr() == &Address::None));
+        assert_eq!(parsed, len);
+
+        // but a heartbeat takes the rest of the datagram
+        let mut buf = encode(&Header::Heartbeat(Heartbeat::with_timestamp(1)));
+        buf.push(0);
+        assert_eq!(
+            err(&buf),
+            ParseError::new(2, "KIND", ParseErrorKind::OutOfRange)
+        );
+
+        let buf = [
+            VERSION,
+            Header::TYPE_CODE_HEARTBEAT,
+            Heartbeat::KIND_REQUEST,
+            0,
+        ];
+        assert_eq!(
+            err(&buf),
+            ParseError::new(2, "TIMESTAMP", ParseErrorKind::Truncated)
+        );
+    }
+}
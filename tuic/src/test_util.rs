@@ -0,0 +1,200 @@
+//! [proptest](https://docs.rs/proptest) generators for every command and
+//! address, with round-trip checks through the wire format
+//!
+//! Shared by the tests of this repository and meant for other implementations
+//! checking their conformance, e.g. by feeding the generated headers to their
+//! own decoder:
+//!
+//! ```no_run
+//! use proptest::prelude::*;
+//! use tuic::test_util;
+//!
+//! proptest! {
+//!     #[test]
+//!     fn round_trip(header in test_util::header()) {
+//!         test_util::check_round_trip(&header)?;
+//!     }
+//! }
+//! ```
+
+use std::net::SocketAddr;
+
+use proptest::{prelude::*, test_runner::TestCaseError};
+use uuid::Uuid;
+
+use crate::{
+    Address, Authenticate, Batch, Bond, Capabilities, Compress, Connect, ConnectStatus, Dissociate,
+    Header, Heartbeat, Negotiate, Notice, NoticeKind, Packet, Padding, Speedtest, Status,
+};
+
+/// Generates addresses of every type, with domain names of up to 255 bytes
+pub fn address() -> impl Strategy<Value = Address> {
+    prop_oneof![
+        Just(Address::None),
+        ("[a-z0-9-]{1,63}(\\.[a-z0-9-]{1,63}){0,3}", any::<u16>())
+            .prop_map(|(domain, port)| Address::DomainAddress(domain, port)),
+        any::<SocketAddr>().prop_map(|mut addr| {
+            // the flow label and scope ID of IPv6 addresses are not sent
+            if let SocketAddr::V6(addr) = &mut addr {
+                addr.set_flowinfo(0);
+                addr.set_scope_id(0);
+            }
+            Address::SocketAddress(addr)
+        }),
+    ]
+}
+
+/// Generates `Authenticate` commands
+pub fn authenticate() -> impl Strategy<Value = Authenticate> {
+    (any::<[u8; 16]>(), any::<[u8; 32]>())
+        .prop_map(|(uuid, token)| Authenticate::new(Uuid::from_bytes(uuid), token))
+}
+
+/// Generates `Connect` commands
+pub fn connect() -> impl Strategy<Value = Connect> {
+    address().prop_map(Connect::new)
+}
+
+/// Generates `Packet` commands, whose `FRAG_ID` is always below `FRAG_TOTAL`
+pub fn packet() -> impl Strategy<Value = Packet> {
+    (1..=u8::MAX)
+        .prop_flat_map(|frag_total| (Just(frag_total), 0..frag_total))
+        .prop_flat_map(|(frag_total, frag_id)| {
+            (
+                any::<u16>(),
+                any::<u16>(),
+                Just(frag_total),
+                Just(frag_id),
+                any::<u16>(),
+                address(),
+            )
+        })
+        .prop_map(|(assoc_id, pkt_id, frag_total, frag_id, size, addr)| {
+            Packet::new(assoc_id, pkt_id, frag_total, frag_id, size, addr)
+        })
+}
+
+/// Generates `Dissociate` commands
+pub fn dissociate() -> impl Strategy<Value = Dissociate> {
+    any::<u16>().prop_map(Dissociate::new)
+}
+
+/// Generates `Heartbeat` commands, empty, asking for an echo or echoing
+pub fn heartbeat() -> impl Strategy<Value = Heartbeat> {
+    prop_oneof![
+        Just(Heartbeat::new()),
+        any::<u64>().prop_map(Heartbeat::with_timestamp),
+        any::<u64>().prop_map(Heartbeat::echo),
+    ]
+}
+
+/// Generates `Notice` commands of every kind, known or not
+pub fn notice() -> impl Strategy<Value = Notice> {
+    (any::<u8>(), ".{0,64}")
+        .prop_map(|(kind, message)| Notice::new(NoticeKind::from(kind), message))
+}
+
+/// Generates `Negotiate` commands, with extension bits unknown to this crate
+/// too
+pub fn negotiate() -> impl Strategy<Value = Negotiate> {
+    (any::<u8>(), any::<u64>())
+        .prop_map(|(version, caps)| Negotiate::new(version, Capabilities::from_bits(caps)))
+}
+
+/// Generates `Padding` commands
+pub fn padding() -> impl Strategy<Value = Padding> {
+    (0..=1024u16).prop_map(Padding::new)
+}
+
+/// Generates `Batch` commands of at least one packet
+pub fn batch() -> impl Strategy<Value = Batch> {
+    (any::<u16>(), 1..=u8::MAX, address())
+        .prop_map(|(assoc_id, count, addr)| Batch::new(assoc_id, count, addr))
+}
+
+/// Generates `Status` commands with every status code, known or not
+pub fn status() -> impl Strategy<Value = Status> {
+    any::<u8>().prop_map(|code| Status::new(ConnectStatus::from(code)))
+}
+
+/// Generates `Speedtest` commands
+pub fn speedtest() -> impl Strategy<Value = Speedtest> {
+    any::<u64>().prop_map(Speedtest::new)
+}
+
+/// Generates `Bond` commands
+pub fn bond() -> impl Strategy<Value = Bond> {
+    any::<u64>().prop_map(Bond::new)
+}
+
+/// Generates headers of every command but `Padding`, which decoders skip. See
+/// [`check_padded_round_trip()`] for it
+pub fn header() -> impl Strategy<Value = Header> {
+    prop_oneof![
+        authenticate().prop_map(Header::Authenticate),
+        connect().prop_map(Header::Connect),
+        packet().prop_map(Header::Packet),
+        dissociate().prop_map(Header::Dissociate),
+        heartbeat().prop_map(Header::Heartbeat),
+        notice().prop_map(Header::Notice),
+        negotiate().prop_map(Header::Negotiate),
+        Just(Header::Compress(Compress::new())),
+        batch().prop_map(Header::Batch),
+        status().prop_map(Header::Status),
+        speedtest().prop_map(Header::Speedtest),
+        bond().prop_map(Header::Bond),
+    ]
+}
+
+/// Checks that `header` encodes to `Header::len()` bytes, and that both the
+/// lenient and the strict parser decode them back to a header encoding to the
+/// same bytes
+pub fn check_round_trip(header: &Header) -> Result<(), TestCaseError> {
+    let mut buf = Vec::new();
+    header.encode(&mut buf);
+    prop_assert_eq!(buf.len(), header.len());
+    check_decodes_to(&buf, &buf)
+}
+
+/// Checks that `header` preceded by `padding` decodes back to `header`
+pub fn check_padded_round_trip(padding: &Padding, header: &Header) -> Result<(), TestCaseError> {
+    let mut expected = Vec::new();
+    header.encode(&mut expected);
+
+    let mut buf = Vec::new();
+    Header::Padding(padding.clone()).encode(&mut buf);
+    buf.extend_from_slice(&expected);
+    check_decodes_to(&buf, &expected)
+}
+
+/// Checks that `address` encodes to `Address::len()` bytes decoding back to
+/// the same address
+pub fn check_address_round_trip(address: &Address) -> Result<(), TestCaseError> {
+    let mut buf = Vec::new();
+    address.encode(&mut buf);
+    prop_assert_eq!(buf.len(), address.len());
+
+    let decoded =
+        Address::decode(&mut &buf[..]).map_err(|err| TestCaseError::fail(err.to_string()))?;
+    prop_assert_eq!(&decoded, address);
+    Ok(())
+}
+
+fn check_decodes_to(buf: &[u8], expected: &[u8]) -> Result<(), TestCaseError> {
+    let mut rest = buf;
+    let decoded = Header::decode(&mut rest).map_err(|err| TestCaseError::fail(err.to_string()))?;
+    prop_assert!(rest.is_empty(), "{} bytes left undecoded", rest.len());
+
+    let mut reencoded = Vec::new();
+    decoded.encode(&mut reencoded);
+    prop_assert_eq!(&reencoded[..], expected);
+
+    let (strict, len) =
+        Header::parse_strict(buf).map_err(|err| TestCaseError::fail(err.to_string()))?;
+    prop_assert_eq!(len, buf.len());
+
+    let mut reencoded = Vec::new();
+    strict.encode(&mut reencoded);
+    prop_assert_eq!(&reencoded[..], expected);
+    Ok(())
+}
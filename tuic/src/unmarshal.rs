@@ -1,5 +1,5 @@
 use std::{
-    io::{Error as IoError, Read},
+    io::{Error as IoError, ErrorKind, Read},
     net::SocketAddr,
     string::FromUtf8Error,
 };
@@ -8,23 +8,35 @@ use futures_util::{AsyncRead, AsyncReadExt};
 use thiserror::Error;
 use uuid::{Error as UuidError, Uuid};
 
-use crate::{Address, Authenticate, Connect, Dissociate, Header, Heartbeat, Packet, VERSION};
+use crate::{
+    Address, Authenticate, Batch, Bond, Capabilities, Compress, Connect, ConnectStatus, Dissociate,
+    Header, Heartbeat, Negotiate, Notice, NoticeKind, Packet, Padding, Speedtest, Status, VERSION,
+};
 
 impl Header {
     /// Unmarshals a header from an `AsyncRead` stream
     #[cfg(feature = "async_marshal")]
+    /// `Padding` commands are skipped, returning the command following them
     pub async fn async_unmarshal(s: &mut (impl AsyncRead + Unpin)) -> Result<Self, UnmarshalError> {
-        let mut buf = [0; 1];
-        s.read_exact(&mut buf).await?;
-        let ver = buf[0];
+        let cmd = loop {
+            let mut buf = [0; 1];
+            s.read_exact(&mut buf).await?;
+            let ver = buf[0];
 
-        if ver != VERSION {
-            return Err(UnmarshalError::InvalidVersion(ver));
-        }
+            if ver != VERSION {
+                return Err(UnmarshalError::InvalidVersion(ver));
+            }
 
-        let mut buf = [0; 1];
-        s.read_exact(&mut buf).await?;
-        let cmd = buf[0];
+            let mut buf = [0; 1];
+            s.read_exact(&mut buf).await?;
+            let cmd = buf[0];
+
+            if cmd != Header::TYPE_CODE_PADDING {
+                break cmd;
+            }
+
+            Padding::async_skip(s).await?;
+        };
 
         match cmd {
             Header::TYPE_CODE_AUTHENTICATE => {
@@ -34,24 +46,40 @@ impl Header {
             Header::TYPE_CODE_PACKET => Packet::async_read(s).await.map(Self::Packet),
             Header::TYPE_CODE_DISSOCIATE => Dissociate::async_read(s).await.map(Self::Dissociate),
             Header::TYPE_CODE_HEARTBEAT => Heartbeat::async_read(s).await.map(Self::Heartbeat),
+            Header::TYPE_CODE_NOTICE => Notice::async_read(s).await.map(Self::Notice),
+            Header::TYPE_CODE_NEGOTIATE => Negotiate::async_read(s).await.map(Self::Negotiate),
+            Header::TYPE_CODE_COMPRESS => Compress::async_read(s).await.map(Self::Compress),
+            Header::TYPE_CODE_BATCH => Batch::async_read(s).await.map(Self::Batch),
+            Header::TYPE_CODE_STATUS => Status::async_read(s).await.map(Self::Status),
+            Header::TYPE_CODE_SPEEDTEST => Speedtest::async_read(s).await.map(Self::Speedtest),
+            Header::TYPE_CODE_BOND => Bond::async_read(s).await.map(Self::Bond),
             _ => Err(UnmarshalError::InvalidCommand(cmd)),
         }
     }
 
     /// Unmarshals a header from a `Read` stream
     #[cfg(feature = "marshal")]
+    /// `Padding` commands are skipped, returning the command following them
     pub fn unmarshal(s: &mut impl Read) -> Result<Self, UnmarshalError> {
-        let mut buf = [0; 1];
-        s.read_exact(&mut buf)?;
-        let ver = buf[0];
+        let cmd = loop {
+            let mut buf = [0; 1];
+            s.read_exact(&mut buf)?;
+            let ver = buf[0];
 
-        if ver != VERSION {
-            return Err(UnmarshalError::InvalidVersion(ver));
-        }
+            if ver != VERSION {
+                return Err(UnmarshalError::InvalidVersion(ver));
+            }
 
-        let mut buf = [0; 1];
-        s.read_exact(&mut buf)?;
-        let cmd = buf[0];
+            let mut buf = [0; 1];
+            s.read_exact(&mut buf)?;
+            let cmd = buf[0];
+
+            if cmd != Header::TYPE_CODE_PADDING {
+                break cmd;
+            }
+
+            Padding::skip(s)?;
+        };
 
         match cmd {
             Header::TYPE_CODE_AUTHENTICATE => Authenticate::read(s).map(Self::Authenticate),
@@ -59,6 +87,13 @@ impl Header {
             Header::TYPE_CODE_PACKET => Packet::read(s).map(Self::Packet),
             Header::TYPE_CODE_DISSOCIATE => Dissociate::read(s).map(Self::Dissociate),
             Header::TYPE_CODE_HEARTBEAT => Heartbeat::read(s).map(Self::Heartbeat),
+            Header::TYPE_CODE_NOTICE => Notice::read(s).map(Self::Notice),
+            Header::TYPE_CODE_NEGOTIATE => Negotiate::read(s).map(Self::Negotiate),
+            Header::TYPE_CODE_COMPRESS => Compress::read(s).map(Self::Compress),
+            Header::TYPE_CODE_BATCH => Batch::read(s).map(Self::Batch),
+            Header::TYPE_CODE_STATUS => Status::read(s).map(Self::Status),
+            Header::TYPE_CODE_SPEEDTEST => Speedtest::read(s).map(Self::Speedtest),
+            Header::TYPE_CODE_BOND => Bond::read(s).map(Self::Bond),
             _ => Err(UnmarshalError::InvalidCommand(cmd)),
         }
     }
@@ -115,7 +150,7 @@ impl Address {
     }
 
     #[cfg(feature = "marshal")]
-    fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
+    pub(crate) fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
         let mut buf = [0; 1];
         s.read_exact(&mut buf)?;
         let type_code = buf[0];
@@ -175,7 +210,7 @@ impl Authenticate {
     }
 
     #[cfg(feature = "marshal")]
-    fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
+    pub(crate) fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
         let mut buf = [0; 48];
         s.read_exact(&mut buf)?;
         let uuid = Uuid::from_slice(&buf[..16])?;
@@ -191,7 +226,7 @@ impl Connect {
     }
 
     #[cfg(feature = "marshal")]
-    fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
+    pub(crate) fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
         Ok(Self::new(Address::read(s)?))
     }
 }
@@ -213,7 +248,7 @@ impl Packet {
     }
 
     #[cfg(feature = "marshal")]
-    fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
+    pub(crate) fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
         let mut buf = [0; 8];
         s.read_exact(&mut buf)?;
 
@@ -238,7 +273,7 @@ impl Dissociate {
     }
 
     #[cfg(feature = "marshal")]
-    fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
+    pub(crate) fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
         let mut buf = [0; 2];
         s.read_exact(&mut buf)?;
         let assoc_id = u16::from_be_bytes(buf);
@@ -247,17 +282,216 @@ impl Dissociate {
 }
 
 impl Heartbeat {
+    #[cfg(feature = "async_marshal")]
+    async fn async_read(s: &mut (impl AsyncRead + Unpin)) -> Result<Self, UnmarshalError> {
+        // the optional fields take the rest of the datagram
+        let mut buf = [0; 9];
+        let mut len = 0;
+        while len < buf.len() {
+            match s.read(&mut buf[len..]).await? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        Self::from_optional_fields(&buf[..len])
+    }
+
+    #[cfg(feature = "marshal")]
+    pub(crate) fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
+        // the optional fields take the rest of the datagram
+        let mut buf = [0; 9];
+        let mut len = 0;
+        while len < buf.len() {
+            match s.read(&mut buf[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        Self::from_optional_fields(&buf[..len])
+    }
+
+    fn from_optional_fields(buf: &[u8]) -> Result<Self, UnmarshalError> {
+        match buf {
+            [] => Ok(Self::new()),
+            [kind, timestamp @ ..] if timestamp.len() == 8 => {
+                let timestamp = u64::from_be_bytes(timestamp.try_into().unwrap());
+                match *kind {
+                    Self::KIND_REQUEST => Ok(Self::with_timestamp(timestamp)),
+                    Self::KIND_ECHO => Ok(Self::echo(timestamp)),
+                    // unknown kinds are left for future use
+                    _ => Ok(Self::new()),
+                }
+            }
+            _ => Err(UnmarshalError::Io(IoError::from(ErrorKind::UnexpectedEof))),
+        }
+    }
+}
+
+impl Batch {
+    #[cfg(feature = "async_marshal")]
+    async fn async_read(s: &mut (impl AsyncRead + Unpin)) -> Result<Self, UnmarshalError> {
+        let mut buf = [0; 3];
+        s.read_exact(&mut buf).await?;
+
+        let assoc_id = u16::from_be_bytes([buf[0], buf[1]]);
+        let count = buf[2];
+        let addr = Address::async_read(s).await?;
+
+        Ok(Self::new(assoc_id, count, addr))
+    }
+
+    #[cfg(feature = "marshal")]
+    pub(crate) fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
+        let mut buf = [0; 3];
+        s.read_exact(&mut buf)?;
+
+        let assoc_id = u16::from_be_bytes([buf[0], buf[1]]);
+        let count = buf[2];
+        let addr = Address::read(s)?;
+
+        Ok(Self::new(assoc_id, count, addr))
+    }
+}
+
+impl Compress {
     #[cfg(feature = "async_marshal")]
     async fn async_read(_s: &mut (impl AsyncRead + Unpin)) -> Result<Self, UnmarshalError> {
         Ok(Self::new())
     }
 
     #[cfg(feature = "marshal")]
-    fn read(_s: &mut impl Read) -> Result<Self, UnmarshalError> {
+    pub(crate) fn read(_s: &mut impl Read) -> Result<Self, UnmarshalError> {
         Ok(Self::new())
     }
 }
 
+impl Padding {
+    #[cfg(feature = "async_marshal")]
+    async fn async_skip(s: &mut (impl AsyncRead + Unpin)) -> Result<(), UnmarshalError> {
+        let mut buf = [0; 2];
+        s.read_exact(&mut buf).await?;
+        let pad_len = u16::from_be_bytes(buf);
+
+        let mut buf = vec![0; pad_len as usize];
+        s.read_exact(&mut buf).await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "marshal")]
+    fn skip(s: &mut impl Read) -> Result<(), UnmarshalError> {
+        Self::read(s).map(|_| ())
+    }
+
+    #[cfg(feature = "marshal")]
+    pub(crate) fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
+        let mut buf = [0; 2];
+        s.read_exact(&mut buf)?;
+        let pad_len = u16::from_be_bytes(buf);
+
+        let mut buf = vec![0; pad_len as usize];
+        s.read_exact(&mut buf)?;
+        Ok(Self::new(pad_len))
+    }
+}
+
+impl Negotiate {
+    #[cfg(feature = "async_marshal")]
+    async fn async_read(s: &mut (impl AsyncRead + Unpin)) -> Result<Self, UnmarshalError> {
+        let mut buf = [0; 9];
+        s.read_exact(&mut buf).await?;
+        let version = buf[0];
+        let caps = u64::from_be_bytes(buf[1..].try_into().unwrap());
+        Ok(Self::new(version, Capabilities::from_bits(caps)))
+    }
+
+    #[cfg(feature = "marshal")]
+    pub(crate) fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
+        let mut buf = [0; 9];
+        s.read_exact(&mut buf)?;
+        let version = buf[0];
+        let caps = u64::from_be_bytes(buf[1..].try_into().unwrap());
+        Ok(Self::new(version, Capabilities::from_bits(caps)))
+    }
+}
+
+impl Bond {
+    #[cfg(feature = "async_marshal")]
+    async fn async_read(s: &mut (impl AsyncRead + Unpin)) -> Result<Self, UnmarshalError> {
+        let mut buf = [0; 8];
+        s.read_exact(&mut buf).await?;
+        Ok(Self::new(u64::from_be_bytes(buf)))
+    }
+
+    #[cfg(feature = "marshal")]
+    pub(crate) fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
+        let mut buf = [0; 8];
+        s.read_exact(&mut buf)?;
+        Ok(Self::new(u64::from_be_bytes(buf)))
+    }
+}
+
+impl Speedtest {
+    #[cfg(feature = "async_marshal")]
+    async fn async_read(s: &mut (impl AsyncRead + Unpin)) -> Result<Self, UnmarshalError> {
+        let mut buf = [0; 8];
+        s.read_exact(&mut buf).await?;
+        Ok(Self::new(u64::from_be_bytes(buf)))
+    }
+
+    #[cfg(feature = "marshal")]
+    pub(crate) fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
+        let mut buf = [0; 8];
+        s.read_exact(&mut buf)?;
+        Ok(Self::new(u64::from_be_bytes(buf)))
+    }
+}
+
+impl Status {
+    #[cfg(feature = "async_marshal")]
+    async fn async_read(s: &mut (impl AsyncRead + Unpin)) -> Result<Self, UnmarshalError> {
+        let mut buf = [0; 1];
+        s.read_exact(&mut buf).await?;
+        Ok(Self::new(ConnectStatus::from(buf[0])))
+    }
+
+    #[cfg(feature = "marshal")]
+    pub(crate) fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
+        let mut buf = [0; 1];
+        s.read_exact(&mut buf)?;
+        Ok(Self::new(ConnectStatus::from(buf[0])))
+    }
+}
+
+impl Notice {
+    #[cfg(feature = "async_marshal")]
+    async fn async_read(s: &mut (impl AsyncRead + Unpin)) -> Result<Self, UnmarshalError> {
+        let mut buf = [0; 3];
+        s.read_exact(&mut buf).await?;
+        let kind = NoticeKind::from(buf[0]);
+        let len = u16::from_be_bytes([buf[1], buf[2]]) as usize;
+
+        let mut buf = vec![0; len];
+        s.read_exact(&mut buf).await?;
+        let message = String::from_utf8_lossy(&buf).into_owned();
+
+        Ok(Self::new(kind, message))
+    }
+
+    #[cfg(feature = "marshal")]
+    pub(crate) fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
+        let mut buf = [0; 3];
+        s.read_exact(&mut buf)?;
+        let kind = NoticeKind::from(buf[0]);
+        let len = u16::from_be_bytes([buf[1], buf[2]]) as usize;
+
+        let mut buf = vec![0; len];
+        s.read_exact(&mut buf)?;
+        let message = String::from_utf8_lossy(&buf).into_owned();
+
+        Ok(Self::new(kind, message))
+    }
+}
+
 /// Errors that can occur when unmarshalling a packet
 #[derive(Debug, Error)]
 pub enum UnmarshalError {
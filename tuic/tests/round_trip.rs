@@ -0,0 +1,22 @@
+use proptest::prelude::*;
+use tuic::test_util;
+
+proptest! {
+    #[test]
+    fn header_round_trip(header in test_util::header()) {
+        test_util::check_round_trip(&header)?;
+    }
+
+    #[test]
+    fn padded_header_round_trip(
+        padding in test_util::padding(),
+        header in test_util::header(),
+    ) {
+        test_util::check_padded_round_trip(&padding, &header)?;
+    }
+
+    #[test]
+    fn address_round_trip(address in test_util::address()) {
+        test_util::check_address_round_trip(&address)?;
+    }
+}